@@ -29,6 +29,15 @@ pub unsafe extern "C" fn diode_new_config(
         diode: aux::DiodeSend::Tcp(socket_addr),
         buffer_size: buffer_size as usize,
         hash: false,
+        format: file::Format::Native,
+        keep_partial: false,
+        atomic_rename: false,
+        mmap: false,
+        untar: false,
+        max_open_files: None,
+        print_hash: None,
+        sparse: false,
+        header_checksum: false,
     });
     Box::into_raw(config)
 }
@@ -92,6 +101,15 @@ pub unsafe extern "C" fn diode_receive_files(
         },
         buffer_size: config.buffer_size,
         hash: false,
+        format: config.format,
+        keep_partial: false,
+        atomic_rename: false,
+        mmap: false,
+        untar: false,
+        max_open_files: None,
+        print_hash: None,
+        sparse: false,
+        header_checksum: false,
     };
 
     if ptr_odir.is_null() {
@@ -0,0 +1,157 @@
+//! Minimal CBOR (RFC 8949) encoding and decoding of the data items used by the `cbor` file
+//! metadata format
+//!
+//! Only definite-length items are produced and accepted. Integers are always encoded in their
+//! shortest form, so that the produced metadata is valid deterministic CBOR.
+
+use crate::aux::file::protocol::Error;
+use std::io::{self, Read, Write};
+
+pub(crate) const MAJOR_UINT: u8 = 0;
+const MAJOR_NEGINT: u8 = 1;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+pub(crate) const MAJOR_ARRAY: u8 = 4;
+pub(crate) const MAJOR_MAP: u8 = 5;
+const MAJOR_TAG: u8 = 6;
+const MAJOR_SIMPLE: u8 = 7;
+
+const SIMPLE_NULL: u8 = 22;
+
+/// Upper bound of decoded byte and text strings lengths
+const MAX_LEN: u64 = 64 * 1024;
+/// Upper bound of decoded maps and arrays sizes
+pub(crate) const MAX_ITEMS: u64 = 64;
+/// Upper bound of nested arrays, maps and tags when skipping unknown items
+const MAX_DEPTH: usize = 8;
+
+pub(crate) enum Value {
+    Uint(u64),
+    /// Negative integer `-1 - n`
+    NegInt(u64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Null,
+    Other,
+}
+
+pub(crate) fn write_head<W: Write>(w: &mut W, major: u8, arg: u64) -> Result<(), io::Error> {
+    let major = major << 5;
+    let bytes = arg.to_be_bytes();
+    if arg < 24 {
+        w.write_all(&[major | bytes[7]])
+    } else if u8::try_from(arg).is_ok() {
+        w.write_all(&[major | 0x18, bytes[7]])
+    } else if u16::try_from(arg).is_ok() {
+        w.write_all(&[major | 0x19])?;
+        w.write_all(&bytes[6..])
+    } else if u32::try_from(arg).is_ok() {
+        w.write_all(&[major | 0x1a])?;
+        w.write_all(&bytes[4..])
+    } else {
+        w.write_all(&[major | 0x1b])?;
+        w.write_all(&bytes)
+    }
+}
+
+pub(crate) fn write_uint<W: Write>(w: &mut W, value: u64) -> Result<(), io::Error> {
+    write_head(w, MAJOR_UINT, value)
+}
+
+pub(crate) fn write_int<W: Write>(w: &mut W, value: i64) -> Result<(), io::Error> {
+    if value < 0 {
+        write_head(w, MAJOR_NEGINT, (-1 - value).cast_unsigned())
+    } else {
+        write_head(w, MAJOR_UINT, value.cast_unsigned())
+    }
+}
+
+pub(crate) fn write_bytes<W: Write>(w: &mut W, value: &[u8]) -> Result<(), io::Error> {
+    write_head(w, MAJOR_BYTES, value.len() as u64)?;
+    w.write_all(value)
+}
+
+pub(crate) fn write_text<W: Write>(w: &mut W, value: &str) -> Result<(), io::Error> {
+    write_head(w, MAJOR_TEXT, value.len() as u64)?;
+    w.write_all(value.as_bytes())
+}
+
+pub(crate) fn write_null<W: Write>(w: &mut W) -> Result<(), io::Error> {
+    w.write_all(&[(MAJOR_SIMPLE << 5) | SIMPLE_NULL])
+}
+
+/// Reads an item head, returning its major type, additional information and argument
+pub(crate) fn read_head<R: Read>(r: &mut R) -> Result<(u8, u8, u64), Error> {
+    let mut initial = [0u8; 1];
+    r.read_exact(&mut initial)?;
+    let major = initial[0] >> 5;
+    let info = initial[0] & 0x1f;
+
+    let len = match info {
+        0..=23 => return Ok((major, info, u64::from(info))),
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        _ => {
+            return Err(Error::InvalidMetadata(format!(
+                "unsupported CBOR additional information {info}"
+            )));
+        }
+    };
+
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes[8 - len..])?;
+    Ok((major, info, u64::from_be_bytes(bytes)))
+}
+
+fn read_string<R: Read>(r: &mut R, len: u64) -> Result<Vec<u8>, Error> {
+    if MAX_LEN < len {
+        return Err(Error::InvalidMetadata(format!(
+            "CBOR string too long ({len} bytes)"
+        )));
+    }
+    let mut value =
+        vec![0; usize::try_from(len).map_err(|e| Error::InvalidMetadata(e.to_string()))?];
+    r.read_exact(&mut value)?;
+    Ok(value)
+}
+
+pub(crate) fn read_value<R: Read>(r: &mut R) -> Result<Value, Error> {
+    read_value_at(r, 0)
+}
+
+fn read_value_at<R: Read>(r: &mut R, depth: usize) -> Result<Value, Error> {
+    if MAX_DEPTH < depth {
+        return Err(Error::InvalidMetadata(
+            "CBOR items nested too deeply".into(),
+        ));
+    }
+
+    let (major, info, arg) = read_head(r)?;
+
+    match major {
+        MAJOR_UINT => Ok(Value::Uint(arg)),
+        MAJOR_NEGINT => Ok(Value::NegInt(arg)),
+        MAJOR_BYTES => Ok(Value::Bytes(read_string(r, arg)?)),
+        MAJOR_TEXT => Ok(Value::Text(String::from_utf8(read_string(r, arg)?)?)),
+        MAJOR_ARRAY | MAJOR_MAP => {
+            if MAX_ITEMS < arg {
+                return Err(Error::InvalidMetadata(format!(
+                    "CBOR array or map too large ({arg} items)"
+                )));
+            }
+            let nb_items = if major == MAJOR_MAP { 2 * arg } else { arg };
+            for _ in 0..nb_items {
+                read_value_at(r, depth + 1)?;
+            }
+            Ok(Value::Other)
+        }
+        MAJOR_TAG => {
+            read_value_at(r, depth + 1)?;
+            Ok(Value::Other)
+        }
+        _ if info == SIMPLE_NULL => Ok(Value::Null),
+        _ => Ok(Value::Other),
+    }
+}
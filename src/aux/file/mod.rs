@@ -1,14 +1,132 @@
 //! Module for sending/receiving entire files into/from Lidi TCP or Unix sockets
+mod cbor;
 pub mod protocol;
 pub mod receive;
 pub mod send;
 
-use std::{fmt, io, num};
+use std::{fmt, io, num, str::FromStr};
 
+#[allow(clippy::struct_excessive_bools)]
 pub struct Config<D> {
     pub diode: D,
     pub buffer_size: usize,
     pub hash: bool,
+    /// Send a checksum of the header, so that a corrupted header is rejected before any file
+    /// is created; both sides must use the same setting
+    pub header_checksum: bool,
+    pub format: Format,
+    /// On the receiving side, rename incomplete files with a `.partial` suffix instead of
+    /// leaving them under their original name
+    pub keep_partial: bool,
+    /// On the receiving side, write files under a hidden temporary name, renamed to their
+    /// final name once completely received (and their hash verified)
+    pub atomic_rename: bool,
+    /// Only transfer data extents of files, holes being recreated on the receiving side; both
+    /// sides must use the same setting
+    pub sparse: bool,
+    /// On the sending side, memory-map files instead of reading them into a buffer; ignored
+    /// with `sparse`
+    pub mmap: bool,
+    /// On the receiving side, extract incoming streams as tar archives into the output
+    /// directory instead of expecting files sent by the sending side
+    pub untar: bool,
+    /// On the receiving side, maximum number of files written at the same time, further
+    /// transfers waiting for one of them to complete
+    pub max_open_files: Option<u32>,
+    /// On the receiving side, print the hash of every file to the standard output once it is
+    /// verified, in the given encoding; ignored without `hash`
+    pub print_hash: Option<HashEncoding>,
+}
+
+/// Encoding of the metadata sent before and after the file content, both sides must use the
+/// same format
+#[derive(Clone, Copy)]
+pub enum Format {
+    /// Little-endian binary header and footer
+    Native,
+    /// CBOR (RFC 8949) maps, parsable with any standard CBOR library
+    Cbor,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "native" => Ok(Self::Native),
+            "cbor" => Ok(Self::Cbor),
+            _ => Err(format!("unknown format \"{s}\"")),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Native => write!(fmt, "native"),
+            Self::Cbor => write!(fmt, "cbor"),
+        }
+    }
+}
+
+/// Encoding of the file hashes printed by the receiving side
+#[derive(Clone, Copy)]
+pub enum HashEncoding {
+    /// Lowercase hexadecimal digits of the big-endian hash
+    Hex,
+    /// Standard base64 (RFC 4648, with padding) of the big-endian hash
+    Base64,
+}
+
+impl HashEncoding {
+    /// Encodes `hash`, taken as a big-endian 16 bytes string as in `cbor` footers
+    pub fn encode(self, hash: u128) -> String {
+        const ALPHABET: &[u8; 64] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+        match self {
+            Self::Hex => format!("{hash:032x}"),
+            Self::Base64 => {
+                let bytes = hash.to_be_bytes();
+                let mut encoded = String::with_capacity(24);
+                for chunk in bytes.chunks(3) {
+                    let mut group = [0; 3];
+                    group[..chunk.len()].copy_from_slice(chunk);
+                    let group = u32::from_be_bytes([0, group[0], group[1], group[2]]);
+                    for i in 0..4 {
+                        if i <= chunk.len() {
+                            let index = (group >> (18 - 6 * i)) & 0x3f;
+                            encoded.push(char::from(ALPHABET[index as usize]));
+                        } else {
+                            encoded.push('=');
+                        }
+                    }
+                }
+                encoded
+            }
+        }
+    }
+}
+
+impl FromStr for HashEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hex" => Ok(Self::Hex),
+            "base64" => Ok(Self::Base64),
+            _ => Err(format!("unknown hash encoding \"{s}\"")),
+        }
+    }
+}
+
+impl fmt::Display for HashEncoding {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Hex => write!(fmt, "hex"),
+            Self::Base64 => write!(fmt, "base64"),
+        }
+    }
 }
 
 pub enum Error {
@@ -44,3 +162,34 @@ impl From<num::TryFromIntError> for Error {
         Self::Other(e.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_encodings() {
+        let hash = 0x0001_0203_0405_0607_0809_0a0b_0c0d_0e0f;
+        assert_eq!(
+            HashEncoding::Hex.encode(hash),
+            "000102030405060708090a0b0c0d0e0f"
+        );
+        assert_eq!(
+            HashEncoding::Base64.encode(hash),
+            "AAECAwQFBgcICQoLDA0ODw=="
+        );
+        assert_eq!(
+            HashEncoding::Base64.encode(u128::MAX),
+            "/////////////////////w=="
+        );
+
+        for encoding in [HashEncoding::Hex, HashEncoding::Base64] {
+            let parsed = encoding.to_string().parse::<HashEncoding>();
+            assert_eq!(
+                parsed.map(|parsed| parsed.encode(hash)),
+                Ok(encoding.encode(hash))
+            );
+        }
+        assert!("base32".parse::<HashEncoding>().is_err());
+    }
+}
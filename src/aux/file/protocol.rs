@@ -1,5 +1,9 @@
+use crate::aux::file::{Format, cbor};
+use fasthash::HasherExt;
 use std::{
-    fmt, io,
+    fmt,
+    hash::Hash,
+    io,
     io::{Read, Write},
     string::FromUtf8Error,
 };
@@ -9,6 +13,8 @@ pub enum Error {
     StringFormatError(FromUtf8Error),
     InvalidFileSize(usize, usize),
     InvalidHash(u128, u128),
+    InvalidHeaderChecksum(u128, u128),
+    InvalidMetadata(String),
 }
 
 impl fmt::Display for Error {
@@ -18,6 +24,10 @@ impl fmt::Display for Error {
             Self::StringFormatError(e) => write!(fmt, "string format error: {e}"),
             Self::InvalidFileSize(s1, s2) => write!(fmt, "invalid file size: {s1} != {s2}"),
             Self::InvalidHash(h1, h2) => write!(fmt, "invalid hash: {h1:x} != {h2:x}"),
+            Self::InvalidHeaderChecksum(c1, c2) => {
+                write!(fmt, "corrupted header, invalid checksum: {c1:x} != {c2:x}")
+            }
+            Self::InvalidMetadata(e) => write!(fmt, "invalid metadata: {e}"),
         }
     }
 }
@@ -34,14 +44,111 @@ impl From<FromUtf8Error> for Error {
     }
 }
 
+/// Name of the hash algorithm advertised in `cbor` metadata: `Murmur3` 128 bits computed over
+/// successive buffers of file content, as done by [`crate::aux::file::send`]
+pub(crate) const CBOR_HASH_ALGO: &str = "lidi-murmur3-128";
+
 pub(crate) struct Header {
     pub(crate) file_name: String,
     pub(crate) mode: u32,
     pub(crate) file_length: u64,
+    /// Modification time in seconds since the Unix epoch, only carried by the `cbor` format
+    pub(crate) mtime: Option<i64>,
+}
+
+/// Upper bound of the length of a file name, a larger one denoting a corrupted header
+const MAX_FILE_NAME_LEN: usize = libc::PATH_MAX as usize;
+
+/// Reader keeping a copy of the bytes read, to compute the checksum of the header
+struct Recorder<'a, R> {
+    inner: &'a mut R,
+    recorded: Vec<u8>,
+}
+
+impl<R: Read> Read for Recorder<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.recorded.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+fn header_checksum(serialized: &[u8]) -> u128 {
+    let mut hasher = fasthash::Murmur3HasherExt::default();
+    serialized.hash(&mut hasher);
+    hasher.finish_ext()
 }
 
 impl Header {
-    pub(crate) fn serialize_to<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+    /// When `checksum` is set, the serialized header is followed by its `Murmur3` 128 bits
+    /// checksum, as a little-endian integer (`Format::Native`) or a 16 bytes big-endian CBOR
+    /// byte string (`Format::Cbor`)
+    pub(crate) fn serialize_to<W: Write>(
+        &self,
+        w: &mut W,
+        format: Format,
+        checksum: bool,
+    ) -> Result<(), Error> {
+        let mut serialized = Vec::new();
+        match format {
+            Format::Native => self.serialize_native_to(&mut serialized)?,
+            Format::Cbor => self.serialize_cbor_to(&mut serialized)?,
+        }
+        w.write_all(&serialized)?;
+
+        if checksum {
+            let checksum = header_checksum(&serialized);
+            match format {
+                Format::Native => w.write_all(&checksum.to_le_bytes())?,
+                Format::Cbor => cbor::write_bytes(w, &checksum.to_be_bytes())?,
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn deserialize_from<R: Read>(
+        r: &mut R,
+        format: Format,
+        checksum: bool,
+    ) -> Result<Self, Error> {
+        let mut recorder = Recorder {
+            inner: r,
+            recorded: Vec::new(),
+        };
+
+        let header = match format {
+            Format::Native => Self::deserialize_native_from(&mut recorder),
+            Format::Cbor => Self::deserialize_cbor_from(&mut recorder),
+        }?;
+
+        if checksum {
+            let expected = match format {
+                Format::Native => {
+                    let mut expected = [0u8; 16];
+                    recorder.inner.read_exact(&mut expected)?;
+                    u128::from_le_bytes(expected)
+                }
+                Format::Cbor => {
+                    let cbor::Value::Bytes(bytes) = cbor::read_value(recorder.inner)? else {
+                        return Err(Error::InvalidMetadata(
+                            "CBOR byte string header checksum expected".into(),
+                        ));
+                    };
+                    u128::from_be_bytes(<[u8; 16]>::try_from(bytes.as_slice()).map_err(|_| {
+                        Error::InvalidMetadata("header checksum must be 16 bytes long".into())
+                    })?)
+                }
+            };
+            let computed = header_checksum(&recorder.recorded);
+            if computed != expected {
+                return Err(Error::InvalidHeaderChecksum(computed, expected));
+            }
+        }
+
+        Ok(header)
+    }
+
+    fn serialize_native_to<W: Write>(&self, w: &mut W) -> Result<(), Error> {
         w.write_all(&self.file_name.len().to_le_bytes())?;
         w.write_all(self.file_name.as_bytes())?;
         w.write_all(&self.mode.to_le_bytes())?;
@@ -49,10 +156,15 @@ impl Header {
         Ok(())
     }
 
-    pub(crate) fn deserialize_from<R: Read>(r: &mut R) -> Result<Self, Error> {
+    fn deserialize_native_from<R: Read>(r: &mut R) -> Result<Self, Error> {
         let mut file_name_len = [0u8; 8];
         r.read_exact(&mut file_name_len)?;
         let file_name_len = usize::from_le_bytes(file_name_len);
+        if MAX_FILE_NAME_LEN < file_name_len {
+            return Err(Error::InvalidMetadata(format!(
+                "file name too long ({file_name_len} bytes)"
+            )));
+        }
 
         let mut file_name = vec![0; file_name_len];
         r.read_exact(&mut file_name)?;
@@ -70,25 +182,261 @@ impl Header {
             file_name,
             mode,
             file_length,
+            mtime: None,
+        })
+    }
+
+    /// Serializes the header as a CBOR map with text keys `name`, `size`, `mode`, `mtime` (null
+    /// if unknown) and `hash-algo`
+    fn serialize_cbor_to<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        cbor::write_head(w, cbor::MAJOR_MAP, 5)?;
+        cbor::write_text(w, "name")?;
+        cbor::write_text(w, &self.file_name)?;
+        cbor::write_text(w, "size")?;
+        cbor::write_uint(w, self.file_length)?;
+        cbor::write_text(w, "mode")?;
+        cbor::write_uint(w, u64::from(self.mode))?;
+        cbor::write_text(w, "mtime")?;
+        match self.mtime {
+            Some(mtime) => cbor::write_int(w, mtime)?,
+            None => cbor::write_null(w)?,
+        }
+        cbor::write_text(w, "hash-algo")?;
+        cbor::write_text(w, CBOR_HASH_ALGO)?;
+        Ok(())
+    }
+
+    /// Deserializes a CBOR header, unknown keys being ignored for forward compatibility
+    fn deserialize_cbor_from<R: Read>(r: &mut R) -> Result<Self, Error> {
+        let (mut file_name, mut mode, mut file_length, mut mtime) = (None, None, None, None);
+
+        for (key, value) in read_cbor_map(r)? {
+            match (key.as_str(), value) {
+                ("name", cbor::Value::Text(name)) => file_name = Some(name),
+                ("size", cbor::Value::Uint(size)) => file_length = Some(size),
+                ("mode", cbor::Value::Uint(m)) => {
+                    mode = Some(
+                        u32::try_from(m)
+                            .map_err(|e| Error::InvalidMetadata(format!("mode: {e}")))?,
+                    );
+                }
+                ("mtime", cbor::Value::Uint(t)) => {
+                    mtime = Some(
+                        i64::try_from(t)
+                            .map_err(|e| Error::InvalidMetadata(format!("mtime: {e}")))?,
+                    );
+                }
+                ("mtime", cbor::Value::NegInt(t)) => {
+                    mtime = Some(
+                        -1 - i64::try_from(t)
+                            .map_err(|e| Error::InvalidMetadata(format!("mtime: {e}")))?,
+                    );
+                }
+                ("mtime", cbor::Value::Null) => (),
+                ("hash-algo", cbor::Value::Text(algo)) => {
+                    if algo != CBOR_HASH_ALGO {
+                        log::warn!("unsupported hash algorithm \"{algo}\"");
+                    }
+                }
+                ("name" | "size" | "mode" | "mtime" | "hash-algo", _) => {
+                    return Err(Error::InvalidMetadata(format!(
+                        "invalid type for \"{key}\""
+                    )));
+                }
+                _ => log::debug!("ignoring unknown metadata \"{key}\""),
+            }
+        }
+
+        Ok(Self {
+            file_name: file_name
+                .ok_or_else(|| Error::InvalidMetadata("missing \"name\"".into()))?,
+            mode: mode.ok_or_else(|| Error::InvalidMetadata("missing \"mode\"".into()))?,
+            file_length: file_length
+                .ok_or_else(|| Error::InvalidMetadata("missing \"size\"".into()))?,
+            mtime,
         })
     }
 }
 
+fn read_cbor_map<R: Read>(r: &mut R) -> Result<Vec<(String, cbor::Value)>, Error> {
+    let (major, _, nb_entries) = cbor::read_head(r)?;
+    if major != cbor::MAJOR_MAP {
+        return Err(Error::InvalidMetadata("CBOR map expected".into()));
+    }
+    if cbor::MAX_ITEMS < nb_entries {
+        return Err(Error::InvalidMetadata(format!(
+            "CBOR map too large ({nb_entries} entries)"
+        )));
+    }
+
+    let mut entries = Vec::new();
+    for _ in 0..nb_entries {
+        let cbor::Value::Text(key) = cbor::read_value(r)? else {
+            return Err(Error::InvalidMetadata("CBOR text key expected".into()));
+        };
+        entries.push((key, cbor::read_value(r)?));
+    }
+    Ok(entries)
+}
+
 pub(crate) struct Footer {
     pub(crate) hash: u128,
 }
 
 impl Footer {
-    pub fn serialize_to<W: Write>(&self, w: &mut W) -> Result<(), Error> {
-        w.write_all(&self.hash.to_le_bytes())?;
+    pub fn serialize_to<W: Write>(&self, w: &mut W, format: Format) -> Result<(), Error> {
+        match format {
+            Format::Native => w.write_all(&self.hash.to_le_bytes())?,
+            Format::Cbor => {
+                // CBOR map with a single "hash" key, the hash being a big-endian byte string
+                cbor::write_head(w, cbor::MAJOR_MAP, 1)?;
+                cbor::write_text(w, "hash")?;
+                cbor::write_bytes(w, &self.hash.to_be_bytes())?;
+            }
+        }
         Ok(())
     }
 
-    pub fn deserialize_from<R: Read>(r: &mut R) -> Result<Self, Error> {
-        let mut hash = [0u8; 16];
-        r.read_exact(&mut hash)?;
-        let hash = u128::from_le_bytes(hash);
+    pub fn deserialize_from<R: Read>(r: &mut R, format: Format) -> Result<Self, Error> {
+        match format {
+            Format::Native => {
+                let mut hash = [0u8; 16];
+                r.read_exact(&mut hash)?;
+                let hash = u128::from_le_bytes(hash);
+
+                Ok(Self { hash })
+            }
+            Format::Cbor => {
+                let mut hash = None;
+                for (key, value) in read_cbor_map(r)? {
+                    match (key.as_str(), value) {
+                        ("hash", cbor::Value::Bytes(bytes)) => {
+                            let bytes = <[u8; 16]>::try_from(bytes.as_slice()).map_err(|_| {
+                                Error::InvalidMetadata("hash must be 16 bytes long".into())
+                            })?;
+                            hash = Some(u128::from_be_bytes(bytes));
+                        }
+                        ("hash", _) => {
+                            return Err(Error::InvalidMetadata("invalid type for \"hash\"".into()));
+                        }
+                        _ => log::debug!("ignoring unknown metadata \"{key}\""),
+                    }
+                }
+                Ok(Self {
+                    hash: hash.ok_or_else(|| Error::InvalidMetadata("missing \"hash\"".into()))?,
+                })
+            }
+        }
+    }
+}
+
+/// Upper bound of the number of extents of a decoded sparse map
+const MAX_EXTENTS: u64 = 1 << 20;
+
+/// Data extents of a sparse file, sent between the header and the file content when sparse
+/// transfers are enabled; only the content of the extents is transferred, holes being recreated
+/// by the receiver
+pub(crate) struct SparseMap {
+    /// Ordered and non-overlapping `(offset, length)` pairs
+    pub(crate) extents: Vec<(u64, u64)>,
+}
+
+impl SparseMap {
+    pub(crate) fn serialize_to<W: Write>(&self, w: &mut W, format: Format) -> Result<(), Error> {
+        match format {
+            Format::Native => {
+                w.write_all(&(self.extents.len() as u64).to_le_bytes())?;
+                for (offset, length) in &self.extents {
+                    w.write_all(&offset.to_le_bytes())?;
+                    w.write_all(&length.to_le_bytes())?;
+                }
+            }
+            Format::Cbor => {
+                // CBOR array of [offset, length] arrays
+                cbor::write_head(w, cbor::MAJOR_ARRAY, self.extents.len() as u64)?;
+                for (offset, length) in &self.extents {
+                    cbor::write_head(w, cbor::MAJOR_ARRAY, 2)?;
+                    cbor::write_uint(w, *offset)?;
+                    cbor::write_uint(w, *length)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn deserialize_from<R: Read>(r: &mut R, format: Format) -> Result<Self, Error> {
+        let nb_extents = match format {
+            Format::Native => read_native_u64(r)?,
+            Format::Cbor => read_cbor_array_head(r)?,
+        };
+        if MAX_EXTENTS < nb_extents {
+            return Err(Error::InvalidMetadata(format!(
+                "too many extents in sparse map ({nb_extents})"
+            )));
+        }
+
+        let mut extents = Vec::new();
+        for _ in 0..nb_extents {
+            let extent = match format {
+                Format::Native => (read_native_u64(r)?, read_native_u64(r)?),
+                Format::Cbor => {
+                    if read_cbor_array_head(r)? != 2 {
+                        return Err(Error::InvalidMetadata(
+                            "sparse map extent must be an [offset, length] array".into(),
+                        ));
+                    }
+                    (read_cbor_uint(r)?, read_cbor_uint(r)?)
+                }
+            };
+            extents.push(extent);
+        }
+
+        Ok(Self { extents })
+    }
+
+    /// Checks that extents are ordered, do not overlap and fit in a file of `file_length`
+    /// bytes, returning the number of data bytes they cover
+    pub(crate) fn data_length(&self, file_length: u64) -> Result<u64, Error> {
+        let mut end = 0;
+        let mut data_length = 0;
+        for (offset, length) in &self.extents {
+            if *offset < end {
+                return Err(Error::InvalidMetadata(
+                    "sparse map extents overlap or are not ordered".into(),
+                ));
+            }
+            end = offset
+                .checked_add(*length)
+                .filter(|end| *end <= file_length)
+                .ok_or_else(|| {
+                    Error::InvalidMetadata("sparse map extent beyond end of file".into())
+                })?;
+            data_length += length;
+        }
+        Ok(data_length)
+    }
+}
+
+fn read_native_u64<R: Read>(r: &mut R) -> Result<u64, Error> {
+    let mut value = [0u8; 8];
+    r.read_exact(&mut value)?;
+    Ok(u64::from_le_bytes(value))
+}
+
+fn read_cbor_array_head<R: Read>(r: &mut R) -> Result<u64, Error> {
+    let (major, _, nb_items) = cbor::read_head(r)?;
+    if major != cbor::MAJOR_ARRAY {
+        return Err(Error::InvalidMetadata("CBOR array expected".into()));
+    }
+    Ok(nb_items)
+}
 
-        Ok(Self { hash })
+fn read_cbor_uint<R: Read>(r: &mut R) -> Result<u64, Error> {
+    let (major, _, value) = cbor::read_head(r)?;
+    if major != cbor::MAJOR_UINT {
+        return Err(Error::InvalidMetadata(
+            "CBOR unsigned integer expected".into(),
+        ));
     }
+    Ok(value)
 }
@@ -4,10 +4,10 @@ use crate::aux::{self, file};
 use std::{
     fs,
     hash::Hash,
-    io::{Read, Write},
+    io::{self, Read, Seek, Write},
     net,
     os::unix::{self, fs::PermissionsExt},
-    path, thread,
+    path, thread, time,
 };
 
 /// # Errors
@@ -23,6 +23,14 @@ pub fn receive_files(
         ));
     }
 
+    let open_files = config
+        .max_open_files
+        .map(|max| {
+            semka::Sem::new(max).ok_or(file::Error::Other("failed to create semaphore".into()))
+        })
+        .transpose()?;
+    let open_files = open_files.as_ref();
+
     thread::scope(|scope| -> Result<(), file::Error> {
         if let Some(from_unix) = &config.diode.from_unix {
             if from_unix.exists() {
@@ -34,14 +42,14 @@ pub fn receive_files(
 
             let server = unix::net::UnixListener::bind(from_unix)?;
             thread::Builder::new().spawn_scoped(scope, move || {
-                receive_unix_loop(config, output_dir, scope, &server)
+                receive_unix_loop(config, output_dir, open_files, scope, &server)
             })?;
         }
 
         if let Some(from_tcp) = &config.diode.from_tcp {
             let server = net::TcpListener::bind(from_tcp)?;
             thread::Builder::new().spawn_scoped(scope, move || {
-                receive_tcp_loop(config, output_dir, scope, &server)
+                receive_tcp_loop(config, output_dir, open_files, scope, &server)
             })?;
         }
 
@@ -52,22 +60,21 @@ pub fn receive_files(
 fn receive_tcp_loop<'a>(
     config: &'a file::Config<aux::DiodeReceive>,
     output_dir: &'a path::Path,
+    open_files: Option<&'a semka::Sem>,
     scope: &'a thread::Scope<'a, '_>,
     server: &net::TcpListener,
 ) -> Result<(), file::Error> {
     loop {
         let (client, client_addr) = server.accept()?;
         log::info!("new TCP client ({client_addr}) connected");
-        scope.spawn(|| match receive_file(config, client, output_dir) {
-            Ok(total) => log::info!("file received, {total} bytes received"),
-            Err(e) => log::error!("failed to receive file: {e}"),
-        });
+        scope.spawn(move || receive(config, client, output_dir, open_files));
     }
 }
 
 fn receive_unix_loop<'a>(
     config: &'a file::Config<aux::DiodeReceive>,
     output_dir: &'a path::Path,
+    open_files: Option<&'a semka::Sem>,
     scope: &'a thread::Scope<'a, '_>,
     server: &unix::net::UnixListener,
 ) -> Result<(), file::Error> {
@@ -79,13 +86,91 @@ fn receive_unix_loop<'a>(
                 .as_pathname()
                 .map_or("unknown".to_string(), |p| p.display().to_string())
         );
-        scope.spawn(|| match receive_file(config, client, output_dir) {
+        scope.spawn(move || receive(config, client, output_dir, open_files));
+    }
+}
+
+/// Receives a file or extracts an archive, waiting first for `open_files` if the number of
+/// files written at the same time is bounded
+fn receive<D>(
+    config: &file::Config<aux::DiodeReceive>,
+    diode: D,
+    output_dir: &path::Path,
+    open_files: Option<&semka::Sem>,
+) where
+    D: Read + Write,
+{
+    if let Some(open_files) = open_files {
+        open_files.wait();
+    }
+
+    if config.untar {
+        match receive_tar(diode, output_dir) {
+            Ok(nb_entries) => log::info!("archive extracted, {nb_entries} entries"),
+            Err(e) => log::error!("failed to extract archive: {e}"),
+        }
+    } else {
+        match receive_file(config, diode, output_dir) {
             Ok(total) => log::info!("file received, {total} bytes received"),
             Err(e) => log::error!("failed to receive file: {e}"),
-        });
+        }
+    }
+
+    if let Some(open_files) = open_files {
+        open_files.signal();
     }
 }
 
+/// Whether `path` stays inside the directory it is relative to
+fn is_contained(path: &path::Path) -> bool {
+    path.components().all(|component| {
+        matches!(
+            component,
+            path::Component::Normal(_) | path::Component::CurDir
+        )
+    })
+}
+
+/// Extracts a tar archive read from `diode` into `output_dir`, returning the number of
+/// extracted entries
+///
+/// Entries whose path, or link target, is absolute or goes up the directory tree are skipped,
+/// as well as entries that would overwrite an existing file.
+fn receive_tar<D>(diode: D, output_dir: &path::Path) -> Result<usize, file::Error>
+where
+    D: Read,
+{
+    let mut archive = tar::Archive::new(diode);
+    archive.set_overwrite(false);
+
+    let mut nb_entries = 0;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+
+        let link_contained = entry
+            .link_name()?
+            .is_none_or(|target| is_contained(&target));
+        if !(is_contained(&entry_path) && link_contained) {
+            log::warn!(
+                "skipping \"{}\": path outside of the output directory",
+                entry_path.display()
+            );
+            continue;
+        }
+
+        log::debug!("extracting \"{}\"", entry_path.display());
+        if entry.unpack_in(output_dir)? {
+            nb_entries += 1;
+        } else {
+            log::warn!("skipping \"{}\"", entry_path.display());
+        }
+    }
+
+    Ok(nb_entries)
+}
+
 fn receive_file<D>(
     config: &file::Config<aux::DiodeReceive>,
     mut diode: D,
@@ -94,12 +179,16 @@ fn receive_file<D>(
 where
     D: Read + Write,
 {
-    let header = file::protocol::Header::deserialize_from(&mut diode)?;
+    let header = file::protocol::Header::deserialize_from(
+        &mut diode,
+        config.format,
+        config.header_checksum,
+    )?;
 
     log::debug!("receiving file \"{}\"", header.file_name);
     log::debug!("file size = {}", header.file_length);
 
-    let file_path = path::PathBuf::from(header.file_name);
+    let file_path = path::PathBuf::from(&header.file_name);
     let file_name = file_path
         .file_name()
         .ok_or(file::Error::Other("unwrap of file_name failed".to_string()))?;
@@ -114,21 +203,147 @@ where
         )));
     }
 
+    // with atomic renames, the file is written under a hidden temporary name, and only gets
+    // its final name once completely received
+    let write_path = if config.atomic_rename {
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(".tmp");
+        let temp_path = output_dir.join(temp_name);
+        if temp_path.exists() {
+            return Err(file::Error::Other(format!(
+                "temporary file \"{}\" already exists",
+                temp_path.display()
+            )));
+        }
+        log::debug!("writing to \"{}\"", temp_path.display());
+        temp_path
+    } else {
+        file_path.clone()
+    };
+
     let mut file = fs::OpenOptions::new()
         .read(false)
         .write(true)
         .create(true)
         .truncate(true)
-        .open(&file_path)?;
+        .open(&write_path)?;
+
+    let res = receive_file_content(config, &mut diode, &header, &mut file);
+
+    if res.is_ok() && config.atomic_rename {
+        fs::rename(&write_path, &file_path)?;
+    }
 
+    if res.is_err() && config.keep_partial {
+        let mut partial_path = file_path.clone().into_os_string();
+        partial_path.push(".partial");
+        let partial_path = path::PathBuf::from(partial_path);
+        log::warn!("keeping incomplete file as \"{}\"", partial_path.display());
+        if let Err(e) = fs::rename(&write_path, &partial_path) {
+            log::error!("failed to rename incomplete file: {e}");
+        }
+    }
+
+    res
+}
+
+fn receive_file_content<D>(
+    config: &file::Config<aux::DiodeReceive>,
+    diode: &mut D,
+    header: &file::protocol::Header,
+    file: &mut fs::File,
+) -> Result<usize, file::Error>
+where
+    D: Read,
+{
     log::debug!("setting mode to {}", header.mode);
     file.set_permissions(fs::Permissions::from_mode(header.mode))?;
 
+    let mut hasher = fasthash::Murmur3HasherExt::default();
+
+    let (expected, received) = if config.sparse {
+        receive_sparse_content(config, diode, header.file_length, file, &mut hasher)?
+    } else {
+        let expected = usize::try_from(header.file_length)?;
+        (
+            expected,
+            receive_content(config, diode, expected, file, &mut hasher)?,
+        )
+    };
+
+    // checked before reading the footer, which is missing from a truncated transfer
+    if received != expected {
+        log::error!(
+            "\"{}\": truncated transfer, {received} bytes received out of {expected} announced",
+            header.file_name
+        );
+        return Err(file::Error::Diode(file::protocol::Error::InvalidFileSize(
+            expected, received,
+        )));
+    }
+
+    let footer = file::protocol::Footer::deserialize_from(diode, config.format)?;
+
+    let written = file.metadata()?.len();
+    if written != header.file_length {
+        log::error!(
+            "\"{}\": {written} bytes written out of {} announced",
+            header.file_name,
+            header.file_length
+        );
+        return Err(file::Error::Diode(file::protocol::Error::InvalidFileSize(
+            usize::try_from(header.file_length)?,
+            usize::try_from(written)?,
+        )));
+    }
+
+    if config.hash {
+        let hash = hasher.finish_ext();
+        log::debug!("expected hash = {}", footer.hash);
+        log::debug!("computed hash = {hash}");
+        if footer.hash != hash {
+            return Err(file::Error::Diode(file::protocol::Error::InvalidHash(
+                hash,
+                footer.hash,
+            )));
+        }
+        if let Some(encoding) = config.print_hash {
+            println!("{}  {}", encoding.encode(hash), header.file_name);
+        }
+    }
+
+    if let Some(mtime) = header.mtime {
+        let since = time::Duration::from_secs(mtime.unsigned_abs());
+        let modified = if mtime < 0 {
+            time::UNIX_EPOCH.checked_sub(since)
+        } else {
+            time::UNIX_EPOCH.checked_add(since)
+        };
+        if let Some(modified) = modified {
+            log::debug!("setting modification time to {mtime}");
+            file.set_modified(modified)?;
+        }
+    }
+
+    Ok(received)
+}
+
+/// Receives up to `file_length` bytes of file content, returning the number of bytes actually
+/// received
+fn receive_content<D>(
+    config: &file::Config<aux::DiodeReceive>,
+    diode: &mut D,
+    file_length: usize,
+    file: &mut fs::File,
+    hasher: &mut fasthash::Murmur3HasherExt,
+) -> Result<usize, file::Error>
+where
+    D: Read,
+{
     let mut buffer = vec![0; config.buffer_size];
     let mut cursor = 0;
-    let mut remaining = usize::try_from(header.file_length)?;
-
-    let mut hasher = fasthash::Murmur3HasherExt::default();
+    let mut remaining = file_length;
 
     loop {
         let end = if remaining >= (config.buffer_size - cursor) {
@@ -140,39 +355,14 @@ where
             0 => {
                 if 0 < cursor {
                     if config.hash {
-                        buffer[..cursor].hash(&mut hasher);
+                        buffer[..cursor].hash(hasher);
                     }
                     file.write_all(&buffer[..cursor])?;
                 }
 
                 file.flush()?;
 
-                let received = usize::try_from(header.file_length)? - remaining;
-
-                let footer = file::protocol::Footer::deserialize_from(&mut diode)?;
-
-                if remaining != 0 {
-                    log::debug!("expected file size = {}", header.file_length);
-                    log::debug!("received file size = {received}");
-                    return Err(file::Error::Diode(file::protocol::Error::InvalidFileSize(
-                        usize::try_from(header.file_length)?,
-                        received,
-                    )));
-                }
-
-                if config.hash {
-                    let hash = hasher.finish_ext();
-                    log::debug!("expected hash = {}", footer.hash);
-                    log::debug!("computed hash = {hash}");
-                    if footer.hash != hash {
-                        return Err(file::Error::Diode(file::protocol::Error::InvalidHash(
-                            hash,
-                            footer.hash,
-                        )));
-                    }
-                }
-
-                return Ok(received);
+                return Ok(file_length - remaining);
             }
             nread => {
                 remaining -= nread;
@@ -181,7 +371,7 @@ where
                     continue;
                 }
                 if config.hash {
-                    buffer.hash(&mut hasher);
+                    buffer.hash(hasher);
                 }
                 file.write_all(&buffer)?;
                 cursor = 0;
@@ -189,3 +379,185 @@ where
         }
     }
 }
+
+/// Receives the sparse map of the file and the content of its data extents, holes being left
+/// unwritten; returns the expected and received numbers of data bytes
+fn receive_sparse_content<D>(
+    config: &file::Config<aux::DiodeReceive>,
+    diode: &mut D,
+    file_length: u64,
+    file: &mut fs::File,
+    hasher: &mut fasthash::Murmur3HasherExt,
+) -> Result<(usize, usize), file::Error>
+where
+    D: Read,
+{
+    let sparse_map = file::protocol::SparseMap::deserialize_from(diode, config.format)?;
+    let expected = usize::try_from(sparse_map.data_length(file_length)?)?;
+
+    log::debug!(
+        "sparse map: {} data extents, {expected} data bytes",
+        sparse_map.extents.len()
+    );
+
+    let mut buffer = vec![0; config.buffer_size];
+    let mut received = 0;
+
+    for (offset, length) in &sparse_map.extents {
+        file.seek(io::SeekFrom::Start(*offset))?;
+        let mut remaining = usize::try_from(*length)?;
+        while 0 < remaining {
+            let chunk = &mut buffer[..remaining.min(config.buffer_size)];
+            diode.read_exact(chunk)?;
+            if config.hash {
+                chunk.hash(hasher);
+            }
+            file.write_all(chunk)?;
+            remaining -= chunk.len();
+            received += chunk.len();
+        }
+    }
+
+    // trailing hole, if any
+    file.set_len(file_length)?;
+    file.flush()?;
+
+    Ok((expected, received))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT: &[u8] = b"content of the file";
+
+    fn config() -> file::Config<aux::DiodeReceive> {
+        file::Config {
+            diode: aux::DiodeReceive {
+                from_tcp: None,
+                from_unix: None,
+            },
+            buffer_size: 4,
+            hash: false,
+            header_checksum: false,
+            format: file::Format::Native,
+            keep_partial: false,
+            atomic_rename: true,
+            sparse: false,
+            mmap: false,
+            untar: false,
+            max_open_files: None,
+            print_hash: None,
+        }
+    }
+
+    /// Header of a file named `name` holding [`CONTENT`], the content itself and the footer,
+    /// returning them with the length of the header
+    fn stream(name: &str) -> (Vec<u8>, usize) {
+        let header = file::protocol::Header {
+            file_name: name.into(),
+            mode: 0o600,
+            file_length: CONTENT.len() as u64,
+            mtime: None,
+        };
+        let mut stream = Vec::new();
+        header
+            .serialize_to(&mut stream, file::Format::Native, false)
+            .unwrap_or_else(|e| panic!("header: {e}"));
+        let header_len = stream.len();
+        stream.extend_from_slice(CONTENT);
+        file::protocol::Footer { hash: 0 }
+            .serialize_to(&mut stream, file::Format::Native)
+            .unwrap_or_else(|e| panic!("footer: {e}"));
+        (stream, header_len)
+    }
+
+    /// Diode reading a stream, calling `check` once `check_at` bytes of it have been read
+    struct Diode<F> {
+        stream: io::Cursor<Vec<u8>>,
+        check_at: u64,
+        check: Option<F>,
+    }
+
+    impl<F: FnOnce()> Read for Diode<F> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let position = self.stream.position();
+            if position == self.check_at
+                && let Some(check) = self.check.take()
+            {
+                check();
+            }
+            let len = if position < self.check_at {
+                buf.len()
+                    .min(usize::try_from(self.check_at - position).expect("length"))
+            } else {
+                buf.len()
+            };
+            self.stream.read(&mut buf[..len])
+        }
+    }
+
+    impl<F> Write for Diode<F> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn output_dir(test: &str) -> path::PathBuf {
+        let output_dir = std::env::temp_dir().join(format!("lidi-{test}-{}", std::process::id()));
+        fs::create_dir_all(&output_dir).expect("output directory");
+        output_dir
+    }
+
+    #[test]
+    fn atomic_rename_on_completion() {
+        let output_dir = output_dir("atomic-rename");
+        let file_path = output_dir.join("file");
+        let temp_path = output_dir.join(".file.tmp");
+        let (stream, header_len) = stream("file");
+
+        let checked = std::cell::Cell::new(false);
+        let diode = Diode {
+            stream: io::Cursor::new(stream),
+            check_at: (header_len + CONTENT.len() / 2) as u64,
+            check: Some(|| {
+                assert!(!file_path.exists());
+                assert!(temp_path.exists());
+                checked.set(true);
+            }),
+        };
+        let res = receive_file(&config(), diode, &output_dir);
+
+        assert_eq!(
+            res.unwrap_or_else(|e| panic!("receive: {e}")),
+            CONTENT.len()
+        );
+        assert!(checked.get());
+        assert_eq!(fs::read(&file_path).expect("final file"), CONTENT);
+        assert!(!temp_path.exists());
+        fs::remove_dir_all(&output_dir).expect("remove output directory");
+    }
+
+    #[test]
+    fn atomic_rename_aborted() {
+        let output_dir = output_dir("atomic-rename-aborted");
+        let (mut stream, header_len) = stream("file");
+        stream.truncate(header_len + CONTENT.len() / 2);
+
+        let diode = Diode {
+            stream: io::Cursor::new(stream),
+            check_at: 0,
+            check: None::<fn()>,
+        };
+        let res = receive_file(&config(), diode, &output_dir);
+
+        assert!(res.is_err());
+        assert!(!output_dir.join("file").exists());
+        assert!(output_dir.join(".file.tmp").exists());
+        fs::remove_dir_all(&output_dir).expect("remove output directory");
+    }
+}
@@ -4,20 +4,44 @@ use crate::aux::{self, file};
 use std::{
     fs,
     hash::Hash,
-    io::{Read, Write},
+    io::{self, BufRead, Read, Seek, Write},
     net,
-    os::unix::{self, fs::PermissionsExt},
-    path,
+    os::{
+        fd::AsRawFd,
+        unix::{self, fs::PermissionsExt},
+    },
+    path, time,
 };
 
+/// Reads a newline-delimited list of file paths, one path per line. Lines are taken verbatim
+/// (paths may contain spaces) and empty lines are skipped.
+///
 /// # Errors
 ///
-/// Will return `Err` if `send_file` function
-/// returns an `Err`.
+/// Will return `Err` if reading from `reader` fails.
+pub fn read_file_list<R: BufRead>(reader: R) -> Result<Vec<String>, file::Error> {
+    let mut files = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if !line.is_empty() {
+            files.push(line);
+        }
+    }
+    Ok(files)
+}
+
+/// # Errors
+///
+/// Will return `Err` if one of the `files` is not a regular file (checked before any
+/// transfer starts) or if `send_file` function returns an `Err`.
 pub fn send_files(
     config: &file::Config<aux::DiodeSend>,
     files: &[String],
 ) -> Result<(), file::Error> {
+    if let Some(file) = files.iter().find(|file| !path::Path::new(file).is_file()) {
+        return Err(file::Error::Other(format!("\"{file}\" is not a file")));
+    }
+
     for file in files {
         let total = send_file(config, file)?;
         log::info!("file send, {total} bytes sent");
@@ -84,38 +108,67 @@ where
     let metadata = file.metadata()?;
     let permissions = metadata.permissions();
 
+    let mtime = metadata.modified().ok().and_then(|modified| {
+        match modified.duration_since(time::UNIX_EPOCH) {
+            Ok(since) => i64::try_from(since.as_secs()).ok(),
+            Err(e) => i64::try_from(e.duration().as_secs())
+                .ok()
+                .map(|before| -before),
+        }
+    });
+
     let header = file::protocol::Header {
         file_name,
         mode: permissions.mode(),
         file_length: metadata.len(),
+        mtime,
     };
 
-    header.serialize_to(&mut diode)?;
+    header.serialize_to(&mut diode, config.format, config.header_checksum)?;
 
+    let mut hasher = fasthash::Murmur3HasherExt::default();
+
+    let total = if config.sparse {
+        send_sparse_content(config, &mut file, metadata.len(), &mut diode, &mut hasher)?
+    } else if config.mmap {
+        send_mmap_content(config, &file, metadata.len(), &mut diode, &mut hasher)?
+    } else {
+        send_content(config, &mut file, &mut diode, &mut hasher)?
+    };
+
+    let footer = file::protocol::Footer {
+        hash: if config.hash { hasher.finish_ext() } else { 0 },
+    };
+
+    footer.serialize_to(&mut diode, config.format)?;
+
+    diode.flush()?;
+    Ok(total)
+}
+
+fn send_content<D>(
+    config: &file::Config<aux::DiodeSend>,
+    file: &mut fs::File,
+    diode: &mut D,
+    hasher: &mut fasthash::Murmur3HasherExt,
+) -> Result<usize, file::Error>
+where
+    D: Write,
+{
     let mut buffer = vec![0; config.buffer_size];
     let mut cursor = 0;
     let mut total = 0;
 
-    let mut hasher = fasthash::Murmur3HasherExt::default();
-
     loop {
         match file.read(&mut buffer[cursor..])? {
             0 => {
                 if 0 < cursor {
                     total += cursor;
                     if config.hash {
-                        buffer[..cursor].hash(&mut hasher);
+                        buffer[..cursor].hash(hasher);
                     }
                     diode.write_all(&buffer[..cursor])?;
                 }
-
-                let footer = file::protocol::Footer {
-                    hash: if config.hash { hasher.finish_ext() } else { 0 },
-                };
-
-                footer.serialize_to(&mut diode)?;
-
-                diode.flush()?;
                 return Ok(total);
             }
             nread => {
@@ -125,7 +178,7 @@ where
                 }
                 total += config.buffer_size;
                 if config.hash {
-                    buffer.hash(&mut hasher);
+                    buffer.hash(hasher);
                 }
                 diode.write_all(&buffer)?;
                 cursor = 0;
@@ -133,3 +186,157 @@ where
         }
     }
 }
+
+/// Read-only private mapping of a whole file, unmapped on drop
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(file: &fs::File, len: usize) -> Result<Self, io::Error> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Self { ptr, len })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.cast::<u8>(), self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// Sends the content of the file directly from a memory mapping, by chunks of `buffer_size`
+/// bytes (so that the hash is the same as when reading the file), without copying it into a
+/// read buffer
+///
+/// The file must not be truncated while it is sent: accessing unmapped pages would kill the
+/// process with `SIGBUS`. A change of size is detected and reported as an error once the content
+/// has been sent.
+fn send_mmap_content<D>(
+    config: &file::Config<aux::DiodeSend>,
+    file: &fs::File,
+    file_length: u64,
+    diode: &mut D,
+    hasher: &mut fasthash::Murmur3HasherExt,
+) -> Result<usize, file::Error>
+where
+    D: Write,
+{
+    let len = usize::try_from(file_length)?;
+
+    if 0 < len {
+        let mmap = Mmap::new(file, len)?;
+        for chunk in mmap.as_slice().chunks(config.buffer_size) {
+            if config.hash {
+                chunk.hash(hasher);
+            }
+            diode.write_all(chunk)?;
+        }
+    }
+
+    let new_length = file.metadata()?.len();
+    if new_length != file_length {
+        return Err(file::Error::Other(format!(
+            "file size changed from {file_length} to {new_length} bytes during send"
+        )));
+    }
+
+    Ok(len)
+}
+
+/// Sends the sparse map of the file followed by the content of its data extents, read and
+/// hashed by chunks of at most `buffer_size` bytes not spanning several extents
+fn send_sparse_content<D>(
+    config: &file::Config<aux::DiodeSend>,
+    file: &mut fs::File,
+    file_length: u64,
+    diode: &mut D,
+    hasher: &mut fasthash::Murmur3HasherExt,
+) -> Result<usize, file::Error>
+where
+    D: Write,
+{
+    let sparse_map = file::protocol::SparseMap {
+        extents: data_extents(file, file_length)?,
+    };
+
+    log::debug!(
+        "sparse map: {} data extents, {} data bytes",
+        sparse_map.extents.len(),
+        sparse_map.data_length(file_length)?
+    );
+
+    sparse_map.serialize_to(diode, config.format)?;
+
+    let mut buffer = vec![0; config.buffer_size];
+    let mut total = 0;
+
+    for (offset, length) in &sparse_map.extents {
+        file.seek(io::SeekFrom::Start(*offset))?;
+        let mut remaining = usize::try_from(*length)?;
+        while 0 < remaining {
+            let chunk = &mut buffer[..remaining.min(config.buffer_size)];
+            file.read_exact(chunk)?;
+            if config.hash {
+                chunk.hash(hasher);
+            }
+            diode.write_all(chunk)?;
+            remaining -= chunk.len();
+            total += chunk.len();
+        }
+    }
+
+    Ok(total)
+}
+
+/// Lists the data extents of a file with `SEEK_DATA` and `SEEK_HOLE`, file systems not
+/// supporting holes reporting a single extent covering the whole file
+fn data_extents(file: &fs::File, file_length: u64) -> Result<Vec<(u64, u64)>, file::Error> {
+    let fd = file.as_raw_fd();
+    let mut extents = Vec::new();
+    let mut offset = 0;
+
+    while offset < file_length {
+        let data = unsafe { libc::lseek(fd, i64::try_from(offset)?, libc::SEEK_DATA) };
+        if data < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ENXIO) {
+                // no data after offset, only a trailing hole
+                break;
+            }
+            return Err(file::Error::Io(e));
+        }
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(file::Error::Io(io::Error::last_os_error()));
+        }
+
+        let data = u64::try_from(data)?;
+        let hole = u64::try_from(hole)?.min(file_length);
+        if data >= hole {
+            break;
+        }
+        extents.push((data, hole - data));
+        offset = hole;
+    }
+
+    Ok(extents)
+}
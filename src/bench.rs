@@ -0,0 +1,190 @@
+//! Measure of the UDP send and receive rates over loopback, to choose the batch sizes of a host
+//!
+//! For every batch size, a receiver and a sender are set up as the diode does, both batching
+//! `recvmmsg`/`sendmmsg` calls by the same number of datagrams (plain `recvmsg`/`sendmsg` for a
+//! batch size of 1). Nothing slows the sender down, so datagrams the receiver cannot keep up
+//! with are dropped by the kernel and accounted as lost.
+
+use crate::{sock_utils, udp};
+use std::{fmt, io, net, os::fd::AsRawFd, sync, thread, time};
+
+/// Batch sizes measured by default, from no batching to the largest batch
+pub const BATCH_SIZES: [u32; 5] = [1, 8, 64, 256, 1024];
+
+/// Size of the UDP socket receive buffer requested for the measures
+const RECV_BUFFER_SIZE: i32 = 64 * 1024 * 1024;
+
+/// Duration without datagram after which the receiver stops once the sender is done
+const RECV_IDLE_TIMEOUT: time::Duration = time::Duration::from_millis(200);
+
+/// Rates measured for a batch size
+pub struct BatchResult {
+    pub batch_size: u32,
+    pub packets_sent: u64,
+    /// Duration of the send loop
+    pub send_elapsed: time::Duration,
+    pub packets_received: u64,
+    /// Duration from the first to the last received datagram
+    pub recv_elapsed: time::Duration,
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn rate(packets: u64, elapsed: time::Duration) -> f64 {
+    if elapsed.is_zero() {
+        0.0
+    } else {
+        packets as f64 / elapsed.as_secs_f64()
+    }
+}
+
+impl BatchResult {
+    /// Packets sent per second
+    pub fn send_rate(&self) -> f64 {
+        rate(self.packets_sent, self.send_elapsed)
+    }
+
+    /// Packets received per second
+    pub fn recv_rate(&self) -> f64 {
+        rate(self.packets_received, self.recv_elapsed)
+    }
+
+    /// Percentage of the sent packets that were not received
+    #[allow(clippy::cast_precision_loss)]
+    pub fn loss(&self) -> f64 {
+        if self.packets_sent == 0 {
+            return 0.0;
+        }
+        100.0 * self.packets_sent.saturating_sub(self.packets_received) as f64
+            / self.packets_sent as f64
+    }
+}
+
+impl fmt::Display for BatchResult {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            fmt,
+            "batch {:>4}: {:>10.0} packets/s sent, {:>10.0} packets/s received, {:.2}% lost",
+            self.batch_size,
+            self.send_rate(),
+            self.recv_rate(),
+            self.loss()
+        )
+    }
+}
+
+/// Receives datagrams on `socket` until `nb_packets` are received or no datagram is received
+/// for a while once `sent` is set, returning the number of datagrams and the duration from the
+/// first to the last one
+fn receive(
+    socket: &net::UdpSocket,
+    mtu: u16,
+    batch_size: u32,
+    nb_packets: u64,
+    sent: &sync::atomic::AtomicBool,
+) -> Result<(u64, time::Duration), io::Error> {
+    let mut udp = udp::Receive::new(
+        socket.as_raw_fd(),
+        mtu,
+        Some(batch_size),
+        false,
+        None,
+        Some(RECV_IDLE_TIMEOUT),
+        false,
+    )?;
+
+    let mut received = 0;
+    let mut first = None;
+    let mut last = time::Instant::now();
+
+    while received < nb_packets {
+        match udp.recv() {
+            Ok(datagrams) => {
+                last = time::Instant::now();
+                first.get_or_insert(last);
+                received += match datagrams {
+                    udp::Datagrams::Single(_) => 1,
+                    udp::Datagrams::Multiple(datagrams) => datagrams.len() as u64,
+                };
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if sent.load(sync::atomic::Ordering::Relaxed) && RECV_IDLE_TIMEOUT <= last.elapsed()
+                {
+                    break;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok((
+        received,
+        first.map_or(time::Duration::ZERO, |first| last.duration_since(first)),
+    ))
+}
+
+/// Sends `nb_packets` datagrams of `payload_len` bytes, `batch_len` at a time, returning the
+/// duration of the send loop
+fn send(
+    udp: &mut udp::Send,
+    payload_len: usize,
+    nb_packets: u32,
+    batch_len: usize,
+) -> Result<time::Duration, io::Error> {
+    let payload = vec![0x5a; payload_len];
+    let start = time::Instant::now();
+    let mut remaining = nb_packets as usize;
+    while 0 < remaining {
+        let len = remaining.min(batch_len);
+        udp.send(vec![payload.clone(); len])?;
+        remaining -= len;
+    }
+    Ok(start.elapsed())
+}
+
+/// Sends `nb_packets` datagrams over loopback for MTU `mtu`, `batch_size` at a time, to a
+/// receiver receiving them `batch_size` at a time
+///
+/// # Errors
+///
+/// Will return `Err` if a socket cannot be set up or a send or receive fails.
+pub fn run(batch_size: u32, nb_packets: u32, mtu: u16) -> Result<BatchResult, io::Error> {
+    let payload_len = usize::from(mtu.checked_sub(20 + 8).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("MTU {mtu} too small"))
+    })?);
+    let batch_len = usize::try_from(batch_size)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("batch_size: {e}")))?;
+
+    let recv_socket = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0))?;
+    sock_utils::set_socket_recv_buffer_size(&recv_socket, RECV_BUFFER_SIZE)?;
+    let send_socket = net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0))?;
+
+    let mut send_udp = udp::Send::new(
+        send_socket.as_raw_fd(),
+        recv_socket.local_addr()?,
+        Some(batch_size),
+        None,
+    )?;
+
+    let sent = sync::atomic::AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        let receiver =
+            scope.spawn(|| receive(&recv_socket, mtu, batch_size, u64::from(nb_packets), &sent));
+
+        let res = send(&mut send_udp, payload_len, nb_packets, batch_len);
+        sent.store(true, sync::atomic::Ordering::Relaxed);
+
+        let (packets_received, recv_elapsed) = receiver
+            .join()
+            .map_err(|_| io::Error::other("receiver thread panicked"))??;
+        let send_elapsed = res?;
+
+        Ok(BatchResult {
+            batch_size,
+            packets_sent: u64::from(nb_packets),
+            send_elapsed,
+            packets_received,
+            recv_elapsed,
+        })
+    })
+}
@@ -0,0 +1,96 @@
+use clap::Parser;
+use diode::bench;
+
+#[derive(clap::Parser)]
+#[clap(about = "Measure UDP send and receive rates over loopback for several batch sizes.")]
+struct Args {
+    #[clap(
+        default_value = "Info",
+        value_name = "Off|Error|Warn|Info|Debug|Trace",
+        long,
+        help = "Log level"
+    )]
+    log_level: log::LevelFilter,
+    #[clap(
+        default_value = "1500",
+        value_name = "bytes",
+        long,
+        help = "MTU of the link between diode-send and diode-receive"
+    )]
+    mtu: u16,
+    #[clap(
+        default_value = "200000",
+        value_name = "nb_packets",
+        long,
+        help = "Number of packets sent for every batch size"
+    )]
+    packets: u32,
+    #[clap(
+        value_name = "1..1024",
+        value_delimiter = ',',
+        value_parser = clap::value_parser!(u32).range(1..=1024),
+        long,
+        help = "Comma-separated batch sizes to measure [default: 1,8,64,256,1024]"
+    )]
+    batch: Vec<u32>,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    if let Err(e) = diode::init_logger(args.log_level, None, false) {
+        eprintln!("failed to initialize logger: {e}");
+        return;
+    }
+
+    log::info!(
+        "{} version {}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    );
+
+    let batch_sizes = if args.batch.is_empty() {
+        bench::BATCH_SIZES.to_vec()
+    } else {
+        args.batch
+    };
+
+    log::info!(
+        "sending {} packets over loopback with MTU {} for every batch size",
+        args.packets,
+        args.mtu
+    );
+
+    let mut results = Vec::with_capacity(batch_sizes.len());
+    for batch_size in batch_sizes {
+        match bench::run(batch_size, args.packets, args.mtu) {
+            Ok(result) => results.push(result),
+            Err(e) => {
+                log::error!("batch {batch_size}: {e}");
+                return;
+            }
+        }
+    }
+
+    let Some(reference) = results.first().map(bench::BatchResult::recv_rate) else {
+        return;
+    };
+    for result in &results {
+        let speedup = if 0.0 < reference {
+            result.recv_rate() / reference
+        } else {
+            0.0
+        };
+        log::info!(
+            "{result}, receive speedup x{speedup:.2} relative to batch {}",
+            results[0].batch_size
+        );
+    }
+
+    if let Some(best) = results
+        .iter()
+        .max_by(|a, b| a.recv_rate().total_cmp(&b.recv_rate()))
+    {
+        log::info!("best receive rate with batch {}", best.batch_size);
+    }
+}
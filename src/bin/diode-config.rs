@@ -1,6 +1,5 @@
 use clap::Parser;
 use diode::protocol;
-use rand::Rng;
 
 #[derive(clap::Parser)]
 #[clap(about = "Test diode config parameters.")]
@@ -62,7 +61,9 @@ fn main() {
         args.repair,
     );
 
-    let raptorq = match protocol::RaptorQ::new(args.mtu, args.block, args.repair) {
+    let raptorq = match protocol::RaptorQ::validate_params(args.mtu, args.block, args.repair)
+        .and_then(|()| protocol::RaptorQ::new(args.mtu, args.block, args.repair))
+    {
         Ok(raptorq) => raptorq,
         Err(e) => {
             log::error!("{e}");
@@ -71,49 +72,10 @@ fn main() {
     };
 
     log::info!("{raptorq}");
+    log::info!("{}", raptorq.summary());
 
-    let mut rng = rand::rng();
-
-    let block_size = raptorq.block_size();
-
-    log::debug!("generating random data block of {block_size} bytes");
-    let mut data = vec![0u8; block_size as usize];
-    for di in &mut data {
-        *di = rng.random();
-    }
-
-    let id = 0;
-
-    /* encoding */
-    let mut packets = raptorq.encode(id, &data);
-    log::info!("{} packets", packets.len(),);
-    log::debug!("len(packet) = {}", packets[0].serialize().len());
-
-    /* shuffling */
-    let nb_packets = packets.len();
-    log::info!("shuffling {nb_packets} packets");
-    let range = nb_packets / 2..nb_packets;
-    for i in 0..(nb_packets / 2) {
-        packets.swap(i, rng.random_range(range.clone()));
-    }
-
-    /* removing */
-    if let Some(remove) = args.remove {
-        let nb = nb_packets * (remove as usize).div_euclid(100);
-        log::info!("removing {remove}% ({nb} packets)");
-        packets = packets.split_off(nb);
-    }
-
-    /* decoding */
-    log::info!("decoding with {} packets", packets.len());
-    match raptorq.decode(id, packets) {
-        None => log::error!("decode failed"),
-        Some(decoded) => {
-            if decoded == data {
-                log::info!("decode OK");
-            } else {
-                log::error!("invalid decoded data");
-            }
-        }
+    match raptorq.check_round_trip(args.remove) {
+        Ok(()) => log::info!("decode OK"),
+        Err(e) => log::error!("{e}"),
     }
 }
@@ -11,6 +11,7 @@ fn parse_duration_seconds(input: &str) -> Result<time::Duration, <u64 as FromStr
 #[clap(
     about = "Receive data from diode-oneshot-send and write them to stdout (no need for diode-send nor diode-receive)."
 )]
+#[allow(clippy::struct_excessive_bools)]
 struct Args {
     #[clap(
         default_value = "Info",
@@ -34,10 +35,17 @@ struct Args {
     from_mtu: u16,
     #[clap(
         value_name = "2..1024",
+        value_parser = clap::value_parser!(u32).range(2..=1024),
         long,
         help = "Use recvmmsg to receive from 2 to 1024 UDP datagrams at once"
     )]
     batch: Option<u32>,
+    #[clap(
+        long,
+        requires = "batch",
+        help = "Block until a full batch of UDP datagrams is received (fewer syscalls, higher latency)"
+    )]
+    batch_wait_full: bool,
     #[clap(
         default_value = "2",
         value_name = "seconds",
@@ -74,8 +82,26 @@ struct Args {
         help = "Percentage of RaptorQ repair data"
     )]
     repair: u32,
+    #[clap(
+        long,
+        help = "Raw mode for lossless links: no RaptorQ encoding/decoding (requires --repair 0, must be set on both sides)"
+    )]
+    raw: bool,
     #[clap(long, help = "Set CPU affinity for threads")]
     cpu_affinity: bool,
+    #[clap(
+        value_name = "-20..19",
+        value_parser = clap::value_parser!(i32).range(-20..=19),
+        allow_hyphen_values = true,
+        long,
+        help = "Nice value of the process (lowering it requires CAP_SYS_NICE)"
+    )]
+    nice: Option<i32>,
+    #[clap(
+        long,
+        help = "Abort active transfers when a gap or reordering in received blocks is detected"
+    )]
+    strict_ordering: bool,
 }
 
 fn main() {
@@ -92,7 +118,17 @@ fn main() {
         env!("CARGO_PKG_VERSION")
     );
 
-    let raptorq = match protocol::RaptorQ::new(args.from_mtu, args.block, args.repair) {
+    if let Some(nice) = args.nice
+        && let Err(e) = diode::set_nice(nice)
+    {
+        log::error!("failed to set nice value: {e}");
+        return;
+    }
+
+    let raptorq = match protocol::RaptorQ::validate_params(args.from_mtu, args.block, args.repair)
+        .and_then(|()| protocol::RaptorQ::new(args.from_mtu, args.block, args.repair))
+        .and_then(|raptorq| raptorq.with_raw_mode(args.raw))
+    {
         Ok(raptorq) => raptorq,
         Err(e) => {
             log::error!("{e}");
@@ -104,18 +140,54 @@ fn main() {
         receive::Config {
             from: args.from,
             from_mtu: args.from_mtu,
+            reuse_port: false,
+            ipv6_only: None,
+            recv_buffer_size: None,
             max_clients: 1,
+            nb_client_threads: 1,
             flush: args.flush,
+            client_buffer_size: None,
             reset_timeout: args.reset_timeout,
             nb_decode_threads: args.decode_threads,
             abort_timeout: args.abort_timeout,
+            max_transfer_duration: None,
+            max_transfer_bytes: None,
+            write_timeout: None,
             heartbeat_interval: None,
             batch_receive: args.batch,
+            batch_wait_full: args.batch_wait_full,
+            gro: false,
+            io_uring: None,
             cpu_affinity: args.cpu_affinity,
+            lock_buffers: false,
+            strict_ordering: args.strict_ordering,
+            keep_transfers_on_loss: false,
+            protocol_dump: None,
+            split_blocks: None,
+            udp_record: None,
+            udp_replay: None,
+            max_queued_transfers: None,
+            queued_transfers_policy: receive::QueuePolicy::RejectNewest,
+            channel_capacity: None,
+            min_decode_rate: None,
+            decode_rate_window: 0,
+            exit_on_low_decode_rate: false,
+            drop_privileges: None,
+            log_timestamps: false,
+            block_ids: protocol::BlockIdScheme::Global,
+            transfer_summary: false,
+            transfer_uuid: false,
+            records: false,
+            dual_feed: false,
+            log_alive: false,
+            log_drops: false,
+            pause_policy: receive::PausePolicy::Buffer,
+            pause_buffer: 0,
+            on_transfer_complete: None,
         },
         raptorq,
         |_| Ok::<_, io::Error>(io::stdout()),
-        |_, ok| {
+        |_, ok, _| {
             if ok {
                 process::exit(0);
             } else {
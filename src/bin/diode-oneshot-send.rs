@@ -36,6 +36,13 @@ struct Args {
         help = "Binding IP for UDP traffic"
     )]
     to_bind: net::SocketAddr,
+    #[clap(
+        default_value = "5",
+        value_name = "nb_retries",
+        long,
+        help = "Number of retries, with backoff, when the binding port is already in use"
+    )]
+    to_bind_retries: u32,
     #[clap(
         default_value = "1500",
         value_name = "nb_bytes",
@@ -45,6 +52,7 @@ struct Args {
     to_mtu: u16,
     #[clap(
         value_name = "2..1024",
+        value_parser = clap::value_parser!(u32).range(2..=1024),
         long,
         help = "Use sendmmsg to send from 2 to 1024 UDP datagrams at once"
     )]
@@ -63,8 +71,21 @@ struct Args {
         help = "Percentage of RaptorQ repair data"
     )]
     repair: u32,
+    #[clap(
+        long,
+        help = "Raw mode for lossless links: no RaptorQ encoding/decoding (requires --repair 0, must be set on both sides)"
+    )]
+    raw: bool,
     #[clap(long, help = "Set CPU affinity for threads")]
     cpu_affinity: bool,
+    #[clap(
+        value_name = "-20..19",
+        value_parser = clap::value_parser!(i32).range(-20..=19),
+        allow_hyphen_values = true,
+        long,
+        help = "Nice value of the process (lowering it requires CAP_SYS_NICE)"
+    )]
+    nice: Option<i32>,
 }
 
 fn main() {
@@ -81,7 +102,17 @@ fn main() {
         env!("CARGO_PKG_VERSION")
     );
 
-    let raptorq = match protocol::RaptorQ::new(args.to_mtu, args.block, args.repair) {
+    if let Some(nice) = args.nice
+        && let Err(e) = diode::set_nice(nice)
+    {
+        log::error!("failed to set nice value: {e}");
+        return;
+    }
+
+    let raptorq = match protocol::RaptorQ::validate_params(args.to_mtu, args.block, args.repair)
+        .and_then(|()| protocol::RaptorQ::new(args.to_mtu, args.block, args.repair))
+        .and_then(|raptorq| raptorq.with_raw_mode(args.raw))
+    {
         Ok(raptorq) => raptorq,
         Err(e) => {
             log::error!("{e}");
@@ -95,11 +126,33 @@ fn main() {
             flush: args.flush,
             nb_encode_threads: args.encode_threads,
             heartbeat_interval: None,
+            probe_interval: None,
+            max_transfer_duration: None,
             to: args.to,
             to_bind: args.to_bind,
+            to_bind_retries: args.to_bind_retries,
             to_mtu: args.to_mtu,
+            ttl: None,
+            ipv6_only: None,
+            send_buffer_size: None,
+            multicast_ttl: None,
             batch_send: args.batch,
+            gso: false,
+            continuous_repair: None,
+            repair_spacing: None,
+            shuffle_packets: None,
+            max_in_flight_blocks: None,
+            virtual_block_size: None,
+            rate_limit_bps: None,
             cpu_affinity: args.cpu_affinity,
+            block_ids: protocol::BlockIdScheme::Global,
+            transfer_summary: false,
+            transfer_uuid: false,
+            records: false,
+            channel_send_timeout: None,
+            log_alive: false,
+            fd_ceiling: None,
+            audit_sink: None,
         },
         raptorq,
     ) {
@@ -112,7 +165,7 @@ fn main() {
 
     let sender = sync::Arc::new(sender);
 
-    thread::scope(|scope| {
+    let err = thread::scope(|scope| {
         if let Err(e) = sender.start(scope) {
             log::error!("failed to start diode sender: {e}");
         }
@@ -128,8 +181,13 @@ fn main() {
             log::error!("failed to send stop: {e}");
         }
 
-        if let Some(err) = err {
-            process::exit(err);
-        }
+        err
     });
+
+    // workers are done, counters are final
+    log::info!("{}", sender.stats());
+
+    if let Some(err) = err {
+        process::exit(err);
+    }
 }
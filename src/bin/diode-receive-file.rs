@@ -21,6 +21,7 @@ struct Listeners {
 
 #[derive(Parser)]
 #[clap(about = "Receive file(s) sent by diode-send-file through lidi.")]
+#[allow(clippy::struct_excessive_bools)]
 struct Args {
     #[clap(
         default_value = "Info",
@@ -40,6 +41,53 @@ struct Args {
     buffer_size: usize,
     #[clap(long, help = "Verify the hash of file content")]
     hash: bool,
+    #[clap(
+        value_name = "hex|base64",
+        long,
+        requires = "hash",
+        help = "Print the hash of every received file to the standard output"
+    )]
+    print_hash: Option<file::HashEncoding>,
+    #[clap(
+        default_value = "native",
+        value_name = "native|cbor",
+        long,
+        help = "Format of file metadata sent along with file content"
+    )]
+    format: file::Format,
+    #[clap(
+        long,
+        help = "Only transfer data extents of files, holes being recreated (must be set on both sides)"
+    )]
+    sparse: bool,
+    #[clap(
+        long,
+        help = "Checksum file headers to detect their corruption (must be set on both sides)"
+    )]
+    header_checksum: bool,
+    #[clap(
+        long,
+        help = "Rename incomplete files with a .partial suffix instead of keeping their name"
+    )]
+    keep_partial: bool,
+    #[clap(
+        long,
+        help = "Write files under a hidden temporary name, renamed once completely received"
+    )]
+    atomic_rename: bool,
+    #[clap(
+        long,
+        conflicts_with_all = ["hash", "sparse", "header_checksum", "keep_partial", "atomic_rename"],
+        help = "Extract incoming streams as tar archives into the output directory"
+    )]
+    untar: bool,
+    #[clap(
+        value_name = "nb_files",
+        value_parser = clap::value_parser!(u32).range(1..),
+        long,
+        help = "Maximum number of files written at the same time"
+    )]
+    max_open_files: Option<u32>,
     #[clap(default_value = ".", help = "Output directory")]
     output_directory: path::PathBuf,
 }
@@ -67,6 +115,15 @@ fn main() {
         diode,
         buffer_size: args.buffer_size,
         hash: args.hash,
+        format: args.format,
+        sparse: args.sparse,
+        header_checksum: args.header_checksum,
+        keep_partial: args.keep_partial,
+        atomic_rename: args.atomic_rename,
+        mmap: false,
+        untar: args.untar,
+        max_open_files: args.max_open_files,
+        print_hash: args.print_hash,
     };
 
     if let Err(e) = file::receive::receive_files(&config, &args.output_directory) {
@@ -1,12 +1,13 @@
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use diode::{protocol, receive};
 use std::{
+    env,
     io::{self, Write},
     net,
-    os::{fd::AsRawFd, unix},
-    path,
+    os::unix,
+    path, process,
     str::FromStr,
-    thread, time,
+    sync, thread, time,
 };
 
 fn parse_duration_seconds(input: &str) -> Result<time::Duration, <u64 as FromStr>::Err> {
@@ -15,7 +16,8 @@ fn parse_duration_seconds(input: &str) -> Result<time::Duration, <u64 as FromStr
 }
 
 #[derive(clap::Args)]
-#[group(required = true, multiple = false)]
+#[group(required = true, multiple = true)]
+#[allow(clippy::struct_field_names)]
 struct Clients {
     #[clap(
         value_name = "ip:port",
@@ -29,10 +31,18 @@ struct Clients {
         help = "Path of socket to connect to Unix server"
     )]
     to_unix: Option<path::PathBuf>,
+    #[clap(
+        value_name = "url",
+        long,
+        requires = "s3_bucket",
+        help = "URL of an S3-compatible object store to upload every transfer to (credentials from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY)"
+    )]
+    to_s3: Option<String>,
 }
 
 #[derive(Parser)]
 #[clap(about = "Receiver part of lidi.")]
+#[allow(clippy::struct_excessive_bools)]
 struct Args {
     #[clap(
         default_value = "Info",
@@ -47,12 +57,19 @@ struct Args {
         help = "Log messages in a file instead of the console"
     )]
     log_file: Option<path::PathBuf>,
+    #[clap(
+        value_name = "path",
+        long,
+        help = "TOML file of receiver configuration, overridden by command line options"
+    )]
+    config: Option<path::PathBuf>,
     #[clap(
         value_name = "ip:port",
         long,
+        required_unless_present = "config",
         help = "IP address and port where to receive UDP packets from diode-send"
     )]
-    from: net::SocketAddr,
+    from: Option<net::SocketAddr>,
     #[clap(
         default_value = "1500",
         value_name = "nb_bytes",
@@ -60,12 +77,50 @@ struct Args {
         help = "MTU of the input UDP link"
     )]
     from_mtu: u16,
+    #[clap(
+        long,
+        help = "Let several receivers bind to the same UDP address (SO_REUSEPORT)"
+    )]
+    reuse_port: bool,
+    #[clap(
+        value_name = "true|false",
+        long,
+        help = "Restrict the IPv6 UDP socket to IPv6 traffic, or also accept IPv4-mapped addresses (IPV6_V6ONLY)"
+    )]
+    ipv6_only: Option<bool>,
+    #[clap(
+        value_name = "nb_bytes",
+        value_parser = clap::value_parser!(i32).range(1..),
+        long,
+        help = "Size of the UDP socket receive buffer (default: computed from the RaptorQ parameters)"
+    )]
+    udp_recv_buffer: Option<i32>,
     #[clap(
         value_name = "2..1024",
+        value_parser = clap::value_parser!(u32).range(2..=1024),
         long,
         help = "Use recvmmsg to receive from 2 to 1024 UDP datagrams at once"
     )]
     batch: Option<u32>,
+    #[clap(
+        long,
+        requires = "batch",
+        help = "Block until a full batch of UDP datagrams is received (fewer syscalls, higher latency)"
+    )]
+    batch_wait_full: bool,
+    #[clap(
+        value_name = "2..4096",
+        value_parser = clap::value_parser!(u32).range(2..=4096),
+        long,
+        conflicts_with = "batch",
+        help = "Receive UDP datagrams through an io_uring ring of 2 to 4096 buffers (falls back to recvmmsg if unavailable)"
+    )]
+    io_uring: Option<u32>,
+    #[clap(
+        long,
+        help = "Let the kernel aggregate received UDP datagrams into large buffers (UDP GRO)"
+    )]
+    gro: bool,
     #[clap(
         default_value = "2",
         value_name = "seconds",
@@ -87,14 +142,45 @@ struct Args {
         help = "Max number of simultaneous clients/transfers"
     )]
     max_clients: protocol::ClientId,
+    #[clap(
+        value_name = "nb_threads",
+        long,
+        help = "Number of client worker threads [default: max_clients]"
+    )]
+    client_threads: Option<u32>,
     #[clap(long, help = "Flush immediately data to clients")]
     flush: bool,
+    #[clap(
+        value_name = "nb_bytes",
+        long,
+        help = "Size of the buffer coalescing writes to clients [default: block capacity]"
+    )]
+    client_buffer: Option<usize>,
     #[clap(
         value_name = "seconds",
         value_parser = parse_duration_seconds,
         long,
         help = "Abort connections if no data received after duration (0 = no abort)")]
     abort_timeout: Option<time::Duration>,
+    #[clap(
+        value_name = "seconds",
+        value_parser = parse_duration_seconds,
+        long,
+        help = "Abort transfers lasting longer than duration from their start"
+    )]
+    max_transfer_duration: Option<time::Duration>,
+    #[clap(
+        value_name = "nb_bytes",
+        long,
+        help = "Abort transfers writing more than this number of bytes to their client"
+    )]
+    max_transfer_bytes: Option<u64>,
+    #[clap(
+        value_name = "seconds",
+        value_parser = parse_duration_seconds,
+        long,
+        help = "Abort transfers blocked for this duration when writing to clients (0 = no timeout)")]
+    write_timeout: Option<time::Duration>,
     #[clap(flatten)]
     to: Clients,
     #[clap(
@@ -111,6 +197,28 @@ struct Args {
         help = "Percentage of RaptorQ repair data"
     )]
     repair: u32,
+    #[clap(
+        long,
+        help = "Raw mode for lossless links: no RaptorQ encoding/decoding (requires --repair 0, must be set on both sides)"
+    )]
+    raw: bool,
+    #[clap(
+        value_name = "path",
+        long,
+        help = "File containing the key authenticating every packet (must be the same on both sides)"
+    )]
+    packet_key: Option<path::PathBuf>,
+    #[clap(
+        long,
+        help = "Encode and decode a random block at startup to check the RaptorQ parameters"
+    )]
+    self_check: bool,
+    #[clap(
+        long,
+        requires = "self_check",
+        help = "Exit if the startup self-check fails"
+    )]
+    exit_on_self_check_failure: bool,
     #[clap(
         default_value = "10",
         value_name = "nb_seconds",
@@ -120,11 +228,163 @@ struct Args {
     heartbeat: Option<time::Duration>,
     #[clap(long, help = "Set CPU affinity for threads")]
     cpu_affinity: bool,
+    #[clap(
+        long,
+        help = "Pre-fault and lock in memory the UDP receive buffers (limited by RLIMIT_MEMLOCK)"
+    )]
+    lock_buffers: bool,
+    #[clap(
+        value_name = "-20..19",
+        value_parser = clap::value_parser!(i32).range(-20..=19),
+        allow_hyphen_values = true,
+        long,
+        help = "Nice value of the process (lowering it requires CAP_SYS_NICE)"
+    )]
+    nice: Option<i32>,
+    #[clap(
+        long,
+        help = "Abort active transfers when a gap or reordering in received blocks is detected"
+    )]
+    strict_ordering: bool,
+    #[clap(
+        long,
+        help = "Keep active transfers going with missing data when a block is lost, instead of aborting them"
+    )]
+    keep_transfers_on_loss: bool,
+    #[clap(
+        value_name = "path",
+        long,
+        help = "Write a record of every decoded block to a file, for protocol analysis"
+    )]
+    protocol_dump: Option<path::PathBuf>,
+    #[clap(
+        value_name = "dir",
+        long,
+        help = "Write the payload of every decoded block to its own numbered file in a directory"
+    )]
+    split_blocks: Option<path::PathBuf>,
+    #[clap(
+        value_name = "path",
+        long,
+        help = "Record every received UDP datagram to a file, to be replayed with --udp-replay"
+    )]
+    udp_record: Option<path::PathBuf>,
+    #[clap(
+        value_name = "path",
+        long,
+        conflicts_with = "udp_record",
+        help = "Replay the UDP datagrams recorded in a file instead of listening, then exit"
+    )]
+    udp_replay: Option<path::PathBuf>,
+    #[clap(
+        long,
+        help = "Log the reception time and offset of every chunk of data, for latency analysis"
+    )]
+    log_timestamps: bool,
+    #[clap(
+        value_name = "nb_transfers",
+        long,
+        help = "Maximum number of transfers waiting for a client thread"
+    )]
+    max_queued_transfers: Option<usize>,
+    #[clap(
+        default_value = "reject-newest",
+        value_name = "reject-newest|drop-oldest",
+        long,
+        help = "Transfer to drop when max_queued_transfers is reached"
+    )]
+    queued_transfers_policy: receive::QueuePolicy,
+    #[clap(
+        value_name = "nb_messages",
+        long,
+        help = "Use bounded channels of this capacity between UDP, reblock and decode workers"
+    )]
+    channel_capacity: Option<usize>,
+    #[clap(
+        value_name = "percentage",
+        long,
+        help = "Log an error when the rate of successfully decoded blocks drops below this percentage"
+    )]
+    min_decode_rate: Option<f64>,
+    #[clap(
+        default_value = "100",
+        value_name = "nb_blocks",
+        long,
+        help = "Number of last blocks over which the decode success rate is computed"
+    )]
+    decode_rate_window: usize,
+    #[clap(
+        long,
+        requires = "min_decode_rate",
+        help = "Exit when the decode success rate drops below min_decode_rate"
+    )]
+    exit_on_low_decode_rate: bool,
+    #[clap(
+        value_name = "user:group",
+        long,
+        help = "Switch to this user and group once the UDP socket and buffers are set up"
+    )]
+    drop_privileges: Option<diode::Credentials>,
+    #[clap(
+        default_value = "global",
+        value_name = "global|per-client",
+        long,
+        help = "Assignment of block ids, per-client giving a sequence of ids to every transfer (must be set on both sides)"
+    )]
+    block_ids: protocol::BlockIdScheme,
+    #[clap(
+        long,
+        help = "Log the summary of every transfer sent in its End block (must be set on both sides)"
+    )]
+    transfer_summary: bool,
+    #[clap(
+        long,
+        help = "Log the UUID identifying every transfer sent in its Start block (must be set on both sides)"
+    )]
+    transfer_uuid: bool,
+    #[clap(
+        long,
+        help = "Transfer records prefixed by their length (big-endian u32) in their own blocks (must be set on both sides)"
+    )]
+    records: bool,
+    #[clap(
+        long,
+        help = "Deliver transfers sent twice (client ids 2n and 2n+1) only if both copies are identical"
+    )]
+    dual_feed: bool,
+    #[clap(
+        long,
+        help = "Log the delivered throughput and the number of active transfers every second"
+    )]
+    log_alive: bool,
+    #[clap(
+        long,
+        help = "Log every drop of received packets with its reason (at debug level)"
+    )]
+    log_drops: bool,
+    #[clap(value_name = "name", long, help = "Bucket of the uploaded objects")]
+    s3_bucket: Option<String>,
+    #[clap(
+        default_value = "us-east-1",
+        value_name = "region",
+        long,
+        help = "Region of the object store"
+    )]
+    s3_region: String,
+    #[clap(
+        default_value = "",
+        value_name = "prefix",
+        long,
+        help = "Prefix of the uploaded object keys"
+    )]
+    s3_prefix: String,
 }
 
 enum Client {
     Tcp(net::TcpStream),
     Unix(unix::net::UnixStream),
+    S3(receive::S3Upload),
+    Broadcast(receive::Broadcast<Client>),
 }
 
 impl Write for Client {
@@ -132,6 +392,8 @@ impl Write for Client {
         match self {
             Self::Tcp(socket) => socket.write(buf),
             Self::Unix(socket) => socket.write(buf),
+            Self::S3(upload) => upload.write(buf),
+            Self::Broadcast(clients) => clients.write(buf),
         }
     }
 
@@ -139,39 +401,325 @@ impl Write for Client {
         match self {
             Self::Tcp(socket) => socket.flush(),
             Self::Unix(socket) => socket.flush(),
+            Self::S3(upload) => upload.flush(),
+            Self::Broadcast(clients) => clients.flush(),
         }
     }
 }
 
-impl AsRawFd for Client {
-    fn as_raw_fd(&self) -> i32 {
+impl receive::Sink for Client {
+    fn finish(&mut self, completed: bool) -> Result<(), std::io::Error> {
         match self {
-            Self::Tcp(socket) => socket.as_raw_fd(),
-            Self::Unix(socket) => socket.as_raw_fd(),
+            Self::Tcp(_) | Self::Unix(_) => Ok(()),
+            Self::S3(upload) => upload.finish(completed),
+            Self::Broadcast(clients) => clients.finish(completed),
+        }
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), std::io::Error> {
+        match self {
+            Self::Tcp(socket) => socket.set_nonblocking(nonblocking),
+            Self::Unix(socket) => socket.set_nonblocking(nonblocking),
+            Self::S3(upload) => upload.set_nonblocking(nonblocking),
+            Self::Broadcast(clients) => clients.set_nonblocking(nonblocking),
         }
     }
 }
 
-impl TryFrom<&Clients> for Client {
-    type Error = io::Error;
+impl Client {
+    /// Connects to every output of the transfer of `client_id`, broadcasting it if there are
+    /// several outputs: an output failing does not prevent the transfer to the other ones
+    fn connect(
+        clients: &Clients,
+        s3: Option<&sync::Arc<receive::S3>>,
+        s3_prefix: &str,
+        client_id: protocol::ClientId,
+    ) -> Result<Self, io::Error> {
+        let mut outputs = Vec::with_capacity(3);
+        let mut error = None;
 
-    fn try_from(clients: &Clients) -> Result<Self, Self::Error> {
         if let Some(to_tcp) = clients.to_tcp.as_ref() {
-            let client = net::TcpStream::connect(to_tcp)?;
-            Ok(Self::Tcp(client))
-        } else if let Some(to_unix) = clients.to_unix.as_ref() {
-            let client = unix::net::UnixStream::connect(to_unix)?;
-            Ok(Self::Unix(client))
-        } else {
-            unreachable!()
+            match net::TcpStream::connect(to_tcp) {
+                Ok(client) => outputs.push(Self::Tcp(client)),
+                Err(e) => {
+                    log::error!("failed to connect to TCP {to_tcp}: {e}");
+                    error = Some(e);
+                }
+            }
+        }
+        if let Some(to_unix) = clients.to_unix.as_ref() {
+            match unix::net::UnixStream::connect(to_unix) {
+                Ok(client) => outputs.push(Self::Unix(client)),
+                Err(e) => {
+                    log::error!("failed to connect to Unix {}: {e}", to_unix.display());
+                    error = Some(e);
+                }
+            }
+        }
+        if let Some(s3) = s3 {
+            let timestamp = time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)
+                .unwrap_or_default();
+            let key = format!(
+                "{s3_prefix}{}.{:06}-{client_id:x}",
+                timestamp.as_secs(),
+                timestamp.subsec_micros()
+            );
+            match receive::S3Upload::new(s3.clone(), key) {
+                Ok(upload) => outputs.push(Self::S3(upload)),
+                Err(e) => {
+                    log::error!("failed to start upload: {e}");
+                    error = Some(e);
+                }
+            }
         }
+
+        match (outputs.len(), error) {
+            (0, Some(e)) if clients.is_single() => Err(e),
+            (0, _) => Err(io::Error::other("failed to connect to any output")),
+            (1, _) => Ok(outputs.remove(0)),
+            _ => Ok(Self::Broadcast(receive::Broadcast::new(outputs))),
+        }
+    }
+}
+
+impl Clients {
+    fn is_single(&self) -> bool {
+        [
+            self.to_tcp.is_some(),
+            self.to_unix.is_some(),
+            self.to_s3.is_some(),
+        ]
+        .into_iter()
+        .filter(|output| *output)
+        .count()
+            == 1
+    }
+}
+
+/// Builds the receiver configuration from the command line options, on top of the configuration
+/// file values if a file is given
+#[allow(clippy::too_many_lines)]
+fn receiver_config(
+    args: &Args,
+    matches: &clap::ArgMatches,
+) -> Result<receive::Config, diode::config::Error> {
+    let Some(path) = args.config.as_ref() else {
+        return Ok(receive::Config {
+            from: args.from.expect("--from is required without --config"),
+            from_mtu: args.from_mtu,
+            reuse_port: args.reuse_port,
+            ipv6_only: args.ipv6_only,
+            recv_buffer_size: args.udp_recv_buffer,
+            max_clients: args.max_clients,
+            nb_client_threads: args.client_threads.unwrap_or(args.max_clients),
+            flush: args.flush,
+            client_buffer_size: args.client_buffer,
+            reset_timeout: args.reset_timeout,
+            nb_decode_threads: args.decode_threads,
+            abort_timeout: args.abort_timeout,
+            max_transfer_duration: args.max_transfer_duration,
+            max_transfer_bytes: args.max_transfer_bytes,
+            write_timeout: args.write_timeout,
+            heartbeat_interval: args.heartbeat,
+            batch_receive: args.batch,
+            batch_wait_full: args.batch_wait_full,
+            io_uring: args.io_uring,
+            gro: args.gro,
+            cpu_affinity: args.cpu_affinity,
+            lock_buffers: args.lock_buffers,
+            strict_ordering: args.strict_ordering,
+            keep_transfers_on_loss: args.keep_transfers_on_loss,
+            protocol_dump: args.protocol_dump.clone(),
+            split_blocks: args.split_blocks.clone(),
+            udp_record: args.udp_record.clone(),
+            udp_replay: args.udp_replay.clone(),
+            log_timestamps: args.log_timestamps,
+            max_queued_transfers: args.max_queued_transfers,
+            queued_transfers_policy: args.queued_transfers_policy,
+            channel_capacity: args.channel_capacity,
+            min_decode_rate: args.min_decode_rate,
+            decode_rate_window: args.decode_rate_window,
+            exit_on_low_decode_rate: args.exit_on_low_decode_rate,
+            drop_privileges: args.drop_privileges,
+            block_ids: args.block_ids,
+            transfer_summary: args.transfer_summary,
+            transfer_uuid: args.transfer_uuid,
+            records: args.records,
+            dual_feed: args.dual_feed,
+            log_alive: args.log_alive,
+            log_drops: args.log_drops,
+            pause_policy: receive::PausePolicy::Buffer,
+            pause_buffer: 0,
+            on_transfer_complete: None,
+        });
+    };
+
+    let mut config: receive::Config = diode::config::load(path)?;
+
+    let from_cli = |id| matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine);
+
+    if let Some(from) = args.from {
+        config.from = from;
+    }
+    if from_cli("from_mtu") {
+        config.from_mtu = args.from_mtu;
+    }
+    if from_cli("reuse_port") {
+        config.reuse_port = args.reuse_port;
+    }
+    if from_cli("ipv6_only") {
+        config.ipv6_only = args.ipv6_only;
+    }
+    if from_cli("udp_recv_buffer") {
+        config.recv_buffer_size = args.udp_recv_buffer;
+    }
+    if from_cli("max_clients") {
+        config.max_clients = args.max_clients;
+    }
+    if from_cli("client_threads") {
+        config.nb_client_threads = args.client_threads.unwrap_or(args.max_clients);
+    }
+    if from_cli("flush") {
+        config.flush = args.flush;
+    }
+    if from_cli("client_buffer") {
+        config.client_buffer_size = args.client_buffer;
+    }
+    if from_cli("reset_timeout") {
+        config.reset_timeout = args.reset_timeout;
+    }
+    if from_cli("decode_threads") {
+        config.nb_decode_threads = args.decode_threads;
+    }
+    if from_cli("abort_timeout") {
+        config.abort_timeout = args.abort_timeout;
+    }
+    if from_cli("max_transfer_duration") {
+        config.max_transfer_duration = args.max_transfer_duration;
+    }
+    if from_cli("max_transfer_bytes") {
+        config.max_transfer_bytes = args.max_transfer_bytes;
+    }
+    if from_cli("write_timeout") {
+        config.write_timeout = args.write_timeout;
+    }
+    if from_cli("heartbeat") {
+        config.heartbeat_interval = args.heartbeat;
+    }
+    if from_cli("batch") {
+        config.batch_receive = args.batch;
+    }
+    if from_cli("batch_wait_full") {
+        config.batch_wait_full = args.batch_wait_full;
+    }
+    if from_cli("io_uring") {
+        config.io_uring = args.io_uring;
+    }
+    if from_cli("gro") {
+        config.gro = args.gro;
+    }
+    if from_cli("cpu_affinity") {
+        config.cpu_affinity = args.cpu_affinity;
+    }
+    if from_cli("lock_buffers") {
+        config.lock_buffers = args.lock_buffers;
     }
+    if from_cli("strict_ordering") {
+        config.strict_ordering = args.strict_ordering;
+    }
+    if from_cli("keep_transfers_on_loss") {
+        config.keep_transfers_on_loss = args.keep_transfers_on_loss;
+    }
+    if from_cli("protocol_dump") {
+        config.protocol_dump.clone_from(&args.protocol_dump);
+    }
+    if from_cli("split_blocks") {
+        config.split_blocks.clone_from(&args.split_blocks);
+    }
+    if from_cli("udp_record") {
+        config.udp_record.clone_from(&args.udp_record);
+    }
+    if from_cli("udp_replay") {
+        config.udp_replay.clone_from(&args.udp_replay);
+    }
+    if from_cli("log_timestamps") {
+        config.log_timestamps = args.log_timestamps;
+    }
+    if from_cli("max_queued_transfers") {
+        config.max_queued_transfers = args.max_queued_transfers;
+    }
+    if from_cli("queued_transfers_policy") {
+        config.queued_transfers_policy = args.queued_transfers_policy;
+    }
+    if from_cli("channel_capacity") {
+        config.channel_capacity = args.channel_capacity;
+    }
+    if from_cli("min_decode_rate") {
+        config.min_decode_rate = args.min_decode_rate;
+    }
+    if from_cli("decode_rate_window") {
+        config.decode_rate_window = args.decode_rate_window;
+    }
+    if from_cli("exit_on_low_decode_rate") {
+        config.exit_on_low_decode_rate = args.exit_on_low_decode_rate;
+    }
+    if from_cli("drop_privileges") {
+        config.drop_privileges = args.drop_privileges;
+    }
+    if from_cli("block_ids") {
+        config.block_ids = args.block_ids;
+    }
+    if from_cli("transfer_summary") {
+        config.transfer_summary = args.transfer_summary;
+    }
+    if from_cli("transfer_uuid") {
+        config.transfer_uuid = args.transfer_uuid;
+    }
+    if from_cli("records") {
+        config.records = args.records;
+    }
+    if from_cli("dual_feed") {
+        config.dual_feed = args.dual_feed;
+    }
+    if from_cli("log_alive") {
+        config.log_alive = args.log_alive;
+    }
+    if from_cli("log_drops") {
+        config.log_drops = args.log_drops;
+    }
+
+    Ok(config)
+}
+
+/// Uploader to the S3 endpoint given by `--to-s3`, if any, authenticated with the credentials
+/// of the environment
+fn s3_uploader(args: &Args) -> Result<Option<sync::Arc<receive::S3>>, String> {
+    let Some(endpoint) = args.to.to_s3.as_deref() else {
+        return Ok(None);
+    };
+    let (Ok(access_key), Ok(secret_key)) = (
+        env::var("AWS_ACCESS_KEY_ID"),
+        env::var("AWS_SECRET_ACCESS_KEY"),
+    ) else {
+        return Err(format!(
+            "AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY must be set to upload to {endpoint}"
+        ));
+    };
+    Ok(Some(sync::Arc::new(receive::S3::new(
+        endpoint,
+        args.s3_region.clone(),
+        args.s3_bucket.clone().unwrap_or_default(),
+        access_key,
+        secret_key,
+    ))))
 }
 
 fn main() {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
-    if let Err(e) = diode::init_logger(args.log_level, args.log_file, false) {
+    if let Err(e) = diode::init_logger(args.log_level, args.log_file.clone(), false) {
         eprintln!("failed to initialize logger: {e}");
         return;
     }
@@ -182,7 +730,46 @@ fn main() {
         env!("CARGO_PKG_VERSION")
     );
 
-    let raptorq = match protocol::RaptorQ::new(args.from_mtu, args.block, args.repair) {
+    if let Some(nice) = args.nice
+        && let Err(e) = diode::set_nice(nice)
+    {
+        log::error!("failed to set nice value: {e}");
+        return;
+    }
+
+    let config = match receiver_config(&args, &matches) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("failed to load configuration: {e}");
+            return;
+        }
+    };
+
+    let packet_key = match args
+        .packet_key
+        .as_deref()
+        .map(protocol::PacketKey::from_file)
+        .transpose()
+    {
+        Ok(packet_key) => packet_key,
+        Err(e) => {
+            log::error!("{e}");
+            return;
+        }
+    };
+
+    // the tag authenticating packets is carried within the MTU
+    let mtu = if packet_key.is_some() {
+        config.from_mtu.saturating_sub(protocol::PACKET_TAG_SIZE)
+    } else {
+        config.from_mtu
+    };
+
+    let raptorq = match protocol::RaptorQ::validate_params(mtu, args.block, args.repair)
+        .and_then(|()| protocol::RaptorQ::new(mtu, args.block, args.repair))
+        .and_then(|raptorq| raptorq.with_raw_mode(args.raw))
+        .map(|raptorq| raptorq.with_packet_key(packet_key))
+    {
         Ok(raptorq) => raptorq,
         Err(e) => {
             log::error!("{e}");
@@ -190,22 +777,31 @@ fn main() {
         }
     };
 
+    if args.self_check {
+        match raptorq.check_round_trip(None) {
+            Ok(()) => log::info!("self-check passed"),
+            Err(e) => {
+                log::error!("self-check failed: {e}");
+                if args.exit_on_self_check_failure {
+                    return;
+                }
+            }
+        }
+    }
+
+    let s3 = match s3_uploader(&args) {
+        Ok(s3) => s3,
+        Err(e) => {
+            log::error!("{e}");
+            return;
+        }
+    };
+
     let receiver = match receive::Receiver::new(
-        receive::Config {
-            from: args.from,
-            from_mtu: args.from_mtu,
-            max_clients: args.max_clients,
-            flush: args.flush,
-            reset_timeout: args.reset_timeout,
-            nb_decode_threads: args.decode_threads,
-            abort_timeout: args.abort_timeout,
-            heartbeat_interval: args.heartbeat,
-            batch_receive: args.batch,
-            cpu_affinity: args.cpu_affinity,
-        },
+        config,
         raptorq,
-        |_| Client::try_from(&args.to),
-        |_, _| (),
+        |client_id| Client::connect(&args.to, s3.as_ref(), &args.s3_prefix, client_id),
+        |_, _, _| (),
     ) {
         Ok(receiver) => receiver,
         Err(e) => {
@@ -219,4 +815,9 @@ fn main() {
             log::error!("failed to start diode receiver: {e}");
         }
     });
+
+    // the receiver only shuts down by itself on a low decode success rate
+    if receiver.stats().low_decode_rate {
+        process::exit(1);
+    }
 }
@@ -1,6 +1,6 @@
 use clap::Parser;
 use diode::aux::{self, file};
-use std::{net, path};
+use std::{fs, io, net, path};
 
 #[derive(clap::Args)]
 #[group(required = true, multiple = false)]
@@ -21,6 +21,7 @@ struct Clients {
 
 #[derive(Parser)]
 #[clap(about = "Send a file to diode-receive-file through lidi.")]
+#[allow(clippy::struct_excessive_bools)]
 struct Args {
     #[clap(
         default_value = "Info",
@@ -40,10 +41,47 @@ struct Args {
     buffer_size: usize,
     #[clap(long, help = "Compute and send the hash of file content")]
     hash: bool,
-    #[clap(help = "Files to send")]
+    #[clap(
+        default_value = "native",
+        value_name = "native|cbor",
+        long,
+        help = "Format of file metadata sent along with file content"
+    )]
+    format: file::Format,
+    #[clap(
+        long,
+        help = "Only transfer data extents of files, holes being recreated (must be set on both sides)"
+    )]
+    sparse: bool,
+    #[clap(
+        long,
+        help = "Checksum file headers to detect their corruption (must be set on both sides)"
+    )]
+    header_checksum: bool,
+    #[clap(
+        long,
+        conflicts_with = "sparse",
+        help = "Memory-map files instead of copying them into the read buffer"
+    )]
+    mmap: bool,
+    #[clap(
+        value_name = "path",
+        long,
+        help = "Read newline-delimited paths of files to send from a file (- for stdin)"
+    )]
+    files_from: Option<String>,
+    #[clap(help = "Files to send (- to read paths from stdin)")]
     files: Vec<String>,
 }
 
+fn read_file_list(from: &str) -> Result<Vec<String>, file::Error> {
+    if from == "-" {
+        file::send::read_file_list(io::stdin().lock())
+    } else {
+        file::send::read_file_list(io::BufReader::new(fs::File::open(from)?))
+    }
+}
+
 fn main() {
     let args = Args::parse();
 
@@ -70,9 +108,43 @@ fn main() {
         diode,
         buffer_size: args.buffer_size,
         hash: args.hash,
+        format: args.format,
+        sparse: args.sparse,
+        header_checksum: args.header_checksum,
+        keep_partial: false,
+        atomic_rename: false,
+        mmap: args.mmap,
+        untar: false,
+        max_open_files: None,
+        print_hash: None,
     };
 
-    if let Err(e) = file::send::send_files(&config, &args.files) {
+    let mut files = Vec::new();
+    for file in args.files {
+        if file == "-" {
+            match read_file_list(&file) {
+                Ok(list) => files.extend(list),
+                Err(e) => {
+                    log::error!("failed to read file list from stdin: {e}");
+                    return;
+                }
+            }
+        } else {
+            files.push(file);
+        }
+    }
+
+    if let Some(files_from) = args.files_from {
+        match read_file_list(&files_from) {
+            Ok(list) => files.extend(list),
+            Err(e) => {
+                log::error!("failed to read file list from {files_from}: {e}");
+                return;
+            }
+        }
+    }
+
+    if let Err(e) = file::send::send_files(&config, &files) {
         log::error!("{e}");
     }
 }
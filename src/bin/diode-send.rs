@@ -1,7 +1,8 @@
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches};
 use diode::{protocol, send};
 use std::{
-    io::Read,
+    fs,
+    io::{self, Read},
     net,
     os::{fd::AsRawFd, unix},
     path,
@@ -14,6 +15,11 @@ fn parse_duration_seconds(input: &str) -> Result<time::Duration, <u64 as FromStr
     Ok(time::Duration::from_secs(input))
 }
 
+fn parse_duration_microseconds(input: &str) -> Result<time::Duration, <u64 as FromStr>::Err> {
+    let input = input.parse()?;
+    Ok(time::Duration::from_micros(input))
+}
+
 #[derive(clap::Args)]
 #[group(required = true, multiple = true)]
 struct Listeners {
@@ -33,6 +39,7 @@ struct Listeners {
 
 #[derive(clap::Parser)]
 #[clap(about = "Sender part of lidi.")]
+#[allow(clippy::struct_excessive_bools)]
 struct Args {
     #[clap(
         default_value = "Info",
@@ -47,6 +54,12 @@ struct Args {
         help = "Log messages in a file instead of the console"
     )]
     log_file: Option<path::PathBuf>,
+    #[clap(
+        value_name = "path",
+        long,
+        help = "TOML file of sender configuration, overridden by command line options"
+    )]
+    config: Option<path::PathBuf>,
     #[clap(flatten)]
     from: Listeners,
     #[clap(
@@ -71,14 +84,29 @@ struct Args {
         help = "Duration between two emitted heartbeat messages, 0 to disable"
     )]
     heartbeat: Option<time::Duration>,
+    #[clap(
+        value_name = "nb_seconds",
+        value_parser = parse_duration_seconds,
+        long,
+        help = "Duration between two probe blocks sent while no client is connected, to keep checking the link"
+    )]
+    probe_interval: Option<time::Duration>,
+    #[clap(
+        value_name = "nb_seconds",
+        value_parser = parse_duration_seconds,
+        long,
+        help = "Abort transfers lasting longer than duration from the client connection"
+    )]
+    max_transfer_duration: Option<time::Duration>,
     #[clap(long, help = "Flush client data immediately")]
     flush: bool,
     #[clap(
         value_name = "ip:port",
         long,
+        required_unless_present = "config",
         help = "IP address and port where to send UDP packets to diode-receive"
     )]
-    to: net::SocketAddr,
+    to: Option<net::SocketAddr>,
     #[clap(
         default_value = "0.0.0.0:0",
         value_name = "ip:port",
@@ -86,6 +114,13 @@ struct Args {
         help = "Binding IP for UDP traffic"
     )]
     to_bind: net::SocketAddr,
+    #[clap(
+        default_value = "5",
+        value_name = "nb_retries",
+        long,
+        help = "Number of retries, with backoff, when the binding port is already in use"
+    )]
+    to_bind_retries: u32,
     #[clap(
         default_value = "1500",
         value_name = "nb_bytes",
@@ -93,12 +128,84 @@ struct Args {
         help = "MTU of the output UDP link"
     )]
     to_mtu: u16,
+    #[clap(
+        value_name = "1..255",
+        value_parser = clap::value_parser!(u8).range(1..),
+        long,
+        help = "IP time to live (IPv6 hop limit) of UDP packets"
+    )]
+    ttl: Option<u8>,
+    #[clap(
+        value_name = "true|false",
+        long,
+        help = "Restrict the IPv6 UDP socket to IPv6 traffic, or also allow IPv4-mapped addresses (IPV6_V6ONLY)"
+    )]
+    ipv6_only: Option<bool>,
+    #[clap(
+        value_name = "nb_bytes",
+        value_parser = clap::value_parser!(i32).range(1..),
+        long,
+        help = "Size of the UDP socket send buffer (default: computed from the RaptorQ parameters)"
+    )]
+    udp_send_buffer: Option<i32>,
+    #[clap(
+        value_name = "0..255",
+        value_parser = clap::value_parser!(u32).range(..=255),
+        long,
+        help = "IP time to live (IPv6 hop limit) of UDP packets sent to a multicast address"
+    )]
+    multicast_ttl: Option<u32>,
     #[clap(
         value_name = "2..1024",
+        value_parser = clap::value_parser!(u32).range(2..=1024),
         long,
         help = "Use sendmmsg to send from 2 to 1024 UDP datagrams at once"
     )]
     batch: Option<u32>,
+    #[clap(
+        long,
+        help = "Let the kernel segment buffers of equal-sized UDP datagrams (GSO), taking precedence over --batch"
+    )]
+    gso: bool,
+    #[clap(
+        value_name = "bits_per_second",
+        long,
+        help = "Maximum rate of UDP traffic, all transfers included"
+    )]
+    rate_limit_bps: Option<u64>,
+    #[clap(
+        value_name = "nb_packets",
+        long,
+        help = "Keep sending up to nb_packets additional repair packets of the last block while waiting for the next one"
+    )]
+    continuous_repair: Option<u32>,
+    #[clap(
+        value_name = "nb_microseconds",
+        value_parser = parse_duration_microseconds,
+        long,
+        help = "Spread repair packets over time, with this delay between two of them"
+    )]
+    repair_spacing: Option<time::Duration>,
+    #[clap(
+        value_name = "seed",
+        long,
+        num_args = 0..=1,
+        default_missing_value = "0",
+        help = "Send the packets of every block in a pseudo-random order, seeded by seed"
+    )]
+    shuffle_packets: Option<u64>,
+    #[clap(
+        value_name = "nb_blocks",
+        long,
+        help = "Maximum number of blocks per transfer waiting to be encoded before pausing client reads"
+    )]
+    max_in_flight_blocks: Option<usize>,
+    #[clap(
+        value_name = "nb_bytes",
+        long,
+        help = "Emit a block as soon as nb_bytes of client data are read, for lower latency"
+    )]
+    virtual_block: Option<usize>,
     #[clap(
         default_value = "734928",
         value_name = "nb_bytes",
@@ -113,8 +220,81 @@ struct Args {
         help = "Percentage of RaptorQ repair data"
     )]
     repair: u32,
+    #[clap(
+        long,
+        help = "Raw mode for lossless links: no RaptorQ encoding/decoding (requires --repair 0, must be set on both sides)"
+    )]
+    raw: bool,
+    #[clap(
+        value_name = "path",
+        long,
+        help = "File containing the key authenticating every packet (must be the same on both sides)"
+    )]
+    packet_key: Option<path::PathBuf>,
+    #[clap(
+        long,
+        help = "Encode and decode a random block at startup to check the RaptorQ parameters"
+    )]
+    self_check: bool,
+    #[clap(
+        long,
+        requires = "self_check",
+        help = "Exit if the startup self-check fails"
+    )]
+    exit_on_self_check_failure: bool,
     #[clap(long, help = "Set CPU affinity for threads")]
     cpu_affinity: bool,
+    #[clap(
+        default_value = "global",
+        value_name = "global|per-client",
+        long,
+        help = "Assignment of block ids, per-client giving a sequence of ids to every transfer (must be set on both sides)"
+    )]
+    block_ids: protocol::BlockIdScheme,
+    #[clap(
+        long,
+        help = "Send a summary of every transfer in its End block (must be set on both sides)"
+    )]
+    transfer_summary: bool,
+    #[clap(
+        long,
+        help = "Send a random UUID identifying every transfer in its Start block, logged on both sides (must be set on both sides)"
+    )]
+    transfer_uuid: bool,
+    #[clap(
+        long,
+        help = "Transfer records prefixed by their length (big-endian u32) in their own blocks (must be set on both sides)"
+    )]
+    records: bool,
+    #[clap(
+        value_name = "nb_seconds",
+        value_parser = parse_duration_seconds,
+        long,
+        help = "Fail instead of hanging when a worker cannot pass a block or packets on within the duration"
+    )]
+    channel_send_timeout: Option<time::Duration>,
+    #[clap(long, help = "Log the throughput and packet rate sent every second")]
+    log_alive: bool,
+    #[clap(
+        value_name = "nb_clients",
+        long,
+        help = "Refuse clients while this number of clients are open, to keep file descriptors available"
+    )]
+    fd_ceiling: Option<u64>,
+    #[clap(
+        value_name = "path",
+        long,
+        help = "Write a copy of every block sent, before encoding, to a file for audit"
+    )]
+    audit_file: Option<path::PathBuf>,
+    #[clap(
+        value_name = "-20..19",
+        value_parser = clap::value_parser!(i32).range(-20..=19),
+        allow_hyphen_values = true,
+        long,
+        help = "Nice value of the process (lowering it requires CAP_SYS_NICE)"
+    )]
+    nice: Option<i32>,
 }
 
 enum Client {
@@ -140,18 +320,30 @@ impl AsRawFd for Client {
     }
 }
 
+/// Pause before accepting again when the process or the system ran out of file descriptors
+const ACCEPT_RETRY_PAUSE: time::Duration = time::Duration::from_millis(100);
+
+/// Whether accepting failed because no file descriptor is available, which is transient
+fn out_of_fds(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE))
+}
+
 fn unix_listener_loop(listener: &unix::net::UnixListener, sender: &send::Sender<Client>) {
     for client in listener.incoming() {
         match client {
+            Err(e) if out_of_fds(&e) => {
+                log::warn!("failed to accept client, retrying: {e}");
+                thread::sleep(ACCEPT_RETRY_PAUSE);
+            }
             Err(e) => {
                 log::error!("failed to accept client: {e}");
                 return;
             }
-            Ok(client) => {
-                if let Err(e) = sender.new_client(Client::Unix(client)) {
-                    log::error!("failed to send Unix client to connect queue: {e}");
-                }
-            }
+            Ok(client) => match sender.new_client(Client::Unix(client)) {
+                Err(e @ send::Error::TooManyClients(_)) => log::warn!("Unix client: {e}"),
+                Err(e) => log::error!("failed to send Unix client to connect queue: {e}"),
+                Ok(()) => (),
+            },
         }
     }
 }
@@ -159,23 +351,170 @@ fn unix_listener_loop(listener: &unix::net::UnixListener, sender: &send::Sender<
 fn tcp_listener_loop(listener: &net::TcpListener, sender: &send::Sender<Client>) {
     for client in listener.incoming() {
         match client {
+            Err(e) if out_of_fds(&e) => {
+                log::warn!("failed to accept TCP client, retrying: {e}");
+                thread::sleep(ACCEPT_RETRY_PAUSE);
+            }
             Err(e) => {
                 log::error!("failed to accept TCP client: {e}");
                 return;
             }
-            Ok(client) => {
-                if let Err(e) = sender.new_client(Client::Tcp(client)) {
-                    log::error!("failed to send TCP client to connect queue: {e}");
-                }
-            }
+            Ok(client) => match sender.new_client(Client::Tcp(client)) {
+                Err(e @ send::Error::TooManyClients(_)) => log::warn!("TCP client: {e}"),
+                Err(e) => log::error!("failed to send TCP client to connect queue: {e}"),
+                Ok(()) => (),
+            },
         }
     }
 }
 
+/// Builds the sender configuration from the command line options, on top of the configuration
+/// file values if a file is given
+#[allow(clippy::too_many_lines)]
+fn sender_config(
+    args: &Args,
+    matches: &clap::ArgMatches,
+) -> Result<send::Config, diode::config::Error> {
+    let Some(path) = args.config.as_ref() else {
+        return Ok(send::Config {
+            max_clients: args.max_clients,
+            flush: args.flush,
+            nb_encode_threads: args.encode_threads,
+            heartbeat_interval: args.heartbeat,
+            probe_interval: args.probe_interval,
+            max_transfer_duration: args.max_transfer_duration,
+            to: args.to.expect("--to is required without --config"),
+            to_bind: args.to_bind,
+            to_bind_retries: args.to_bind_retries,
+            to_mtu: args.to_mtu,
+            ttl: args.ttl,
+            ipv6_only: args.ipv6_only,
+            send_buffer_size: args.udp_send_buffer,
+            multicast_ttl: args.multicast_ttl,
+            batch_send: args.batch,
+            gso: args.gso,
+            continuous_repair: args.continuous_repair,
+            repair_spacing: args.repair_spacing,
+            shuffle_packets: args.shuffle_packets,
+            max_in_flight_blocks: args.max_in_flight_blocks,
+            virtual_block_size: args.virtual_block,
+            rate_limit_bps: args.rate_limit_bps,
+            cpu_affinity: args.cpu_affinity,
+            block_ids: args.block_ids,
+            transfer_summary: args.transfer_summary,
+            transfer_uuid: args.transfer_uuid,
+            records: args.records,
+            channel_send_timeout: args.channel_send_timeout,
+            log_alive: args.log_alive,
+            fd_ceiling: args.fd_ceiling,
+            audit_sink: None,
+        });
+    };
+
+    let mut config: send::Config = diode::config::load(path)?;
+
+    let from_cli = |id| matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine);
+
+    if from_cli("max_clients") {
+        config.max_clients = args.max_clients;
+    }
+    if from_cli("flush") {
+        config.flush = args.flush;
+    }
+    if from_cli("encode_threads") {
+        config.nb_encode_threads = args.encode_threads;
+    }
+    if from_cli("heartbeat") {
+        config.heartbeat_interval = args.heartbeat;
+    }
+    if from_cli("probe_interval") {
+        config.probe_interval = args.probe_interval;
+    }
+    if from_cli("max_transfer_duration") {
+        config.max_transfer_duration = args.max_transfer_duration;
+    }
+    if let Some(to) = args.to {
+        config.to = to;
+    }
+    if from_cli("to_bind") {
+        config.to_bind = args.to_bind;
+    }
+    if from_cli("to_bind_retries") {
+        config.to_bind_retries = args.to_bind_retries;
+    }
+    if from_cli("to_mtu") {
+        config.to_mtu = args.to_mtu;
+    }
+    if from_cli("ipv6_only") {
+        config.ipv6_only = args.ipv6_only;
+    }
+    if from_cli("udp_send_buffer") {
+        config.send_buffer_size = args.udp_send_buffer;
+    }
+    if from_cli("ttl") {
+        config.ttl = args.ttl;
+    }
+    if from_cli("multicast_ttl") {
+        config.multicast_ttl = args.multicast_ttl;
+    }
+    if from_cli("batch") {
+        config.batch_send = args.batch;
+    }
+    if from_cli("gso") {
+        config.gso = args.gso;
+    }
+    if from_cli("continuous_repair") {
+        config.continuous_repair = args.continuous_repair;
+    }
+    if from_cli("repair_spacing") {
+        config.repair_spacing = args.repair_spacing;
+    }
+    if from_cli("shuffle_packets") {
+        config.shuffle_packets = args.shuffle_packets;
+    }
+    if from_cli("max_in_flight_blocks") {
+        config.max_in_flight_blocks = args.max_in_flight_blocks;
+    }
+    if from_cli("virtual_block") {
+        config.virtual_block_size = args.virtual_block;
+    }
+    if from_cli("rate_limit_bps") {
+        config.rate_limit_bps = args.rate_limit_bps;
+    }
+    if from_cli("cpu_affinity") {
+        config.cpu_affinity = args.cpu_affinity;
+    }
+    if from_cli("block_ids") {
+        config.block_ids = args.block_ids;
+    }
+    if from_cli("transfer_summary") {
+        config.transfer_summary = args.transfer_summary;
+    }
+    if from_cli("transfer_uuid") {
+        config.transfer_uuid = args.transfer_uuid;
+    }
+    if from_cli("records") {
+        config.records = args.records;
+    }
+    if from_cli("channel_send_timeout") {
+        config.channel_send_timeout = args.channel_send_timeout;
+    }
+    if from_cli("log_alive") {
+        config.log_alive = args.log_alive;
+    }
+    if from_cli("fd_ceiling") {
+        config.fd_ceiling = args.fd_ceiling;
+    }
+
+    Ok(config)
+}
+
+#[allow(clippy::too_many_lines)]
 fn main() {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
-    if let Err(e) = diode::init_logger(args.log_level, args.log_file, false) {
+    if let Err(e) = diode::init_logger(args.log_level, args.log_file.clone(), false) {
         eprintln!("failed to initialize logger: {e}");
         return;
     }
@@ -186,7 +525,59 @@ fn main() {
         env!("CARGO_PKG_VERSION")
     );
 
-    let raptorq = match protocol::RaptorQ::new(args.to_mtu, args.block, args.repair) {
+    if let Some(nice) = args.nice
+        && let Err(e) = diode::set_nice(nice)
+    {
+        log::error!("failed to set nice value: {e}");
+        return;
+    }
+
+    let mut config = match sender_config(&args, &matches) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("failed to load configuration: {e}");
+            return;
+        }
+    };
+
+    if let Some(audit_file) = args.audit_file.as_ref() {
+        match fs::File::create(audit_file) {
+            Ok(file) => {
+                log::info!("copying sent blocks to {}", audit_file.display());
+                config.audit_sink = Some(sync::Mutex::new(Box::new(file)));
+            }
+            Err(e) => {
+                log::error!("failed to create {}: {e}", audit_file.display());
+                return;
+            }
+        }
+    }
+
+    let packet_key = match args
+        .packet_key
+        .as_deref()
+        .map(protocol::PacketKey::from_file)
+        .transpose()
+    {
+        Ok(packet_key) => packet_key,
+        Err(e) => {
+            log::error!("{e}");
+            return;
+        }
+    };
+
+    // the tag authenticating packets is carried within the MTU
+    let mtu = if packet_key.is_some() {
+        config.to_mtu.saturating_sub(protocol::PACKET_TAG_SIZE)
+    } else {
+        config.to_mtu
+    };
+
+    let raptorq = match protocol::RaptorQ::validate_params(mtu, args.block, args.repair)
+        .and_then(|()| protocol::RaptorQ::new(mtu, args.block, args.repair))
+        .and_then(|raptorq| raptorq.with_raw_mode(args.raw))
+        .map(|raptorq| raptorq.with_packet_key(packet_key))
+    {
         Ok(raptorq) => raptorq,
         Err(e) => {
             log::error!("{e}");
@@ -194,20 +585,19 @@ fn main() {
         }
     };
 
-    let sender = match send::Sender::new(
-        send::Config {
-            max_clients: args.max_clients,
-            flush: args.flush,
-            nb_encode_threads: args.encode_threads,
-            heartbeat_interval: args.heartbeat,
-            to: args.to,
-            to_bind: args.to_bind,
-            to_mtu: args.to_mtu,
-            batch_send: args.batch,
-            cpu_affinity: args.cpu_affinity,
-        },
-        raptorq,
-    ) {
+    if args.self_check {
+        match raptorq.check_round_trip(None) {
+            Ok(()) => log::info!("self-check passed"),
+            Err(e) => {
+                log::error!("self-check failed: {e}");
+                if args.exit_on_self_check_failure {
+                    return;
+                }
+            }
+        }
+    }
+
+    let sender = match send::Sender::new(config, raptorq) {
         Ok(sender) => sender,
         Err(e) => {
             log::error!("{e}");
@@ -0,0 +1,61 @@
+//! Loading of [`crate::send::Config`] and [`crate::receive::Config`] from TOML files
+//!
+//! Socket addresses are written as strings (`"127.0.0.1:6000"`), durations as integers whose
+//! unit is given by the documentation of each field.
+
+use serde::{Deserialize, Deserializer, de::DeserializeOwned};
+use std::{fmt, fs, io, path, time};
+
+pub enum Error {
+    Io(io::Error),
+    Parse(toml::de::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Io(e) => write!(fmt, "I/O error: {e}"),
+            Self::Parse(e) => write!(fmt, "configuration error: {e}"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Self {
+        Self::Parse(e)
+    }
+}
+
+/// Reads a configuration from a TOML file
+///
+/// # Errors
+///
+/// Will return `Err` if `path` cannot be read or does not contain a valid configuration.
+pub fn load<T: DeserializeOwned>(path: &path::Path) -> Result<T, Error> {
+    let content = fs::read_to_string(path)?;
+    Ok(toml::from_str(&content)?)
+}
+
+pub(crate) fn seconds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<time::Duration, D::Error> {
+    u64::deserialize(deserializer).map(time::Duration::from_secs)
+}
+
+pub(crate) fn option_seconds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<time::Duration>, D::Error> {
+    Option::<u64>::deserialize(deserializer).map(|secs| secs.map(time::Duration::from_secs))
+}
+
+pub(crate) fn option_microseconds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<time::Duration>, D::Error> {
+    Option::<u64>::deserialize(deserializer).map(|micros| micros.map(time::Duration::from_micros))
+}
@@ -1,6 +1,15 @@
 use std::{fs, path};
 
 pub mod aux;
+pub mod bench;
+pub mod config;
+// Allow unsafe code to call libc functions setpriority and getpriority.
+#[allow(unsafe_code)]
+mod priority;
+// Allow unsafe code to call libc functions getpwnam_r, getgrnam_r, setgroups, setgid and
+// setuid.
+#[allow(unsafe_code)]
+mod privileges;
 pub mod protocol;
 pub mod receive;
 pub mod send;
@@ -8,10 +17,13 @@ pub mod send;
 #[allow(unsafe_code)]
 mod sock_utils;
 // Allow unsafe code to initialize C structs and call
-// libc functions recv_mmsg and send_mmsg.
+// libc functions recv_mmsg, send_mmsg, sysconf and mlock.
 #[allow(unsafe_code)]
 mod udp;
 
+pub use priority::set_nice;
+pub use privileges::{Credentials, drop_privileges};
+
 /// # Errors
 ///
 /// Will return `Err` if `file` cannot be opened
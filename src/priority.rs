@@ -0,0 +1,53 @@
+//! Bindings and wrappers for process priority libc functions
+
+use std::{io, ops};
+
+const NICE_RANGE: ops::RangeInclusive<i32> = -20..=19;
+
+/// Sets the nice value of the calling thread, which is inherited by all threads spawned
+/// afterwards, and logs the effective nice value
+///
+/// Must therefore be called at startup, before any worker thread is spawned. Lacking the
+/// privilege (`CAP_SYS_NICE`) to lower the nice value is not an error: a warning is logged and
+/// the current priority is kept.
+///
+/// # Errors
+///
+/// Will return `Err` if `nice` is out of range or if the priority cannot be set or read.
+pub fn set_nice(nice: i32) -> Result<(), io::Error> {
+    if !NICE_RANGE.contains(&nice) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "invalid nice value {nice}, must be between {} and {}",
+                NICE_RANGE.start(),
+                NICE_RANGE.end()
+            ),
+        ));
+    }
+
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == -1 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::PermissionDenied {
+            return Err(e);
+        }
+        log::warn!("not allowed to set nice value to {nice} ({e}), keeping current priority");
+    }
+
+    log::info!("process nice value is {}", get_nice()?);
+
+    Ok(())
+}
+
+fn get_nice() -> Result<i32, io::Error> {
+    // -1 is a valid nice value, errno must be checked to detect errors
+    unsafe { *libc::__errno_location() = 0 };
+    let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+    if nice == -1 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(0) {
+            return Err(e);
+        }
+    }
+    Ok(nice)
+}
@@ -0,0 +1,133 @@
+//! Bindings and wrappers for user and group libc functions, to drop root privileges
+
+use std::{ffi, fmt, io, mem, ptr, str::FromStr};
+
+/// Size of the buffer receiving the strings of `getpwnam_r` and `getgrnam_r` entries
+const ENTRY_BUFFER_SIZE: usize = 16384;
+
+/// User and group to run as, parsed from `user:group` (names or numeric ids)
+#[derive(Clone, Copy, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Credentials {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+impl FromStr for Credentials {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((user, group)) = s.split_once(':') else {
+            return Err(format!("invalid credentials \"{s}\", expecting user:group"));
+        };
+        let uid = user_id(user).map_err(|e| format!("user \"{user}\": {e}"))?;
+        let gid = group_id(group).map_err(|e| format!("group \"{group}\": {e}"))?;
+        Ok(Self { uid, gid })
+    }
+}
+
+impl TryFrom<String> for Credentials {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Credentials {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(fmt, "{}:{}", self.uid, self.gid)
+    }
+}
+
+fn user_id(user: &str) -> Result<libc::uid_t, io::Error> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+
+    let name =
+        ffi::CString::new(user).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut buffer = vec![0; ENTRY_BUFFER_SIZE];
+    let mut entry: libc::passwd = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+
+    let res = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &raw mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &raw mut result,
+        )
+    };
+
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "unknown user"));
+    }
+    Ok(entry.pw_uid)
+}
+
+fn group_id(group: &str) -> Result<libc::gid_t, io::Error> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let name =
+        ffi::CString::new(group).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut buffer = vec![0; ENTRY_BUFFER_SIZE];
+    let mut entry: libc::group = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+
+    let res = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &raw mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &raw mut result,
+        )
+    };
+
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "unknown group"));
+    }
+    Ok(entry.gr_gid)
+}
+
+/// Switches the process to the given user and group, dropping supplementary groups
+///
+/// The change applies to all threads of the process. It cannot be reverted: it must be called
+/// once privileged resources (sockets bound to low ports, large socket buffers) are set up.
+///
+/// # Errors
+///
+/// Will return `Err` if the process is not allowed to change its user or group, or if the
+/// change did not take effect.
+pub fn drop_privileges(credentials: Credentials) -> Result<(), io::Error> {
+    if unsafe { libc::setgroups(0, ptr::null()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::setgid(credentials.gid) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::setuid(credentials.uid) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    if uid != credentials.uid || gid != credentials.gid {
+        return Err(io::Error::other(format!(
+            "running as {uid}:{gid} instead of {credentials} after dropping privileges"
+        )));
+    }
+
+    log::info!("privileges dropped, running as {credentials}");
+
+    Ok(())
+}
@@ -1,7 +1,7 @@
 //! Definition of the Lidi protocol used to transfer data over UDP
 //!
 //! The Lidi protocol is rather simple: since the communications are unidirectional, it is defined
-//! by the blocks structure. There are 5 block types:
+//! by the blocks structure. There are 6 block types:
 //! - `BlockType::Heartbeat` lets know the receiver that transfer can happen,
 //! - `BlockType::Start` informs the receiver that the sent data chunk represents the beginning of
 //!   a new transfer,
@@ -10,34 +10,59 @@
 //! - `BlockType::Abort` informs the receiver that the current transfer has been aborted on the
 //!   sender side,
 //! - `BlockType::End` informs the receiver that the current transfer is completed (i.e. all
-//!   data have been sent).
+//!   data have been sent),
+//! - `BlockType::Probe` carries a known pattern sent while the sender is idle, so that the
+//!   receiver keeps checking the link.
+//!
+//! Block types from [`ID_SKIPPABLE_FIRST`] up are reserved for extensions of the protocol:
+//! receivers not knowing such a type read it as `BlockType::Skippable` and silently ignore the
+//! block, whereas other unknown types are reported as invalid.
 //!
 //! A block is stored in a `Vec` of `u8`s, with the following representation:
 //!
 //! ```text
 //!
-//! <-- 4 bytes -> <-- 1 byte --> <-- 4 bytes -->
-//! --------------+--------------+---------------+--------------------------------------
-//! |             |              |               |                                     |
-//! |  client_id  |  block_type  |  data_length  |  payload = data + optional padding  |
-//! |             |              |               |                                     |
-//! --------------+--------------+---------------+--------------------------------------
-//!  <----------- SERIALIZE_OVERHEAD -----------> <----------- block_length ----------->
+//! <-- 4 bytes -> <-- 1 byte --> <-- 1 byte --> <-- 4 bytes --> <-- 4 bytes --> <-- 4 bytes -->
+//! --------------+--------------+--------------+---------------+---------------+---------------+--------------------------------------
+//! |             |              |              |               |               |               |                                     |
+//! |  client_id  |   version    |  block_type  |  data_length  |   data_crc    |  chunk_index  |  payload = data + optional padding  |
+//! |             |              |              |               |               |               |                                     |
+//! --------------+--------------+--------------+---------------+---------------+---------------+--------------------------------------
+//!  <----------------------------------------- SERIALIZE_OVERHEAD ----------------------------------------> <----------- block_length ----------->
 //!
 //! ```
 //!
 //! 4-bytes values are encoded in little-endian byte order.
 //!
-//! In `Heartbeat` blocks, `client_id` is unused and should be set to 0 by the constructor
+//! `version` is [`PROTOCOL_VERSION`], so that the receiver drops blocks sent by a sender with
+//! an incompatible representation instead of delivering garbage.
+//!
+//! `data_crc` is the CRC32C (Castagnoli) of the data, so that a block corrupted in a way
+//! `RaptorQ` decoding did not detect is not delivered to clients.
+//!
+//! `chunk_index` numbers the `Start` (index 0), `Data` and `End` blocks of a transfer, so that
+//! the receiver can count chunks lost within a transfer (blocks that could not be decoded). It
+//! is set to 0 in `Heartbeat` and `Abort` blocks, and numbers `Probe` blocks.
+//!
+//! When transfer summaries are enabled on both sides, the payload of `End` blocks is a
+//! [`TransferSummary`] rather than the last data of the transfer, sent in a `Data` block
+//! beforehand.
+//!
+//! In `Heartbeat` and `Probe` blocks, `client_id` is unused and should be set to 0 by the constructor
 //! caller. Also no data payload should be provided by the constructor caller in case the block
 //! is of type `Heartbeat`, `Abort` or `End`. Then the `data_length` will be set to 0 by the
 //! block constructor and the data chunk will be fully padded with zeros.
 
-use std::{fmt, io, num, sync};
+use std::{error, fmt, fs, io, num, path, str::FromStr, sync, time};
 
+#[derive(Debug)]
 pub enum Error {
     Io(io::Error),
     InvalidBlockType(Option<u8>),
+    /// Block data not matching its CRC32C
+    Corrupt,
+    /// Block of another protocol version
+    VersionMismatch(u8),
     Other(String),
 }
 
@@ -46,11 +71,28 @@ impl fmt::Display for Error {
         match self {
             Self::Io(e) => write!(fmt, "I/O error: {e}"),
             Self::InvalidBlockType(b) => write!(fmt, "invalid block type: {b:?}"),
+            Self::Corrupt => write!(fmt, "corrupted block data (CRC32C mismatch)"),
+            Self::VersionMismatch(version) => write!(
+                fmt,
+                "protocol version {version} instead of {PROTOCOL_VERSION}"
+            ),
             Self::Other(e) => write!(fmt, "{e}"),
         }
     }
 }
 
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::InvalidBlockType(_)
+            | Self::Corrupt
+            | Self::VersionMismatch(_)
+            | Self::Other(_) => None,
+        }
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
         Self::Io(e)
@@ -65,7 +107,67 @@ impl From<num::TryFromIntError> for Error {
 
 const PACKET_HEADER_SIZE: u16 = 20 + 8;
 const RAPTORQ_ALIGNMENT: u16 = 8;
-const RAPTORQ_HEADER_SIZE: u16 = 4;
+pub(crate) const RAPTORQ_HEADER_SIZE: u16 = 4;
+/// Maximum number of source symbols in a `RaptorQ` source block (RFC 6330)
+const RAPTORQ_MAX_SOURCE_SYMBOLS: u32 = 56403;
+/// Size of the tag appended to every datagram when packets are authenticated, which must be
+/// taken out of the MTU given to [`RaptorQ::new`]
+pub const PACKET_TAG_SIZE: u16 = 16;
+
+/// Key shared by the sender and the receiver to authenticate packets: every datagram is
+/// followed by the truncated HMAC-SHA256 of its content, so that the receiver drops spoofed or
+/// corrupted packets before they are buffered for reassembly
+#[derive(Clone)]
+pub struct PacketKey(Vec<u8>);
+
+impl PacketKey {
+    /// Reads the key from the content of the file at `path`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the file cannot be read or is empty.
+    pub fn from_file(path: &path::Path) -> Result<Self, Error> {
+        let key = fs::read(path)
+            .map_err(|e| Error::Other(format!("packet key {}: {e}", path.display())))?;
+        if key.is_empty() {
+            return Err(Error::Other(format!(
+                "packet key {}: empty file",
+                path.display()
+            )));
+        }
+        Ok(Self(key))
+    }
+
+    fn tag(&self, packet: &[u8]) -> [u8; PACKET_TAG_SIZE as usize] {
+        let mac = hmac_sha256::HMAC::mac(packet, &self.0);
+        let mut tag = [0; PACKET_TAG_SIZE as usize];
+        tag.copy_from_slice(&mac[..usize::from(PACKET_TAG_SIZE)]);
+        tag
+    }
+
+    /// Appends the tag of `datagram` to it
+    pub(crate) fn sign(&self, datagram: &mut Vec<u8>) {
+        let tag = self.tag(datagram);
+        datagram.extend_from_slice(&tag);
+    }
+
+    /// Returns the packet carried by `datagram` if it is followed by its tag
+    pub(crate) fn verify<'a>(&self, datagram: &'a [u8]) -> Option<&'a [u8]> {
+        let (packet, tag) =
+            datagram.split_at_checked(datagram.len().checked_sub(usize::from(PACKET_TAG_SIZE))?)?;
+        // constant-time comparison, not to leak how many bytes of a forged tag are right
+        let diff = self
+            .tag(packet)
+            .iter()
+            .zip(tag)
+            .fold(0, |diff, (a, b)| diff | (a ^ b));
+        (diff == 0).then_some(packet)
+    }
+}
+
+const fn tag_len(authenticated: bool) -> u16 {
+    if authenticated { PACKET_TAG_SIZE } else { 0 }
+}
 
 pub struct RaptorQ {
     max_packet_size: u16,
@@ -74,15 +176,113 @@ pub struct RaptorQ {
     plan: raptorq::SourceBlockEncodingPlan,
     config: raptorq::ObjectTransmissionInformation,
     nb_repair_packets: u16,
+    /// Percentage `nb_repair_packets` was computed from, `None` if it was set as is
+    repair_percentage: Option<u32>,
+    raw: bool,
+    packet_key: Option<PacketKey>,
 }
 
 impl RaptorQ {
+    /// Checks a MTU, block size and repair percentage combination before building the (costly)
+    /// configuration with [`Self::new`]. Derived values are logged, as well as the bytes of
+    /// every packet lost to alignment, and warnings are logged when the block size or the
+    /// repair percentage are rounded down.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err`, listing all the problems found, if the combination cannot be used.
+    pub fn validate_params(mtu: u16, block_size: u32, repair_percentage: u32) -> Result<(), Error> {
+        let min_mtu = PACKET_HEADER_SIZE + RAPTORQ_HEADER_SIZE + RAPTORQ_ALIGNMENT;
+        if mtu < min_mtu {
+            return Err(Error::Other(format!(
+                "MTU {mtu} is too small, must be at least {min_mtu}"
+            )));
+        }
+
+        let payload_size = mtu - PACKET_HEADER_SIZE - RAPTORQ_HEADER_SIZE;
+        let max_packet_size = payload_size - payload_size % RAPTORQ_ALIGNMENT;
+        let symbol_count = block_size / u32::from(max_packet_size);
+        let transfer_length = u32::from(max_packet_size) * symbol_count;
+        let nb_repair_packets = (u64::from(transfer_length / 100) * u64::from(repair_percentage))
+            / u64::from(max_packet_size);
+
+        log::info!(
+            "MTU {mtu}, block {block_size} bytes, repair {repair_percentage}%: packets of {max_packet_size} bytes, {symbol_count} source and {nb_repair_packets} repair packets per block of {transfer_length} bytes"
+        );
+
+        if max_packet_size < payload_size {
+            log::info!(
+                "{} bytes of every packet unused to align packets on {RAPTORQ_ALIGNMENT} bytes",
+                payload_size - max_packet_size
+            );
+        }
+        if 0 < symbol_count && transfer_length < block_size {
+            log::warn!(
+                "block size {block_size} is not a multiple of packet size {max_packet_size}, {} bytes unused per block",
+                block_size - transfer_length
+            );
+        }
+        if 0 < repair_percentage && 0 < symbol_count && nb_repair_packets == 0 {
+            log::warn!(
+                "repair percentage {repair_percentage}% is too low for {symbol_count} packets per block, no repair packet"
+            );
+        }
+
+        let mut errors = vec![];
+        if symbol_count == 0 {
+            errors.push(format!(
+                "block size {block_size} is smaller than packet size {max_packet_size}"
+            ));
+        }
+        if RAPTORQ_MAX_SOURCE_SYMBOLS < symbol_count {
+            errors.push(format!(
+                "{symbol_count} packets per block exceed the RaptorQ maximum of {RAPTORQ_MAX_SOURCE_SYMBOLS}"
+            ));
+        }
+        if u64::from(u16::MAX) < nb_repair_packets {
+            errors.push(format!(
+                "{nb_repair_packets} repair packets per block exceed the maximum of {}",
+                u16::MAX
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Other(errors.join(", ")))
+        }
+    }
+
     /// # Errors
     ///
     /// Will return `Err` if `symbol_count`
     ///   or
     /// `nb_repair_packets` parsing fails
     pub fn new(mtu: u16, block_size: u32, repair_percentage: u32) -> Result<Self, Error> {
+        let mut raptorq = Self::with_repair_packets(mtu, block_size, 0)?;
+
+        raptorq.nb_repair_packets = u16::try_from(
+            ((raptorq.transfer_length / 100) * repair_percentage)
+                / u32::from(raptorq.max_packet_size),
+        )
+        .map_err(|e| Error::Other(format!("nb_repair_packets: {e}")))?;
+        raptorq.repair_percentage = Some(repair_percentage);
+
+        Ok(raptorq)
+    }
+
+    /// Same as [`Self::new`], but with a fixed number of repair packets per block instead of a
+    /// percentage of the block size, so that the overhead does not depend on the MTU and block
+    /// size rounding
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `symbol_count` parsing fails
+    pub fn with_repair_packets(
+        mtu: u16,
+        block_size: u32,
+        nb_repair_packets: u16,
+    ) -> Result<Self, Error> {
         let mut max_packet_size = mtu - PACKET_HEADER_SIZE - RAPTORQ_HEADER_SIZE;
         max_packet_size -= max_packet_size % RAPTORQ_ALIGNMENT;
 
@@ -100,11 +300,6 @@ impl RaptorQ {
             max_packet_size,
         );
 
-        let nb_repair_packets = u16::try_from(
-            ((transfer_length / 100) * repair_percentage) / u32::from(max_packet_size),
-        )
-        .map_err(|e| Error::Other(format!("nb_repair_packets: {e}")))?;
-
         Ok(Self {
             max_packet_size,
             symbol_count,
@@ -112,9 +307,49 @@ impl RaptorQ {
             plan,
             config,
             nb_repair_packets,
+            repair_percentage: None,
+            raw: false,
+            packet_key: None,
         })
     }
 
+    /// Enables or disables raw mode, for perfectly reliable links: blocks are sliced into source
+    /// symbols sent as is, and reassembled by simply concatenating them on the receiver side,
+    /// without running the `RaptorQ` encoder nor decoder. Both sides must agree on this setting.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if raw mode is enabled while repair packets are configured.
+    pub fn with_raw_mode(mut self, raw: bool) -> Result<Self, Error> {
+        if raw && 0 < self.nb_repair_packets {
+            return Err(Error::Other(format!(
+                "raw mode requires no repair packets (got {})",
+                self.nb_repair_packets
+            )));
+        }
+        self.raw = raw;
+        Ok(self)
+    }
+
+    #[must_use]
+    pub const fn is_raw(&self) -> bool {
+        self.raw
+    }
+
+    /// Sets the key authenticating packets, `None` to send and accept packets without tag.
+    /// Both sides must agree on this setting, and the MTU given to [`Self::new`] must leave
+    /// room for [`PACKET_TAG_SIZE`] bytes.
+    #[must_use]
+    pub fn with_packet_key(mut self, packet_key: Option<PacketKey>) -> Self {
+        self.packet_key = packet_key;
+        self
+    }
+
+    #[must_use]
+    pub const fn packet_key(&self) -> Option<&PacketKey> {
+        self.packet_key.as_ref()
+    }
+
     #[must_use]
     pub const fn block_size(&self) -> u32 {
         self.transfer_length
@@ -125,19 +360,41 @@ impl RaptorQ {
         self.symbol_count
     }
 
+    #[must_use]
+    pub const fn nb_repair_packets(&self) -> u16 {
+        self.nb_repair_packets
+    }
+
     #[must_use]
     pub fn nb_packets(&self) -> u32 {
         u32::from(self.symbol_count) + u32::from(self.nb_repair_packets)
     }
 
+    /// Length of the UDP datagrams carrying packets: a `RaptorQ` payload id followed by a
+    /// symbol, and by a tag when packets are authenticated
+    #[must_use]
+    pub fn datagram_len(&self) -> usize {
+        usize::from(RAPTORQ_HEADER_SIZE + self.max_packet_size + tag_len(self.packet_key.is_some()))
+    }
+
+    /// Encodes `data` into source packets followed by repair packets. Encoding is
+    /// deterministic: the same `block_id` and `data` always produce the same packets.
     #[must_use]
     pub fn encode(&self, block_id: u8, data: &[u8]) -> Vec<raptorq::EncodingPacket> {
-        let encoder = raptorq::SourceBlockEncoder::with_encoding_plan(
-            block_id,
-            &self.config,
-            data,
-            &self.plan,
-        );
+        if self.raw {
+            return data
+                .chunks(usize::from(self.max_packet_size))
+                .zip(0..)
+                .map(|(symbol, symbol_id)| {
+                    raptorq::EncodingPacket::new(
+                        raptorq::PayloadId::new(block_id, symbol_id),
+                        symbol.to_vec(),
+                    )
+                })
+                .collect();
+        }
+
+        let encoder = self.encoder(block_id, data);
         let mut packets = encoder.source_packets();
         if 0 < self.nb_repair_packets {
             packets.extend(encoder.repair_packets(
@@ -148,8 +405,56 @@ impl RaptorQ {
         packets
     }
 
+    /// Encodes `data` into the packets selected by `packet_ids` only, to simulate targeted
+    /// losses. Packets are identified by their index in the sequence returned by
+    /// [`Self::encode`] (source packets first, then repair ones), indexes beyond
+    /// [`Self::nb_packets`] selecting additional repair packets. Packets are returned in the
+    /// order of `packet_ids`; in raw mode, only source packets can be selected.
+    #[must_use]
+    pub fn encode_selected(
+        &self,
+        block_id: u8,
+        data: &[u8],
+        packet_ids: &[u32],
+    ) -> Vec<raptorq::EncodingPacket> {
+        let symbol_count = u32::from(self.symbol_count);
+
+        let encoder = (!self.raw).then(|| self.encoder(block_id, data));
+        let source_packets = match encoder.as_ref() {
+            None => self.encode(block_id, data),
+            Some(encoder) => encoder.source_packets(),
+        };
+
+        packet_ids
+            .iter()
+            .filter_map(|id| {
+                if *id < symbol_count {
+                    source_packets.get(usize::try_from(*id).ok()?).cloned()
+                } else {
+                    let repair_id = u32::from(self.config.symbol_size()) + (id - symbol_count);
+                    encoder.as_ref()?.repair_packets(repair_id, 1).pop()
+                }
+            })
+            .collect()
+    }
+
+    /// Creates the encoder of a block, to generate more repair packets than [`Self::encode`]
+    pub(crate) fn encoder(&self, block_id: u8, data: &[u8]) -> raptorq::SourceBlockEncoder {
+        raptorq::SourceBlockEncoder::with_encoding_plan(block_id, &self.config, data, &self.plan)
+    }
+
+    /// Repair symbol id following the one of the last repair packet returned by
+    /// [`Self::encode`]
+    pub(crate) fn next_repair_symbol_id(&self) -> u32 {
+        u32::from(self.config.symbol_size()) + u32::from(self.nb_repair_packets)
+    }
+
     #[must_use]
     pub fn decode(&self, block_id: u8, packets: Vec<raptorq::EncodingPacket>) -> Option<Vec<u8>> {
+        if self.raw {
+            return self.concatenate(packets);
+        }
+
         let mut decoder = raptorq::SourceBlockDecoder::new(
             block_id,
             &self.config,
@@ -157,14 +462,120 @@ impl RaptorQ {
         );
         decoder.decode(packets)
     }
+
+    /// Raw mode reassembly: every source symbol must have been received
+    fn concatenate(&self, packets: Vec<raptorq::EncodingPacket>) -> Option<Vec<u8>> {
+        let symbol_size = usize::from(self.max_packet_size);
+        let mut block = vec![0u8; usize::try_from(self.transfer_length).ok()?];
+        let mut received = vec![false; usize::from(self.symbol_count)];
+
+        for packet in packets {
+            let symbol_id = usize::try_from(packet.payload_id().encoding_symbol_id()).ok()?;
+            let offset = symbol_id * symbol_size;
+            let data = packet.data();
+            if received.len() <= symbol_id || data.len() != symbol_size {
+                return None;
+            }
+            block[offset..offset + symbol_size].copy_from_slice(data);
+            received[symbol_id] = true;
+        }
+
+        received.iter().all(|received| *received).then_some(block)
+    }
+
+    /// Encodes a block of random data, shuffles its packets, removes `remove` percent of them,
+    /// and decodes the remaining ones, to check that the configuration round-trips before
+    /// transferring real data
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the block cannot be decoded, or is not decoded to its original
+    /// content.
+    pub fn check_round_trip(&self, remove: Option<u32>) -> Result<(), Error> {
+        use rand::Rng;
+
+        let mut rng = rand::rng();
+
+        let block_size = usize::try_from(self.block_size())?;
+        log::debug!("generating random data block of {block_size} bytes");
+        let mut data = vec![0u8; block_size];
+        rng.fill(&mut data[..]);
+
+        let id = 0;
+
+        let mut packets = self.encode(id, &data);
+        log::debug!("{} packets", packets.len());
+
+        let nb_packets = packets.len();
+        log::debug!("shuffling {nb_packets} packets");
+        let range = nb_packets / 2..nb_packets;
+        for i in 0..(nb_packets / 2) {
+            packets.swap(i, rng.random_range(range.clone()));
+        }
+
+        if let Some(remove) = remove {
+            let nb = (nb_packets * remove as usize / 100).min(nb_packets);
+            log::debug!("removing {remove}% ({nb} packets)");
+            packets = packets.split_off(nb);
+        }
+
+        log::debug!("decoding with {} packets", packets.len());
+        match self.decode(id, packets) {
+            None => Err(Error::Other("decode failed".into())),
+            Some(decoded) if decoded != data => Err(Error::Other("invalid decoded data".into())),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+impl RaptorQ {
+    /// Derived metrics of the configuration, computed for a full data block:
+    /// - packet efficiency: share of each UDP packet (including IP and UDP headers) carrying
+    ///   symbol data,
+    /// - overhead: share of the bytes sent on the link not carrying client data (IP, UDP and
+    ///   `RaptorQ` headers, block header and repair packets),
+    /// - symbols/packets ratio: share of the packets of a block that are source ones.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn summary(&self) -> String {
+        let packet_size = u32::from(
+            self.max_packet_size
+                + RAPTORQ_HEADER_SIZE
+                + PACKET_HEADER_SIZE
+                + tag_len(self.packet_key.is_some()),
+        );
+        let wire_size = f64::from(packet_size) * f64::from(self.nb_packets());
+        let data_size = Block::max_data_len(self) as f64;
+        format!(
+            "packet efficiency = {:.2}% overhead = {:.2}% symbols/packets = {}/{} ({:.2}%)",
+            100.0 * f64::from(self.max_packet_size) / f64::from(packet_size),
+            100.0 * (wire_size - data_size) / wire_size,
+            self.symbol_count,
+            self.nb_packets(),
+            100.0 * f64::from(self.symbol_count) / f64::from(self.nb_packets()),
+        )
+    }
 }
 
 impl fmt::Display for RaptorQ {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         write!(
             fmt,
-            "RaptorQ max_packet_size == {} transfer_length = {} symbol_count|nb_packets == {} nb_repair_packets == {}",
-            self.max_packet_size, self.transfer_length, self.symbol_count, self.nb_repair_packets
+            "RaptorQ max_packet_size == {} transfer_length = {} symbol_count|nb_packets == {} nb_repair_packets == {} ({}){}{}",
+            self.max_packet_size,
+            self.transfer_length,
+            self.symbol_count,
+            self.nb_repair_packets,
+            self.repair_percentage.map_or_else(
+                || "fixed count".to_string(),
+                |percentage| format!("{percentage}% repair")
+            ),
+            if self.raw { " (raw mode)" } else { "" },
+            if self.packet_key.is_some() {
+                " (authenticated packets)"
+            } else {
+                ""
+            }
         )
     }
 }
@@ -175,6 +586,9 @@ pub(crate) enum BlockType {
     Data,
     Abort,
     End,
+    Probe,
+    /// Block of a type reserved for protocol extensions and unknown to this version
+    Skippable(u8),
 }
 
 impl BlockType {
@@ -185,6 +599,8 @@ impl BlockType {
             Self::Data => ID_DATA,
             Self::Abort => ID_ABORT,
             Self::End => ID_END,
+            Self::Probe => ID_PROBE,
+            Self::Skippable(id) => id,
         }
     }
 }
@@ -197,43 +613,307 @@ impl fmt::Display for BlockType {
             Self::Data => write!(fmt, "Data"),
             Self::Abort => write!(fmt, "Abort"),
             Self::End => write!(fmt, "End"),
+            Self::Probe => write!(fmt, "Probe"),
+            Self::Skippable(id) => write!(fmt, "Skippable({id:#04x})"),
         }
     }
 }
 
+/// Version of the block representation, to be bumped whenever it changes. Values 0 to 4 must
+/// not be used: blocks sent before the version was introduced have their block type at its
+/// offset, and must not be taken for blocks of a known version.
+pub const PROTOCOL_VERSION: u8 = 0x10;
+
 const ID_HEARTBEAT: u8 = 0x00;
 const ID_START: u8 = 0x01;
 const ID_DATA: u8 = 0x02;
 const ID_ABORT: u8 = 0x03;
 const ID_END: u8 = 0x04;
+const ID_PROBE: u8 = 0x05;
+/// First block type of the range reserved for protocol extensions, which receivers ignore
+/// when they do not know them
+pub const ID_SKIPPABLE_FIRST: u8 = 0x80;
 
 pub type ClientId = u32;
 
 static CLIENT_ID_COUNTER: sync::atomic::AtomicU32 = sync::atomic::AtomicU32::new(0);
 
 pub(crate) fn new_client_id() -> ClientId {
-    CLIENT_ID_COUNTER.fetch_add(1, sync::atomic::Ordering::Relaxed)
+    let client_id = CLIENT_ID_COUNTER.fetch_add(1, sync::atomic::Ordering::Relaxed);
+    if client_id == ClientId::MAX {
+        log::warn!("client ids wrapped around");
+    }
+    client_id
+}
+
+/// Number of upper bits of block ids identifying the slot of a transfer with
+/// [`BlockIdScheme::PerClient`]
+pub const BLOCK_ID_SLOT_BITS: u32 = 3;
+
+/// How the sender assigns block ids (`RaptorQ` source block numbers), both sides must use the
+/// same scheme
+#[derive(Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlockIdScheme {
+    /// A single sequence of ids shared by the blocks of all transfers
+    #[default]
+    Global,
+    /// A sequence of ids per transfer: the upper [`BLOCK_ID_SLOT_BITS`] bits of a block id
+    /// identify the slot allocated to the transfer by the sender (slot 0 carrying heartbeat
+    /// blocks), the lower bits number the blocks of the slot
+    PerClient,
+}
+
+impl BlockIdScheme {
+    fn sequence_bits(self) -> u32 {
+        match self {
+            Self::Global => u8::BITS,
+            Self::PerClient => u8::BITS - BLOCK_ID_SLOT_BITS,
+        }
+    }
+
+    fn sequence_mask(self) -> u8 {
+        u8::MAX >> (u8::BITS - self.sequence_bits())
+    }
+
+    /// Number of independent sequences of block ids
+    pub fn nb_slots(self) -> u8 {
+        1u8.checked_shl(u8::BITS - self.sequence_bits())
+            .unwrap_or(u8::MAX)
+    }
+
+    /// Number of transfers which can be active at the same time
+    pub fn max_clients(self) -> Option<ClientId> {
+        match self {
+            Self::Global => None,
+            Self::PerClient => Some(ClientId::from(self.nb_slots()) - 1),
+        }
+    }
+
+    pub(crate) fn slot(self, block_id: u8) -> u8 {
+        block_id.checked_shr(self.sequence_bits()).unwrap_or(0)
+    }
+
+    /// First block id of the sequence of `slot`
+    pub(crate) fn first_id(self, slot: u8) -> u8 {
+        slot.checked_shl(self.sequence_bits()).unwrap_or(0)
+    }
+
+    /// Block id `n` positions after `block_id` in its sequence, wrapping around within the
+    /// sequence
+    pub(crate) fn add(self, block_id: u8, n: u8) -> u8 {
+        let mask = self.sequence_mask();
+        (block_id & !mask) | (block_id.wrapping_add(n) & mask)
+    }
+
+    /// Number of blocks of a sequence which can be reassembled at the same time, to handle
+    /// reordering, while telling old blocks from new ones
+    pub(crate) fn window_width(self) -> u8 {
+        self.sequence_mask() / 2
+    }
+}
+
+impl FromStr for BlockIdScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(Self::Global),
+            "per-client" => Ok(Self::PerClient),
+            _ => Err(format!("unknown block id scheme \"{s}\"")),
+        }
+    }
+}
+
+impl fmt::Display for BlockIdScheme {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Global => write!(fmt, "global"),
+            Self::PerClient => write!(fmt, "per-client"),
+        }
+    }
+}
+
+/// Sender side statistics of a transfer, carried by its `End` block
+///
+/// It is serialized as 20 bytes: `bytes` (8 bytes), `blocks` (4 bytes) and `elapsed` in
+/// microseconds (8 bytes), in little-endian byte order.
+#[derive(Clone, Copy)]
+pub struct TransferSummary {
+    /// Number of bytes read from the client
+    pub bytes: u64,
+    /// Number of blocks of the transfer, from the `Start` block to the `End` block
+    pub blocks: u32,
+    /// Duration from the client connection to the end of the transfer
+    pub elapsed: time::Duration,
+}
+
+const TRANSFER_SUMMARY_LEN: usize = 8 + 4 + 8;
+
+impl TransferSummary {
+    pub(crate) fn serialize(&self) -> [u8; TRANSFER_SUMMARY_LEN] {
+        let elapsed = u64::try_from(self.elapsed.as_micros()).unwrap_or(u64::MAX);
+        let mut content = [0u8; TRANSFER_SUMMARY_LEN];
+        content[0..8].copy_from_slice(&self.bytes.to_le_bytes());
+        content[8..12].copy_from_slice(&self.blocks.to_le_bytes());
+        content[12..20].copy_from_slice(&elapsed.to_le_bytes());
+        content
+    }
+
+    pub(crate) fn deserialize(content: &[u8]) -> Result<Self, Error> {
+        let content: &[u8; TRANSFER_SUMMARY_LEN] = content.try_into().map_err(|_| {
+            Error::Other(format!(
+                "invalid transfer summary of {} bytes, expecting {TRANSFER_SUMMARY_LEN}",
+                content.len()
+            ))
+        })?;
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&content[0..8]);
+        let mut blocks = [0u8; 4];
+        blocks.copy_from_slice(&content[8..12]);
+        let mut elapsed = [0u8; 8];
+        elapsed.copy_from_slice(&content[12..20]);
+        Ok(Self {
+            bytes: u64::from_le_bytes(bytes),
+            blocks: u32::from_le_bytes(blocks),
+            elapsed: time::Duration::from_micros(u64::from_le_bytes(elapsed)),
+        })
+    }
+}
+
+impl fmt::Display for TransferSummary {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            fmt,
+            "{} bytes in {} blocks sent in {:.3} seconds",
+            self.bytes,
+            self.blocks,
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+/// Identifier of a transfer carried by its `Start` block, unique across sender restarts unlike
+/// client ids, so that a transfer can be traced in the logs of both sides
+///
+/// It is a random (version 4) UUID, serialized as its 16 bytes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct TransferUuid([u8; TRANSFER_UUID_LEN]);
+
+const TRANSFER_UUID_LEN: usize = 16;
+
+impl TransferUuid {
+    pub(crate) fn new_random() -> Self {
+        let mut uuid: [u8; TRANSFER_UUID_LEN] = rand::random();
+        uuid[6] = (uuid[6] & 0x0f) | 0x40;
+        uuid[8] = (uuid[8] & 0x3f) | 0x80;
+        Self(uuid)
+    }
+
+    pub(crate) const fn serialize(&self) -> [u8; TRANSFER_UUID_LEN] {
+        self.0
+    }
+
+    pub(crate) fn deserialize(content: &[u8]) -> Result<Self, Error> {
+        let content = content.try_into().map_err(|_| {
+            Error::Other(format!(
+                "invalid transfer UUID of {} bytes, expecting {TRANSFER_UUID_LEN}",
+                content.len()
+            ))
+        })?;
+        Ok(Self(content))
+    }
+}
+
+impl fmt::Display for TransferUuid {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        for (i, byte) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                write!(fmt, "-")?;
+            }
+            write!(fmt, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Lookup table of the reflected CRC32C polynomial
+#[allow(clippy::cast_possible_truncation)]
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, byte| {
+        CRC32C_TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Content of probe blocks, spanning every byte value so that no symbol is sent all zeros
+#[allow(clippy::cast_possible_truncation)]
+fn probe_pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 167 + i / 256) as u8).collect()
 }
 
 pub(crate) struct Block(Vec<u8>);
 
-const SERIALIZE_OVERHEAD: usize = 4 + 1 + 4;
+const SERIALIZE_OVERHEAD: usize = 4 + 1 + 1 + 4 + 4 + 4;
 
 impl Block {
     /// Block constructor, craft a block according to the representation introduced in
     /// [`crate::protocol`].
     ///
-    /// Some (unchecked) constraints on arguments must be respected:
-    /// - if `block` is `BlockType::Heartbeat`, `BlockType::Abort` or `BlockType::End`
-    ///   then no data should be provided,
-    /// - if `block` is `BlockType::Heartbeat` then `client_id` should be equal to 0,
-    /// - if there is some `data`, its length must be lower than `Messsage::max_data_len()`.
+    /// Some constraints on arguments must be respected, they are only checked by debug
+    /// assertions:
+    /// - if `block` is `BlockType::Heartbeat` or `BlockType::Abort` then no data should be
+    ///   provided (`BlockType::End` may carry the last data of the transfer),
+    /// - if `block` is `BlockType::Heartbeat` or `BlockType::Probe` then `client_id` should be
+    ///   equal to 0.
+    ///
+    /// Callers must split their data in chunks of at most `Block::max_data_len()` bytes, an
+    /// `Err` being returned for longer `data`.
     pub(crate) fn new(
         block: BlockType,
         raptorq: &RaptorQ,
         client_id: ClientId,
+        chunk_index: u32,
         data: Option<&[u8]>,
     ) -> Result<Self, Error> {
+        debug_assert!(
+            !matches!(block, BlockType::Heartbeat | BlockType::Abort)
+                || data.is_none_or(<[u8]>::is_empty),
+            "{block} block must not carry data"
+        );
+        debug_assert!(
+            !matches!(block, BlockType::Heartbeat | BlockType::Probe) || client_id == 0,
+            "{block} block must have client id 0, got {client_id:x}"
+        );
+        if let Some(data) = data
+            && Self::max_data_len(raptorq) < data.len()
+        {
+            return Err(Error::Other(format!(
+                "{block} block data of {} bytes exceeds {} bytes",
+                data.len(),
+                Self::max_data_len(raptorq)
+            )));
+        }
+
         match data {
             None => {
                 let mut content = vec![
@@ -247,7 +927,10 @@ impl Block {
                 content[1] = bytes[1];
                 content[2] = bytes[2];
                 content[3] = bytes[3];
-                content[4] = block.serialized();
+                content[4] = PROTOCOL_VERSION;
+                content[5] = block.serialized();
+                content[10..14].copy_from_slice(&crc32c(&[]).to_le_bytes());
+                content[14..SERIALIZE_OVERHEAD].copy_from_slice(&chunk_index.to_le_bytes());
                 Ok(Self(content))
             }
             Some(data) => {
@@ -256,11 +939,14 @@ impl Block {
                         .map_err(|e| Error::Other(format!("transfer_length: {e}")))?,
                 );
                 content.extend_from_slice(&client_id.to_le_bytes());
+                content.push(PROTOCOL_VERSION);
                 content.push(block.serialized());
                 content.extend_from_slice(&u32::to_le_bytes(
                     u32::try_from(data.len())
                         .map_err(|e| Error::Other(format!("data.len(): {e}")))?,
                 ));
+                content.extend_from_slice(&crc32c(data).to_le_bytes());
+                content.extend_from_slice(&chunk_index.to_le_bytes());
                 content.extend_from_slice(data);
                 if content.len() < content.capacity() {
                     content.resize(content.capacity(), 0);
@@ -275,22 +961,63 @@ impl Block {
         u32::from_le_bytes(bytes)
     }
 
-    pub(crate) fn block_type(&self) -> Result<BlockType, Error> {
+    /// Protocol version of the block, checked against [`PROTOCOL_VERSION`]
+    pub(crate) fn version(&self) -> Result<u8, Error> {
         match self.0.get(4) {
+            Some(&PROTOCOL_VERSION) => Ok(PROTOCOL_VERSION),
+            Some(&version) => Err(Error::VersionMismatch(version)),
+            None => Err(Error::Other("truncated block".to_string())),
+        }
+    }
+
+    pub(crate) fn block_type(&self) -> Result<BlockType, Error> {
+        self.version()?;
+        match self.0.get(5) {
             Some(&ID_HEARTBEAT) => Ok(BlockType::Heartbeat),
             Some(&ID_START) => Ok(BlockType::Start),
             Some(&ID_DATA) => Ok(BlockType::Data),
             Some(&ID_ABORT) => Ok(BlockType::Abort),
             Some(&ID_END) => Ok(BlockType::End),
+            Some(&ID_PROBE) => Ok(BlockType::Probe),
+            Some(&id) if ID_SKIPPABLE_FIRST <= id => Ok(BlockType::Skippable(id)),
             b => Err(Error::InvalidBlockType(b.copied())),
         }
     }
 
     fn payload_len(&self) -> u32 {
-        let data_len_bytes = [self.0[5], self.0[6], self.0[7], self.0[8]];
+        let data_len_bytes = [self.0[6], self.0[7], self.0[8], self.0[9]];
         u32::from_le_bytes(data_len_bytes)
     }
 
+    fn data_crc(&self) -> u32 {
+        let bytes = [self.0[10], self.0[11], self.0[12], self.0[13]];
+        u32::from_le_bytes(bytes)
+    }
+
+    pub(crate) fn chunk_index(&self) -> u32 {
+        let bytes = [self.0[14], self.0[15], self.0[16], self.0[17]];
+        u32::from_le_bytes(bytes)
+    }
+
+    /// Probe block numbered `sequence`, filled with a pattern the receiver can check
+    pub(crate) fn probe(raptorq: &RaptorQ, sequence: u32) -> Result<Self, Error> {
+        Self::new(
+            BlockType::Probe,
+            raptorq,
+            0,
+            sequence,
+            Some(&probe_pattern(Self::max_data_len(raptorq))),
+        )
+    }
+
+    /// Whether the payload of a probe block is the expected pattern
+    pub(crate) fn is_intact_probe(&self) -> bool {
+        self.verified_payload().is_ok_and(|payload| {
+            payload.len() == self.0.len() - SERIALIZE_OVERHEAD
+                && payload == probe_pattern(payload.len()).as_slice()
+        })
+    }
+
     pub(crate) const fn deserialize(data: Vec<u8>) -> Self {
         Self(data)
     }
@@ -299,9 +1026,30 @@ impl Block {
         raptorq.transfer_length as usize - SERIALIZE_OVERHEAD
     }
 
+    /// Payload bytes, or `None` if the payload length exceeds the block
+    fn checked_payload(&self) -> Option<&[u8]> {
+        let end = SERIALIZE_OVERHEAD.checked_add(usize::try_from(self.payload_len()).ok()?)?;
+        self.0.get(SERIALIZE_OVERHEAD..end)
+    }
+
+    /// Payload bytes, truncated to the block if its length is corrupted
     pub(crate) fn payload(&self) -> &[u8] {
-        let len = self.payload_len();
-        &self.0[SERIALIZE_OVERHEAD..(SERIALIZE_OVERHEAD + len as usize)]
+        self.checked_payload()
+            .unwrap_or_else(|| self.0.get(SERIALIZE_OVERHEAD..).unwrap_or_default())
+    }
+
+    /// Payload checked against the CRC32C computed by the sender
+    pub(crate) fn verified_payload(&self) -> Result<&[u8], Error> {
+        let payload = self.checked_payload().ok_or(Error::Corrupt)?;
+        if crc32c(payload) != self.data_crc() {
+            return Err(Error::Corrupt);
+        }
+        Ok(payload)
+    }
+
+    /// Number of padding bytes following the payload, up to the block size
+    pub(crate) fn padding_len(&self) -> usize {
+        self.0.len() - SERIALIZE_OVERHEAD - self.payload_len() as usize
     }
 
     pub(crate) fn serialized(&self) -> &[u8] {
@@ -317,10 +1065,217 @@ impl fmt::Display for Block {
         };
         write!(
             fmt,
-            "client {:x} block = {} data = {} byte(s)",
+            "client {:x} block = {} chunk = {} data = {} byte(s)",
             self.client_id(),
             msg_type,
+            self.chunk_index(),
             self.payload_len()
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raptorq() -> RaptorQ {
+        RaptorQ::new(1500, 8 * 1464, 10).expect("valid parameters")
+    }
+
+    #[test]
+    fn round_trip() {
+        let raptorq = raptorq();
+        assert!(raptorq.check_round_trip(None).is_ok());
+        assert!(raptorq.check_round_trip(Some(0)).is_ok());
+        // more packets removed than there are repair ones
+        assert!(raptorq.check_round_trip(Some(50)).is_err());
+        assert!(raptorq.check_round_trip(Some(200)).is_err());
+    }
+
+    #[test]
+    fn padding_len() {
+        let raptorq = raptorq();
+        let transfer_length = raptorq.transfer_length as usize;
+
+        let last = Block::new(BlockType::End, &raptorq, 1, 3, Some(b"short")).expect("block");
+        assert_eq!(last.serialized().len(), transfer_length);
+        assert_eq!(
+            last.padding_len(),
+            transfer_length - SERIALIZE_OVERHEAD - b"short".len()
+        );
+
+        let data = vec![0; Block::max_data_len(&raptorq)];
+        let full = Block::new(BlockType::Data, &raptorq, 1, 2, Some(&data)).expect("block");
+        assert_eq!(full.padding_len(), 0);
+    }
+
+    #[test]
+    fn block_id_wraparound() {
+        assert_eq!(BlockIdScheme::Global.add(u8::MAX, 1), 0);
+        assert_eq!(BlockIdScheme::Global.add(250, 10), 4);
+
+        // ids wrap around within the sequence of their slot
+        let scheme = BlockIdScheme::PerClient;
+        let first = scheme.first_id(3);
+        let last = scheme.add(first, scheme.sequence_mask());
+        assert_eq!(scheme.slot(last), 3);
+        assert_eq!(scheme.add(last, 1), first);
+        assert_eq!(
+            scheme.add(last, scheme.window_width()),
+            first + scheme.window_width() - 1
+        );
+        assert_eq!(scheme.slot(scheme.add(u8::MAX, 1)), scheme.nb_slots() - 1);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "block must not carry data")]
+    fn heartbeat_with_data() {
+        let _ = Block::new(BlockType::Heartbeat, &raptorq(), 0, 0, Some(b"data"));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "block must not carry data")]
+    fn abort_with_data() {
+        let _ = Block::new(BlockType::Abort, &raptorq(), 1, 0, Some(b"data"));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "block must have client id 0")]
+    fn heartbeat_with_client_id() {
+        let _ = Block::new(BlockType::Heartbeat, &raptorq(), 1, 0, None);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "block must have client id 0")]
+    fn probe_with_client_id() {
+        let _ = Block::new(BlockType::Probe, &raptorq(), 1, 0, None);
+    }
+
+    #[test]
+    fn default_config_overhead() {
+        // client id, protocol version, block type, data length, data CRC32C and chunk index
+        assert_eq!(SERIALIZE_OVERHEAD, 4 + 1 + 1 + 4 + 4 + 4);
+
+        // 1500 - 28 (IP and UDP headers) - 4 (RaptorQ header) = 1468, aligned down to 1464
+        // bytes of symbol data per packet, so 502 source packets carrying 734928 bytes and
+        // 734928 / 100 * 2 / 1464 = 10 repair packets; 512 packets of 1496 bytes, 765952
+        // bytes, are sent for 734928 - 18 = 734910 bytes of data, hence 31042 bytes (4.05%)
+        // of overhead
+        let raptorq = RaptorQ::new(1500, 734_928, 2).expect("valid parameters");
+        assert_eq!(
+            raptorq.summary(),
+            "packet efficiency = 97.86% overhead = 4.05% symbols/packets = 502/512 (98.05%)"
+        );
+    }
+
+    #[test]
+    fn oversized_data() {
+        let raptorq = raptorq();
+        let data = vec![0; Block::max_data_len(&raptorq) + 1];
+        assert!(matches!(
+            Block::new(BlockType::Data, &raptorq, 1, 0, Some(&data)),
+            Err(Error::Other(_))
+        ));
+    }
+
+    #[test]
+    fn payload_byte_flip() {
+        let block = Block::new(BlockType::Data, &raptorq(), 1, 0, Some(b"payload")).expect("block");
+        assert_eq!(block.verified_payload().expect("intact"), b"payload");
+
+        for i in SERIALIZE_OVERHEAD..SERIALIZE_OVERHEAD + b"payload".len() {
+            let mut content = block.serialized().to_vec();
+            content[i] ^= 0x10;
+            assert!(matches!(
+                Block::deserialize(content).verified_payload(),
+                Err(Error::Corrupt)
+            ));
+        }
+
+        // a corrupted payload length pointing past the end of the block
+        let mut content = block.serialized().to_vec();
+        content[6..10].copy_from_slice(&u32::MAX.to_le_bytes());
+        let corrupted = Block::deserialize(content);
+        assert!(matches!(corrupted.verified_payload(), Err(Error::Corrupt)));
+        assert_eq!(
+            corrupted.payload().len(),
+            block.serialized().len() - SERIALIZE_OVERHEAD
+        );
+    }
+
+    #[test]
+    fn skippable_block_type() {
+        let block = Block::new(BlockType::Data, &raptorq(), 1, 0, Some(b"payload")).expect("block");
+
+        let mut content = block.serialized().to_vec();
+        content[5] = ID_SKIPPABLE_FIRST;
+        assert!(matches!(
+            Block::deserialize(content).block_type(),
+            Ok(BlockType::Skippable(ID_SKIPPABLE_FIRST))
+        ));
+
+        let mut content = block.serialized().to_vec();
+        content[5] = ID_SKIPPABLE_FIRST - 1;
+        assert!(matches!(
+            Block::deserialize(content).block_type(),
+            Err(Error::InvalidBlockType(Some(id))) if id == ID_SKIPPABLE_FIRST - 1
+        ));
+    }
+
+    #[test]
+    fn fixed_repair_packets() {
+        for nb_repair_packets in [0, 1, 5, 37] {
+            let raptorq =
+                RaptorQ::with_repair_packets(1500, 8 * 1464, nb_repair_packets).expect("valid");
+            assert_eq!(raptorq.nb_repair_packets(), nb_repair_packets);
+
+            let packets = raptorq.encode(0, &vec![0; raptorq.block_size() as usize]);
+            let repair = packets
+                .iter()
+                .filter(|packet| {
+                    u32::from(raptorq.min_nb_packets()) <= packet.payload_id().encoding_symbol_id()
+                })
+                .count();
+            assert_eq!(repair, usize::from(nb_repair_packets));
+            assert_eq!(packets.len(), raptorq.nb_packets() as usize);
+        }
+    }
+
+    #[test]
+    fn decode_repair_packets_only() {
+        let raptorq = raptorq();
+        let data = (0..=u8::MAX)
+            .cycle()
+            .take(raptorq.block_size() as usize)
+            .collect::<Vec<_>>();
+
+        // as many repair packets as source ones, plus a couple of spare ones, some of them
+        // beyond the repair packets sent by encode
+        let symbol_count = u32::from(raptorq.min_nb_packets());
+        let repair_ids = (symbol_count..2 * symbol_count + 2).collect::<Vec<_>>();
+        let packets = raptorq.encode_selected(3, &data, &repair_ids);
+        assert_eq!(packets.len(), repair_ids.len());
+        assert!(packets.iter().all(|packet| {
+            packet.payload_id().source_block_number() == 3
+                && symbol_count <= packet.payload_id().encoding_symbol_id()
+        }));
+        assert!(packets.windows(2).all(|pair| {
+            pair[0].payload_id().encoding_symbol_id() < pair[1].payload_id().encoding_symbol_id()
+        }));
+
+        // packets also sent by encode are identical
+        let encoded = raptorq.encode(3, &data);
+        assert!(
+            packets
+                .iter()
+                .take(usize::from(raptorq.nb_repair_packets()))
+                .all(|packet| encoded.contains(packet))
+        );
+
+        assert_eq!(raptorq.decode(3, packets), Some(data));
+    }
+}
@@ -0,0 +1,39 @@
+//! Optional worker that logs a compact liveness line every second
+
+use crate::receive;
+use std::{thread, time};
+
+/// Interval between two liveness lines
+const ALIVE_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+pub(crate) fn start<ClientNew, ClientEnd>(receiver: &receive::Receiver<ClientNew, ClientEnd>) {
+    let mut last = receiver.stats.snapshot();
+    let mut next = time::Instant::now() + ALIVE_INTERVAL;
+
+    loop {
+        // sleeping until a deadline avoids drifting from one line per second
+        thread::sleep(next.saturating_duration_since(time::Instant::now()));
+        next += ALIVE_INTERVAL;
+
+        if receiver.is_shutting_down() {
+            return;
+        }
+
+        let stats = receiver.stats.snapshot();
+        let delivered_bytes = stats.bytes_delivered - last.bytes_delivered;
+        let received_bytes = stats.bytes_received - last.bytes_received;
+        let packets = stats.packets_received - last.packets_received;
+        let active_transfers = stats.active_transfers;
+        last = stats;
+
+        #[allow(clippy::cast_precision_loss)]
+        let (delivered_mbits, received_mbits) = (
+            (8 * delivered_bytes) as f64 / 1_000_000.0,
+            (8 * received_bytes) as f64 / 1_000_000.0,
+        );
+
+        log::info!(
+            "alive: {delivered_mbits:.1} Mb/s delivered, {received_mbits:.1} Mb/s in {packets} packets/s received, {active_transfers} active transfer(s)"
+        );
+    }
+}
@@ -3,72 +3,373 @@
 use crate::{protocol, receive};
 use std::{
     io::{self, Write},
-    os::fd::AsRawFd,
-    thread,
+    thread, time,
 };
 
+/// Pause between two attempts to write to a client output that would block
+const WOULD_BLOCK_PAUSE: time::Duration = time::Duration::from_millis(1);
+
+/// Formats `at` as seconds and microseconds since the Unix epoch
+fn unix_timestamp(at: time::SystemTime) -> String {
+    let since_epoch = at.duration_since(time::UNIX_EPOCH).unwrap_or_default();
+    format!(
+        "{}.{:06}",
+        since_epoch.as_secs(),
+        since_epoch.subsec_micros()
+    )
+}
+
+fn wait_writable(deadline: time::Instant) -> Result<(), io::Error> {
+    if deadline <= time::Instant::now() {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "client output blocked, write timeout expired",
+        ));
+    }
+    thread::sleep(WOULD_BLOCK_PAUSE);
+    Ok(())
+}
+
+/// Writes all `buf` to a non-blocking `client` if `timeout` is set, failing when no progress
+/// is made during `timeout`
+pub(crate) fn write_all<W: Write>(
+    client: &mut W,
+    mut buf: &[u8],
+    timeout: Option<time::Duration>,
+) -> Result<(), io::Error> {
+    let Some(timeout) = timeout else {
+        return client.write_all(buf);
+    };
+
+    let mut deadline = time::Instant::now() + timeout;
+    while !buf.is_empty() {
+        match client.write(buf) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                deadline = time::Instant::now() + timeout;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => wait_writable(deadline)?,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Flushes a non-blocking `client` if `timeout` is set, failing when no progress is made
+/// during `timeout`
+pub(crate) fn flush<W: Write>(
+    client: &mut io::BufWriter<W>,
+    timeout: Option<time::Duration>,
+) -> Result<(), io::Error> {
+    let Some(timeout) = timeout else {
+        return client.flush();
+    };
+
+    let mut deadline = time::Instant::now() + timeout;
+    let mut buffered = client.buffer().len();
+    loop {
+        match client.flush() {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if client.buffer().len() < buffered {
+                    buffered = client.buffer().len();
+                    deadline = time::Instant::now() + timeout;
+                }
+                wait_writable(deadline)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Flushes `client` and finishes its inner sink, restoring blocking mode if it was changed
+///
+/// The inner sink is returned even on failure, and then finished as not completed, so that it
+/// can always be handed over to `client_end`; data that could not be flushed is discarded.
+pub(crate) fn finish<C: receive::Sink>(
+    mut client: io::BufWriter<C>,
+    write_timeout: Option<time::Duration>,
+    completed: bool,
+) -> (C, Result<(), receive::Error>) {
+    let mut res = flush(&mut client, write_timeout).map_err(receive::Error::from);
+    let (mut client, _) = client.into_parts();
+    if write_timeout.is_some()
+        && let Err(e) = client.set_nonblocking(false)
+        && res.is_ok()
+    {
+        res = Err(e.into());
+    }
+    if let Err(e) = client.finish(completed && res.is_ok())
+        && res.is_ok()
+    {
+        res = Err(e.into());
+    }
+    (client, res)
+}
+
+/// Logs the summary of a transfer sent by the sender, and whether it differs from what was
+/// received
+fn check_summary(
+    client_id: protocol::ClientId,
+    summary: &protocol::TransferSummary,
+    transmitted: usize,
+    blocks: u32,
+) {
+    log::info!("client {client_id:x}: sender summary: {summary}");
+    if summary.bytes != transmitted as u64 || summary.blocks != blocks {
+        log::warn!(
+            "client {client_id:x}: {transmitted} bytes in {blocks} blocks received, differing from the sender summary"
+        );
+    }
+}
+
+/// Receives the next block of a transfer started at `started_at`, failing if none is received
+/// before the abort timeout or if the transfer lasted longer than the maximum duration
+pub(crate) fn recv_block(
+    config: &receive::Config,
+    recvq: &crossbeam_channel::Receiver<protocol::Block>,
+    started_at: time::Instant,
+) -> Result<protocol::Block, receive::Error> {
+    let Some(max) = config.max_transfer_duration else {
+        return match config.abort_timeout {
+            Some(timeout) => recvq.recv_timeout(timeout).map_err(receive::Error::from),
+            None => recvq.recv().map_err(receive::Error::from),
+        };
+    };
+
+    let remaining = max.saturating_sub(started_at.elapsed());
+    if remaining.is_zero() {
+        return Err(receive::Error::TransferTooLong(max));
+    }
+
+    let timeout = config
+        .abort_timeout
+        .map_or(remaining, |timeout| timeout.min(remaining));
+    match recvq.recv_timeout(timeout) {
+        Err(crossbeam_channel::RecvTimeoutError::Timeout) if timeout == remaining => {
+            Err(receive::Error::TransferTooLong(max))
+        }
+        res => res.map_err(receive::Error::from),
+    }
+}
+
+/// Fails if writing `len` more bytes to a client which was already written `transmitted` bytes
+/// would exceed the maximum transfer size
+pub(crate) fn check_size(
+    config: &receive::Config,
+    transmitted: usize,
+    len: usize,
+) -> Result<(), receive::Error> {
+    match config.max_transfer_bytes {
+        Some(max) if max < (transmitted + len) as u64 => Err(receive::Error::TransferTooLarge(max)),
+        _ => Ok(()),
+    }
+}
+
+/// Splits the payload of a block into the metadata it may carry and the transfer data: with
+/// transfer summaries, the payload of the `End` block is the summary, and with transfer UUIDs,
+/// the payload of the `Start` block is the UUID, which is logged
+fn split_metadata<'a>(
+    config: &receive::Config,
+    client_id: protocol::ClientId,
+    block_type: &protocol::BlockType,
+    block_payload: &'a [u8],
+) -> Result<(Option<protocol::TransferSummary>, &'a [u8]), receive::Error> {
+    match block_type {
+        protocol::BlockType::End if config.transfer_summary => Ok((
+            Some(protocol::TransferSummary::deserialize(block_payload)?),
+            &[],
+        )),
+        protocol::BlockType::Start if config.transfer_uuid => {
+            let uuid = protocol::TransferUuid::deserialize(block_payload)?;
+            log::info!("client {client_id:x}: transfer {uuid}");
+            Ok((None, &[]))
+        }
+        _ => Ok((None, block_payload)),
+    }
+}
+
+/// Hands the outcome of a transfer to the `on_transfer_complete` callback, if any
+pub(crate) fn report_completion(
+    config: &receive::Config,
+    client_id: protocol::ClientId,
+    transmitted: usize,
+    completed: bool,
+) {
+    if let Some(on_transfer_complete) = config.on_transfer_complete.as_ref() {
+        on_transfer_complete(client_id, transmitted, completed);
+    }
+}
+
 pub(crate) fn start<C, ClientNew, ClientEnd, E>(
     receiver: &receive::Receiver<ClientNew, ClientEnd>,
     client_id: protocol::ClientId,
     recvq: &crossbeam_channel::Receiver<protocol::Block>,
 ) -> Result<(), receive::Error>
 where
-    C: Write + AsRawFd,
+    C: receive::Sink,
     ClientNew: Send + Sync + Fn(protocol::ClientId) -> Result<C, E>,
-    ClientEnd: Send + Sync + Fn(C, bool),
+    ClientEnd: Send + Sync + Fn(C, bool, Option<protocol::TransferSummary>),
+    E: Into<receive::Error>,
+{
+    if receiver.config.dual_feed {
+        return receive::dual_feed::start(receiver, client_id, recvq);
+    }
+
+    // every way the transfer ends, including errors, is reported exactly once
+    let mut transmitted = 0;
+    let res = transfer(receiver, client_id, recvq, &mut transmitted);
+    report_completion(
+        &receiver.config,
+        client_id,
+        transmitted,
+        matches!(res, Ok(true)),
+    );
+    res.map(|_| ())
+}
+
+/// Writes the blocks of a transfer to a new client, counting the bytes written in
+/// `transmitted`, and returns whether the transfer completed (`false` if it was aborted)
+fn transfer<C, ClientNew, ClientEnd, E>(
+    receiver: &receive::Receiver<ClientNew, ClientEnd>,
+    client_id: protocol::ClientId,
+    recvq: &crossbeam_channel::Receiver<protocol::Block>,
+    transmitted: &mut usize,
+) -> Result<bool, receive::Error>
+where
+    C: receive::Sink,
+    ClientNew: Send + Sync + Fn(protocol::ClientId) -> Result<C, E>,
+    ClientEnd: Send + Sync + Fn(C, bool, Option<protocol::TransferSummary>),
     E: Into<receive::Error>,
 {
     log::info!("client {client_id:x}: starting transfer");
 
-    let client = (receiver.client_new)(client_id).map_err(Into::into)?;
-    let mut client =
-        io::BufWriter::with_capacity(protocol::Block::max_data_len(&receiver.raptorq), client);
+    let started_at = time::Instant::now();
 
-    let mut transmitted = 0;
+    let mut client = (receiver.client_new)(client_id).map_err(Into::into)?;
+
+    let mut write_timeout = receiver.config.write_timeout;
+    if write_timeout.is_some()
+        && let Err(e) = client.set_nonblocking(true)
+    {
+        log::warn!("client {client_id:x}: cannot use non-blocking output, no write timeout: {e}");
+        write_timeout = None;
+    }
+
+    // small payloads are coalesced into writes of up to the buffer size; the buffer is flushed
+    // when full, after every block with --flush or records, and by `finish` on End and Abort
+    let buffer_size = receiver
+        .config
+        .client_buffer_size
+        .unwrap_or_else(|| protocol::Block::max_data_len(&receiver.raptorq));
+    let mut client = io::BufWriter::with_capacity(buffer_size, client);
+
+    let res = write_blocks(
+        receiver,
+        client_id,
+        recvq,
+        started_at,
+        &mut client,
+        write_timeout,
+        transmitted,
+    );
+
+    // every way the transfer ends, including errors, finishes the client and hands it over
+    let (res, summary) = match res {
+        Ok((completed, summary)) => (Ok(completed), summary),
+        Err(e) => (Err(e), None),
+    };
+    let completed = matches!(res, Ok(true));
+    let (client, finished) = finish(client, write_timeout, completed);
+    (receiver.client_end)(client, completed && finished.is_ok(), summary);
+    res.and_then(|completed| finished.map(|()| completed))
+}
+
+/// Writes the blocks of a transfer to `client` until its end, returning whether it completed
+/// and the summary sent by the sender, if any
+fn write_blocks<C, ClientNew, ClientEnd>(
+    receiver: &receive::Receiver<ClientNew, ClientEnd>,
+    client_id: protocol::ClientId,
+    recvq: &crossbeam_channel::Receiver<protocol::Block>,
+    started_at: time::Instant,
+    client: &mut io::BufWriter<C>,
+    write_timeout: Option<time::Duration>,
+    transmitted: &mut usize,
+) -> Result<(bool, Option<protocol::TransferSummary>), receive::Error>
+where
+    C: receive::Sink,
+{
+    let mut expected_chunk_index: u32 = 0;
+    let mut lost_chunks: u64 = 0;
 
     loop {
-        let block = if let Some(timeout) = receiver.config.abort_timeout {
-            recvq.recv_timeout(timeout).map_err(receive::Error::from)?
-        } else {
-            recvq.recv().map_err(receive::Error::from)?
-        };
+        let block = recv_block(&receiver.config, recvq, started_at)?;
+        let received_at = receiver.config.log_timestamps.then(time::SystemTime::now);
+
+        receiver.stats.core_block();
 
         let block_type = block.block_type()?;
+        let block_payload = block.verified_payload()?;
+
+        if !matches!(block_type, protocol::BlockType::Abort) {
+            let chunk_index = block.chunk_index();
+            if chunk_index != expected_chunk_index {
+                let lost = chunk_index.wrapping_sub(expected_chunk_index);
+                log::warn!("client {client_id:x}: {lost} chunk(s) lost before chunk {chunk_index}");
+                lost_chunks += u64::from(lost);
+                receiver.stats.chunks_lost(lost);
+            }
+            expected_chunk_index = chunk_index.wrapping_add(1);
+        }
 
-        let payload = block.payload();
+        let (summary, payload) =
+            split_metadata(&receiver.config, client_id, &block_type, block_payload)?;
 
-        if !payload.is_empty() {
+        // in records mode, every Data block carries a single record, possibly empty
+        let is_record = receiver.config.records && matches!(block_type, protocol::BlockType::Data);
+
+        if is_record || !payload.is_empty() {
             log::trace!("client {client_id:x}: payload {} bytes", payload.len());
-            transmitted += payload.len();
-            client.write_all(payload)?;
-            if receiver.config.flush {
-                client.flush()?;
+            if let Some(received_at) = received_at {
+                log::info!(
+                    "client {client_id:x}: chunk {} of {} bytes at offset {transmitted} received at {}",
+                    block.chunk_index(),
+                    payload.len(),
+                    unix_timestamp(received_at)
+                );
+            }
+            check_size(&receiver.config, *transmitted, payload.len())?;
+            *transmitted += payload.len();
+            if is_record {
+                let record_len = u32::try_from(payload.len())
+                    .map_err(|e| receive::Error::Other(format!("record length: {e}")))?;
+                write_all(client, &record_len.to_be_bytes(), write_timeout)?;
+            }
+            write_all(client, payload, write_timeout)?;
+            receiver.stats.bytes_delivered(payload.len());
+            if receiver.config.flush || is_record {
+                flush(client, write_timeout)?;
             }
         }
 
         match block_type {
             protocol::BlockType::Abort => {
-                log::warn!("client {client_id:x}: aborting transfer");
-                (receiver.client_end)(
-                    client.into_inner().map_err(|e| {
-                        receive::Error::Other(format!("failed to retrieve client inner: {e}",))
-                    })?,
-                    false,
+                log::warn!(
+                    "client {client_id:x}: aborting transfer, {transmitted} bytes transmitted, {lost_chunks} chunk(s) lost"
                 );
-                return Ok(());
+                return Ok((false, None));
             }
             protocol::BlockType::End => {
                 log::info!(
-                    "client {client_id:x}: finished transfer, {transmitted} bytes transmitted"
-                );
-                client.flush()?;
-                (receiver.client_end)(
-                    client.into_inner().map_err(|e| {
-                        receive::Error::Other(format!("failed to retrieve client inner: {e}",))
-                    })?,
-                    true,
+                    "client {client_id:x}: finished transfer, {transmitted} bytes transmitted, {lost_chunks} chunk(s) lost"
                 );
-                return Ok(());
+                if let Some(summary) = summary {
+                    check_summary(client_id, &summary, *transmitted, expected_chunk_index);
+                }
+                return Ok((true, summary));
             }
             _ => (),
         }
@@ -76,3 +377,201 @@ where
         thread::yield_now();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync;
+
+    const CLIENT_ID: protocol::ClientId = 0x1234;
+
+    /// Content and outcome of the clients handed over to `client_end`
+    type Ended = Vec<(Vec<u8>, bool)>;
+
+    fn raptorq() -> protocol::RaptorQ {
+        protocol::RaptorQ::new(1500, 8 * 1464, 10).expect("valid parameters")
+    }
+
+    fn block(
+        block_type: protocol::BlockType,
+        chunk_index: u32,
+        data: Option<&[u8]>,
+    ) -> protocol::Block {
+        protocol::Block::new(block_type, &raptorq(), CLIENT_ID, chunk_index, data).expect("block")
+    }
+
+    /// Mandatory configuration keys followed by `extra_config`
+    fn config(extra_config: &str) -> receive::Config {
+        toml::from_str(&format!(
+            r#"
+            from = "127.0.0.1:5000"
+            from_mtu = 1500
+            reset_timeout = 2
+            nb_decode_threads = 1
+            max_clients = 2
+            nb_client_threads = 2
+            {extra_config}
+            "#
+        ))
+        .expect("valid configuration")
+    }
+
+    fn queue(blocks: Vec<protocol::Block>) -> crossbeam_channel::Receiver<protocol::Block> {
+        let (sendq, recvq) = crossbeam_channel::unbounded();
+        for block in blocks {
+            sendq.send(block).expect("send");
+        }
+        recvq
+    }
+
+    /// Runs a transfer of `blocks` with the mandatory configuration keys followed by
+    /// `extra_config`, returning its result and how the client was ended
+    fn run_transfer(
+        extra_config: &str,
+        blocks: Vec<protocol::Block>,
+    ) -> (Result<bool, receive::Error>, Ended) {
+        let ended = sync::Mutex::new(Vec::new());
+        let receiver = receive::Receiver::new(
+            config(extra_config),
+            raptorq(),
+            |_| Ok::<_, io::Error>(Vec::new()),
+            |client, completed, _| ended.lock().expect("lock").push((client, completed)),
+        )
+        .expect("receiver");
+
+        let res = transfer(&receiver, CLIENT_ID, &queue(blocks), &mut 0);
+        drop(receiver);
+        (res, ended.into_inner().expect("lock"))
+    }
+
+    /// Sink recording the length of every write it receives
+    #[derive(Default)]
+    struct Writes(Vec<usize>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+            self.0.push(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    impl receive::Sink for Writes {}
+
+    #[test]
+    fn completed() {
+        let (res, ended) = run_transfer(
+            "",
+            vec![
+                block(protocol::BlockType::Start, 0, None),
+                block(protocol::BlockType::Data, 1, Some(b"da")),
+                block(protocol::BlockType::End, 2, Some(b"ta")),
+            ],
+        );
+        assert!(matches!(res, Ok(true)));
+        assert_eq!(ended, vec![(b"data".to_vec(), true)]);
+    }
+
+    #[test]
+    fn corrupt_block_finishes_client() {
+        let data = block(protocol::BlockType::Data, 1, Some(b"data"));
+        let mut content = data.serialized().to_vec();
+        let last_payload_byte = content.len() - data.padding_len() - 1;
+        content[last_payload_byte] ^= 1;
+
+        let (res, ended) = run_transfer(
+            "",
+            vec![
+                block(protocol::BlockType::Start, 0, None),
+                protocol::Block::deserialize(content),
+            ],
+        );
+        assert!(matches!(
+            res,
+            Err(receive::Error::Protocol(protocol::Error::Corrupt))
+        ));
+        assert_eq!(ended, vec![(Vec::new(), false)]);
+    }
+
+    #[test]
+    fn too_large_finishes_client() {
+        let (res, ended) = run_transfer(
+            "max_transfer_bytes = 2",
+            vec![
+                block(protocol::BlockType::Start, 0, None),
+                block(protocol::BlockType::Data, 1, Some(b"da")),
+                block(protocol::BlockType::Data, 2, Some(b"ta")),
+            ],
+        );
+        assert!(matches!(res, Err(receive::Error::TransferTooLarge(2))));
+        assert_eq!(ended, vec![(b"da".to_vec(), false)]);
+    }
+
+    #[test]
+    fn small_payloads_coalesced() {
+        let mut blocks = vec![block(protocol::BlockType::Start, 0, None)];
+        for chunk_index in 1..=6 {
+            blocks.push(block(protocol::BlockType::Data, chunk_index, Some(b"abc")));
+        }
+        blocks.push(block(protocol::BlockType::End, 7, None));
+
+        let writes = sync::Mutex::new(Vec::new());
+        let receiver = receive::Receiver::new(
+            config("client_buffer_size = 8"),
+            raptorq(),
+            |_| Ok::<_, io::Error>(Writes::default()),
+            |client: Writes, completed, _| {
+                assert!(completed);
+                *writes.lock().expect("lock") = client.0;
+            },
+        )
+        .expect("receiver");
+        let res = transfer(&receiver, CLIENT_ID, &queue(blocks), &mut 0);
+        drop(receiver);
+
+        assert!(matches!(res, Ok(true)));
+        // the six payloads of 3 bytes are written by pairs, the buffer holding up to 8 bytes
+        assert_eq!(writes.into_inner().expect("lock"), vec![6, 6, 6]);
+    }
+
+    #[test]
+    fn lost_chunks_counted() {
+        let ended = sync::Mutex::new(Vec::new());
+        let receiver = receive::Receiver::new(
+            config("keep_transfers_on_loss = true"),
+            raptorq(),
+            |_| Ok::<_, io::Error>(Vec::new()),
+            |client, completed, _| ended.lock().expect("lock").push((client, completed)),
+        )
+        .expect("receiver");
+
+        // the blocks of chunks 2 and 3 fail to decode
+        let mut dispatch = receive::dispatch::Dispatch::new(&receiver).expect("dispatch");
+        let messages = [
+            Some(block(protocol::BlockType::Start, 0, None)),
+            Some(block(protocol::BlockType::Data, 1, Some(b"da"))),
+            None,
+            None,
+            Some(block(protocol::BlockType::Data, 4, Some(b"ta"))),
+            Some(block(protocol::BlockType::End, 5, None)),
+        ];
+        for (block_id, message) in (0..).zip(messages) {
+            dispatch
+                .step(&receiver, message.map(|block| (block_id, block)))
+                .expect("dispatch");
+        }
+        let (client_id, recvq) = receiver.for_clients.try_recv().expect("transfer started");
+
+        let res = transfer(&receiver, client_id, &recvq, &mut 0);
+        assert!(matches!(res, Ok(true)));
+        assert_eq!(receiver.stats().chunks_lost, 2);
+        drop(receiver);
+        assert_eq!(
+            ended.into_inner().expect("lock"),
+            vec![(b"data".to_vec(), true)]
+        );
+    }
+}
@@ -1,25 +1,29 @@
 //! Worker that acquires multiplex access and then becomes a `crate::receive::client` worker
 
 use crate::{protocol, receive, receive::client};
-use std::{io::Write, os::fd::AsRawFd, thread};
+use std::thread;
 
 pub(crate) fn start<C, ClientNew, ClientEnd, E>(
     receiver: &receive::Receiver<ClientNew, ClientEnd>,
 ) -> Result<(), receive::Error>
 where
-    C: Write + AsRawFd,
+    C: receive::Sink,
     ClientNew: Send + Sync + Fn(protocol::ClientId) -> Result<C, E>,
-    ClientEnd: Send + Sync + Fn(C, bool),
+    ClientEnd: Send + Sync + Fn(C, bool, Option<protocol::TransferSummary>),
     E: Into<receive::Error>,
 {
     loop {
-        let (client_id, recvq) = receiver.for_clients.recv()?;
+        let Some((client_id, recvq)) = receiver.recv_or_shutdown(&receiver.for_clients)? else {
+            return Ok(());
+        };
 
         log::debug!("try to acquire multiplex access..");
         receiver.multiplex_control.wait();
         log::debug!("multiplex access acquired");
 
+        receiver.stats.transfer_started();
         let client_res = client::start(receiver, client_id, &recvq);
+        receiver.stats.transfer_ended();
 
         receiver.multiplex_control.signal();
 
@@ -1,24 +1,120 @@
 //! Worker that decodes `RaptorQ` packets into protocol blocks
 
 use crate::{protocol, receive};
-use std::thread;
+use std::{fmt::Write, thread};
+
+/// Bitmap of the encoding symbol ids of `packets`, as hexadecimal bytes whose most significant
+/// bit stands for the lowest symbol id, covering at least `nb_symbols` symbols
+fn received_symbols(packets: &[raptorq::EncodingPacket], nb_symbols: u32) -> String {
+    let nb_symbols = packets
+        .iter()
+        .map(|packet| packet.payload_id().encoding_symbol_id() + 1)
+        .fold(nb_symbols, u32::max) as usize;
+
+    let mut bitmap = vec![0u8; nb_symbols.div_ceil(8)];
+    for packet in packets {
+        let id = packet.payload_id().encoding_symbol_id() as usize;
+        bitmap[id / 8] |= 0x80 >> (id % 8);
+    }
+
+    bitmap.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// Accounts a decoding outcome, shutting the receiver down and failing if the decode success
+/// rate got too low and the receiver is configured to stop then
+fn check_decode_rate<ClientNew, ClientEnd>(
+    receiver: &receive::Receiver<ClientNew, ClientEnd>,
+    success: bool,
+) -> Result<(), receive::Error> {
+    let Some(min_rate) = receiver.config.min_decode_rate else {
+        return Ok(());
+    };
+
+    match receiver.stats.decode_outcome(success, min_rate) {
+        None => (),
+        Some(receive::DecodeRate::Breached(rate)) => {
+            log::error!(
+                "decode success rate {rate:.1}% over the last {} blocks is below {min_rate}%, link is too lossy for the repair budget",
+                receiver.config.decode_rate_window
+            );
+            if receiver.config.exit_on_low_decode_rate {
+                receiver.shutdown();
+                return Err(receive::Error::LowDecodeRate(rate));
+            }
+        }
+        Some(receive::DecodeRate::Recovered(rate)) => {
+            log::warn!(
+                "decode success rate {rate:.1}% over the last {} blocks is back above {min_rate}%",
+                receiver.config.decode_rate_window
+            );
+        }
+    }
+
+    Ok(())
+}
 
 pub(crate) fn start<ClientNew, ClientEnd>(
     receiver: &receive::Receiver<ClientNew, ClientEnd>,
 ) -> Result<(), receive::Error> {
+    let min_nb_packets = u32::from(receiver.raptorq.min_nb_packets());
+
     loop {
-        match receiver.for_decode.recv()? {
+        let Some(reassembled) = receiver.recv_or_shutdown(&receiver.for_decode)? else {
+            return Ok(());
+        };
+
+        match reassembled {
             super::Reassembled::Block { id, packets } => {
+                receiver.stats.core_block();
+
+                // packets whose encoding symbol id is beyond the source symbols are repair ones,
+                // every missing source packet having to be replaced by a repair one
+                let source_received = packets
+                    .iter()
+                    .filter(|packet| packet.payload_id().encoding_symbol_id() < min_nb_packets)
+                    .count();
+                let repair_used = (min_nb_packets as usize).saturating_sub(source_received);
+                let nb_packets = packets.len();
+
+                // computed before packets are consumed by the decoder, only when it may be logged
+                let symbols = log::log_enabled!(log::Level::Debug)
+                    .then(|| received_symbols(&packets, receiver.raptorq.nb_packets()));
+
                 match receiver.raptorq.decode(id, packets) {
                     None => {
                         log::error!("lost block {id} (failed to decode)");
+                        receiver
+                            .stats
+                            .packets_dropped(receive::DropReason::DecodeFailed, nb_packets);
+                        if let Some(symbols) = symbols {
+                            log::debug!("block {id} received symbols: {symbols}");
+                        }
+                        if receiver.stats.block_failed() {
+                            log::error!(
+                                "repair headroom exhausted: block {id} lost, the sender repair percentage should be raised"
+                            );
+                        }
+                        check_decode_rate(receiver, false)?;
                         receiver.to_dispatch.send(None)?;
                     }
                     Some(block) => {
-                        log::debug!("block {id} decoded with {} bytes!", block.len());
+                        log::debug!(
+                            "block {id} decoded with {} bytes using {repair_used}/{} repair packets!",
+                            block.len(),
+                            receiver.raptorq.nb_repair_packets()
+                        );
+                        if receiver.stats.block_decoded(repair_used) {
+                            log::warn!(
+                                "repair headroom exhausted: block {id} needed all the repair packets, the sender repair percentage should be raised"
+                            );
+                        }
+                        check_decode_rate(receiver, true)?;
                         receiver
                             .to_dispatch
-                            .send(Some(protocol::Block::deserialize(block)))?;
+                            .send(Some((id, protocol::Block::deserialize(block))))?;
                     }
                 }
             }
@@ -32,3 +128,177 @@ pub(crate) fn start<ClientNew, ClientEnd>(
         thread::yield_now();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    type ClientNew = fn(protocol::ClientId) -> Result<Vec<u8>, io::Error>;
+    type ClientEnd = fn(Vec<u8>, bool, Option<protocol::TransferSummary>);
+    type Receiver = receive::Receiver<ClientNew, ClientEnd>;
+
+    /// Receiver of blocks of 8 source packets followed by `nb_repair_packets` repair packets
+    fn receiver(nb_repair_packets: u16) -> Receiver {
+        let config = toml::from_str(
+            r#"
+            from = "127.0.0.1:5000"
+            from_mtu = 1500
+            reset_timeout = 2
+            nb_decode_threads = 1
+            max_clients = 2
+            nb_client_threads = 2
+            "#,
+        )
+        .expect("valid configuration");
+        let raptorq = protocol::RaptorQ::with_repair_packets(1500, 8 * 1464, nb_repair_packets)
+            .expect("valid parameters");
+        let client_new: ClientNew = |_| Ok(Vec::new());
+        let client_end: ClientEnd = |_, _, _| ();
+        receive::Receiver::new(config, raptorq, client_new, client_end).expect("receiver")
+    }
+
+    /// Runs the decode worker on `reassembled`, returning the ids of the decoded blocks, `None`
+    /// standing for a lost block
+    fn decode(receiver: &Receiver, reassembled: Vec<receive::Reassembled>) -> Vec<Option<u8>> {
+        thread::scope(|scope| {
+            let worker = scope.spawn(|| start(receiver));
+            let decoded = reassembled
+                .into_iter()
+                .map(|reassembled| {
+                    receiver.to_decode.send(reassembled).expect("send");
+                    receiver
+                        .for_dispatch
+                        .recv()
+                        .expect("decoded")
+                        .map(|(id, _)| id)
+                })
+                .collect();
+            receiver.shutdown();
+            assert!(worker.join().expect("decode worker").is_ok());
+            decoded
+        })
+    }
+
+    fn packets(receiver: &Receiver, id: u8) -> Vec<raptorq::EncodingPacket> {
+        let block = protocol::Block::new(
+            protocol::BlockType::Data,
+            &receiver.raptorq,
+            1,
+            u32::from(id),
+            Some(&[id; 64]),
+        )
+        .expect("block");
+        receiver.raptorq.encode(id, block.serialized())
+    }
+
+    #[test]
+    fn undecodable_block_counted() {
+        let receiver = receiver(0);
+
+        let mut missing = packets(&receiver, 1);
+        let nb_packets = missing.len() - 1;
+        missing.pop();
+        let reassembled = vec![
+            receive::Reassembled::Block {
+                id: 0,
+                packets: packets(&receiver, 0),
+            },
+            receive::Reassembled::Block {
+                id: 1,
+                packets: missing,
+            },
+        ];
+
+        assert_eq!(decode(&receiver, reassembled), vec![Some(0), None]);
+        let stats = receiver.stats();
+        assert_eq!(
+            stats.packets_dropped.get(receive::DropReason::DecodeFailed),
+            u64::try_from(nb_packets).expect("count")
+        );
+        assert_eq!(
+            stats.packets_dropped.total(),
+            stats.packets_dropped.get(receive::DropReason::DecodeFailed)
+        );
+    }
+
+    #[test]
+    fn received_symbols_bitmap() {
+        let packet = |id| raptorq::EncodingPacket::new(raptorq::PayloadId::new(0, id), vec![0; 8]);
+
+        // a duplicate sets its bit once, symbols beyond `nb_symbols` extend the bitmap
+        assert_eq!(received_symbols(&[0, 0, 3, 9].map(packet), 8), "9040");
+        assert_eq!(received_symbols(&[], 8), "00");
+
+        // symbols 1, 2 and 5 of a block lost
+        let receiver = receiver(0);
+        let mut packets = packets(&receiver, 0);
+        packets.retain(|packet| ![1, 2, 5].contains(&packet.payload_id().encoding_symbol_id()));
+        assert_eq!(
+            received_symbols(&packets, receiver.raptorq.nb_packets()),
+            "9b"
+        );
+    }
+
+    /// Packets of block `id`, its source packets `lost` being replaced by as many repair ones
+    fn received(receiver: &Receiver, id: u8, lost: u8) -> receive::Reassembled {
+        let mut packets = packets(receiver, id);
+        packets.retain(|packet| u32::from(lost) <= packet.payload_id().encoding_symbol_id());
+        packets.truncate(8);
+        receive::Reassembled::Block { id, packets }
+    }
+
+    #[test]
+    fn repair_packets_used() {
+        let receiver = receiver(2);
+
+        let reassembled = (0..=2)
+            .map(|lost| received(&receiver, lost as u8, lost))
+            .collect();
+        assert_eq!(
+            decode(&receiver, reassembled),
+            vec![Some(0), Some(1), Some(2)]
+        );
+
+        let stats = receiver.stats();
+        assert_eq!(stats.repair_used, vec![1, 1, 1]);
+        assert_eq!(stats.decode_failed, 0);
+    }
+
+    #[test]
+    fn min_repair_headroom() {
+        // a block failing to decode leaves no headroom
+        let failing = receiver(2);
+        let mut failed = packets(&failing, 0);
+        failed.truncate(7);
+        let reassembled = vec![receive::Reassembled::Block {
+            id: 0,
+            packets: failed,
+        }];
+        assert_eq!(decode(&failing, reassembled), vec![None]);
+        assert_eq!(failing.stats().min_repair_headroom(), Some(0));
+
+        let receiver = receiver(2);
+        assert_eq!(receiver.stats().min_repair_headroom(), None);
+
+        // the block which barely decoded sets the headroom
+        thread::scope(|scope| {
+            let worker = scope.spawn(|| start(&receiver));
+            for (lost, headroom) in [(1, 1), (0, 1), (2, 0)] {
+                receiver
+                    .to_decode
+                    .send(received(&receiver, lost, lost))
+                    .expect("send");
+                let (id, _) = receiver
+                    .for_dispatch
+                    .recv()
+                    .expect("decoded")
+                    .expect("block");
+                assert_eq!(id, lost);
+                assert_eq!(receiver.stats().min_repair_headroom(), Some(headroom));
+            }
+            receiver.shutdown();
+            assert!(worker.join().expect("decode worker").is_ok());
+        });
+    }
+}
@@ -2,64 +2,327 @@
 //! blocks to clients
 
 use crate::{protocol, receive};
-use std::{collections::HashMap, thread, time};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    path, sync, thread, time,
+};
 
-pub(crate) fn start<ClientNew, ClientEnd>(
+/// Number of payload bytes written in protocol dump records
+const DUMP_HEAD_LEN: usize = 16;
+
+fn open_protocol_dump<ClientNew, ClientEnd>(
+    receiver: &receive::Receiver<ClientNew, ClientEnd>,
+) -> Result<Option<io::LineWriter<fs::File>>, io::Error> {
+    let Some(path) = receiver.config.protocol_dump.as_ref() else {
+        return Ok(None);
+    };
+    log::info!("dumping decoded blocks to {}", path.display());
+    Ok(Some(io::LineWriter::new(fs::File::create(path)?)))
+}
+
+fn dump_block(dump: &mut io::LineWriter<fs::File>, block_id: u8, block: &protocol::Block) {
+    let head = block.payload().iter().take(DUMP_HEAD_LEN).fold(
+        String::with_capacity(2 * DUMP_HEAD_LEN),
+        |mut head, byte| {
+            let _ = write!(head, "{byte:02x}");
+            head
+        },
+    );
+    if let Err(e) = writeln!(dump, "block {block_id} {block} head = {head}") {
+        log::error!("failed to dump block {block_id}: {e}");
+    }
+}
+
+/// Directory to which the payload of every decoded block is written, in sequentially numbered
+/// files
+struct SplitBlocks {
+    dir: path::PathBuf,
+    next: u64,
+}
+
+impl SplitBlocks {
+    fn open<ClientNew, ClientEnd>(
+        receiver: &receive::Receiver<ClientNew, ClientEnd>,
+    ) -> Result<Option<Self>, io::Error> {
+        let Some(dir) = receiver.config.split_blocks.as_ref() else {
+            return Ok(None);
+        };
+        fs::create_dir_all(dir)?;
+        log::info!("writing decoded blocks payloads to {}", dir.display());
+        Ok(Some(Self {
+            dir: dir.clone(),
+            next: 0,
+        }))
+    }
+
+    fn write(&mut self, block_id: u8, block: &protocol::Block) {
+        let path = self.dir.join(format!("block-{:08}", self.next));
+        self.next += 1;
+        if let Err(e) = fs::write(&path, block.payload()) {
+            log::error!(
+                "failed to write block {block_id} to {}: {e}",
+                path.display()
+            );
+        }
+    }
+}
+
+/// Block queues of the transfers, by client id
+type Transfers = HashMap<protocol::ClientId, crossbeam_channel::Sender<protocol::Block>>;
+
+/// Sends an abort block to every active transfer, then forgets them
+fn abort_all<ClientNew, ClientEnd>(
     receiver: &receive::Receiver<ClientNew, ClientEnd>,
+    active_transfers: &mut Transfers,
 ) -> Result<(), receive::Error> {
-    let mut active_transfers: HashMap<
-        protocol::ClientId,
-        crossbeam_channel::Sender<protocol::Block>,
-    > = HashMap::new();
-    let mut ended_transfers: HashMap<
-        protocol::ClientId,
-        crossbeam_channel::Sender<protocol::Block>,
-    > = HashMap::new();
+    for (client_id, client_sendq) in active_transfers.drain() {
+        let block = protocol::Block::new(
+            protocol::BlockType::Abort,
+            &receiver.raptorq,
+            client_id,
+            0,
+            None,
+        )?;
+
+        if let Err(e) = client_sendq.send(block) {
+            log::error!("failed to send payload to client {client_id:x}: {e}");
+        }
+    }
+    Ok(())
+}
 
-    let mut last_heartbeat = time::Instant::now();
+/// Sends an abort block to the transfer of `client_id`, if active, then forgets it
+fn abort_transfer<ClientNew, ClientEnd>(
+    receiver: &receive::Receiver<ClientNew, ClientEnd>,
+    active_transfers: &mut Transfers,
+    client_id: protocol::ClientId,
+) -> Result<(), receive::Error> {
+    let Some(client_sendq) = active_transfers.remove(&client_id) else {
+        return Ok(());
+    };
+    log::warn!("client {client_id:x}: block dropped while delivery is paused, aborting transfer");
+    let block = protocol::Block::new(
+        protocol::BlockType::Abort,
+        &receiver.raptorq,
+        client_id,
+        0,
+        None,
+    )?;
+    if let Err(e) = client_sendq.send(block) {
+        log::error!("failed to send payload to client {client_id:x}: {e}");
+    }
+    Ok(())
+}
 
-    loop {
-        let block = match receiver.config.heartbeat_interval.as_ref() {
-            None => receiver.for_dispatch.recv()?,
-            Some(hb_interval) => match receiver.for_dispatch.recv_timeout(*hb_interval) {
-                Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
-                    if last_heartbeat.elapsed() > *hb_interval {
-                        log::warn!(
-                            "no heartbeat block received for {} second(s)",
-                            hb_interval.as_secs()
-                        );
-                    }
-                    continue;
+/// Queues a new transfer for the client workers, applying the configured policy when too many
+/// transfers are already waiting for a client worker
+fn start_transfer<ClientNew, ClientEnd>(
+    receiver: &receive::Receiver<ClientNew, ClientEnd>,
+    active_transfers: &mut Transfers,
+    ended_transfers: &mut Transfers,
+    client_id: protocol::ClientId,
+) -> Result<(), receive::Error> {
+    if let Some(max) = receiver.config.max_queued_transfers
+        && max <= receiver.for_clients.len()
+    {
+        receiver.stats.transfer_dropped();
+        match receiver.config.queued_transfers_policy {
+            receive::QueuePolicy::RejectNewest => {
+                log::warn!("client {client_id:x}: {max} transfers already queued, rejecting it");
+                return Ok(());
+            }
+            receive::QueuePolicy::DropOldest => {
+                if let Ok((oldest_id, _)) = receiver.for_clients.try_recv() {
+                    log::warn!(
+                        "client {client_id:x}: {max} transfers already queued, dropping the oldest one ({oldest_id:x})"
+                    );
+                    active_transfers.remove(&oldest_id);
+                    ended_transfers.remove(&oldest_id);
                 }
-                other => other?,
-            },
+            }
+        }
+    }
+
+    let (client_sendq, client_recvq) = crossbeam_channel::unbounded::<protocol::Block>();
+    active_transfers.insert(client_id, client_sendq);
+    receiver.to_clients.send((client_id, client_recvq))?;
+    Ok(())
+}
+
+/// State of the dispatch worker, updated one message at a time by [`Dispatch::step`] so that
+/// its transitions do not depend on the timing of the channels
+pub(crate) struct Dispatch {
+    active_transfers: Transfers,
+    ended_transfers: Transfers,
+    last_heartbeat: time::Instant,
+    /// Next expected block id of every sequence
+    expected_block_ids: Vec<Option<u8>>,
+    protocol_dump: Option<io::LineWriter<fs::File>>,
+    split_blocks: Option<SplitBlocks>,
+    /// Messages received while delivery is paused, handled once resumed
+    held: VecDeque<Option<(u8, protocol::Block)>>,
+}
+
+impl Dispatch {
+    pub(crate) fn new<ClientNew, ClientEnd>(
+        receiver: &receive::Receiver<ClientNew, ClientEnd>,
+    ) -> Result<Self, receive::Error> {
+        Ok(Self {
+            active_transfers: Transfers::new(),
+            ended_transfers: Transfers::new(),
+            last_heartbeat: time::Instant::now(),
+            expected_block_ids: vec![None; usize::from(receiver.config.block_ids.nb_slots())],
+            protocol_dump: open_protocol_dump(receiver)?,
+            split_blocks: SplitBlocks::open(receiver)?,
+            held: VecDeque::new(),
+        })
+    }
+
+    /// Called when no message was received during `hb_interval`
+    pub(crate) fn heartbeat_timeout(&self, hb_interval: time::Duration) {
+        if self.last_heartbeat.elapsed() > hb_interval {
+            log::warn!(
+                "no heartbeat block received for {} second(s)",
+                hb_interval.as_secs()
+            );
+        }
+    }
+
+    /// Handles the held messages if delivery has been resumed
+    pub(crate) fn release<ClientNew, ClientEnd>(
+        &mut self,
+        receiver: &receive::Receiver<ClientNew, ClientEnd>,
+    ) -> Result<(), receive::Error> {
+        if receiver.paused.load(sync::atomic::Ordering::Relaxed) || self.held.is_empty() {
+            return Ok(());
+        }
+        log::debug!("delivering {} held message(s)", self.held.len());
+        while let Some(block) = self.held.pop_front() {
+            self.handle(receiver, block)?;
+        }
+        Ok(())
+    }
+
+    /// Holds or drops a message received while delivery is paused, according to the pause
+    /// policy
+    fn hold<ClientNew, ClientEnd>(
+        &mut self,
+        receiver: &receive::Receiver<ClientNew, ClientEnd>,
+        block: Option<(u8, protocol::Block)>,
+    ) -> Result<(), receive::Error> {
+        if matches!(receiver.config.pause_policy, receive::PausePolicy::Buffer) {
+            // a synchronization loss is held even when the buffer is full so that the transfers
+            // it interrupted are aborted once resumed, consecutive ones being equivalent
+            if block.is_none() && matches!(self.held.back(), Some(None)) {
+                return Ok(());
+            }
+            if block.is_none() || self.held.len() < receiver.config.pause_buffer {
+                self.held.push_back(block);
+                return Ok(());
+            }
+        }
+
+        let Some((block_id, block)) = block else {
+            // nothing is held when dropping, so the transfers can be aborted right away
+            return self.handle(receiver, None);
         };
+        // ordering is checked again from the first block delivered once resumed
+        self.expected_block_ids.fill(None);
+        if let Ok(
+            protocol::BlockType::Start
+            | protocol::BlockType::Data
+            | protocol::BlockType::End
+            | protocol::BlockType::Abort,
+        ) = block.block_type()
+        {
+            log::debug!("delivery paused, dropping block {block_id}");
+            receiver.stats.block_dropped();
+            abort_transfer(receiver, &mut self.active_transfers, block.client_id())
+        } else {
+            // heartbeats, probes and unknown blocks belong to no transfer
+            log::trace!("delivery paused, ignoring block {block_id}");
+            Ok(())
+        }
+    }
 
-        let Some(block) = block else {
-            // Synchonization has been lost
-            // Marking all active transfers as failed
-            for (client_id, client_sendq) in active_transfers {
-                let block = protocol::Block::new(
-                    protocol::BlockType::Abort,
-                    &receiver.raptorq,
-                    client_id,
-                    None,
-                )?;
+    /// Handles a message from the decode workers: a decoded block with its id, or `None` when
+    /// synchronization has been lost
+    ///
+    /// While delivery is paused, the message is held or dropped instead.
+    pub(crate) fn step<ClientNew, ClientEnd>(
+        &mut self,
+        receiver: &receive::Receiver<ClientNew, ClientEnd>,
+        block: Option<(u8, protocol::Block)>,
+    ) -> Result<(), receive::Error> {
+        // checked first so that a block of an incompatible sender cannot disturb the ordering
+        // checks nor the held messages
+        if let Some((_, block)) = &block
+            && let Err(e) = block.version()
+        {
+            log::error!("block of incompatible sender received ({e}), dropping it");
+            return Ok(());
+        }
 
-                if let Err(e) = client_sendq.send(block) {
-                    log::error!("failed to send payload to client {client_id:x}: {e}");
-                }
+        if receiver.paused.load(sync::atomic::Ordering::Relaxed) {
+            return self.hold(receiver, block);
+        }
+        self.release(receiver)?;
+        self.handle(receiver, block)
+    }
+
+    fn handle<ClientNew, ClientEnd>(
+        &mut self,
+        receiver: &receive::Receiver<ClientNew, ClientEnd>,
+        block: Option<(u8, protocol::Block)>,
+    ) -> Result<(), receive::Error> {
+        let Some((block_id, block)) = block else {
+            // Synchonization has been lost
+            if receiver.config.keep_transfers_on_loss {
+                // the client workers count the chunks missing from their transfer
+                log::warn!("block lost, keeping active transfers going");
+            } else {
+                // Marking all active transfers as failed
+                abort_all(receiver, &mut self.active_transfers)?;
             }
-            active_transfers = HashMap::new();
-            continue;
+            self.expected_block_ids.fill(None);
+            return Ok(());
         };
 
+        receiver.stats.core_block();
+
+        let block_ids = receiver.config.block_ids;
+        let expected_block_id = &mut self.expected_block_ids[usize::from(block_ids.slot(block_id))];
+        if let Some(expected) = *expected_block_id
+            && block_id != expected
+        {
+            if receiver.config.strict_ordering {
+                log::error!(
+                    "block gap: expected block {expected}, received {block_id}, aborting active transfers"
+                );
+                abort_all(receiver, &mut self.active_transfers)?;
+            } else {
+                log::warn!("block gap: expected block {expected}, received {block_id}");
+            }
+        }
+        *expected_block_id = Some(block_ids.add(block_id, 1));
+
         log::trace!("received {block}");
 
+        if let Some(dump) = self.protocol_dump.as_mut() {
+            dump_block(dump, block_id, &block);
+        }
+
+        if let Some(split_blocks) = self.split_blocks.as_mut() {
+            split_blocks.write(block_id, &block);
+        }
+
         let block_type = match block.block_type() {
             Err(e) => {
                 log::error!("block of UNKNOWN type received ({e}), dropping it");
-                continue;
+                return Ok(());
             }
             Ok(mt) => mt,
         };
@@ -71,38 +334,55 @@ pub(crate) fn start<ClientNew, ClientEnd>(
         match block_type {
             protocol::BlockType::Heartbeat => {
                 log::debug!("heartbeat received");
-                last_heartbeat = time::Instant::now();
-                continue;
+                self.last_heartbeat = time::Instant::now();
+                return Ok(());
+            }
+            protocol::BlockType::Probe => {
+                let intact = block.is_intact_probe();
+                if intact {
+                    log::debug!("probe {} received", block.chunk_index());
+                } else {
+                    log::error!("probe {} received corrupted", block.chunk_index());
+                }
+                receiver.stats.probe_received(intact);
+                return Ok(());
+            }
+            protocol::BlockType::Skippable(id) => {
+                log::debug!("block of unsupported skippable type {id:#04x} received, ignoring it");
+                return Ok(());
             }
             protocol::BlockType::Start => {
-                let (client_sendq, client_recvq) =
-                    crossbeam_channel::unbounded::<protocol::Block>();
-                active_transfers.insert(client_id, client_sendq);
-                receiver.to_clients.send((client_id, client_recvq))?;
+                start_transfer(
+                    receiver,
+                    &mut self.active_transfers,
+                    &mut self.ended_transfers,
+                    client_id,
+                )?;
             }
             protocol::BlockType::Abort | protocol::BlockType::End => will_end = true,
             protocol::BlockType::Data => (),
         }
 
-        let Some(client_sendq) = active_transfers.get(&client_id) else {
+        let Some(client_sendq) = self.active_transfers.get(&client_id) else {
             log::debug!("receive data for inactive transfer {client_id:x}");
-            continue;
+            return Ok(());
         };
 
         if let Err(e) = client_sendq.send(block) {
             log::error!("failed to send block to client {client_id:x}: {e}");
-            active_transfers.remove(&client_id);
-            continue;
+            self.active_transfers.remove(&client_id);
+            return Ok(());
         }
 
         if will_end {
-            let client_sendq = active_transfers
-                .remove(&client_id)
-                .ok_or(receive::Error::Other(format!(
-                    "transfer {client_id} is not active"
-                )))?;
+            let client_sendq =
+                self.active_transfers
+                    .remove(&client_id)
+                    .ok_or(receive::Error::Other(format!(
+                        "transfer {client_id} is not active"
+                    )))?;
 
-            ended_transfers.retain(|client_id, client_sendq| {
+            self.ended_transfers.retain(|client_id, client_sendq| {
                 let retain = !client_sendq.is_empty();
                 if !retain {
                     log::debug!("purging ended transfer of client {client_id:x}");
@@ -110,9 +390,483 @@ pub(crate) fn start<ClientNew, ClientEnd>(
                 retain
             });
 
-            ended_transfers.insert(client_id, client_sendq);
+            self.ended_transfers.insert(client_id, client_sendq);
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn start<ClientNew, ClientEnd>(
+    receiver: &receive::Receiver<ClientNew, ClientEnd>,
+) -> Result<(), receive::Error> {
+    let mut dispatch = Dispatch::new(receiver)?;
+    let mut idle_since = time::Instant::now();
+
+    loop {
+        if receiver.is_shutting_down() {
+            log::debug!("aborting active transfers on shutdown");
+            return abort_all(receiver, &mut dispatch.active_transfers);
         }
 
+        let hb_interval = receiver.config.heartbeat_interval;
+        // held blocks must be delivered once resumed, and shutdown noticed, even if no other
+        // block is received
+        let timeout = hb_interval.map_or(receive::SHUTDOWN_POLL_INTERVAL, |hb| {
+            hb.min(receive::SHUTDOWN_POLL_INTERVAL)
+        });
+
+        let block = match receiver.for_dispatch.recv_timeout(timeout) {
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                dispatch.release(receiver)?;
+                if let Some(hb_interval) = hb_interval
+                    && hb_interval <= idle_since.elapsed()
+                {
+                    dispatch.heartbeat_timeout(hb_interval);
+                    idle_since = time::Instant::now();
+                }
+                continue;
+            }
+            other => other?,
+        };
+
+        dispatch.step(receiver, block)?;
+        idle_since = time::Instant::now();
+
         thread::yield_now();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_ID: protocol::ClientId = 0x1234;
+
+    type ClientNew = fn(protocol::ClientId) -> Result<Vec<u8>, io::Error>;
+    type ClientEnd = fn(Vec<u8>, bool, Option<protocol::TransferSummary>);
+    type Receiver = receive::Receiver<ClientNew, ClientEnd>;
+
+    /// Receiver configured with the mandatory keys followed by `extra_config`
+    fn receiver(extra_config: &str) -> Receiver {
+        let config = toml::from_str(&format!(
+            r#"
+            from = "127.0.0.1:5000"
+            from_mtu = 1500
+            reset_timeout = 2
+            nb_decode_threads = 1
+            max_clients = 2
+            nb_client_threads = 2
+            {extra_config}
+            "#
+        ))
+        .expect("valid configuration");
+        let raptorq = protocol::RaptorQ::new(1500, 8 * 1464, 10).expect("valid parameters");
+        let client_new: ClientNew = |_| Ok(Vec::new());
+        let client_end: ClientEnd = |_, _, _| ();
+        receive::Receiver::new(config, raptorq, client_new, client_end).expect("receiver")
+    }
+
+    fn block(
+        receiver: &Receiver,
+        block_type: protocol::BlockType,
+        data: Option<&[u8]>,
+    ) -> protocol::Block {
+        protocol::Block::new(block_type, &receiver.raptorq, CLIENT_ID, 0, data).expect("block")
+    }
+
+    /// Block queue of the transfer started by the dispatch worker
+    fn transfer_queue(receiver: &Receiver) -> crossbeam_channel::Receiver<protocol::Block> {
+        let (client_id, recvq) = receiver.for_clients.try_recv().expect("transfer started");
+        assert_eq!(client_id, CLIENT_ID);
+        recvq
+    }
+
+    fn next_block_type(
+        recvq: &crossbeam_channel::Receiver<protocol::Block>,
+    ) -> protocol::BlockType {
+        recvq
+            .try_recv()
+            .expect("block delivered")
+            .block_type()
+            .expect("known block type")
+    }
+
+    #[test]
+    fn start_data_end() {
+        let receiver = receiver("");
+        let mut dispatch = Dispatch::new(&receiver).expect("dispatch");
+
+        let start = block(&receiver, protocol::BlockType::Start, None);
+        dispatch.step(&receiver, Some((0, start))).expect("start");
+        let recvq = transfer_queue(&receiver);
+        assert!(dispatch.active_transfers.contains_key(&CLIENT_ID));
+
+        let data = block(&receiver, protocol::BlockType::Data, Some(b"data"));
+        dispatch.step(&receiver, Some((1, data))).expect("data");
+        let end = block(&receiver, protocol::BlockType::End, Some(b"end"));
+        dispatch.step(&receiver, Some((2, end))).expect("end");
+
+        assert!(matches!(
+            next_block_type(&recvq),
+            protocol::BlockType::Start
+        ));
+        assert!(matches!(next_block_type(&recvq), protocol::BlockType::Data));
+        assert!(matches!(next_block_type(&recvq), protocol::BlockType::End));
+        assert!(recvq.is_empty());
+        assert!(dispatch.active_transfers.is_empty());
+        assert!(dispatch.ended_transfers.contains_key(&CLIENT_ID));
+        assert_eq!(dispatch.expected_block_ids, vec![Some(3)]);
+    }
+
+    #[test]
+    fn abort() {
+        let receiver = receiver("");
+        let mut dispatch = Dispatch::new(&receiver).expect("dispatch");
+
+        let start = block(&receiver, protocol::BlockType::Start, None);
+        dispatch.step(&receiver, Some((0, start))).expect("start");
+        let recvq = transfer_queue(&receiver);
+
+        let abort = block(&receiver, protocol::BlockType::Abort, None);
+        dispatch.step(&receiver, Some((1, abort))).expect("abort");
+
+        assert!(matches!(
+            next_block_type(&recvq),
+            protocol::BlockType::Start
+        ));
+        assert!(matches!(
+            next_block_type(&recvq),
+            protocol::BlockType::Abort
+        ));
+        assert!(dispatch.active_transfers.is_empty());
+
+        // the transfer being no longer active, its late blocks are not delivered
+        let data = block(&receiver, protocol::BlockType::Data, Some(b"late"));
+        dispatch.step(&receiver, Some((2, data))).expect("data");
+        assert!(recvq.is_empty());
+    }
+
+    #[test]
+    fn synchronization_loss() {
+        let receiver = receiver("");
+        let mut dispatch = Dispatch::new(&receiver).expect("dispatch");
+
+        let start = block(&receiver, protocol::BlockType::Start, None);
+        dispatch.step(&receiver, Some((0, start))).expect("start");
+        let recvq = transfer_queue(&receiver);
+
+        dispatch
+            .step(&receiver, None)
+            .expect("synchronization loss");
+
+        assert!(matches!(
+            next_block_type(&recvq),
+            protocol::BlockType::Start
+        ));
+        assert!(matches!(
+            next_block_type(&recvq),
+            protocol::BlockType::Abort
+        ));
+        assert!(dispatch.active_transfers.is_empty());
+        assert_eq!(dispatch.expected_block_ids, vec![None]);
+    }
+
+    /// Delivers a synchronization loss received while paused, with a full buffer
+    fn paused_synchronization_loss(pause_config: &str) {
+        let receiver = receiver(pause_config);
+        let mut dispatch = Dispatch::new(&receiver).expect("dispatch");
+
+        let start = block(&receiver, protocol::BlockType::Start, None);
+        dispatch.step(&receiver, Some((0, start))).expect("start");
+        let recvq = transfer_queue(&receiver);
+        assert!(matches!(
+            next_block_type(&recvq),
+            protocol::BlockType::Start
+        ));
+
+        receiver.pause();
+        dispatch
+            .step(&receiver, None)
+            .expect("synchronization loss");
+        dispatch
+            .step(&receiver, None)
+            .expect("synchronization loss");
+        receiver.resume();
+        dispatch.release(&receiver).expect("release");
+
+        assert!(matches!(
+            next_block_type(&recvq),
+            protocol::BlockType::Abort
+        ));
+        assert!(recvq.is_empty());
+        assert!(dispatch.active_transfers.is_empty());
+        assert!(dispatch.held.is_empty());
+    }
+
+    #[test]
+    fn synchronization_loss_held_in_full_buffer() {
+        paused_synchronization_loss("pause_buffer = 0");
+    }
+
+    #[test]
+    fn synchronization_loss_not_dropped() {
+        paused_synchronization_loss(r#"pause_policy = "drop""#);
+    }
+
+    #[test]
+    fn paused_transfer_resumed() {
+        let receiver = receiver("pause_buffer = 4");
+        let mut dispatch = Dispatch::new(&receiver).expect("dispatch");
+
+        let start = block(&receiver, protocol::BlockType::Start, None);
+        dispatch.step(&receiver, Some((0, start))).expect("start");
+        let recvq = transfer_queue(&receiver);
+        assert!(matches!(
+            next_block_type(&recvq),
+            protocol::BlockType::Start
+        ));
+
+        receiver.pause();
+        let data = block(&receiver, protocol::BlockType::Data, Some(b"held"));
+        dispatch.step(&receiver, Some((1, data))).expect("data");
+        assert!(recvq.is_empty());
+
+        receiver.resume();
+        let end = block(&receiver, protocol::BlockType::End, Some(b"end"));
+        dispatch.step(&receiver, Some((2, end))).expect("end");
+
+        assert!(matches!(next_block_type(&recvq), protocol::BlockType::Data));
+        assert!(matches!(next_block_type(&recvq), protocol::BlockType::End));
+        assert!(dispatch.active_transfers.is_empty());
+        assert_eq!(receiver.stats().blocks_dropped, 0);
+    }
+
+    #[test]
+    fn paused_heartbeat_dropped_without_abort() {
+        let receiver = receiver(r#"pause_policy = "drop""#);
+        let mut dispatch = Dispatch::new(&receiver).expect("dispatch");
+
+        let start = block(&receiver, protocol::BlockType::Start, None);
+        dispatch.step(&receiver, Some((0, start))).expect("start");
+        let recvq = transfer_queue(&receiver);
+        assert!(matches!(
+            next_block_type(&recvq),
+            protocol::BlockType::Start
+        ));
+
+        receiver.pause();
+        let heartbeat = protocol::Block::new(
+            protocol::BlockType::Heartbeat,
+            &receiver.raptorq,
+            0,
+            0,
+            None,
+        )
+        .expect("heartbeat");
+        dispatch
+            .step(&receiver, Some((1, heartbeat)))
+            .expect("heartbeat");
+        assert!(dispatch.active_transfers.contains_key(&CLIENT_ID));
+        assert_eq!(receiver.stats().blocks_dropped, 0);
+
+        let data = block(&receiver, protocol::BlockType::Data, Some(b"dropped"));
+        dispatch.step(&receiver, Some((2, data))).expect("data");
+        assert!(matches!(
+            next_block_type(&recvq),
+            protocol::BlockType::Abort
+        ));
+        assert!(dispatch.active_transfers.is_empty());
+        assert_eq!(receiver.stats().blocks_dropped, 1);
+    }
+
+    #[test]
+    fn skippable_block_ignored() {
+        let receiver = receiver("");
+        let mut dispatch = Dispatch::new(&receiver).expect("dispatch");
+
+        let start = block(&receiver, protocol::BlockType::Start, None);
+        dispatch.step(&receiver, Some((0, start))).expect("start");
+        let recvq = transfer_queue(&receiver);
+        assert!(matches!(
+            next_block_type(&recvq),
+            protocol::BlockType::Start
+        ));
+
+        let skippable = block(
+            &receiver,
+            protocol::BlockType::Skippable(protocol::ID_SKIPPABLE_FIRST),
+            Some(b"extension"),
+        );
+        dispatch
+            .step(&receiver, Some((1, skippable)))
+            .expect("skippable");
+        assert!(recvq.is_empty());
+        assert!(dispatch.active_transfers.contains_key(&CLIENT_ID));
+
+        let end = block(&receiver, protocol::BlockType::End, None);
+        dispatch.step(&receiver, Some((2, end))).expect("end");
+        assert!(matches!(next_block_type(&recvq), protocol::BlockType::End));
+    }
+
+    /// Delivers a transfer whose block 2 was lost, returning its blocks as received by the
+    /// client worker
+    fn transfer_with_gap(receiver: &Receiver) -> Vec<protocol::BlockType> {
+        let mut dispatch = Dispatch::new(receiver).expect("dispatch");
+
+        let start = block(receiver, protocol::BlockType::Start, None);
+        dispatch.step(receiver, Some((0, start))).expect("start");
+        let recvq = transfer_queue(receiver);
+        for block_id in [1, 3] {
+            let data = block(receiver, protocol::BlockType::Data, Some(b"data"));
+            dispatch
+                .step(receiver, Some((block_id, data)))
+                .expect("data");
+        }
+
+        assert_eq!(dispatch.expected_block_ids, vec![Some(4)]);
+        recvq
+            .try_iter()
+            .map(|block| block.block_type().expect("known block type"))
+            .collect()
+    }
+
+    #[test]
+    fn block_gap_aborts_transfers() {
+        let receiver = receiver("strict_ordering = true");
+        // the block following the gap is not delivered to the aborted transfer
+        assert!(matches!(
+            transfer_with_gap(&receiver)[..],
+            [
+                protocol::BlockType::Start,
+                protocol::BlockType::Data,
+                protocol::BlockType::Abort,
+            ]
+        ));
+    }
+
+    #[test]
+    fn block_gap_tolerated() {
+        let receiver = receiver("");
+        assert!(matches!(
+            transfer_with_gap(&receiver)[..],
+            [
+                protocol::BlockType::Start,
+                protocol::BlockType::Data,
+                protocol::BlockType::Data,
+            ]
+        ));
+    }
+
+    /// Starts transfers of client ids 1 to 5 while no client worker dequeues them, returning
+    /// the client ids of the queued transfers
+    fn flood_transfers(receiver: &Receiver) -> Vec<protocol::ClientId> {
+        let mut dispatch = Dispatch::new(receiver).expect("dispatch");
+
+        for client_id in 1..=5 {
+            let start = protocol::Block::new(
+                protocol::BlockType::Start,
+                &receiver.raptorq,
+                client_id,
+                0,
+                None,
+            )
+            .expect("block");
+            let block_id = u8::try_from(client_id).expect("block id");
+            dispatch
+                .step(receiver, Some((block_id, start)))
+                .expect("start");
+            assert!(receiver.for_clients.len() <= 2);
+        }
+
+        let queued = receiver
+            .for_clients
+            .try_iter()
+            .map(|(client_id, _)| client_id)
+            .collect::<Vec<_>>();
+        let mut active = dispatch
+            .active_transfers
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        active.sort_unstable();
+        assert_eq!(active, queued);
+        assert_eq!(receiver.stats().transfers_dropped, 3);
+        queued
+    }
+
+    #[test]
+    fn queued_transfers_newest_rejected() {
+        let receiver = receiver("max_queued_transfers = 2");
+        assert_eq!(flood_transfers(&receiver), vec![1, 2]);
+    }
+
+    #[test]
+    fn queued_transfers_oldest_dropped() {
+        let receiver = receiver(
+            r#"
+            max_queued_transfers = 2
+            queued_transfers_policy = "drop-oldest"
+            "#,
+        );
+        assert_eq!(flood_transfers(&receiver), vec![4, 5]);
+    }
+
+    #[test]
+    fn protocol_dump() {
+        let path = std::env::temp_dir().join(format!("lidi-dump-{}", std::process::id()));
+        let receiver = receiver(&format!("protocol_dump = '{}'", path.display()));
+        let mut dispatch = Dispatch::new(&receiver).expect("dispatch");
+
+        let payload = (0..20).collect::<Vec<u8>>();
+        let start = block(&receiver, protocol::BlockType::Start, None);
+        dispatch.step(&receiver, Some((0, start))).expect("start");
+        let data = block(&receiver, protocol::BlockType::Data, Some(&payload));
+        dispatch.step(&receiver, Some((1, data))).expect("data");
+        drop(dispatch);
+
+        let dump = fs::read_to_string(&path).expect("protocol dump");
+        fs::remove_file(&path).expect("remove protocol dump");
+        assert_eq!(
+            dump.lines().collect::<Vec<_>>(),
+            [
+                "block 0 client 1234 block = Start chunk = 0 data = 0 byte(s) head = ",
+                "block 1 client 1234 block = Data chunk = 0 data = 20 byte(s) head = 000102030405060708090a0b0c0d0e0f",
+            ]
+        );
+    }
+
+    #[test]
+    fn split_blocks() {
+        let dir = std::env::temp_dir().join(format!("lidi-split-{}", std::process::id()));
+        let receiver = receiver(&format!("split_blocks = '{}'", dir.display()));
+        let mut dispatch = Dispatch::new(&receiver).expect("dispatch");
+
+        let payloads: [&[u8]; 3] = [b"", b"first", b"second"];
+        let blocks = [
+            protocol::BlockType::Start,
+            protocol::BlockType::Data,
+            protocol::BlockType::End,
+        ];
+        for (block_id, (block_type, payload)) in (0..).zip(blocks.into_iter().zip(payloads)) {
+            let block = block(&receiver, block_type, Some(payload));
+            dispatch
+                .step(&receiver, Some((block_id, block)))
+                .expect("block");
+        }
+
+        let mut files = fs::read_dir(&dir)
+            .expect("split blocks directory")
+            .map(|entry| entry.expect("entry").file_name())
+            .collect::<Vec<_>>();
+        files.sort_unstable();
+        assert_eq!(
+            files,
+            ["block-00000000", "block-00000001", "block-00000002"]
+        );
+        for (file, payload) in files.iter().zip(payloads) {
+            assert_eq!(fs::read(dir.join(file)).expect("block file"), payload);
+        }
+        fs::remove_dir_all(&dir).expect("remove split blocks directory");
+    }
+}
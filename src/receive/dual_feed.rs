@@ -0,0 +1,313 @@
+//! Worker delivering transfers sent twice only once both copies are found identical
+//!
+//! The sender numbers transfers in the order clients connect, so copies are paired by client
+//! id: transfer `2n` and transfer `2n + 1` are the two copies of the same data. Both copies are
+//! buffered in memory; once the second one is complete, they are compared, and the content is
+//! delivered under the client id of the first copy only if they are identical. A pair one of
+//! whose copies is aborted, lost chunks or failed is dropped.
+//!
+//! Pairing relies on the sender numbering copies with consecutive client ids: once its counter
+//! wraps around, the sender skips ids still in use, and from then on every pair is misaligned.
+
+use crate::{protocol, receive};
+use std::{collections::HashMap, io, sync, thread, time};
+
+/// Copies waiting for their counterpart, by pair, `None` for a failed copy
+#[derive(Default)]
+pub(crate) struct Copies(sync::Mutex<HashMap<protocol::ClientId, Option<Vec<u8>>>>);
+
+/// Receives a whole transfer in memory, returning its content, or `None` if it was aborted or
+/// chunks were lost, along with its summary
+fn buffer<C, ClientNew, ClientEnd, E>(
+    receiver: &receive::Receiver<ClientNew, ClientEnd>,
+    client_id: protocol::ClientId,
+    recvq: &crossbeam_channel::Receiver<protocol::Block>,
+) -> Result<(Option<Vec<u8>>, Option<protocol::TransferSummary>), receive::Error>
+where
+    C: receive::Sink,
+    ClientNew: Send + Sync + Fn(protocol::ClientId) -> Result<C, E>,
+    ClientEnd: Send + Sync + Fn(C, bool, Option<protocol::TransferSummary>),
+    E: Into<receive::Error>,
+{
+    let started_at = time::Instant::now();
+    let mut data = Vec::new();
+    let mut expected_chunk_index: u32 = 0;
+    let mut lost_chunks: u64 = 0;
+
+    loop {
+        let block = receive::client::recv_block(&receiver.config, recvq, started_at)?;
+
+        receiver.stats.core_block();
+
+        let block_type = block.block_type()?;
+        let block_payload = block.verified_payload()?;
+
+        if !matches!(block_type, protocol::BlockType::Abort) {
+            let chunk_index = block.chunk_index();
+            if chunk_index != expected_chunk_index {
+                let lost = chunk_index.wrapping_sub(expected_chunk_index);
+                log::warn!("client {client_id:x}: {lost} chunk(s) lost before chunk {chunk_index}");
+                lost_chunks += u64::from(lost);
+            }
+            expected_chunk_index = chunk_index.wrapping_add(1);
+        }
+
+        match block_type {
+            protocol::BlockType::Abort => {
+                log::warn!("client {client_id:x}: dual-feed copy aborted");
+                return Ok((None, None));
+            }
+            protocol::BlockType::End => {
+                let summary = if receiver.config.transfer_summary {
+                    Some(protocol::TransferSummary::deserialize(block_payload)?)
+                } else {
+                    receive::client::check_size(&receiver.config, data.len(), block_payload.len())?;
+                    data.extend_from_slice(block_payload);
+                    None
+                };
+                if 0 < lost_chunks {
+                    log::warn!(
+                        "client {client_id:x}: dual-feed copy incomplete, {lost_chunks} chunk(s) lost"
+                    );
+                    return Ok((None, summary));
+                }
+                log::info!(
+                    "client {client_id:x}: dual-feed copy of {} bytes received",
+                    data.len()
+                );
+                return Ok((Some(data), summary));
+            }
+            protocol::BlockType::Start if receiver.config.transfer_uuid => {
+                let uuid = protocol::TransferUuid::deserialize(block_payload)?;
+                log::info!("client {client_id:x}: dual-feed copy of transfer {uuid}");
+            }
+            protocol::BlockType::Data => {
+                receive::client::check_size(&receiver.config, data.len(), block_payload.len())?;
+                if receiver.config.records {
+                    let record_len = u32::try_from(block_payload.len())
+                        .map_err(|e| receive::Error::Other(format!("record length: {e}")))?;
+                    data.extend_from_slice(&record_len.to_be_bytes());
+                }
+                data.extend_from_slice(block_payload);
+            }
+            _ => (),
+        }
+
+        thread::yield_now();
+    }
+}
+
+/// Writes a verified transfer to a new client
+fn deliver<C, ClientNew, ClientEnd, E>(
+    receiver: &receive::Receiver<ClientNew, ClientEnd>,
+    client_id: protocol::ClientId,
+    data: &[u8],
+    summary: Option<protocol::TransferSummary>,
+) -> Result<(), receive::Error>
+where
+    C: receive::Sink,
+    ClientNew: Send + Sync + Fn(protocol::ClientId) -> Result<C, E>,
+    ClientEnd: Send + Sync + Fn(C, bool, Option<protocol::TransferSummary>),
+    E: Into<receive::Error>,
+{
+    let mut client = (receiver.client_new)(client_id).map_err(Into::into)?;
+
+    let mut write_timeout = receiver.config.write_timeout;
+    if write_timeout.is_some()
+        && let Err(e) = client.set_nonblocking(true)
+    {
+        log::warn!("client {client_id:x}: cannot use non-blocking output, no write timeout: {e}");
+        write_timeout = None;
+    }
+
+    let mut client =
+        io::BufWriter::with_capacity(protocol::Block::max_data_len(&receiver.raptorq), client);
+
+    let written = receive::client::write_all(&mut client, data, write_timeout);
+    let (client, finished) = receive::client::finish(client, write_timeout, written.is_ok());
+    (receiver.client_end)(client, written.is_ok() && finished.is_ok(), summary);
+    written?;
+    finished?;
+    receiver.stats.bytes_delivered(data.len());
+
+    log::info!(
+        "client {client_id:x}: dual-feed transfer verified, {} bytes transmitted",
+        data.len()
+    );
+
+    Ok(())
+}
+
+pub(crate) fn start<C, ClientNew, ClientEnd, E>(
+    receiver: &receive::Receiver<ClientNew, ClientEnd>,
+    client_id: protocol::ClientId,
+    recvq: &crossbeam_channel::Receiver<protocol::Block>,
+) -> Result<(), receive::Error>
+where
+    C: receive::Sink,
+    ClientNew: Send + Sync + Fn(protocol::ClientId) -> Result<C, E>,
+    ClientEnd: Send + Sync + Fn(C, bool, Option<protocol::TransferSummary>),
+    E: Into<receive::Error>,
+{
+    log::info!("client {client_id:x}: starting dual-feed copy");
+
+    let (copy, summary, failure) = match buffer(receiver, client_id, recvq) {
+        Ok((copy, summary)) => (copy, summary, None),
+        // recorded as a failed copy so that its counterpart is not kept waiting for it
+        Err(e) => (None, None, Some(e)),
+    };
+
+    let first_id = client_id & !1;
+    let other_id = client_id ^ 1;
+
+    let other = {
+        let mut copies = receiver
+            .dual_feed
+            .0
+            .lock()
+            .map_err(|e| receive::Error::Other(format!("dual-feed copies: {e}")))?;
+        let Some(other) = copies.remove(&(client_id / 2)) else {
+            copies.insert(client_id / 2, copy);
+            log::debug!("client {client_id:x}: waiting for dual-feed copy {other_id:x}");
+            return failure.map_or(Ok(()), Err);
+        };
+        other
+    };
+
+    let res = match (copy, other) {
+        (Some(copy), Some(other)) if copy == other => {
+            deliver(receiver, first_id, &copy, summary).map(|()| Some(copy.len()))
+        }
+        (Some(copy), Some(other)) => {
+            let offset = copy
+                .iter()
+                .zip(&other)
+                .position(|(a, b)| a != b)
+                .unwrap_or(copy.len().min(other.len()));
+            log::error!(
+                "client {first_id:x}: dual-feed copies of {} and {} bytes differ from offset {offset}, transfer dropped",
+                other.len(),
+                copy.len()
+            );
+            receiver.stats.dual_feed_mismatch();
+            Ok(None)
+        }
+        _ => {
+            log::error!(
+                "client {first_id:x}: dual-feed copy {other_id:x} or {client_id:x} failed, transfer dropped"
+            );
+            Ok(None)
+        }
+    };
+
+    // the pair is reported once both copies are received, as a single transfer
+    let delivered = res.as_ref().ok().copied().flatten();
+    receive::client::report_completion(
+        &receiver.config,
+        first_id,
+        delivered.unwrap_or_default(),
+        delivered.is_some(),
+    );
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    res.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Content and outcome of the clients handed over to `client_end`
+    type Ended = Vec<(Vec<u8>, bool)>;
+
+    fn raptorq() -> protocol::RaptorQ {
+        protocol::RaptorQ::new(1500, 8 * 1464, 10).expect("valid parameters")
+    }
+
+    /// Blocks of a copy of `data`, sent as transfer `client_id`
+    fn copy(client_id: protocol::ClientId, data: &[u8]) -> Vec<protocol::Block> {
+        let raptorq = raptorq();
+        vec![
+            protocol::Block::new(protocol::BlockType::Start, &raptorq, client_id, 0, None)
+                .expect("start"),
+            protocol::Block::new(protocol::BlockType::End, &raptorq, client_id, 1, Some(data))
+                .expect("end"),
+        ]
+    }
+
+    /// Receives both copies of a pair in dual-feed mode, returning the result of each copy, how
+    /// clients were ended and the number of mismatches
+    fn run_pair(
+        first: Vec<protocol::Block>,
+        second: Vec<protocol::Block>,
+    ) -> ([Result<(), receive::Error>; 2], Ended, u64) {
+        let config = toml::from_str(
+            r#"
+            from = "127.0.0.1:5000"
+            from_mtu = 1500
+            reset_timeout = 2
+            nb_decode_threads = 1
+            max_clients = 2
+            nb_client_threads = 2
+            dual_feed = true
+            "#,
+        )
+        .expect("valid configuration");
+        let ended = sync::Mutex::new(Vec::new());
+        let receiver = receive::Receiver::new(
+            config,
+            raptorq(),
+            |_| Ok::<_, io::Error>(Vec::new()),
+            |client, completed, _| ended.lock().expect("lock").push((client, completed)),
+        )
+        .expect("receiver");
+
+        let res = [(2, first), (3, second)].map(|(client_id, blocks)| {
+            let (sendq, recvq) = crossbeam_channel::unbounded();
+            for block in blocks {
+                sendq.send(block).expect("send");
+            }
+            start(&receiver, client_id, &recvq)
+        });
+        assert!(receiver.dual_feed.0.lock().expect("lock").is_empty());
+
+        let mismatches = receiver.stats().dual_feed_mismatches;
+        drop(receiver);
+        (res, ended.into_inner().expect("lock"), mismatches)
+    }
+
+    #[test]
+    fn identical_copies_delivered() {
+        let (res, ended, mismatches) = run_pair(copy(2, b"data"), copy(3, b"data"));
+        assert!(res.iter().all(Result::is_ok));
+        assert_eq!(ended, vec![(b"data".to_vec(), true)]);
+        assert_eq!(mismatches, 0);
+    }
+
+    #[test]
+    fn different_copies_dropped() {
+        let (res, ended, mismatches) = run_pair(copy(2, b"data"), copy(3, b"dada"));
+        assert!(res.iter().all(Result::is_ok));
+        assert!(ended.is_empty());
+        assert_eq!(mismatches, 1);
+    }
+
+    #[test]
+    fn failed_copy_drops_pair() {
+        let mut first = copy(2, b"data");
+        let mut content = first[1].serialized().to_vec();
+        let last_payload_byte = content.len() - first[1].padding_len() - 1;
+        content[last_payload_byte] ^= 1;
+        first[1] = protocol::Block::deserialize(content);
+
+        let (res, ended, mismatches) = run_pair(first, copy(3, b"data"));
+        assert!(matches!(
+            res[0],
+            Err(receive::Error::Protocol(protocol::Error::Corrupt))
+        ));
+        assert!(res[1].is_ok());
+        assert!(ended.is_empty());
+        assert_eq!(mismatches, 0);
+    }
+}
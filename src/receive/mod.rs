@@ -13,38 +13,256 @@
 //! Notes:
 //! - heartbeat does not need a dedicated worker on the receiver side, heartbeat blocks are
 //!   handled by the dispatch worker,
-//! - there are `max_clients` clients workers running in parallel,
+//! - there are `nb_client_threads` clients workers running in parallel, at most `max_clients`
+//!   of them transferring data at the same time,
 //! - there are `nb_decode_threads` decode workers running in parallel.
 
 use crate::protocol;
-use std::{
-    fmt,
-    io::{self, Write},
-    iter, net,
-    os::fd::AsRawFd,
-    thread, time,
-};
+use std::{error, fmt, io, iter, net, path, str::FromStr, sync, thread, time};
 
+mod alive;
 mod client;
 mod clients;
 mod decode;
 mod dispatch;
+mod dual_feed;
 mod reblock;
+mod replay;
+mod s3;
+mod sink;
+mod stats;
 mod udp;
 
+pub use s3::{S3, S3Upload};
+pub use sink::{Broadcast, Sink};
+pub use stats::{CoreStats, DropReason, DroppedPackets, StatsSnapshot};
+use stats::{DecodeRate, Stats};
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub from: net::SocketAddr,
     pub from_mtu: u16,
+    /// Let several receivers bind to the same `from` address (`SO_REUSEPORT`), the kernel
+    /// spreading the datagrams of distinct senders between them
+    #[serde(default)]
+    pub reuse_port: bool,
+    /// Whether an IPv6 `from` socket only receives IPv6 traffic (`IPV6_V6ONLY`), or also IPv4
+    /// traffic through IPv4-mapped addresses when bound to `::`, the system default being used
+    /// when `None`
+    pub ipv6_only: Option<bool>,
+    /// Size requested for the UDP socket receive buffer, instead of one holding the reassembly
+    /// windows of all the block id sequences
+    pub recv_buffer_size: Option<i32>,
     pub batch_receive: Option<u32>,
+    #[serde(default)]
+    pub batch_wait_full: bool,
+    /// Number of receive buffers kept submitted to an `io_uring` ring, taking precedence over
+    /// `batch_receive`; requires the `io_uring` feature, `recvmmsg` being used otherwise or
+    /// when `io_uring` is not available
+    pub io_uring: Option<u32>,
+    /// Let the kernel aggregate received datagrams into large buffers, split back by the udp
+    /// worker (UDP Generic Receive Offload); not supported with `io_uring`
+    #[serde(default)]
+    pub gro: bool,
+    /// Duration without received packets after which reassembly is reset (seconds in
+    /// configuration files)
+    #[serde(deserialize_with = "crate::config::seconds")]
     pub reset_timeout: time::Duration,
     pub nb_decode_threads: u8,
     pub max_clients: protocol::ClientId,
+    pub nb_client_threads: u32,
+    #[serde(default)]
     pub flush: bool,
+    /// Number of bytes of payloads accumulated before they are written to a client (default:
+    /// the block capacity); the buffer is also flushed at the end of every transfer
+    pub client_buffer_size: Option<usize>,
+    /// Maximum duration a write or flush to a client may stay blocked, the transfer being
+    /// aborted when it expires; the client output is then used in non-blocking mode (seconds in
+    /// configuration files)
+    #[serde(default, deserialize_with = "crate::config::option_seconds")]
+    pub write_timeout: Option<time::Duration>,
+    /// Duration without data after which a transfer is aborted (seconds in configuration files)
+    #[serde(default, deserialize_with = "crate::config::option_seconds")]
     pub abort_timeout: Option<time::Duration>,
+    /// Maximum duration of a transfer from its `Start` block, the transfer being aborted when it
+    /// expires (seconds in configuration files)
+    #[serde(default, deserialize_with = "crate::config::option_seconds")]
+    pub max_transfer_duration: Option<time::Duration>,
+    /// Maximum number of bytes written to the client of a transfer, the transfer being aborted
+    /// when it is exceeded
+    pub max_transfer_bytes: Option<u64>,
+    /// Maximum duration expected between two heartbeat blocks (seconds in configuration files)
+    #[serde(default, deserialize_with = "crate::config::option_seconds")]
     pub heartbeat_interval: Option<time::Duration>,
+    #[serde(default)]
     pub cpu_affinity: bool,
+    /// Pre-fault and lock in memory the buffers UDP packets are received into, so that no page
+    /// fault happens once receiving
+    #[serde(default)]
+    pub lock_buffers: bool,
+    /// Capacity of the bounded (array-based) channels used between the udp, reblock and decode
+    /// workers instead of unbounded (list-based) ones, providing backpressure to the udp worker
+    pub channel_capacity: Option<usize>,
+    /// Abort all active transfers when decoded block ids are not consecutive, meaning that a
+    /// block was lost or that blocks were reordered (by parallel decode workers)
+    #[serde(default)]
+    pub strict_ordering: bool,
+    /// Keep active transfers going when a block is lost (failed to decode or synchronization
+    /// lost) instead of aborting them, their lost chunks being counted
+    #[serde(default)]
+    pub keep_transfers_on_loss: bool,
+    /// Maximum number of transfers waiting for a client worker, `queued_transfers_policy` being
+    /// applied to new transfers beyond it
+    pub max_queued_transfers: Option<usize>,
+    #[serde(default)]
+    pub queued_transfers_policy: QueuePolicy,
+    /// File to which a record (block id, type, client id, chunk index, length and first
+    /// payload bytes) is written for every decoded block, to analyze the block sequence
+    /// produced by a sender
+    pub protocol_dump: Option<path::PathBuf>,
+    /// Directory to which the payload of every decoded block is written, each to its own
+    /// sequentially numbered file, for forensic capture
+    pub split_blocks: Option<path::PathBuf>,
+    /// File to which every datagram received on the UDP socket is written, to be replayed later
+    /// with `udp_replay`
+    pub udp_record: Option<path::PathBuf>,
+    /// File of datagrams recorded with `udp_record`, replayed through the receiver instead of
+    /// receiving from the UDP socket; the receiver shuts down once they are all decoded and
+    /// delivered
+    pub udp_replay: Option<path::PathBuf>,
+    /// Log the time at which every chunk of data is received, with its offset in the transfer,
+    /// to be correlated with timestamps embedded in the stream by the sending application
+    #[serde(default)]
+    pub log_timestamps: bool,
+    /// Minimum percentage of blocks successfully decoded over the last `decode_rate_window`
+    /// blocks, below which the link is considered too lossy for the repair budget
+    pub min_decode_rate: Option<f64>,
+    #[serde(default)]
+    pub decode_rate_window: usize,
+    /// Shut the receiver down when the decode success rate drops below `min_decode_rate` instead
+    /// of only logging an error
+    #[serde(default)]
+    pub exit_on_low_decode_rate: bool,
+    /// User and group to switch to once the UDP socket and receive buffers are set up, before
+    /// receiving data
+    pub drop_privileges: Option<crate::Credentials>,
+    /// Assignment of block ids to the blocks of the transfers, must be the same as the sender;
+    /// with [`protocol::BlockIdScheme::PerClient`], blocks are reassembled and their ordering
+    /// checked per transfer
+    #[serde(default)]
+    pub block_ids: protocol::BlockIdScheme,
+    /// Expect a [`protocol::TransferSummary`] in the `End` block of every transfer, which is
+    /// logged and handed to the `client_end` closure; must be the same as the sender
+    #[serde(default)]
+    pub transfer_summary: bool,
+    /// Expect a [`protocol::TransferUuid`] in the `Start` block of every transfer, which is
+    /// logged; must be the same as the sender
+    #[serde(default)]
+    pub transfer_uuid: bool,
+    /// Record mode: every block received carries a single record, written to the client
+    /// prefixed by its length as a big-endian `u32` then flushed; must be the same as the
+    /// sender
+    #[serde(default)]
+    pub records: bool,
+    /// Dual-feed mode: every transfer is sent twice, transfers `2n` and `2n + 1` being copies
+    /// of the same data, delivered under client id `2n` only once both are identical
+    #[serde(default)]
+    pub dual_feed: bool,
+    /// Log a line with the throughput delivered to clients and the number of active transfers
+    /// every second
+    #[serde(default)]
+    pub log_alive: bool,
+    /// Log every drop of received packets with its reason (at debug level), drops being
+    /// counted per reason in the statistics anyway
+    #[serde(default)]
+    pub log_drops: bool,
+    /// What to do with decoded blocks while delivery to clients is paused with
+    /// [`Receiver::pause`]
+    #[serde(default)]
+    pub pause_policy: PausePolicy,
+    /// Maximum number of decoded blocks held while delivery is paused with
+    /// [`PausePolicy::Buffer`], further blocks being dropped
+    #[serde(default)]
+    pub pause_buffer: usize,
+    /// Called once whenever a transfer ends, with its client id, the number of bytes written
+    /// to the client and whether it completed, e.g. to keep a machine-readable audit trail; in
+    /// dual-feed mode, called once per pair of copies
+    #[serde(skip)]
+    pub on_transfer_complete: Option<TransferCompleteFn>,
+}
+
+/// Callback receiving the client id, number of bytes written to the client and completion
+/// status of every ended transfer
+pub type TransferCompleteFn = Box<dyn Fn(protocol::ClientId, usize, bool) + Send + Sync>;
+
+/// What to do with decoded blocks while delivery to clients is paused
+#[derive(Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PausePolicy {
+    /// Hold up to `pause_buffer` blocks, delivered once resumed
+    #[default]
+    Buffer,
+    /// Drop blocks, aborting the transfers they belong to
+    Drop,
+}
+
+impl FromStr for PausePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "buffer" => Ok(Self::Buffer),
+            "drop" => Ok(Self::Drop),
+            _ => Err(format!("unknown pause policy \"{s}\"")),
+        }
+    }
+}
+
+impl fmt::Display for PausePolicy {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Buffer => write!(fmt, "buffer"),
+            Self::Drop => write!(fmt, "drop"),
+        }
+    }
+}
+
+/// What to do with a new transfer when `max_queued_transfers` are already waiting for a client
+/// worker
+#[derive(Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueuePolicy {
+    /// Drop the new transfer
+    #[default]
+    RejectNewest,
+    /// Drop the transfer waiting for the longest time, and queue the new one
+    DropOldest,
+}
+
+impl FromStr for QueuePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject-newest" => Ok(Self::RejectNewest),
+            "drop-oldest" => Ok(Self::DropOldest),
+            _ => Err(format!("unknown queue policy \"{s}\"")),
+        }
+    }
+}
+
+impl fmt::Display for QueuePolicy {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::RejectNewest => write!(fmt, "reject-newest"),
+            Self::DropOldest => write!(fmt, "drop-oldest"),
+        }
+    }
 }
 
+#[derive(Debug)]
 pub enum Error {
     Io(io::Error),
     SendPackets,
@@ -54,6 +272,9 @@ pub enum Error {
     Receive(crossbeam_channel::RecvError),
     ReceiveTimeout(crossbeam_channel::RecvTimeoutError),
     Protocol(protocol::Error),
+    TransferTooLong(time::Duration),
+    TransferTooLarge(u64),
+    LowDecodeRate(f64),
     Other(String),
 }
 
@@ -68,11 +289,42 @@ impl fmt::Display for Error {
             Self::Receive(e) => write!(fmt, "crossbeam receive error: {e}"),
             Self::ReceiveTimeout(e) => write!(fmt, "crossbeam receive timeout error: {e}"),
             Self::Protocol(e) => write!(fmt, "diode protocol error: {e}"),
+            Self::TransferTooLong(max) => write!(
+                fmt,
+                "transfer aborted after the maximum duration of {} second(s)",
+                max.as_secs()
+            ),
+            Self::TransferTooLarge(max) => write!(
+                fmt,
+                "transfer aborted after exceeding the maximum size of {max} byte(s)"
+            ),
+            Self::LowDecodeRate(rate) => {
+                write!(fmt, "stopped on a decode success rate of {rate:.1}%")
+            }
             Self::Other(e) => write!(fmt, "{e}"),
         }
     }
 }
 
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Receive(e) => Some(e),
+            Self::ReceiveTimeout(e) => Some(e),
+            Self::Protocol(e) => Some(e),
+            Self::SendPackets
+            | Self::SendBlockPackets
+            | Self::SendBlock
+            | Self::SendClients
+            | Self::TransferTooLong(_)
+            | Self::TransferTooLarge(_)
+            | Self::LowDecodeRate(_)
+            | Self::Other(_) => None,
+        }
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
         Self::Io(e)
@@ -91,8 +343,8 @@ impl From<crossbeam_channel::SendError<Reassembled>> for Error {
     }
 }
 
-impl From<crossbeam_channel::SendError<Option<protocol::Block>>> for Error {
-    fn from(_: crossbeam_channel::SendError<Option<protocol::Block>>) -> Self {
+impl From<crossbeam_channel::SendError<Option<(u8, protocol::Block)>>> for Error {
+    fn from(_: crossbeam_channel::SendError<Option<(u8, protocol::Block)>>) -> Self {
         Self::SendBlock
     }
 }
@@ -133,6 +385,10 @@ impl From<protocol::Error> for Error {
     }
 }
 
+/// Interval at which workers waiting for packets or blocks check whether the receiver is
+/// shutting down
+const SHUTDOWN_POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
 enum Reassembled {
     Error,
     Block {
@@ -146,13 +402,14 @@ enum Reassembled {
 pub struct Receiver<ClientNew, ClientEnd> {
     config: Config,
     raptorq: protocol::RaptorQ,
+    stats: Stats,
     multiplex_control: semka::Sem,
     to_reblock: crossbeam_channel::Sender<crate::udp::Datagrams>,
     for_reblock: crossbeam_channel::Receiver<crate::udp::Datagrams>,
     to_decode: crossbeam_channel::Sender<Reassembled>,
     for_decode: crossbeam_channel::Receiver<Reassembled>,
-    to_dispatch: crossbeam_channel::Sender<Option<protocol::Block>>,
-    for_dispatch: crossbeam_channel::Receiver<Option<protocol::Block>>,
+    to_dispatch: crossbeam_channel::Sender<Option<(u8, protocol::Block)>>,
+    for_dispatch: crossbeam_channel::Receiver<Option<(u8, protocol::Block)>>,
     to_clients: crossbeam_channel::Sender<(
         protocol::ClientId,
         crossbeam_channel::Receiver<protocol::Block>,
@@ -163,13 +420,51 @@ pub struct Receiver<ClientNew, ClientEnd> {
     )>,
     client_new: ClientNew,
     client_end: ClientEnd,
+    /// Set once the receiver is shutting down, every worker returning as soon as it notices it
+    shutdown: sync::atomic::AtomicBool,
+    /// Set while delivery of decoded blocks to clients is paused
+    paused: sync::atomic::AtomicBool,
+    /// First copies of dual-feed transfers, waiting for the second ones
+    dual_feed: dual_feed::Copies,
+}
+
+impl<ClientNew, ClientEnd> Receiver<ClientNew, ClientEnd> {
+    fn is_shutting_down(&self) -> bool {
+        self.shutdown.load(sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Stops the receiver: workers return as soon as they notice it, so that the scope given to
+    /// [`Self::start`] can be joined. Transfers in progress are aborted. Workers failing in the
+    /// meantime, their pipeline being stopped under them, exit quietly instead of reporting a
+    /// fatal error.
+    pub fn shutdown(&self) {
+        log::info!("shutting down");
+        self.shutdown.store(true, sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Receives the next message of `channel`, or `None` once the receiver is shutting down
+    fn recv_or_shutdown<T>(
+        &self,
+        channel: &crossbeam_channel::Receiver<T>,
+    ) -> Result<Option<T>, Error> {
+        loop {
+            if self.is_shutting_down() {
+                return Ok(None);
+            }
+            match channel.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+                Ok(message) => return Ok(Some(message)),
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
 }
 
 impl<C, ClientNew, ClientEnd, E> Receiver<ClientNew, ClientEnd>
 where
-    C: Write + AsRawFd,
+    C: Sink,
     ClientNew: Send + Sync + Fn(protocol::ClientId) -> Result<C, E>,
-    ClientEnd: Send + Sync + Fn(C, bool),
+    ClientEnd: Send + Sync + Fn(C, bool, Option<protocol::TransferSummary>),
     E: Into<Error>,
 {
     /// # Errors
@@ -185,14 +480,38 @@ where
         let multiplex_control = semka::Sem::new(config.max_clients)
             .ok_or(Error::Other("failed to create semaphore".into()))?;
 
-        let (to_reblock, for_reblock) = crossbeam_channel::unbounded();
-        let (to_decode, for_decode) = crossbeam_channel::unbounded();
+        let ((to_reblock, for_reblock), (to_decode, for_decode)) = match config.channel_capacity {
+            None => (
+                crossbeam_channel::unbounded(),
+                crossbeam_channel::unbounded(),
+            ),
+            Some(capacity) => (
+                crossbeam_channel::bounded(capacity),
+                crossbeam_channel::bounded(capacity),
+            ),
+        };
         let (to_dispatch, for_dispatch) = crossbeam_channel::unbounded();
         let (to_clients, for_clients) = crossbeam_channel::unbounded();
 
+        let nb_cores = if config.cpu_affinity {
+            core_affinity::get_core_ids()
+                .and_then(|ids| ids.iter().map(|id| id.id + 1).max())
+                .unwrap_or(0)
+        } else {
+            0
+        };
+
+        let stats = Stats::new(
+            raptorq.nb_repair_packets(),
+            config.min_decode_rate.map(|_| config.decode_rate_window),
+            nb_cores,
+            config.log_drops,
+        );
+
         Ok(Self {
             config,
             raptorq,
+            stats,
             multiplex_control,
             to_reblock,
             for_reblock,
@@ -204,6 +523,9 @@ where
             for_clients,
             client_new,
             client_end,
+            shutdown: sync::atomic::AtomicBool::new(false),
+            paused: sync::atomic::AtomicBool::new(false),
+            dual_feed: dual_feed::Copies::default(),
         })
     }
 
@@ -213,10 +535,19 @@ where
     #[allow(clippy::too_many_lines)]
     pub fn start<'a>(&'a self, scope: &'a thread::Scope<'a, '_>) -> Result<(), Error> {
         log::info!(
-            "max {} simultaneous clients/transfers",
-            self.config.max_clients
+            "max {} simultaneous clients/transfers handled by {} client workers",
+            self.config.max_clients,
+            self.config.nb_client_threads
         );
 
+        if self.config.max_clients < self.config.nb_client_threads {
+            log::warn!(
+                "more client workers ({}) than simultaneous clients/transfers ({}), some will stay idle",
+                self.config.nb_client_threads,
+                self.config.max_clients
+            );
+        }
+
         if let Some(batch) = self.config.batch_receive.as_ref() {
             log::info!("batch receive {batch} packets");
 
@@ -224,6 +555,10 @@ where
             if *batch < nb_packets {
                 log::warn!("batch size ({batch} packets) < {nb_packets}");
             }
+
+            if self.config.batch_wait_full {
+                log::info!("waiting for full batches of {batch} packets");
+            }
         }
 
         log::info!(
@@ -240,6 +575,21 @@ where
             log::info!("no connection abort timeout");
         }
 
+        if let Some(max) = self.config.max_transfer_duration {
+            log::info!("transfers aborted after {} seconds", max.as_secs());
+        }
+
+        if let Some(max) = self.config.max_transfer_bytes {
+            log::info!("transfers aborted beyond {max} bytes");
+        }
+
+        if let Some(write_timeout) = self.config.write_timeout {
+            log::info!(
+                "client write timeout set to {} seconds",
+                write_timeout.as_secs()
+            );
+        }
+
         if let Some(hb_interval) = self.config.heartbeat_interval {
             log::info!(
                 "heartbeat interval is set to {} seconds",
@@ -249,13 +599,37 @@ where
             log::info!("heartbeat is disabled");
         }
 
+        if let Some(capacity) = self.config.channel_capacity {
+            log::info!("udp, reblock and decode workers channels bounded to {capacity} messages");
+        }
+
+        if self.config.strict_ordering {
+            log::info!("strict block ordering enabled, transfers will be aborted on gaps");
+        }
+
+        if self.config.keep_transfers_on_loss {
+            log::info!("transfers kept going with missing data when blocks are lost");
+        }
+
+        if let Some(min_decode_rate) = self.config.min_decode_rate {
+            log::info!(
+                "minimum decode success rate set to {min_decode_rate}% over {} blocks{}",
+                self.config.decode_rate_window,
+                if self.config.exit_on_low_decode_rate {
+                    ", exiting when breached"
+                } else {
+                    ""
+                }
+            );
+        }
+
         let mut cpu_ids = if self.config.cpu_affinity {
             core_affinity::get_core_ids().map(|ids| ids.into_iter().rev())
         } else {
             None
         };
 
-        for i in 0..self.config.max_clients {
+        for i in 0..self.config.nb_client_threads {
             let cpu_id = cpu_ids.as_mut().and_then(iter::Iterator::next);
             thread::Builder::new()
                 .name(format!("client_{i}"))
@@ -263,10 +637,9 @@ where
                     if let Some(cpu_id) = cpu_id {
                         log::debug!("set CPU affinity to {}", cpu_id.id);
                         core_affinity::set_for_current(cpu_id);
+                        stats::set_worker_core(cpu_id.id);
                     }
-                    if let Err(e) = clients::start(self) {
-                        log::error!("fatal client_{i} error: {e}");
-                    }
+                    self.worker_exited(&format!("client_{i}"), clients::start(self));
                 })?;
         }
 
@@ -277,10 +650,9 @@ where
                 if let Some(cpu_id) = cpu_id {
                     log::debug!("set CPU affinity to {}", cpu_id.id);
                     core_affinity::set_for_current(cpu_id);
+                    stats::set_worker_core(cpu_id.id);
                 }
-                if let Err(e) = dispatch::start(self) {
-                    log::error!("fatal dispatch error: {e}");
-                }
+                self.worker_exited("dispatch", dispatch::start(self));
             })?;
 
         let cpu_id = cpu_ids.as_mut().and_then(iter::Iterator::next);
@@ -291,10 +663,9 @@ where
                     if let Some(cpu_id) = cpu_id {
                         log::debug!("set CPU affinity to {}", cpu_id.id);
                         core_affinity::set_for_current(cpu_id);
+                        stats::set_worker_core(cpu_id.id);
                     }
-                    if let Err(e) = decode::start(self) {
-                        log::error!("fatal decode_{i} error: {e}");
-                    }
+                    self.worker_exited(&format!("decode_{i}"), decode::start(self));
                 })?;
         }
 
@@ -305,12 +676,17 @@ where
                 if let Some(cpu_id) = cpu_id {
                     log::debug!("set CPU affinity to {}", cpu_id.id);
                     core_affinity::set_for_current(cpu_id);
+                    stats::set_worker_core(cpu_id.id);
                 }
-                if let Err(e) = reblock::start(self) {
-                    log::error!("fatal reblock error: {e}");
-                }
+                self.worker_exited("reblock", reblock::start(self));
             })?;
 
+        if self.config.log_alive {
+            thread::Builder::new()
+                .name("alive".to_string())
+                .spawn_scoped(scope, move || alive::start(self))?;
+        }
+
         let cpu_id = cpu_ids.as_mut().and_then(iter::Iterator::next);
         thread::Builder::new()
             .name("udp".to_string())
@@ -318,10 +694,9 @@ where
                 if let Some(cpu_id) = cpu_id {
                     log::debug!("set CPU affinity to {}", cpu_id.id);
                     core_affinity::set_for_current(cpu_id);
+                    stats::set_worker_core(cpu_id.id);
                 }
-                if let Err(e) = udp::start(self) {
-                    log::error!("fatal udp error: {e}");
-                }
+                self.worker_exited("udp", udp::start(self));
             })?;
 
         log::info!(
@@ -334,4 +709,86 @@ where
 
         Ok(())
     }
+
+    /// Returns a snapshot of the receiver counters, can be called while workers are running
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Pauses the delivery of decoded blocks to clients, UDP packets being still received and
+    /// decoded; blocks are then held or dropped according to `pause_policy`
+    pub fn pause(&self) {
+        log::info!("delivery to clients paused");
+        self.paused.store(true, sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Resumes the delivery of decoded blocks to clients, held blocks being delivered first
+    pub fn resume(&self) {
+        log::info!("delivery to clients resumed");
+        self.paused.store(false, sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Reports the end of a worker, a failure being fatal only when the receiver is not shutting
+    /// down
+    fn worker_exited(&self, worker: &str, res: Result<(), Error>) {
+        match res {
+            Ok(()) => log::debug!("{worker} worker exited"),
+            Err(e) if self.is_shutting_down() => {
+                log::debug!("{worker} worker exited on shutdown: {e}");
+            }
+            Err(e) => log::error!("fatal {worker} error: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type ClientNew = fn(protocol::ClientId) -> Result<Vec<u8>, io::Error>;
+    type ClientEnd = fn(Vec<u8>, bool, Option<protocol::TransferSummary>);
+
+    fn receiver() -> Receiver<ClientNew, ClientEnd> {
+        let config = toml::from_str(
+            r#"
+            from = "127.0.0.1:0"
+            from_mtu = 1500
+            reset_timeout = 2
+            nb_decode_threads = 2
+            max_clients = 2
+            nb_client_threads = 2
+            "#,
+        )
+        .expect("valid configuration");
+        let raptorq = protocol::RaptorQ::new(1500, 8 * 1464, 10).expect("valid parameters");
+        let client_new: ClientNew = |_| Ok(Vec::new());
+        let client_end: ClientEnd = |_, _, _| ();
+        Receiver::new(config, raptorq, client_new, client_end).expect("receiver")
+    }
+
+    #[test]
+    fn workers_exit_on_shutdown() {
+        let receiver = receiver();
+        receiver.shutdown();
+        assert!(clients::start(&receiver).is_ok());
+        assert!(dispatch::start(&receiver).is_ok());
+        assert!(decode::start(&receiver).is_ok());
+        assert!(reblock::start(&receiver).is_ok());
+    }
+
+    #[test]
+    fn running_receiver_shutdown() {
+        let receiver = receiver();
+        let started_at = time::Instant::now();
+        thread::scope(|scope| {
+            receiver.start(scope).expect("start");
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+            receiver.shutdown();
+        });
+        assert!(started_at.elapsed() < 10 * SHUTDOWN_POLL_INTERVAL);
+    }
 }
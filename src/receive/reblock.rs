@@ -1,10 +1,129 @@
 //! Worker for grouping packets according to their block numbers to handle potential UDP packets
 //! reordering
 
-use crate::{receive, udp};
-use std::{mem, thread};
+use crate::{protocol, receive};
+use std::{collections::HashSet, mem, thread, time};
 
-pub(crate) const WINDOW_WIDTH: u8 = u8::MAX / 2;
+/// Opens the reassembly window of the sequence of `first_id`, starting from it
+fn open_window(scheme: protocol::BlockIdScheme, blocks_ignore: &mut [bool], first_id: u8) {
+    let mut id = first_id;
+    let last = scheme.add(id, scheme.window_width());
+    while id != last {
+        blocks_ignore[usize::from(id)] = false;
+        id = scheme.add(id, 1);
+    }
+}
+
+/// Whether a block of the reassembly window was only partially received and is lost, completed
+/// blocks being ignored
+fn is_window_damaged<T>(blocks_ignore: &[bool], blocks_data: &[Vec<T>]) -> bool {
+    blocks_ignore
+        .iter()
+        .zip(blocks_data)
+        .any(|(ignore, data)| !ignore && !data.is_empty())
+}
+
+/// Reports the loss of the partially received blocks of the reassembly window once no datagram
+/// was received for the reset timeout
+fn check_window_after_timeout<ClientNew, ClientEnd, T>(
+    receiver: &receive::Receiver<ClientNew, ClientEnd>,
+    blocks_ignore: &[bool],
+    blocks_data: &[Vec<T>],
+) -> Result<(), receive::Error> {
+    if is_window_damaged(blocks_ignore, blocks_data) {
+        log::error!("non empty block after timeout");
+        receiver.to_decode.send(super::Reassembled::Error)?;
+    }
+    Ok(())
+}
+
+/// Resets reassembly, the packets of the blocks being reassembled being discarded and accounted
+/// as dropped for `reason`, and forgotten by `duplicates`
+fn reset_reassembly<T>(
+    stats: &receive::stats::Stats,
+    blocks_data: &mut [Vec<T>],
+    blocks_ignore: &mut [bool],
+    cur_ids: &mut [Option<u8>],
+    duplicates: &mut Duplicates,
+    reason: receive::DropReason,
+) {
+    let mut discarded = 0;
+    for block in blocks_data {
+        discarded += block.len();
+        block.clear();
+    }
+    stats.packets_dropped(reason, discarded);
+    blocks_ignore.fill(true);
+    cur_ids.fill(None);
+    duplicates.clear_all();
+}
+
+/// Encoding symbol ids of the packets received for every block, so that a packet received
+/// twice (e.g. from a looping source or a second sender) is not decoded twice
+struct Duplicates {
+    seen: Vec<HashSet<u32>>,
+    logged: bool,
+}
+
+impl Duplicates {
+    fn new(nb_packets: usize) -> Self {
+        Self {
+            seen: vec![HashSet::with_capacity(nb_packets); usize::from(u8::MAX) + 1],
+            logged: false,
+        }
+    }
+
+    /// Whether `packet` was not already received for its block, a duplicate being accounted
+    /// as dropped and reported the first time
+    fn is_new(&mut self, stats: &receive::stats::Stats, packet: &raptorq::EncodingPacket) -> bool {
+        let payload_id = packet.payload_id();
+        let seen = &mut self.seen[usize::from(payload_id.source_block_number())];
+        if seen.insert(payload_id.encoding_symbol_id()) {
+            return true;
+        }
+        if !self.logged {
+            log::warn!("duplicate packet received, is another source sending to the link?");
+            self.logged = true;
+        }
+        stats.packets_dropped(receive::DropReason::Duplicate, 1);
+        false
+    }
+
+    /// Forgets the packets received for block `id`
+    fn clear(&mut self, id: u8) {
+        self.seen[usize::from(id)].clear();
+    }
+
+    fn clear_all(&mut self) {
+        self.seen.iter_mut().for_each(HashSet::clear);
+    }
+}
+
+/// Packet carried by `datagram`, `None` if it is malformed or fails authentication
+fn checked_packet<'a>(
+    raptorq: &protocol::RaptorQ,
+    stats: &receive::stats::Stats,
+    datagram: &'a [u8],
+) -> Option<&'a [u8]> {
+    let datagram_len = raptorq.datagram_len();
+    if datagram.len() != datagram_len {
+        log::warn!(
+            "ignoring malformed datagram of {} bytes instead of {datagram_len}",
+            datagram.len()
+        );
+        stats.packets_dropped(receive::DropReason::Malformed, 1);
+        return None;
+    }
+    let Some(packet_key) = raptorq.packet_key() else {
+        return Some(datagram);
+    };
+    let packet = packet_key.verify(datagram);
+    if packet.is_none() {
+        log::debug!("ignoring datagram failing authentication");
+        stats.packets_dropped(receive::DropReason::Unauthenticated, 1);
+    }
+    packet
+}
 
 pub(crate) fn start<ClientNew, ClientEnd>(
     receiver: &receive::Receiver<ClientNew, ClientEnd>,
@@ -15,111 +134,333 @@ pub(crate) fn start<ClientNew, ClientEnd>(
 
     let mut blocks_data = vec![Vec::with_capacity(nb_packets); usize::from(u8::MAX) + 1];
     let mut blocks_ignore = vec![true; usize::from(u8::MAX) + 1];
+    let mut duplicates = Duplicates::new(nb_packets);
+
+    let scheme = receiver.config.block_ids;
 
-    let mut cur_id: u8 = 0;
+    // next block id to reassemble of every sequence, unknown until a packet of the sequence is
+    // received after a reset
+    let mut cur_ids: Vec<Option<u8>> = vec![None; usize::from(scheme.nb_slots())];
 
-    let mut reset = true;
+    // waiting is split in short timeouts so that shutdown is noticed, the reset timeout being
+    // counted from the last datagram or the last reset
+    let reset_timeout = receiver.config.reset_timeout;
+    let mut idle_since = time::Instant::now();
 
     loop {
+        if receiver.is_shutting_down() {
+            return Ok(());
+        }
+
         let datagrams = match receiver
             .for_reblock
-            .recv_timeout(receiver.config.reset_timeout)
+            .recv_timeout(reset_timeout.min(receive::SHUTDOWN_POLL_INTERVAL))
         {
             Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
-                reset = true;
-
-                let mut damaged = false;
-
-                for id in 0..=u8::MAX as usize {
-                    if blocks_ignore[id] && !blocks_data[id].is_empty() {
-                        damaged = true;
-                    }
-                }
-
-                if damaged {
-                    log::error!("non empty block after timeout");
-                    receiver.to_decode.send(super::Reassembled::Error)?;
+                if idle_since.elapsed() < reset_timeout {
+                    continue;
                 }
+                idle_since = time::Instant::now();
 
+                // the window is reset right away so that a damaged one is reported only once
+                check_window_after_timeout(receiver, &blocks_ignore, &blocks_data)?;
+                reset_reassembly(
+                    &receiver.stats,
+                    &mut blocks_data,
+                    &mut blocks_ignore,
+                    &mut cur_ids,
+                    &mut duplicates,
+                    receive::DropReason::Timeout,
+                );
                 continue;
             }
             Err(e) => return Err(receive::Error::from(e)),
             Ok(datagrams) => datagrams,
         };
+        idle_since = time::Instant::now();
 
-        if reset {
-            reset = false;
-
-            for block in &mut blocks_data {
-                block.clear();
+        // a malformed or unauthenticated datagram is skipped before its block id is read, so that
+        // it can neither anchor the reassembly window of a sequence after a reset nor inject junk
+        // into a block of the window
+        let mut push = |datagram: &[u8]| {
+            let Some(packet) = checked_packet(&receiver.raptorq, &receiver.stats, datagram) else {
+                return;
+            };
+            let packet = raptorq::EncodingPacket::deserialize(packet);
+            let id = packet.payload_id().source_block_number();
+            let cur_id = &mut cur_ids[usize::from(scheme.slot(id))];
+            if cur_id.is_none() {
+                *cur_id = Some(id);
+                open_window(scheme, &mut blocks_ignore, id);
+            }
+            if blocks_ignore[usize::from(id)] {
+                receiver
+                    .stats
+                    .packets_dropped(receive::DropReason::Ignored, 1);
+            } else if duplicates.is_new(&receiver.stats, &packet) {
+                blocks_data[usize::from(id)].push(packet);
             }
-            blocks_ignore.fill(true);
+        };
 
-            let first_datagram = match &datagrams {
-                udp::Datagrams::Single(datagram) => datagram,
-                udp::Datagrams::Multiple(datagrams) => &datagrams[0],
-            };
+        datagrams.iter().for_each(&mut push);
 
-            let packet = raptorq::EncodingPacket::deserialize(first_datagram);
-            cur_id = packet.payload_id().source_block_number();
+        let mut too_far = false;
+        for cur_id in cur_ids.iter_mut().flatten() {
+            while blocks_data[usize::from(*cur_id)].len() >= min_nb_packets {
+                let packets = mem::replace(
+                    &mut blocks_data[usize::from(*cur_id)],
+                    Vec::with_capacity(nb_packets),
+                );
+                duplicates.clear(*cur_id);
 
-            let mut id = cur_id;
-            let last = id.wrapping_add(WINDOW_WIDTH);
-            while id != last {
-                blocks_ignore[usize::from(id)] = false;
-                id = id.wrapping_add(1);
-            }
-        }
+                log::trace!("reassembled block {cur_id}");
+                receiver.stats.core_block();
+
+                receiver.to_decode.send(super::Reassembled::Block {
+                    id: *cur_id,
+                    packets,
+                })?;
 
-        match datagrams {
-            udp::Datagrams::Single(datagram) => {
-                let packet = raptorq::EncodingPacket::deserialize(&datagram);
-                let id = usize::from(packet.payload_id().source_block_number());
-                if !blocks_ignore[id] {
-                    blocks_data[id].push(packet);
+                blocks_ignore[usize::from(*cur_id)] = true;
+
+                let opposite = usize::from(scheme.add(*cur_id, scheme.window_width()));
+                blocks_ignore[opposite] = false;
+
+                if !blocks_data[opposite].is_empty() {
+                    log::error!("lost block {opposite} (too far)");
+                    receiver.to_decode.send(super::Reassembled::Error)?;
+                    too_far = true;
+                    break;
                 }
+
+                *cur_id = scheme.add(*cur_id, 1);
             }
-            udp::Datagrams::Multiple(datagrams) => {
-                datagrams
-                    .into_iter()
-                    .map(|datagram| {
-                        let packet = raptorq::EncodingPacket::deserialize(&datagram);
-                        let id = usize::from(packet.payload_id().source_block_number());
-                        (id, packet)
-                    })
-                    .filter(|(id, _)| !blocks_ignore[*id])
-                    .for_each(|(id, packet)| blocks_data[id].push(packet));
+
+            if too_far {
+                break;
             }
         }
 
-        while blocks_data[usize::from(cur_id)].len() >= min_nb_packets {
-            let packets = mem::replace(
-                &mut blocks_data[usize::from(cur_id)],
-                Vec::with_capacity(nb_packets),
+        if too_far {
+            reset_reassembly(
+                &receiver.stats,
+                &mut blocks_data,
+                &mut blocks_ignore,
+                &mut cur_ids,
+                &mut duplicates,
+                receive::DropReason::TooFar,
             );
+        }
 
-            log::trace!("reassembled block {cur_id}");
+        thread::yield_now();
+    }
+}
 
-            receiver.to_decode.send(super::Reassembled::Block {
-                id: cur_id,
-                packets,
-            })?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::udp;
+    use std::io;
 
-            blocks_ignore[usize::from(cur_id)] = true;
+    type ClientNew = fn(protocol::ClientId) -> Result<Vec<u8>, io::Error>;
+    type ClientEnd = fn(Vec<u8>, bool, Option<protocol::TransferSummary>);
+    type Receiver = receive::Receiver<ClientNew, ClientEnd>;
 
-            let opposite = usize::from(cur_id.wrapping_add(WINDOW_WIDTH));
-            blocks_ignore[opposite] = false;
+    fn raptorq() -> protocol::RaptorQ {
+        protocol::RaptorQ::new(1500, 8 * 1464, 10).expect("valid parameters")
+    }
 
-            if !blocks_data[opposite].is_empty() {
-                log::error!("lost block {opposite} (too far)");
-                receiver.to_decode.send(super::Reassembled::Error)?;
-                reset = true;
-                break;
+    /// Receiver configured with the mandatory keys followed by `extra_config`
+    fn receiver(extra_config: &str, raptorq: protocol::RaptorQ) -> Receiver {
+        let config = toml::from_str(&format!(
+            r#"
+            from = "127.0.0.1:5000"
+            from_mtu = 1500
+            reset_timeout = 2
+            nb_decode_threads = 1
+            max_clients = 2
+            nb_client_threads = 2
+            {extra_config}
+            "#
+        ))
+        .expect("valid configuration");
+        let client_new: ClientNew = |_| Ok(Vec::new());
+        let client_end: ClientEnd = |_, _, _| ();
+        receive::Receiver::new(config, raptorq, client_new, client_end).expect("receiver")
+    }
+
+    /// Datagrams of the packets of block `block_id`, signed if the receiver expects it
+    fn datagrams(receiver: &Receiver, block_id: u8) -> Vec<Vec<u8>> {
+        let data = vec![block_id; receiver.raptorq.block_size() as usize];
+        receiver
+            .raptorq
+            .encode(block_id, &data)
+            .iter()
+            .map(|packet| {
+                let mut datagram = packet.serialize();
+                if let Some(packet_key) = receiver.raptorq.packet_key() {
+                    packet_key.sign(&mut datagram);
+                }
+                datagram
+            })
+            .collect()
+    }
+
+    /// Runs the reblock worker on `datagrams`, returning the ids of the reassembled blocks,
+    /// `None` standing for a synchronization loss
+    fn run(receiver: &Receiver, datagrams: Vec<Vec<u8>>) -> Vec<Option<u8>> {
+        thread::scope(|scope| {
+            let worker = scope.spawn(|| start(receiver));
+            for datagram in datagrams {
+                receiver
+                    .to_reblock
+                    .send(udp::Datagrams::Single(datagram))
+                    .expect("send");
+            }
+            // the datagram dequeued last is handled before shutdown is checked again
+            while !receiver.for_reblock.is_empty() {
+                thread::sleep(time::Duration::from_millis(1));
             }
+            receiver.shutdown();
+            assert!(worker.join().expect("reblock worker").is_ok());
+        });
+        receiver
+            .for_decode
+            .try_iter()
+            .map(|reassembled| match reassembled {
+                receive::Reassembled::Error => None,
+                receive::Reassembled::Block { id, .. } => Some(id),
+            })
+            .collect()
+    }
 
-            cur_id = cur_id.wrapping_add(1);
-        }
+    #[test]
+    fn malformed_first_datagram_skipped() {
+        let receiver = receiver("", raptorq());
 
-        thread::yield_now();
+        // a truncated datagram of a block far ahead must not anchor the reassembly window
+        let mut malformed = datagrams(&receiver, 200).swap_remove(0);
+        malformed.pop();
+        let mut all = vec![malformed];
+        all.extend(datagrams(&receiver, 0));
+
+        assert_eq!(run(&receiver, all), vec![Some(0)]);
+        let dropped = receiver.stats().packets_dropped;
+        assert_eq!(dropped.get(receive::DropReason::Malformed), 1);
+        assert_eq!(dropped.total(), 1);
+    }
+
+    #[test]
+    fn duplicate_counted_once() {
+        let stats = receive::stats::Stats::new(2, None, 0, false);
+        let mut duplicates = Duplicates::new(4);
+        let packet = raptorq::EncodingPacket::new(raptorq::PayloadId::new(3, 7), vec![0; 8]);
+        let other_block = raptorq::EncodingPacket::new(raptorq::PayloadId::new(4, 7), vec![0; 8]);
+
+        assert!(duplicates.is_new(&stats, &packet));
+        assert!(!duplicates.is_new(&stats, &packet));
+        assert!(duplicates.is_new(&stats, &other_block));
+        assert_eq!(stats.snapshot().duplicates_dropped, 1);
+
+        // a block id is reused once its block has been reassembled
+        duplicates.clear(3);
+        assert!(duplicates.is_new(&stats, &packet));
+        assert_eq!(stats.snapshot().duplicates_dropped, 1);
+    }
+
+    fn packet_key(name: &str, key: &[u8]) -> protocol::PacketKey {
+        let path = std::env::temp_dir().join(format!("lidi-{name}-{}", std::process::id()));
+        std::fs::write(&path, key).expect("key file");
+        let packet_key = protocol::PacketKey::from_file(&path).expect("packet key");
+        std::fs::remove_file(&path).expect("remove key file");
+        packet_key
+    }
+
+    #[test]
+    fn forged_datagrams_dropped() {
+        let target = receiver(
+            "",
+            raptorq().with_packet_key(Some(packet_key("key", b"shared key"))),
+        );
+        let valid = datagrams(&target, 0);
+
+        let other = receiver(
+            "",
+            raptorq().with_packet_key(Some(packet_key("forged", b"forged"))),
+        );
+        let forged = datagrams(&other, 0).swap_remove(0);
+        let mut tampered = valid[1].clone();
+        tampered[8] ^= 1;
+        let mut unsigned = valid[2].clone();
+        unsigned.truncate(unsigned.len() - usize::from(protocol::PACKET_TAG_SIZE));
+
+        let mut all = vec![forged, tampered, unsigned];
+        all.extend(valid);
+        assert_eq!(run(&target, all), vec![Some(0)]);
+
+        let stats = target.stats();
+        assert_eq!(stats.packets_rejected, 2);
+        assert_eq!(
+            stats
+                .packets_dropped
+                .get(receive::DropReason::Unauthenticated),
+            2
+        );
+        assert_eq!(stats.packets_dropped.get(receive::DropReason::Malformed), 1);
+        assert_eq!(stats.packets_dropped.total(), 3);
+    }
+
+    #[test]
+    fn ignored_and_duplicate_packets_counted() {
+        let receiver = receiver("", raptorq());
+
+        let block = datagrams(&receiver, 0);
+        let mut all = vec![block[0].clone()];
+        all.extend(block.iter().cloned());
+        // received again once its block was reassembled
+        all.push(block[1].clone());
+
+        assert_eq!(run(&receiver, all), vec![Some(0)]);
+        let dropped = receiver.stats().packets_dropped;
+        assert_eq!(dropped.get(receive::DropReason::Duplicate), 1);
+        assert_eq!(dropped.get(receive::DropReason::Ignored), 1);
+        assert_eq!(dropped.total(), 2);
+    }
+
+    #[test]
+    fn reset_discards_partial_blocks() {
+        let stats = receive::stats::Stats::new(2, None, 0, false);
+        let packet = raptorq::EncodingPacket::new(raptorq::PayloadId::new(3, 7), vec![0; 8]);
+        let mut duplicates = Duplicates::new(4);
+        let mut cur_ids = vec![Some(3)];
+
+        for (reason, nb_packets) in [
+            (receive::DropReason::TooFar, 2),
+            (receive::DropReason::Timeout, 3),
+        ] {
+            let mut blocks_data = vec![Vec::new(); usize::from(u8::MAX) + 1];
+            blocks_data[3] = vec![packet.clone(); nb_packets - 1];
+            blocks_data[4].push(packet.clone());
+            let mut blocks_ignore = vec![false; usize::from(u8::MAX) + 1];
+            assert!(duplicates.is_new(&stats, &packet));
+
+            reset_reassembly(
+                &stats,
+                &mut blocks_data,
+                &mut blocks_ignore,
+                &mut cur_ids,
+                &mut duplicates,
+                reason,
+            );
+
+            assert!(blocks_data.iter().all(Vec::is_empty));
+            assert!(blocks_ignore.iter().all(|ignore| *ignore));
+            assert_eq!(cur_ids, vec![None]);
+            assert_eq!(
+                stats.snapshot().packets_dropped.get(reason),
+                u64::try_from(nb_packets).expect("count")
+            );
+        }
+        assert_eq!(stats.snapshot().packets_dropped.total(), 5);
     }
 }
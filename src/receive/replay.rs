@@ -0,0 +1,271 @@
+//! Recording of the datagrams received on the UDP diode link to a file, and replay of such a
+//! file through the reblock, decode and dispatch workers in place of the UDP socket
+//!
+//! A record file starts with [`MAGIC`], followed by every datagram prefixed by its length as a
+//! big-endian `u32`.
+
+use crate::{receive, udp};
+use std::{
+    fs,
+    io::{self, BufRead, Read, Write},
+    path, thread, time,
+};
+
+/// First bytes of a record file
+const MAGIC: &[u8; 8] = b"lidiudp1";
+
+/// Number of recorded datagrams sent at once to the reblock worker when replaying
+const REPLAY_BATCH_SIZE: usize = 1024;
+
+/// Size of the buffers of record files
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Maximum length of a recorded datagram, that of a UDP payload
+const MAX_DATAGRAM_LEN: usize = u16::MAX as usize;
+
+pub(crate) struct Recorder {
+    path: path::PathBuf,
+    file: io::BufWriter<fs::File>,
+}
+
+impl Recorder {
+    pub(crate) fn create(path: &path::Path) -> Result<Self, io::Error> {
+        let mut file = io::BufWriter::with_capacity(BUFFER_SIZE, fs::File::create(path)?);
+        file.write_all(MAGIC)?;
+        log::info!("recording received UDP datagrams to {}", path.display());
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    fn write(&mut self, datagram: &[u8]) -> Result<(), io::Error> {
+        let len = u32::try_from(datagram.len())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("len: {e}")))?;
+        self.file.write_all(&len.to_be_bytes())?;
+        self.file.write_all(datagram)
+    }
+
+    /// Appends `datagrams` to the record file
+    pub(crate) fn record(&mut self, datagrams: &udp::Datagrams) -> Result<(), io::Error> {
+        match datagrams {
+            udp::Datagrams::Single(datagram) => self.write(datagram),
+            udp::Datagrams::Multiple(datagrams) => datagrams
+                .iter()
+                .try_for_each(|datagram| self.write(datagram)),
+        }
+    }
+
+    /// Writes the buffered datagrams to the record file, so that it is complete while the link
+    /// is idle
+    pub(crate) fn flush(&mut self) -> Result<(), io::Error> {
+        self.file.flush()
+    }
+
+    pub(crate) fn path(&self) -> &path::Path {
+        &self.path
+    }
+}
+
+/// Reads the next recorded datagram, `None` at the end of the file
+fn read_datagram<R: BufRead>(file: &mut R) -> Result<Option<Vec<u8>>, io::Error> {
+    if file.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let mut len = [0; 4];
+    file.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if MAX_DATAGRAM_LEN < len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("recorded datagram of {len} bytes exceeds {MAX_DATAGRAM_LEN} bytes"),
+        ));
+    }
+    let mut datagram = vec![0; len];
+    file.read_exact(&mut datagram)?;
+    Ok(Some(datagram))
+}
+
+/// Waits for all the replayed datagrams to be decoded and delivered, then shuts the receiver
+/// down: the pipeline is drained once its channels are empty, no transfer is active and no
+/// block was reassembled for `reset_timeout`
+fn wait_drained<ClientNew, ClientEnd>(receiver: &receive::Receiver<ClientNew, ClientEnd>) {
+    let mut quiet_since = time::Instant::now();
+    let mut last = receiver.stats.snapshot();
+    let mut waiting_logged = false;
+
+    while !receiver.is_shutting_down() {
+        thread::sleep(receive::SHUTDOWN_POLL_INTERVAL);
+
+        let stats = receiver.stats.snapshot();
+        let busy = !(receiver.for_reblock.is_empty()
+            && receiver.for_decode.is_empty()
+            && receiver.for_dispatch.is_empty()
+            && receiver.for_clients.is_empty())
+            || stats.bytes_delivered != last.bytes_delivered;
+        last = stats;
+        if busy {
+            quiet_since = time::Instant::now();
+            continue;
+        }
+        if quiet_since.elapsed() < receiver.config.reset_timeout {
+            continue;
+        }
+        if last.active_transfers == 0 {
+            receiver.shutdown();
+            return;
+        }
+        if !waiting_logged {
+            log::info!(
+                "waiting for {} active transfer(s) to end",
+                last.active_transfers
+            );
+            waiting_logged = true;
+        }
+    }
+}
+
+/// Sends the datagrams recorded in `path` to the reblock worker, then shuts the receiver down
+/// once they are all decoded and delivered
+pub(crate) fn start<ClientNew, ClientEnd>(
+    receiver: &receive::Receiver<ClientNew, ClientEnd>,
+    path: &path::Path,
+) -> Result<(), receive::Error> {
+    let mut file = io::BufReader::with_capacity(BUFFER_SIZE, fs::File::open(path)?);
+
+    let mut magic = [0; MAGIC.len()];
+    file.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(receive::Error::Other(format!(
+            "{} is not a UDP record file",
+            path.display()
+        )));
+    }
+
+    if let Some(credentials) = receiver.config.drop_privileges {
+        crate::drop_privileges(credentials)?;
+    }
+
+    log::info!("replaying UDP datagrams recorded in {}", path.display());
+
+    let mut nb_datagrams = 0;
+    loop {
+        if receiver.is_shutting_down() {
+            return Ok(());
+        }
+
+        let mut datagrams = Vec::with_capacity(REPLAY_BATCH_SIZE);
+        while datagrams.len() < REPLAY_BATCH_SIZE {
+            match read_datagram(&mut file)? {
+                Some(datagram) => datagrams.push(datagram),
+                None => break,
+            }
+        }
+        if datagrams.is_empty() {
+            break;
+        }
+
+        nb_datagrams += datagrams.len();
+        let datagrams = udp::Datagrams::Multiple(datagrams);
+        receiver
+            .stats
+            .packets_received(datagrams.len(), datagrams.nb_bytes());
+        receiver.stats.core_packets(datagrams.len());
+        receiver.to_reblock.send(datagrams)?;
+    }
+
+    log::info!("{nb_datagrams} datagrams replayed from {}", path.display());
+
+    wait_drained(receiver);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+    use std::sync;
+
+    #[test]
+    fn oversized_datagram_rejected() {
+        let mut record = (u32::try_from(MAX_DATAGRAM_LEN).expect("length") + 1)
+            .to_be_bytes()
+            .to_vec();
+        record.resize(record.len() + MAX_DATAGRAM_LEN + 1, 0);
+        let err = read_datagram(&mut record.as_slice()).expect_err("oversized datagram");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn record_then_replay() {
+        let path = std::env::temp_dir().join(format!("lidi-replay-{}", std::process::id()));
+        let raptorq = protocol::RaptorQ::new(1500, 8 * 1464, 10).expect("valid parameters");
+        let data = (0..=u8::MAX)
+            .cycle()
+            .take(3 * protocol::Block::max_data_len(&raptorq))
+            .collect::<Vec<_>>();
+
+        let mut blocks = vec![
+            protocol::Block::new(protocol::BlockType::Start, &raptorq, 1, 0, None).expect("start"),
+        ];
+        for (chunk_index, chunk) in (1..).zip(data.chunks(protocol::Block::max_data_len(&raptorq)))
+        {
+            blocks.push(
+                protocol::Block::new(
+                    protocol::BlockType::Data,
+                    &raptorq,
+                    1,
+                    chunk_index,
+                    Some(chunk),
+                )
+                .expect("data"),
+            );
+        }
+        let chunk_index = u32::try_from(blocks.len()).expect("chunk index");
+        blocks.push(
+            protocol::Block::new(protocol::BlockType::End, &raptorq, 1, chunk_index, None)
+                .expect("end"),
+        );
+        let mut recorder = Recorder::create(&path).expect("record file");
+        for (block_id, block) in (0..).zip(&blocks) {
+            let datagrams = raptorq
+                .encode(block_id, block.serialized())
+                .iter()
+                .map(raptorq::EncodingPacket::serialize)
+                .collect();
+            recorder
+                .record(&udp::Datagrams::Multiple(datagrams))
+                .expect("record");
+        }
+        recorder.flush().expect("flush");
+        drop(recorder);
+
+        let config = toml::from_str(&format!(
+            r#"
+            from = "127.0.0.1:0"
+            from_mtu = 1500
+            reset_timeout = 1
+            nb_decode_threads = 1
+            max_clients = 2
+            nb_client_threads = 2
+            udp_replay = "{}"
+            "#,
+            path.display()
+        ))
+        .expect("valid configuration");
+        let ended = sync::Mutex::new(Vec::new());
+        let receiver = receive::Receiver::new(
+            config,
+            raptorq,
+            |_| Ok::<_, io::Error>(Vec::new()),
+            |client, completed, _| ended.lock().expect("lock").push((client, completed)),
+        )
+        .expect("receiver");
+        thread::scope(|scope| receiver.start(scope).expect("start"));
+        drop(receiver);
+        fs::remove_file(&path).expect("remove record file");
+
+        assert_eq!(ended.into_inner().expect("lock"), vec![(data, true)]);
+    }
+}
@@ -0,0 +1,462 @@
+//! Sink streaming the content of a transfer into an S3 multipart upload
+//!
+//! Every transfer is uploaded to its own object: the upload is created with the sink, parts are
+//! sent as soon as [`PART_SIZE`] bytes are buffered, and the upload is completed when the
+//! transfer ends or aborted when the transfer is aborted (or the sink dropped before the end),
+//! so that no partial object is left in the bucket. Requests are signed with AWS signature
+//! version 4, and objects are addressed path-style (`<endpoint>/<bucket>/<key>`) to work with
+//! S3-compatible stores.
+
+use crate::receive::Sink;
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    sync, time,
+};
+
+/// Size of the parts sent while the transfer goes on; every part but the last one must be at
+/// least 5 MiB
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Object store settings, shared by every upload
+pub struct S3 {
+    endpoint: String,
+    host: String,
+    region: String,
+    bucket: String,
+    access_key: String,
+    secret_key: String,
+    agent: ureq::Agent,
+}
+
+impl S3 {
+    /// `endpoint` is the URL of the store, without path (e.g. `http://127.0.0.1:9000`)
+    pub fn new(
+        endpoint: &str,
+        region: String,
+        bucket: String,
+        access_key: String,
+        secret_key: String,
+    ) -> Self {
+        let endpoint = endpoint.trim_end_matches('/').to_string();
+        let host = endpoint
+            .split_once("://")
+            .map_or(endpoint.as_str(), |(_, host)| host)
+            .to_string();
+        Self {
+            endpoint,
+            host,
+            region,
+            bucket,
+            access_key,
+            secret_key,
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    /// Sends a signed request on `key`; `query` must be in canonical form (parameters sorted
+    /// and URI-encoded)
+    fn request(
+        &self,
+        method: &str,
+        key: &str,
+        query: &str,
+        payload: &[u8],
+    ) -> Result<ureq::Response, io::Error> {
+        let path = format!(
+            "/{}/{}",
+            uri_encode(&self.bucket, true),
+            uri_encode(key, false)
+        );
+
+        let (amz_date, date) = amz_dates(time::SystemTime::now());
+        let payload_hash = hex(&hmac_sha256::Hash::hash(payload));
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{method}\n{path}\n{query}\nhost:{}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{amz_date}\n\n{signed_headers}\n{payload_hash}",
+            self.host
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex(&hmac_sha256::Hash::hash(canonical_request.as_bytes()))
+        );
+
+        let mut signing_key =
+            hmac_sha256::HMAC::mac(date.as_bytes(), format!("AWS4{}", self.secret_key));
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac_sha256::HMAC::mac(part.as_bytes(), signing_key);
+        }
+        let signature = hex(&hmac_sha256::HMAC::mac(
+            string_to_sign.as_bytes(),
+            signing_key,
+        ));
+
+        let mut url = format!("{}{path}", self.endpoint);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(query);
+        }
+
+        self.agent
+            .request(method, &url)
+            .set("Host", &self.host)
+            .set("x-amz-content-sha256", &payload_hash)
+            .set("x-amz-date", &amz_date)
+            .set(
+                "Authorization",
+                &format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                    self.access_key
+                ),
+            )
+            .send_bytes(payload)
+            .map_err(|e| io::Error::other(format!("S3 {method} {key}: {e}")))
+    }
+}
+
+/// Multipart upload of a transfer into an object
+pub struct S3Upload {
+    s3: sync::Arc<S3>,
+    key: String,
+    upload_id: String,
+    part: Vec<u8>,
+    etags: Vec<String>,
+    open: bool,
+}
+
+impl S3Upload {
+    /// Creates the multipart upload of object `key`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the store cannot be reached or refuses the upload.
+    pub fn new(s3: sync::Arc<S3>, key: String) -> Result<Self, io::Error> {
+        let response = s3.request("POST", &key, "uploads=", &[])?;
+        let body = response.into_string()?;
+        let upload_id = xml_element(&body, "UploadId")
+            .ok_or_else(|| io::Error::other(format!("S3 upload of {key}: no upload id")))?
+            .to_string();
+
+        log::debug!("S3 upload of {key} created with id {upload_id}");
+
+        Ok(Self {
+            s3,
+            key,
+            upload_id,
+            part: Vec::with_capacity(PART_SIZE),
+            etags: Vec::new(),
+            open: true,
+        })
+    }
+
+    fn upload_part(&mut self) -> Result<(), io::Error> {
+        let part_number = self.etags.len() + 1;
+        let query = format!(
+            "partNumber={part_number}&uploadId={}",
+            uri_encode(&self.upload_id, true)
+        );
+        let response = self.s3.request("PUT", &self.key, &query, &self.part)?;
+        let etag = response.header("ETag").ok_or_else(|| {
+            io::Error::other(format!("S3 upload of {}: no ETag for part", self.key))
+        })?;
+
+        log::debug!(
+            "S3 upload of {}: part {part_number} of {} bytes sent",
+            self.key,
+            self.part.len()
+        );
+
+        self.etags.push(etag.to_string());
+        self.part.clear();
+        Ok(())
+    }
+
+    fn complete(&mut self) -> Result<(), io::Error> {
+        // the last part may be smaller than the minimum part size, and is empty only for an
+        // empty object, which needs one part
+        if !self.part.is_empty() || self.etags.is_empty() {
+            self.upload_part()?;
+        }
+
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (part_number, etag) in (1..).zip(&self.etags) {
+            let _ = write!(
+                body,
+                "<Part><PartNumber>{part_number}</PartNumber><ETag>{etag}</ETag></Part>"
+            );
+        }
+        body.push_str("</CompleteMultipartUpload>");
+
+        let query = format!("uploadId={}", uri_encode(&self.upload_id, true));
+        let response = self
+            .s3
+            .request("POST", &self.key, &query, body.as_bytes())?;
+        // errors may be reported in the body of a successful response
+        let response = response.into_string()?;
+        if let Some(error) = xml_element(&response, "Message") {
+            return Err(io::Error::other(format!(
+                "S3 upload of {}: {error}",
+                self.key
+            )));
+        }
+
+        log::info!(
+            "S3 upload of {} completed in {} part(s)",
+            self.key,
+            self.etags.len()
+        );
+        Ok(())
+    }
+
+    fn abort(&mut self) -> Result<(), io::Error> {
+        let query = format!("uploadId={}", uri_encode(&self.upload_id, true));
+        self.s3.request("DELETE", &self.key, &query, &[])?;
+        log::warn!("S3 upload of {} aborted", self.key);
+        Ok(())
+    }
+}
+
+impl Write for S3Upload {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.part.extend_from_slice(buf);
+        if PART_SIZE <= self.part.len() {
+            self.upload_part()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        // parts cannot be smaller than 5 MiB, data is sent by write or finish only
+        Ok(())
+    }
+}
+
+impl Sink for S3Upload {
+    fn finish(&mut self, completed: bool) -> Result<(), io::Error> {
+        self.open = false;
+        if !completed {
+            return self.abort();
+        }
+        let res = self.complete();
+        // an upload which failed to complete would otherwise keep its parts in the bucket
+        if res.is_err()
+            && let Err(e) = self.abort()
+        {
+            log::error!("{e}");
+        }
+        res
+    }
+}
+
+impl Drop for S3Upload {
+    fn drop(&mut self) {
+        if self.open
+            && let Err(e) = self.abort()
+        {
+            log::error!("{e}");
+        }
+    }
+}
+
+/// URI-encodes `s` as required by signature version 4, keeping `/` if `slash` is `false`
+fn uri_encode(s: &str, slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric()
+            || matches!(byte, b'-' | b'.' | b'_' | b'~')
+            || (byte == b'/' && !slash)
+        {
+            encoded.push(char::from(byte));
+        } else {
+            let _ = write!(encoded, "%{byte:02X}");
+        }
+    }
+    encoded
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(2 * bytes.len());
+    for byte in bytes {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+/// Content of the first `name` element of `xml`
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{name}>"))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{name}>"))?;
+    Some(&xml[start..end])
+}
+
+/// Formats `now` as the `x-amz-date` timestamp (`YYYYMMDDTHHMMSSZ`) and date (`YYYYMMDD`)
+fn amz_dates(now: time::SystemTime) -> (String, String) {
+    let secs = now
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // civil date from the number of days since 1970-01-01 (proleptic Gregorian calendar)
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    let date = format!("{year:04}{month:02}{day:02}");
+    let timestamp = format!(
+        "{date}T{:02}{:02}{:02}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    );
+    (timestamp, date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, Read},
+        net, thread,
+    };
+
+    /// Requests received by the mock store, as method and query
+    type Requests = sync::Arc<sync::Mutex<Vec<String>>>;
+
+    /// Answers one request of the mock store, `complete` being the body of the response to
+    /// upload completions
+    fn serve(stream: net::TcpStream, complete: &str, requests: &Requests) -> io::Result<()> {
+        let mut reader = io::BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header)?;
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':')
+                && name.eq_ignore_ascii_case("content-length")
+            {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+        io::copy(&mut reader.take(content_length), &mut io::sink())?;
+
+        let mut words = request_line.split_whitespace();
+        let method = words.next().unwrap_or_default();
+        let query = words
+            .next()
+            .and_then(|target| target.split_once('?'))
+            .map_or("", |(_, query)| query);
+        requests
+            .lock()
+            .expect("lock")
+            .push(format!("{method} {query}"));
+
+        let (status, headers, body) = match method {
+            "POST" if query == "uploads=" => ("200 OK", "", "<UploadId>id</UploadId>"),
+            "POST" => ("200 OK", "", complete),
+            "PUT" => ("200 OK", "ETag: \"etag\"\r\n", ""),
+            _ => ("204 No Content", "", ""),
+        };
+        let mut stream = stream;
+        write!(
+            stream,
+            "HTTP/1.1 {status}\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    /// Starts a mock store answering upload completions with `complete`
+    fn mock_s3(complete: &'static str) -> (sync::Arc<S3>, Requests) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let endpoint = format!("http://{}", listener.local_addr().expect("address"));
+        let requests = Requests::default();
+        let served = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Err(e) = stream.and_then(|stream| serve(stream, complete, &served)) {
+                    log::error!("mock S3: {e}");
+                }
+            }
+        });
+        let s3 = S3::new(
+            &endpoint,
+            "region".into(),
+            "bucket".into(),
+            "access".into(),
+            "secret".into(),
+        );
+        (sync::Arc::new(s3), requests)
+    }
+
+    fn requests(requests: &Requests) -> Vec<String> {
+        requests.lock().expect("lock").clone()
+    }
+
+    #[test]
+    fn completed_upload() {
+        let (s3, recorded) = mock_s3("<CompleteMultipartUploadResult/>");
+        let mut upload = S3Upload::new(s3, "key".into()).expect("upload");
+        upload.write_all(b"data").expect("write");
+        upload.finish(true).expect("finish");
+        drop(upload);
+        assert_eq!(
+            requests(&recorded),
+            [
+                "POST uploads=",
+                "PUT partNumber=1&uploadId=id",
+                "POST uploadId=id"
+            ]
+        );
+    }
+
+    #[test]
+    fn full_last_part_not_followed_by_empty_one() {
+        let (s3, recorded) = mock_s3("<CompleteMultipartUploadResult/>");
+        let mut upload = S3Upload::new(s3, "key".into()).expect("upload");
+        upload.write_all(&vec![0; PART_SIZE]).expect("write");
+        upload.finish(true).expect("finish");
+        assert_eq!(
+            requests(&recorded),
+            [
+                "POST uploads=",
+                "PUT partNumber=1&uploadId=id",
+                "POST uploadId=id"
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_completion_aborted() {
+        let (s3, recorded) = mock_s3("<Error><Message>refused</Message></Error>");
+        let mut upload = S3Upload::new(s3, "key".into()).expect("upload");
+        upload.write_all(b"data").expect("write");
+        assert!(upload.finish(true).is_err());
+        drop(upload);
+        assert_eq!(
+            requests(&recorded),
+            [
+                "POST uploads=",
+                "PUT partNumber=1&uploadId=id",
+                "POST uploadId=id",
+                "DELETE uploadId=id"
+            ]
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! Destination of the data of a transfer on the receiver side
+//!
+//! A [`Sink`] is created for each transfer by the `client_new` closure given to
+//! [`crate::receive::Receiver::new`], receives the transfer content through its [`Write`]
+//! implementation, and is notified of the transfer outcome with [`Sink::finish`] before being
+//! handed back to the `client_end` closure.
+//!
+//! Implementations are provided for TCP and Unix streams, files, standard output and in-memory
+//! vectors, [`crate::receive::S3Upload`] uploads a transfer to an S3-compatible object store,
+//! and [`Broadcast`] delivers a transfer to several sinks at once. Routing transfers to another
+//! destination (a message broker, ...) only requires implementing [`Write`] to forward the
+//! content and, if the destination has a notion of committing or discarding a message,
+//! overriding [`Sink::finish`].
+
+use std::{
+    fs,
+    io::{self, Write},
+    net,
+    os::unix,
+};
+
+pub trait Sink: Write {
+    /// Called once the whole transfer content has been written and flushed (`completed` is
+    /// `true`), or when the transfer was aborted by the sender (`completed` is `false`)
+    ///
+    /// The default implementation does nothing.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the sink fails to commit or discard the transfer.
+    fn finish(&mut self, completed: bool) -> Result<(), io::Error> {
+        let _ = completed;
+        Ok(())
+    }
+
+    /// Switches the sink to non-blocking mode, so that writes return
+    /// [`io::ErrorKind::WouldBlock`] instead of blocking; required for the write timeout of
+    /// [`crate::receive::Config`] to be enforced
+    ///
+    /// The default implementation returns an [`io::ErrorKind::Unsupported`] error.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the sink does not support non-blocking mode.
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), io::Error> {
+        let _ = nonblocking;
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl Sink for net::TcpStream {
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), io::Error> {
+        net::TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl Sink for unix::net::UnixStream {
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), io::Error> {
+        unix::net::UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl Sink for fs::File {}
+
+impl Sink for io::Stdout {}
+
+impl Sink for Vec<u8> {}
+
+/// Sink delivering the content of a transfer to several sinks at once
+///
+/// A sink failing is dropped, the others still receiving the transfer; writing to a
+/// [`Broadcast`] only fails once every sink has failed.
+pub struct Broadcast<S> {
+    sinks: Vec<Option<S>>,
+}
+
+impl<S> Broadcast<S> {
+    pub fn new(sinks: Vec<S>) -> Self {
+        Self {
+            sinks: sinks.into_iter().map(Some).collect(),
+        }
+    }
+
+    /// Returns the sinks that did not fail
+    pub fn into_inner(self) -> Vec<S> {
+        self.sinks.into_iter().flatten().collect()
+    }
+
+    fn for_each_sink<F>(&mut self, operation: &str, mut f: F) -> Result<(), io::Error>
+    where
+        F: FnMut(&mut S) -> Result<(), io::Error>,
+    {
+        for (i, slot) in self.sinks.iter_mut().enumerate() {
+            if let Some(sink) = slot
+                && let Err(e) = f(sink)
+            {
+                log::error!("broadcast sink {i}: {operation} failed, dropping sink: {e}");
+                *slot = None;
+            }
+        }
+
+        if self.sinks.iter().all(Option::is_none) {
+            return Err(io::Error::other(format!(
+                "{operation} failed on every broadcast sink"
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl<S: Write> Write for Broadcast<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.for_each_sink("write", |sink| sink.write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.for_each_sink("flush", Write::flush)
+    }
+}
+
+impl<S: Sink> Sink for Broadcast<S> {
+    fn finish(&mut self, completed: bool) -> Result<(), io::Error> {
+        self.for_each_sink("finish", |sink| sink.finish(completed))
+    }
+}
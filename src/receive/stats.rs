@@ -0,0 +1,510 @@
+//! Counters updated by the receiver workers and readable while the receiver is running
+//!
+//! Counters are plain atomics updated with relaxed ordering, so reading a snapshot never blocks
+//! the workers pipeline. The optional rolling decode success rate is the only state behind a
+//! lock, only taken by decode workers.
+//!
+//! With CPU affinity, every worker thread records the core it is pinned to, and the packets and
+//! blocks it handles are also accounted to that core.
+
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    fmt,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
+
+thread_local! {
+    /// Core the current worker thread is pinned to
+    static WORKER_CORE: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Attributes the packets and blocks handled by the current worker thread to `core`
+pub(crate) fn set_worker_core(core: usize) {
+    WORKER_CORE.set(Some(core));
+}
+
+#[derive(Default)]
+struct CoreCounters {
+    packets: AtomicU64,
+    blocks: AtomicU64,
+}
+
+/// Reason why received packets were discarded before being decoded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    /// Datagram whose size is not the one of a packet (e.g. truncated)
+    Malformed,
+    /// Datagram failing authentication
+    Unauthenticated,
+    /// Packet of a block outside the reassembly window, or already reassembled
+    Ignored,
+    /// Packet already received for the block being reassembled
+    Duplicate,
+    /// Packet of a block being reassembled when reassembly was reset because a block too far
+    /// ahead was received
+    TooFar,
+    /// Packet of a block being reassembled when reassembly was reset after a timeout
+    Timeout,
+    /// Packet of a block that could not be decoded
+    DecodeFailed,
+}
+
+impl DropReason {
+    const ALL: [Self; 7] = [
+        Self::Malformed,
+        Self::Unauthenticated,
+        Self::Ignored,
+        Self::Duplicate,
+        Self::TooFar,
+        Self::Timeout,
+        Self::DecodeFailed,
+    ];
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Malformed => write!(fmt, "malformed"),
+            Self::Unauthenticated => write!(fmt, "unauthenticated"),
+            Self::Ignored => write!(fmt, "outside reassembly window"),
+            Self::Duplicate => write!(fmt, "duplicate"),
+            Self::TooFar => write!(fmt, "block too far"),
+            Self::Timeout => write!(fmt, "reassembly timeout"),
+            Self::DecodeFailed => write!(fmt, "decode failure"),
+        }
+    }
+}
+
+/// Outcomes of the last decoded blocks
+struct DecodeWindow {
+    outcomes: VecDeque<bool>,
+    failures: usize,
+    capacity: usize,
+    breached: bool,
+}
+
+/// Change of the rolling decode success rate relative to the configured minimum
+pub(crate) enum DecodeRate {
+    /// The rate dropped below the minimum
+    Breached(f64),
+    /// The rate is back above the minimum
+    Recovered(f64),
+}
+
+pub(crate) struct Stats {
+    packets_received: AtomicU64,
+    bytes_received: AtomicU64,
+    packets_dropped: [AtomicU64; DropReason::ALL.len()],
+    /// Log every drop of packets, with its reason
+    log_drops: bool,
+    repair_used: Vec<AtomicU64>,
+    decode_failed: AtomicU64,
+    /// Set once a block has been decoded with no repair packet to spare, or has failed to decode
+    headroom_exhausted: AtomicBool,
+    transfers_dropped: AtomicU64,
+    blocks_dropped: AtomicU64,
+    chunks_lost: AtomicU64,
+    dual_feed_mismatches: AtomicU64,
+    probes_received: AtomicU64,
+    probes_corrupted: AtomicU64,
+    active_transfers: AtomicU64,
+    bytes_delivered: AtomicU64,
+    decode_window: Option<Mutex<DecodeWindow>>,
+    cores: Vec<CoreCounters>,
+}
+
+impl Stats {
+    /// `nb_cores` is the number of cores workers may be pinned to, 0 without CPU affinity
+    pub(crate) fn new(
+        nb_repair_packets: u16,
+        decode_window: Option<usize>,
+        nb_cores: usize,
+        log_drops: bool,
+    ) -> Self {
+        Self {
+            packets_received: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            packets_dropped: Default::default(),
+            log_drops,
+            repair_used: (0..=nb_repair_packets).map(|_| AtomicU64::new(0)).collect(),
+            decode_failed: AtomicU64::new(0),
+            headroom_exhausted: AtomicBool::new(false),
+            transfers_dropped: AtomicU64::new(0),
+            blocks_dropped: AtomicU64::new(0),
+            chunks_lost: AtomicU64::new(0),
+            dual_feed_mismatches: AtomicU64::new(0),
+            probes_received: AtomicU64::new(0),
+            probes_corrupted: AtomicU64::new(0),
+            active_transfers: AtomicU64::new(0),
+            bytes_delivered: AtomicU64::new(0),
+            decode_window: decode_window.map(|capacity| {
+                Mutex::new(DecodeWindow {
+                    outcomes: VecDeque::with_capacity(capacity),
+                    failures: 0,
+                    capacity: capacity.max(1),
+                    breached: false,
+                })
+            }),
+            cores: (0..nb_cores).map(|_| CoreCounters::default()).collect(),
+        }
+    }
+
+    /// Accounts `nb_packets` UDP packets carrying `nb_bytes` of payload received from the kernel
+    pub(crate) fn packets_received(&self, nb_packets: usize, nb_bytes: usize) {
+        self.packets_received
+            .fetch_add(nb_packets as u64, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(nb_bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Accounts `nb_packets` packets discarded for `reason`
+    pub(crate) fn packets_dropped(&self, reason: DropReason, nb_packets: usize) {
+        if nb_packets == 0 {
+            return;
+        }
+        self.packets_dropped[reason as usize].fetch_add(nb_packets as u64, Ordering::Relaxed);
+        if self.log_drops {
+            log::debug!("{nb_packets} packet(s) dropped: {reason}");
+        }
+    }
+
+    fn worker_core(&self) -> Option<&CoreCounters> {
+        WORKER_CORE.get().and_then(|core| self.cores.get(core))
+    }
+
+    /// Accounts `nb_packets` packets handled by the current worker to its core
+    pub(crate) fn core_packets(&self, nb_packets: usize) {
+        if let Some(counters) = self.worker_core() {
+            counters
+                .packets
+                .fetch_add(nb_packets as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Accounts a block handled by the current worker to its core
+    pub(crate) fn core_block(&self) {
+        if let Some(counters) = self.worker_core() {
+            counters.blocks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records the outcome of a block decoding in the rolling window and compares the success
+    /// rate (in percent) to `min_rate` once the window is full, returning the crossings of
+    /// the threshold
+    pub(crate) fn decode_outcome(&self, success: bool, min_rate: f64) -> Option<DecodeRate> {
+        let mut window = self.decode_window.as_ref()?.lock().ok()?;
+
+        if window.outcomes.len() == window.capacity && window.outcomes.pop_front() == Some(false) {
+            window.failures -= 1;
+        }
+        window.outcomes.push_back(success);
+        if !success {
+            window.failures += 1;
+        }
+
+        if window.outcomes.len() < window.capacity {
+            return None;
+        }
+
+        #[allow(clippy::cast_precision_loss)]
+        let rate = 100.0 * (window.capacity - window.failures) as f64 / window.capacity as f64;
+
+        match (window.breached, rate < min_rate) {
+            (false, true) => {
+                window.breached = true;
+                Some(DecodeRate::Breached(rate))
+            }
+            (true, false) => {
+                window.breached = false;
+                Some(DecodeRate::Recovered(rate))
+            }
+            _ => None,
+        }
+    }
+
+    /// Records a successfully decoded block that needed `repair_used` repair packets, i.e. for
+    /// which `repair_used` source packets were lost
+    ///
+    /// Returns `true` the first time a block is decoded with no repair packet to spare.
+    pub(crate) fn block_decoded(&self, repair_used: usize) -> bool {
+        let nb_repair_packets = self.repair_used.len() - 1;
+        self.repair_used[repair_used.min(nb_repair_packets)].fetch_add(1, Ordering::Relaxed);
+        0 < nb_repair_packets && nb_repair_packets <= repair_used && self.exhaust_headroom()
+    }
+
+    /// Records a block that could not be decoded
+    ///
+    /// Returns `true` if it is the first time the repair headroom is exhausted.
+    pub(crate) fn block_failed(&self) -> bool {
+        self.decode_failed.fetch_add(1, Ordering::Relaxed);
+        self.exhaust_headroom()
+    }
+
+    fn exhaust_headroom(&self) -> bool {
+        !self.headroom_exhausted.swap(true, Ordering::Relaxed)
+    }
+
+    pub(crate) fn transfer_dropped(&self) {
+        self.transfers_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn block_dropped(&self) {
+        self.blocks_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Accounts `nb_chunks` chunks missing from a transfer kept going despite lost blocks
+    pub(crate) fn chunks_lost(&self, nb_chunks: u32) {
+        self.chunks_lost
+            .fetch_add(u64::from(nb_chunks), Ordering::Relaxed);
+    }
+
+    pub(crate) fn dual_feed_mismatch(&self) {
+        self.dual_feed_mismatches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Accounts a probe block, `intact` if its payload is the expected pattern
+    pub(crate) fn probe_received(&self, intact: bool) {
+        self.probes_received.fetch_add(1, Ordering::Relaxed);
+        if !intact {
+            self.probes_corrupted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn transfer_started(&self) {
+        self.active_transfers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn transfer_ended(&self) {
+        self.active_transfers.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Accounts `nb_bytes` of transfer data written to a client
+    pub(crate) fn bytes_delivered(&self, nb_bytes: usize) {
+        self.bytes_delivered
+            .fetch_add(nb_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> StatsSnapshot {
+        let packets_dropped = DroppedPackets(
+            DropReason::ALL
+                .map(|reason| self.packets_dropped[reason as usize].load(Ordering::Relaxed)),
+        );
+        StatsSnapshot {
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            packets_rejected: packets_dropped.get(DropReason::Unauthenticated),
+            duplicates_dropped: packets_dropped.get(DropReason::Duplicate),
+            packets_dropped,
+            repair_used: self
+                .repair_used
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect(),
+            decode_failed: self.decode_failed.load(Ordering::Relaxed),
+            low_decode_rate: self
+                .decode_window
+                .as_ref()
+                .and_then(|window| window.lock().ok())
+                .is_some_and(|window| window.breached),
+            transfers_dropped: self.transfers_dropped.load(Ordering::Relaxed),
+            blocks_dropped: self.blocks_dropped.load(Ordering::Relaxed),
+            chunks_lost: self.chunks_lost.load(Ordering::Relaxed),
+            dual_feed_mismatches: self.dual_feed_mismatches.load(Ordering::Relaxed),
+            probes_received: self.probes_received.load(Ordering::Relaxed),
+            probes_corrupted: self.probes_corrupted.load(Ordering::Relaxed),
+            active_transfers: self.active_transfers.load(Ordering::Relaxed),
+            bytes_delivered: self.bytes_delivered.load(Ordering::Relaxed),
+            per_core: self
+                .cores
+                .iter()
+                .enumerate()
+                .map(|(core, counters)| CoreStats {
+                    core,
+                    packets: counters.packets.load(Ordering::Relaxed),
+                    blocks: counters.blocks.load(Ordering::Relaxed),
+                })
+                .filter(|stats| 0 < stats.packets || 0 < stats.blocks)
+                .collect(),
+        }
+    }
+}
+
+/// Packets and blocks handled by the workers pinned to a core
+pub struct CoreStats {
+    pub core: usize,
+    /// Number of UDP packets received
+    pub packets: u64,
+    /// Number of blocks reassembled, decoded, dispatched or written to clients
+    pub blocks: u64,
+}
+
+/// Number of packets discarded before being decoded, per reason
+#[derive(Clone, Copy, Default)]
+pub struct DroppedPackets([u64; DropReason::ALL.len()]);
+
+impl DroppedPackets {
+    pub fn get(&self, reason: DropReason) -> u64 {
+        self.0[reason as usize]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.0.iter().sum()
+    }
+}
+
+impl fmt::Display for DroppedPackets {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(fmt, "{}", self.total())?;
+        let mut reasons = DropReason::ALL
+            .iter()
+            .filter(|reason| 0 < self.get(**reason))
+            .peekable();
+        if reasons.peek().is_some() {
+            write!(fmt, " (")?;
+            for (i, reason) in reasons.enumerate() {
+                if 0 < i {
+                    write!(fmt, ", ")?;
+                }
+                write!(fmt, "{reason}: {}", self.get(*reason))?;
+            }
+            write!(fmt, ")")?;
+        }
+        Ok(())
+    }
+}
+
+pub struct StatsSnapshot {
+    /// Number of UDP packets received
+    pub packets_received: u64,
+    /// Number of bytes of UDP payload received
+    pub bytes_received: u64,
+    /// Number of UDP packets dropped because they failed authentication, also accounted in
+    /// `packets_dropped`
+    pub packets_rejected: u64,
+    /// Number of packets dropped because they were already received for their block, also
+    /// accounted in `packets_dropped`
+    pub duplicates_dropped: u64,
+    /// Number of packets discarded before being decoded, per reason
+    pub packets_dropped: DroppedPackets,
+    /// Number of decoded blocks indexed by the number of repair packets used to decode them,
+    /// the last index being the configured number of repair packets (no headroom left)
+    pub repair_used: Vec<u64>,
+    /// Number of blocks that could not be decoded
+    pub decode_failed: u64,
+    /// Whether the decode success rate is below the configured minimum
+    pub low_decode_rate: bool,
+    /// Number of transfers rejected or dropped because too many transfers were queued
+    pub transfers_dropped: u64,
+    /// Number of decoded blocks dropped while delivery to clients was paused
+    pub blocks_dropped: u64,
+    /// Number of chunks missing from the transfers delivered to clients, which are kept going
+    /// when blocks are lost only if configured so
+    pub chunks_lost: u64,
+    /// Number of dual-feed transfers dropped because their two copies differ
+    pub dual_feed_mismatches: u64,
+    /// Number of probe blocks sent by an idle sender and decoded, which are also accounted in
+    /// the decoding counters
+    pub probes_received: u64,
+    /// Number of probe blocks whose payload is not the expected pattern
+    pub probes_corrupted: u64,
+    /// Number of transfers being written to clients
+    pub active_transfers: u64,
+    /// Number of bytes of transfer data written to clients
+    pub bytes_delivered: u64,
+    /// Activity of the cores workers are pinned to, empty without CPU affinity
+    pub per_core: Vec<CoreStats>,
+}
+
+impl StatsSnapshot {
+    /// Smallest number of unused repair packets over all decoded blocks, 0 if a block failed to
+    /// decode, `None` if no block was decoded yet
+    ///
+    /// Since nothing goes back through the diode, this is the number to watch to decide whether
+    /// the repair percentage of the sender should be raised.
+    pub fn min_repair_headroom(&self) -> Option<usize> {
+        if 0 < self.decode_failed {
+            return Some(0);
+        }
+        let nb_repair_packets = self.repair_used.len() - 1;
+        self.repair_used
+            .iter()
+            .rposition(|count| 0 < *count)
+            .map(|max_used| nb_repair_packets - max_used)
+    }
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            fmt,
+            "received packets: {}, received bytes: {}, rejected packets: {}, dropped packets: {}, ",
+            self.packets_received, self.bytes_received, self.packets_rejected, self.packets_dropped
+        )?;
+        write!(fmt, "repair packets used per block:")?;
+        for (used, count) in self.repair_used.iter().enumerate() {
+            if 0 < *count {
+                write!(fmt, " {used}:{count}")?;
+            }
+        }
+        if let Some(headroom) = self.min_repair_headroom() {
+            write!(fmt, ", min repair headroom: {headroom}")?;
+        }
+        write!(
+            fmt,
+            ", decode failures: {}, dropped transfers: {}, dropped blocks: {}, lost chunks: {}, dual-feed mismatches: {}, probes: {} ({} corrupted), active transfers: {}, delivered bytes: {}",
+            self.decode_failed,
+            self.transfers_dropped,
+            self.blocks_dropped,
+            self.chunks_lost,
+            self.dual_feed_mismatches,
+            self.probes_received,
+            self.probes_corrupted,
+            self.active_transfers,
+            self.bytes_delivered
+        )?;
+        if !self.per_core.is_empty() {
+            write!(fmt, ", packets/blocks per core:")?;
+            for stats in &self.per_core {
+                write!(fmt, " {}:{}/{}", stats.core, stats.packets, stats.blocks)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_rate_breach_and_recovery() {
+        let stats = Stats::new(2, Some(4), 0, false);
+
+        // no verdict until the window is full
+        for _ in 0..3 {
+            assert!(stats.decode_outcome(true, 75.0).is_none());
+        }
+        assert!(stats.decode_outcome(false, 75.0).is_none());
+        assert!(!stats.snapshot().low_decode_rate);
+
+        assert!(matches!(
+            stats.decode_outcome(false, 75.0),
+            Some(DecodeRate::Breached(rate)) if (rate - 50.0).abs() < f64::EPSILON
+        ));
+        assert!(stats.snapshot().low_decode_rate);
+        assert!(stats.decode_outcome(false, 75.0).is_none());
+
+        for _ in 0..2 {
+            stats.decode_outcome(true, 75.0);
+        }
+        assert!(matches!(
+            stats.decode_outcome(true, 75.0),
+            Some(DecodeRate::Recovered(rate)) if (rate - 75.0).abs() < f64::EPSILON
+        ));
+        assert!(!stats.snapshot().low_decode_rate);
+    }
+}
@@ -1,24 +1,59 @@
 //! Worker that actually receives packets from the UDP diode link
 
-use crate::{receive, sock_utils, udp};
-use std::{net, os::fd::AsRawFd};
+use crate::{receive, receive::replay, sock_utils, udp};
+use std::{io, net, os::fd::AsRawFd};
 
-pub(crate) fn start<ClientNew, ClientEnd>(
+/// Binds the UDP socket, joining the group of a multicast address, and sizes its receive buffer
+/// as configured or to hold the reassembly windows
+fn socket<ClientNew, ClientEnd>(
     receiver: &receive::Receiver<ClientNew, ClientEnd>,
-) -> Result<(), receive::Error> {
-    log::info!(
-        "listening for UDP packets at {} with MTU {}",
-        receiver.config.from,
-        receiver.config.from_mtu,
-    );
-
-    let socket = net::UdpSocket::bind(receiver.config.from)?;
+) -> Result<net::UdpSocket, receive::Error> {
+    let from = receiver.config.from;
+    let socket = sock_utils::bind_udp_socket(from, |socket| {
+        if receiver.config.reuse_port {
+            sock_utils::set_socket_reuseport(socket)?;
+            log::info!("UDP port may be shared with other receivers");
+        }
+        if !sock_utils::configure_ipv6_only(socket, from, receiver.config.ipv6_only)? {
+            log::warn!("{from} is not an IPv6 address, IPv6 only setting ignored");
+        }
+        Ok(())
+    })?;
     socket.set_nonblocking(false)?;
 
-    let buffer_size = i32::from(super::reblock::WINDOW_WIDTH)
-        * i32::try_from(receiver.raptorq.nb_packets())
-            .map_err(|e| receive::Error::Other(format!("nb_packets: {e}")))?
-        * i32::from(receiver.config.from_mtu);
+    if receiver.config.ipv6_only.is_some() && from.is_ipv6() {
+        log::info!(
+            "UDP socket IPv6 only set to {}",
+            sock_utils::get_socket_ipv6_only(&socket)?
+        );
+    }
+
+    // bound to a multicast address, packets are only received once the group is joined, on the
+    // interface chosen by the system
+    match receiver.config.from.ip() {
+        net::IpAddr::V4(group) if group.is_multicast() => {
+            socket.join_multicast_v4(&group, &net::Ipv4Addr::UNSPECIFIED)?;
+            log::info!("joined multicast group {group}");
+        }
+        net::IpAddr::V6(group) if group.is_multicast() => {
+            socket.join_multicast_v6(&group, 0)?;
+            log::info!("joined multicast group {group}");
+        }
+        _ => (),
+    }
+
+    // enough for the reassembly windows of all the block id sequences, unless configured
+    let block_ids = receiver.config.block_ids;
+    let buffer_size = match receiver.config.recv_buffer_size {
+        Some(size) => size,
+        None => {
+            i32::from(block_ids.window_width())
+                * i32::from(block_ids.nb_slots())
+                * i32::try_from(receiver.raptorq.nb_packets())
+                    .map_err(|e| receive::Error::Other(format!("nb_packets: {e}")))?
+                * i32::from(receiver.config.from_mtu)
+        }
+    };
     sock_utils::set_socket_recv_buffer_size(&socket, buffer_size)?;
     let sock_buffer_size = sock_utils::get_socket_recv_buffer_size(&socket)?;
     log::info!("UDP socket receive buffer size set to {sock_buffer_size}");
@@ -30,14 +65,94 @@ pub(crate) fn start<ClientNew, ClientEnd>(
         log::warn!("Please review the kernel parameters using sysctl");
     }
 
+    Ok(socket)
+}
+
+/// Applies `op` to the recorder, if any, recording being stopped when it fails
+fn record(
+    recorder: &mut Option<replay::Recorder>,
+    op: impl FnOnce(&mut replay::Recorder) -> Result<(), io::Error>,
+) {
+    if let Some(rec) = recorder.as_mut()
+        && let Err(e) = op(rec)
+    {
+        log::error!(
+            "failed to record UDP datagrams to {}, recording stopped: {e}",
+            rec.path().display()
+        );
+        *recorder = None;
+    }
+}
+
+pub(crate) fn start<ClientNew, ClientEnd>(
+    receiver: &receive::Receiver<ClientNew, ClientEnd>,
+) -> Result<(), receive::Error> {
+    if let Some(path) = receiver.config.udp_replay.as_ref() {
+        return replay::start(receiver, path);
+    }
+
+    log::info!(
+        "listening for UDP packets at {} with MTU {}",
+        receiver.config.from,
+        receiver.config.from_mtu,
+    );
+
+    let socket = socket(receiver)?;
+
+    let mut recorder = receiver
+        .config
+        .udp_record
+        .as_deref()
+        .map(replay::Recorder::create)
+        .transpose()?;
+
     let mut udp = udp::Receive::new(
         socket.as_raw_fd(),
         receiver.config.from_mtu,
         receiver.config.batch_receive,
-    );
+        receiver.config.batch_wait_full,
+        receiver.config.io_uring,
+        Some(receive::SHUTDOWN_POLL_INTERVAL),
+        receiver.config.gro,
+    )?;
+
+    if receiver.config.lock_buffers {
+        match udp.lock_buffers() {
+            Ok(size) => log::info!("{size} bytes of receive buffers locked in memory"),
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOMEM | libc::EPERM)) => {
+                log::warn!(
+                    "failed to lock receive buffers in memory ({e}), please review RLIMIT_MEMLOCK"
+                );
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    // dropped last, as creating the io_uring ring and locking buffers may require privileges
+    if let Some(credentials) = receiver.config.drop_privileges {
+        crate::drop_privileges(credentials)?;
+    }
 
     loop {
-        let datagrams = udp.recv()?;
+        if receiver.is_shutting_down() {
+            return Ok(());
+        }
+        let datagrams = match udp.recv() {
+            Ok(datagrams) => datagrams,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                record(&mut recorder, replay::Recorder::flush);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if datagrams.is_empty() {
+            continue;
+        }
+        record(&mut recorder, |rec| rec.record(&datagrams));
+        receiver
+            .stats
+            .packets_received(datagrams.len(), datagrams.nb_bytes());
+        receiver.stats.core_packets(datagrams.len());
         receiver.to_reblock.send(datagrams)?;
     }
 }
@@ -0,0 +1,28 @@
+//! Optional worker that logs a compact liveness line every second
+
+use crate::send;
+use std::{thread, time};
+
+/// Interval between two liveness lines
+const ALIVE_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+pub(crate) fn start<C>(sender: &send::Sender<C>) {
+    let mut last = sender.stats.snapshot();
+    let mut next = time::Instant::now() + ALIVE_INTERVAL;
+
+    loop {
+        // sleeping until a deadline avoids drifting from one line per second
+        thread::sleep(next.saturating_duration_since(time::Instant::now()));
+        next += ALIVE_INTERVAL;
+
+        let stats = sender.stats.snapshot();
+        let packets = stats.packets_sent - last.packets_sent;
+        let bytes = stats.bytes_sent - last.bytes_sent;
+        last = stats;
+
+        #[allow(clippy::cast_precision_loss)]
+        let mbits = (8 * bytes) as f64 / 1_000_000.0;
+
+        log::info!("alive: {mbits:.1} Mb/s, {packets} packets/s sent");
+    }
+}
@@ -1,7 +1,163 @@
 //! Worker that reads data from a client socket and split it into [`crate::protocol`] blocks
 
-use crate::{protocol, send};
-use std::{io, os::fd::AsRawFd, thread};
+use crate::{protocol, send, sock_utils};
+use std::{io, os::fd::AsRawFd, thread, time};
+
+/// Pause between two attempts to read from a non-blocking client with no data available
+const WOULD_BLOCK_PAUSE: time::Duration = time::Duration::from_millis(1);
+
+/// Ends a transfer once the client reached EOF, draining the `tail` bytes read since the last
+/// block so that none is lost: the `End` block carries them, unless it carries the transfer
+/// summary, in which case they are sent in a last `Data` block before it
+fn end_transfer<C>(
+    sender: &send::Sender<C>,
+    client_id: protocol::ClientId,
+    mut chunk_index: u32,
+    tail: &[u8],
+    mut transmitted: usize,
+    connected_at: time::Instant,
+) -> Result<(), send::Error> {
+    if sender.config.transfer_summary && !tail.is_empty() {
+        chunk_index = chunk_index.wrapping_add(1);
+        let block = protocol::Block::new(
+            protocol::BlockType::Data,
+            &sender.raptorq,
+            client_id,
+            chunk_index,
+            Some(tail),
+        )?;
+        sender.acquire_in_flight_block(client_id)?;
+        sender.send_to_worker(&sender.to_encoding, Some(block))?;
+        transmitted += tail.len();
+    }
+
+    chunk_index = chunk_index.wrapping_add(1);
+
+    let summary = sender.config.transfer_summary.then(|| {
+        let summary = protocol::TransferSummary {
+            bytes: transmitted as u64,
+            blocks: chunk_index.wrapping_add(1),
+            elapsed: connected_at.elapsed(),
+        };
+        log::debug!("client {client_id:x}: summary: {summary}");
+        summary.serialize()
+    });
+
+    let block = protocol::Block::new(
+        protocol::BlockType::End,
+        &sender.raptorq,
+        client_id,
+        chunk_index,
+        Some(summary.as_ref().map_or(tail, |summary| &summary[..])),
+    )?;
+
+    if summary.is_none() {
+        log::debug!(
+            "client {client_id:x}: final block carries {} bytes out of {} max, {} bytes of padding",
+            tail.len(),
+            protocol::Block::max_data_len(&sender.raptorq),
+            block.padding_len()
+        );
+        transmitted += tail.len();
+    }
+
+    sender.acquire_in_flight_block(client_id)?;
+    sender.send_to_worker(&sender.to_encoding, Some(block))?;
+
+    log::info!("client {client_id:x}: disconnect, {transmitted} bytes sent");
+
+    Ok(())
+}
+
+/// Fails once the transfer lasted longer than the configured maximum duration
+fn check_duration<C>(
+    sender: &send::Sender<C>,
+    connected_at: time::Instant,
+) -> Result<(), send::Error> {
+    match sender.config.max_transfer_duration {
+        Some(max) if max <= connected_at.elapsed() => Err(send::Error::TransferTooLong(max)),
+        _ => Ok(()),
+    }
+}
+
+/// Fills `buffer` from `client`, returning `false` if the client disconnected before any byte
+/// was read
+fn read_exact<C: io::Read>(
+    sender: &send::Sender<C>,
+    client: &mut C,
+    buffer: &mut [u8],
+    connected_at: time::Instant,
+) -> Result<bool, send::Error> {
+    let mut cursor = 0;
+    while cursor < buffer.len() {
+        check_duration(sender, connected_at)?;
+        match client.read(&mut buffer[cursor..]) {
+            Ok(0) if cursor == 0 => return Ok(false),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(read) => cursor += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(WOULD_BLOCK_PAUSE),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
+
+/// Sends every record read from `client`, framed by its length as a big-endian `u32`, in its
+/// own `Data` block, so that records are neither split nor coalesced
+fn send_records<C: io::Read>(
+    sender: &send::Sender<C>,
+    client_id: protocol::ClientId,
+    client: &mut C,
+    connected_at: time::Instant,
+) -> Result<(), send::Error> {
+    let max_data_len = protocol::Block::max_data_len(&sender.raptorq);
+    let mut buffer = vec![0; max_data_len];
+    let mut transmitted = 0;
+    let mut chunk_index: u32 = 0;
+
+    loop {
+        let mut record_len = [0; 4];
+        if !read_exact(sender, client, &mut record_len, connected_at)? {
+            break;
+        }
+        let record_len = u32::from_be_bytes(record_len) as usize;
+        if max_data_len < record_len {
+            return Err(send::Error::Other(format!(
+                "record of {record_len} bytes larger than block capacity ({max_data_len} bytes)"
+            )));
+        }
+
+        let record = &mut buffer[..record_len];
+        if !(read_exact(sender, client, record, connected_at)? || record.is_empty()) {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        log::trace!("client {client_id:x}: send record of {record_len} bytes");
+        sender.stats.bytes_read(record_len + 4);
+
+        chunk_index = chunk_index.wrapping_add(1);
+        let block = protocol::Block::new(
+            protocol::BlockType::Data,
+            &sender.raptorq,
+            client_id,
+            chunk_index,
+            Some(record),
+        )?;
+        sender.acquire_in_flight_block(client_id)?;
+        sender.send_to_worker(&sender.to_encoding, Some(block))?;
+        transmitted += record_len;
+    }
+
+    end_transfer(
+        sender,
+        client_id,
+        chunk_index,
+        &[],
+        transmitted,
+        connected_at,
+    )
+}
 
 pub(crate) fn start<C>(
     sender: &send::Sender<C>,
@@ -11,26 +167,72 @@ pub(crate) fn start<C>(
 where
     C: io::Read + AsRawFd + Send,
 {
-    log::info!("client {client_id:x}: connected");
+    let uuid = sender
+        .config
+        .transfer_uuid
+        .then(protocol::TransferUuid::new_random);
+    match uuid {
+        Some(uuid) => log::info!("client {client_id:x}: connected, transfer {uuid}"),
+        None => log::info!("client {client_id:x}: connected"),
+    }
 
-    sender.to_encoding.send(Some(protocol::Block::new(
-        protocol::BlockType::Start,
-        &sender.raptorq,
-        client_id,
-        None,
-    )?))?;
+    let connected_at = time::Instant::now();
 
-    let mut buffer = vec![0; protocol::Block::max_data_len(&sender.raptorq)];
+    // reads from a blocking client return at the latest when the transfer expires, so that it
+    // is aborted even if the client stays silent
+    if let Some(max) = sender.config.max_transfer_duration
+        && let Err(e) = sock_utils::set_socket_recv_timeout(&client, Some(max))
+    {
+        log::debug!("client {client_id:x}: cannot set read timeout: {e}");
+    }
+
+    let uuid = uuid.map(|uuid| uuid.serialize());
+    sender.acquire_in_flight_block(client_id)?;
+    sender.send_to_worker(
+        &sender.to_encoding,
+        Some(protocol::Block::new(
+            protocol::BlockType::Start,
+            &sender.raptorq,
+            client_id,
+            0,
+            uuid.as_ref().map(<[u8; _]>::as_slice),
+        )?),
+    )?;
+
+    if sender.config.records {
+        return send_records(sender, client_id, &mut client, connected_at);
+    }
+
+    let max_data_len = protocol::Block::max_data_len(&sender.raptorq);
+    let block_data_len = sender
+        .config
+        .virtual_block_size
+        .map_or(max_data_len, |size| size.min(max_data_len));
+
+    let mut buffer = vec![0; block_data_len];
     let mut cursor = 0;
     let mut transmitted = 0;
+    let mut chunk_index: u32 = 0;
 
     loop {
+        check_duration(sender, connected_at)?;
+
         log::trace!("client {client_id:x}: read...");
 
-        let read = client.read(&mut buffer[cursor..])?;
+        let read = match client.read(&mut buffer[cursor..]) {
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                // non-blocking client with no data available yet
+                thread::sleep(WOULD_BLOCK_PAUSE);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
 
         if 0 < read {
             log::trace!("client {client_id:x}: {read} bytes read");
+            sender.stats.bytes_read(read);
             cursor += read;
 
             if !(sender.config.flush || cursor >= buffer.len()) {
@@ -38,29 +240,209 @@ where
             }
         }
 
-        let block_type = if 0 == read {
-            protocol::BlockType::End
-        } else {
-            protocol::BlockType::Data
-        };
+        if 0 == read {
+            return end_transfer(
+                sender,
+                client_id,
+                chunk_index,
+                &buffer[..cursor],
+                transmitted,
+                connected_at,
+            );
+        }
 
         log::trace!("client {client_id:x}: send {cursor} bytes");
 
-        sender.to_encoding.send(Some(protocol::Block::new(
-            block_type,
+        chunk_index = chunk_index.wrapping_add(1);
+
+        let block = protocol::Block::new(
+            protocol::BlockType::Data,
             &sender.raptorq,
             client_id,
+            chunk_index,
             Some(&buffer[..cursor]),
-        )?))?;
+        )?;
+
+        sender.acquire_in_flight_block(client_id)?;
+        sender.send_to_worker(&sender.to_encoding, Some(block))?;
 
         transmitted += cursor;
         cursor = 0;
 
-        if 0 == read {
-            log::info!("client {client_id:x}: disconnect, {transmitted} bytes sent");
-            return Ok(());
+        thread::yield_now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::VecDeque, os::fd::RawFd};
+
+    /// Client returning scripted reads, then EOF
+    struct Client(VecDeque<io::Result<Vec<u8>>>);
+
+    impl Client {
+        fn new(reads: impl IntoIterator<Item = io::Result<Vec<u8>>>) -> Self {
+            Self(reads.into_iter().collect())
         }
+    }
 
-        thread::yield_now();
+    impl io::Read for Client {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some(data) = self.0.pop_front() else {
+                return Ok(0);
+            };
+            let mut data = data?;
+            let read = data.len().min(buf.len());
+            buf[..read].copy_from_slice(&data[..read]);
+            if read < data.len() {
+                self.0.push_front(Ok(data.split_off(read)));
+            }
+            Ok(read)
+        }
+    }
+
+    impl AsRawFd for Client {
+        fn as_raw_fd(&self) -> RawFd {
+            -1
+        }
+    }
+
+    /// Mandatory configuration keys followed by `extra_config`
+    fn sender(extra_config: &str) -> send::Sender<Client> {
+        let config = toml::from_str(&format!(
+            r#"
+            max_clients = 2
+            nb_encode_threads = 1
+            to = "127.0.0.1:5000"
+            to_bind = "0.0.0.0:0"
+            to_bind_retries = 0
+            to_mtu = 1500
+            {extra_config}
+            "#
+        ))
+        .expect("valid configuration");
+        let raptorq = protocol::RaptorQ::new(1500, 8 * 1464, 10).expect("valid parameters");
+        send::Sender::new(config, raptorq).expect("sender")
+    }
+
+    /// Runs the client worker on `client`, returning the type and payload of the blocks it
+    /// enqueued for encoding
+    fn transfer(
+        sender: &send::Sender<Client>,
+        client: Client,
+    ) -> Vec<(protocol::BlockType, Vec<u8>)> {
+        thread::scope(|scope| {
+            let encoding = scope.spawn(|| {
+                let mut blocks = Vec::new();
+                while let Some(block) = sender.for_encoding.recv().expect("block") {
+                    let block_type = block.block_type().expect("known block type");
+                    blocks.push((block_type, block.payload().to_vec()));
+                }
+                blocks
+            });
+            start(sender, 1, client).expect("transfer");
+            sender.to_encoding.send(None).expect("stop");
+            encoding.join().expect("encoding worker")
+        })
+    }
+
+    #[test]
+    fn interrupted_reads_retried() {
+        let sender = sender("");
+        let client = Client::new([
+            Err(io::ErrorKind::Interrupted.into()),
+            Ok(b"hello ".to_vec()),
+            Err(io::ErrorKind::WouldBlock.into()),
+            Ok(b"world".to_vec()),
+        ]);
+
+        let blocks = transfer(&sender, client);
+
+        assert!(matches!(
+            blocks[..],
+            [
+                (protocol::BlockType::Start, _),
+                (protocol::BlockType::End, _)
+            ]
+        ));
+        assert_eq!(blocks[1].1, b"hello world");
+    }
+
+    #[test]
+    fn records_sent_in_own_blocks() {
+        let sender = sender("records = true");
+        let records: [&[u8]; 3] = [b"first", b"", b"third record"];
+        let framed = records
+            .iter()
+            .flat_map(|record| {
+                let record_len = u32::try_from(record.len()).expect("record length");
+                [&record_len.to_be_bytes()[..], record].concat()
+            })
+            .collect::<Vec<_>>();
+        // short reads split both lengths and records
+        let client = Client::new(framed.chunks(3).map(|chunk| Ok(chunk.to_vec())));
+
+        let blocks = transfer(&sender, client);
+
+        assert_eq!(blocks.len(), 5);
+        assert!(matches!(blocks[0], (protocol::BlockType::Start, _)));
+        for (block, record) in blocks[1..4].iter().zip(records) {
+            assert!(matches!(block.0, protocol::BlockType::Data));
+            assert_eq!(block.1, record);
+        }
+        assert!(matches!(blocks[4], (protocol::BlockType::End, ref tail) if tail.is_empty()));
+    }
+
+    #[test]
+    fn truncated_record_fails() {
+        let sender = sender("records = true");
+        let client = Client::new([Ok([&8u32.to_be_bytes()[..], b"short"].concat())]);
+
+        thread::scope(|scope| {
+            let encoding =
+                scope.spawn(|| while sender.for_encoding.recv().expect("block").is_some() {});
+            assert!(start(&sender, 1, client).is_err());
+            sender.to_encoding.send(None).expect("stop");
+            encoding.join().expect("encoding worker");
+        });
+    }
+
+    #[test]
+    fn full_buffer_then_eof() {
+        let data = (0..=u8::MAX).cycle().take(20).collect::<Vec<_>>();
+
+        // the last read fills the buffer exactly
+        let exact = sender("virtual_block_size = 16");
+        let blocks = transfer(&exact, Client::new([Ok(data[..16].to_vec())]));
+        assert!(matches!(
+            blocks[..],
+            [
+                (protocol::BlockType::Start, _),
+                (protocol::BlockType::Data, ref block),
+                (protocol::BlockType::End, ref tail),
+            ] if block[..] == data[..16] && tail.is_empty()
+        ));
+
+        // the bytes read since the last block are sent before the summary
+        let summarized = sender(
+            r"
+            virtual_block_size = 16
+            transfer_summary = true
+            ",
+        );
+        let blocks = transfer(&summarized, Client::new([Ok(data.clone())]));
+        assert!(matches!(
+            blocks[..],
+            [
+                (protocol::BlockType::Start, _),
+                (protocol::BlockType::Data, ref block),
+                (protocol::BlockType::Data, ref tail),
+                (protocol::BlockType::End, ref summary),
+            ] if block[..] == data[..16]
+                && tail[..] == data[16..]
+                && protocol::TransferSummary::deserialize(summary)
+                    .is_ok_and(|summary| summary.bytes == 20 && summary.blocks == 4)
+        ));
     }
 }
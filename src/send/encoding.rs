@@ -1,45 +1,424 @@
 //! Worker that encodes protocol blocks into `RaptorQ` packets
+//!
+//! Block ids are assigned in the order blocks are read from the encoding queue, whatever the
+//! number of encoding workers: the `block_to_encode` lock is held while dequeuing a block and
+//! assigning its id and its position in the emission order. Packets are then forwarded in
+//! this order thanks to `block_to_send`, workers done encoding before their turn sleeping on
+//! `block_sent` until the previous block is forwarded, so that extra workers do not spin when
+//! there are fewer blocks to encode than workers. Since `RaptorQ` encoding is deterministic, a given
+//! sequence of blocks always produces the same sequence of block ids and packets.
+//!
+//! When stopping, every worker receives a `None` sentinel and waits for the others to have
+//! forwarded their last block before the udp worker is told to stop.
 
-use crate::send;
-use std::thread;
+use crate::{protocol, send};
+use std::{collections::HashMap, sync::atomic::Ordering, thread};
+
+/// Block ids assignment state, according to the configured [`protocol::BlockIdScheme`]
+pub(crate) struct BlockIds {
+    scheme: protocol::BlockIdScheme,
+    /// Position of the next block in the emission order, whatever its id
+    next_position: u8,
+    /// Next block id of every slot
+    next_ids: Vec<u8>,
+    /// Slots allocated to active transfers with [`protocol::BlockIdScheme::PerClient`]
+    slots: HashMap<protocol::ClientId, u8>,
+}
+
+impl BlockIds {
+    pub(crate) fn new(scheme: protocol::BlockIdScheme) -> Self {
+        Self {
+            scheme,
+            next_position: 0,
+            next_ids: (0..scheme.nb_slots())
+                .map(|slot| scheme.first_id(slot))
+                .collect(),
+            slots: HashMap::new(),
+        }
+    }
+
+    /// Returns the slot of the transfer of `block`, allocating a free one to a new transfer and
+    /// releasing it with the last block of the transfer
+    fn slot(&mut self, block: &protocol::Block) -> Result<u8, send::Error> {
+        let block_type = block.block_type()?;
+        if matches!(self.scheme, protocol::BlockIdScheme::Global)
+            || matches!(
+                block_type,
+                protocol::BlockType::Heartbeat | protocol::BlockType::Probe
+            )
+        {
+            return Ok(0);
+        }
+
+        let client_id = block.client_id();
+        let slot = if let Some(slot) = self.slots.get(&client_id) {
+            *slot
+        } else {
+            let slot = (1..self.scheme.nb_slots())
+                .find(|slot| !self.slots.values().any(|used| used == slot))
+                .ok_or(send::Error::Other(format!(
+                    "client {client_id:x}: no block id slot available"
+                )))?;
+            log::debug!("client {client_id:x}: block id slot {slot}");
+            self.slots.insert(client_id, slot);
+            slot
+        };
+
+        if matches!(
+            block_type,
+            protocol::BlockType::End | protocol::BlockType::Abort
+        ) {
+            self.slots.remove(&client_id);
+        }
+
+        Ok(slot)
+    }
+
+    /// Assigns a position in the emission order and an id to `block`
+    fn assign(&mut self, block: &protocol::Block) -> Result<(u8, u8), send::Error> {
+        let slot = usize::from(self.slot(block)?);
+
+        let position = self.next_position;
+        self.next_position = self.next_position.wrapping_add(1);
+
+        let block_id = self.next_ids[slot];
+        self.next_ids[slot] = self.scheme.add(block_id, 1);
+
+        Ok((position, block_id))
+    }
+}
+
+/// Forwards the packets of `block` to the udp worker once the blocks before `position` have
+/// been, then hands the turn over to the next block, even on failure: the encoding workers
+/// waiting for their turn are then woken up to fail as well, rather than waiting forever
+fn forward<C>(
+    sender: &send::Sender<C>,
+    position: u8,
+    block_id: u8,
+    block: &protocol::Block,
+    packets: Vec<raptorq::EncodingPacket>,
+) -> Result<(), send::Error> {
+    let to_send = sender
+        .block_to_send
+        .lock()
+        .map_err(|e| send::Error::Other(format!("failed to acquire lock: {e}")))?;
+    let mut to_send = sender
+        .block_sent
+        .wait_while(to_send, |to_send| {
+            #[cfg(test)]
+            sender.turn_checks.fetch_add(1, Ordering::Relaxed);
+            *to_send != position && !sender.encoding_failed.load(Ordering::Relaxed)
+        })
+        .map_err(|e| send::Error::Other(format!("failed to acquire lock: {e}")))?;
+
+    if sender.encoding_failed.load(Ordering::Relaxed) {
+        return Err(send::Error::Other(
+            "another encoding worker failed to forward a block".into(),
+        ));
+    }
+
+    log::trace!("send block {block_id}");
+    let res = sender
+        .audit(block)
+        .and_then(|()| sender.send_to_worker(&sender.to_send, Some(packets)));
+    if res.is_err() {
+        sender.encoding_failed.store(true, Ordering::Relaxed);
+    }
+    *to_send = to_send.wrapping_add(1);
+    drop(to_send);
+    sender.block_sent.notify_all();
+
+    res
+}
+
+/// Marks encoding as failed, waking up the encoding workers waiting for their turn so that they
+/// fail too
+fn fail<C>(sender: &send::Sender<C>) -> Result<(), send::Error> {
+    let to_send = sender
+        .block_to_send
+        .lock()
+        .map_err(|e| send::Error::Other(format!("failed to acquire lock: {e}")))?;
+    sender.encoding_failed.store(true, Ordering::Relaxed);
+    drop(to_send);
+    sender.block_sent.notify_all();
+    Ok(())
+}
+
+fn release<C>(sender: &send::Sender<C>, block: &protocol::Block) -> Result<(), send::Error> {
+    // heartbeat and probe blocks are not accounted, and may share id 0 with a client
+    if matches!(
+        block.block_type(),
+        Ok(protocol::BlockType::Heartbeat | protocol::BlockType::Probe)
+    ) {
+        Ok(())
+    } else {
+        sender.release_in_flight_block(block.client_id())
+    }
+}
+
+/// Discards the blocks still enqueued once forwarding failed, releasing their clients, until the
+/// stop sentinel so that the workers waiting on the barrier are not left waiting for this one
+fn drain<C>(sender: &send::Sender<C>) -> Result<(), send::Error> {
+    while let Some(block) = sender.for_encoding.recv()? {
+        log::warn!("dropping block of client {:x}", block.client_id());
+        release(sender, &block)?;
+    }
+    if sender.encoding_stopped.wait().is_leader() {
+        sender.send_to_worker(&sender.to_send, None)?;
+    }
+    Ok(())
+}
 
 pub(crate) fn start<C>(sender: &send::Sender<C>) -> Result<(), send::Error> {
     loop {
-        let mut block_id_to_encode = sender
+        // lock must be acquired before dequeuing to assign ids in queue order
+        let mut block_ids = sender
             .block_to_encode
             .lock()
             .map_err(|e| send::Error::Other(format!("failed to acquire lock: {e}")))?;
         let Some(block) = sender.for_encoding.recv()? else {
-            sender.to_send.send(None)?;
+            drop(block_ids);
+            // the other workers may still be encoding blocks dequeued before this sentinel
+            if sender.encoding_stopped.wait().is_leader() {
+                sender.send_to_worker(&sender.to_send, None)?;
+            }
             return Ok(());
         };
 
-        let block_id = *block_id_to_encode;
-        *block_id_to_encode = block_id_to_encode.wrapping_add(1);
+        let assigned = block_ids.assign(&block);
 
         // explicitly release the mutex
-        drop(block_id_to_encode);
+        drop(block_ids);
+
+        let (position, block_id) = match assigned {
+            Ok(assigned) => assigned,
+            Err(e) => {
+                fail(sender)?;
+                release(sender, &block)?;
+                drain(sender)?;
+                return Err(e);
+            }
+        };
 
         let client_id = block.client_id();
 
         log::debug!("encoding block {block_id} for client {client_id:x}");
 
         let packets = sender.raptorq.encode(block_id, block.serialized());
+        sender.stats.block_encoded();
 
-        loop {
-            let mut to_send = sender
-                .block_to_send
-                .lock()
-                .map_err(|e| send::Error::Other(format!("failed to acquire lock: {e}")))?;
-
-            if *to_send == block_id {
-                log::trace!("send block {block_id}");
-                sender.to_send.send(Some(packets))?;
-                *to_send = to_send.wrapping_add(1);
-                break;
-            }
+        if let Err(e) = forward(sender, position, block_id, &block, packets) {
+            drain(sender)?;
+            return Err(e);
         }
 
+        release(sender, &block)?;
+
         thread::yield_now();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io, net, sync};
+
+    /// Mandatory configuration keys followed by `extra_config`
+    fn config(extra_config: &str) -> send::Config {
+        toml::from_str(&format!(
+            r#"
+            max_clients = 2
+            nb_encode_threads = 2
+            to = "127.0.0.1:5000"
+            to_bind = "0.0.0.0:0"
+            to_bind_retries = 0
+            to_mtu = 1500
+            {extra_config}
+            "#
+        ))
+        .expect("valid configuration")
+    }
+
+    fn sender(config: send::Config) -> send::Sender<net::TcpStream> {
+        let raptorq = protocol::RaptorQ::new(1500, 8 * 1464, 10).expect("valid parameters");
+        send::Sender::new(config, raptorq).expect("sender")
+    }
+
+    /// Runs the encoding workers on `blocks`, returning their results and whether the udp
+    /// worker was told to stop
+    fn encode(
+        sender: &send::Sender<net::TcpStream>,
+        blocks: Vec<protocol::Block>,
+    ) -> (Vec<Result<(), send::Error>>, bool) {
+        thread::scope(|scope| {
+            let workers = (0..sender.config.nb_encode_threads)
+                .map(|_| scope.spawn(|| start(sender)))
+                .collect::<Vec<_>>();
+            let udp = scope.spawn(|| {
+                while sender.for_send.recv().expect("packets").is_some() {}
+                true
+            });
+
+            for block in blocks {
+                sender.to_encoding.send(Some(block)).expect("enqueue");
+            }
+            for _ in 0..sender.config.nb_encode_threads {
+                sender.to_encoding.send(None).expect("enqueue");
+            }
+
+            let results = workers
+                .into_iter()
+                .map(|worker| worker.join().expect("encoding worker"))
+                .collect();
+            (results, udp.join().expect("udp worker"))
+        })
+    }
+
+    #[test]
+    fn no_free_slot_stops_workers() {
+        let sender = sender(config(r#"block_ids = "per-client""#));
+
+        // one more transfer than slots available besides the heartbeat one
+        let blocks = (1..=protocol::BlockIdScheme::PerClient.nb_slots())
+            .map(|client_id| {
+                protocol::Block::new(
+                    protocol::BlockType::Start,
+                    &sender.raptorq,
+                    protocol::ClientId::from(client_id),
+                    0,
+                    None,
+                )
+                .expect("block")
+            })
+            .collect();
+        let (results, stopped) = encode(&sender, blocks);
+
+        assert!(results.iter().any(Result::is_err));
+        assert!(stopped);
+        assert!(sender.encoding_failed.load(Ordering::Relaxed));
+    }
+
+    /// Audit sink shared with the test
+    #[derive(Clone, Default)]
+    struct Audit(sync::Arc<sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for Audit {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().expect("lock").extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn audit_sink_receives_blocks_in_order() {
+        let audit = Audit::default();
+        let mut config = config("");
+        config.nb_encode_threads = 4;
+        config.audit_sink = Some(sync::Mutex::new(Box::new(audit.clone())));
+        let sender = sender(config);
+
+        let blocks = (0..32u8)
+            .map(|i| {
+                protocol::Block::new(
+                    protocol::BlockType::Data,
+                    &sender.raptorq,
+                    1,
+                    u32::from(i),
+                    Some(&[i; 16]),
+                )
+                .expect("block")
+            })
+            .collect::<Vec<_>>();
+        let expected = blocks
+            .iter()
+            .flat_map(|block| block.serialized().to_vec())
+            .collect::<Vec<_>>();
+
+        let (results, stopped) = encode(&sender, blocks);
+
+        assert!(results.iter().all(Result::is_ok));
+        assert!(stopped);
+        assert!(*audit.0.lock().expect("lock") == expected);
+    }
+
+    #[test]
+    fn idle_workers_do_not_spin() {
+        let mut config = config("");
+        config.nb_encode_threads = 8;
+        let sender = sender(config);
+
+        let data = [7; 64];
+        let block = protocol::Block::new(
+            protocol::BlockType::Data,
+            &sender.raptorq,
+            1,
+            0,
+            Some(&data),
+        )
+        .expect("block");
+
+        let (results, stopped) = encode(&sender, vec![block]);
+
+        assert!(results.iter().all(Result::is_ok));
+        assert!(stopped);
+        assert_eq!(sender.stats().blocks_encoded, 1);
+        // the only block is forwarded on its first check, the idle workers never check
+        assert_eq!(sender.turn_checks.load(Ordering::Relaxed), 1);
+        assert_eq!(*sender.block_to_send.lock().expect("lock"), 1);
+    }
+
+    #[test]
+    fn stop_waits_for_blocks_in_flight() {
+        let mut config = config("");
+        config.nb_encode_threads = 4;
+        let sender = sender(config);
+
+        let blocks = (0..16u8)
+            .map(|i| {
+                protocol::Block::new(
+                    protocol::BlockType::Data,
+                    &sender.raptorq,
+                    1,
+                    u32::from(i),
+                    Some(&[i; 1024]),
+                )
+                .expect("block")
+            })
+            .collect::<Vec<_>>();
+        let expected_packets =
+            blocks.len() * usize::try_from(sender.raptorq.nb_packets()).expect("packet count");
+
+        let packets = thread::scope(|scope| {
+            let workers = (0..sender.config.nb_encode_threads)
+                .map(|_| scope.spawn(|| start(&sender)))
+                .collect::<Vec<_>>();
+            let udp = scope.spawn(|| {
+                let mut packets = 0;
+                while let Some(block_packets) = sender.for_send.recv().expect("packets") {
+                    packets += block_packets.len();
+                }
+                packets
+            });
+
+            // the stop sentinels immediately follow the last block
+            for block in blocks {
+                sender.to_encoding.send(Some(block)).expect("enqueue");
+            }
+            for _ in 0..sender.config.nb_encode_threads {
+                sender.to_encoding.send(None).expect("enqueue");
+            }
+
+            for worker in workers {
+                worker.join().expect("encoding worker").expect("encoding");
+            }
+            udp.join().expect("udp worker")
+        });
+
+        assert_eq!(packets, expected_packets);
+        assert_eq!(sender.stats().blocks_encoded, 16);
+    }
+}
@@ -13,12 +13,16 @@ pub(crate) fn start<C>(sender: &send::Sender<C>) -> Result<(), send::Error> {
     loop {
         log::debug!("send heartbeat");
 
-        sender.to_encoding.send(Some(protocol::Block::new(
-            protocol::BlockType::Heartbeat,
-            &sender.raptorq,
-            0,
-            None,
-        )?))?;
+        sender.send_to_worker(
+            &sender.to_encoding,
+            Some(protocol::Block::new(
+                protocol::BlockType::Heartbeat,
+                &sender.raptorq,
+                0,
+                0,
+                None,
+            )?),
+        )?;
 
         thread::sleep(duration);
     }
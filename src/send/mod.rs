@@ -15,44 +15,165 @@
 //!
 //! Notes:
 //! - listeners threads are spawned from binary and not the library crate,
-//! - heartbeat worker has been omitted from the representation for readability,
+//! - heartbeat and probe workers have been omitted from the representation for readability,
 //! - there are `max_clients` clients workers running in parallel,
-//! - there are `nb_encode_threads` encoding workers running in parallel.
+//! - there are `nb_encode_threads` encoding workers running in parallel,
+//! - block ids are assigned in the order blocks are enqueued for encoding, so that with a
+//!   single client and heartbeat disabled, ids follow the client read order and the emitted
+//!   packets are reproducible for a given input,
+//! - with [`protocol::BlockIdScheme::PerClient`], every transfer has its own sequence of block
+//!   ids, blocks being still emitted in the order they are enqueued.
 
 use crate::protocol;
 use std::{
-    fmt,
-    io::{self, Read},
+    collections::{HashMap, HashSet},
+    error, fmt,
+    io::{self, Read, Write},
     iter, net,
     os::fd::AsRawFd,
     sync, thread, time,
 };
 
+mod alive;
 mod client;
 mod encoding;
 mod heartbeat;
+mod probe;
 mod server;
+mod stats;
 mod udp;
 
+pub use stats::StatsSnapshot;
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub max_clients: protocol::ClientId,
+    #[serde(default)]
     pub flush: bool,
     pub nb_encode_threads: u8,
+    /// Duration between two heartbeat blocks (seconds in configuration files)
+    #[serde(default, deserialize_with = "crate::config::option_seconds")]
     pub heartbeat_interval: Option<time::Duration>,
+    /// Duration between two probe blocks sent while no client is connected, so that the
+    /// receiver decoding statistics keep reflecting the link quality when idle (seconds in
+    /// configuration files)
+    #[serde(default, deserialize_with = "crate::config::option_seconds")]
+    pub probe_interval: Option<time::Duration>,
+    /// Maximum duration of a transfer from the client connection, the transfer being aborted
+    /// when it expires (seconds in configuration files)
+    #[serde(default, deserialize_with = "crate::config::option_seconds")]
+    pub max_transfer_duration: Option<time::Duration>,
     pub to: net::SocketAddr,
     pub to_bind: net::SocketAddr,
+    /// Number of bind attempts to retry, with backoff, when a fixed `to_bind` port is in use
+    pub to_bind_retries: u32,
+    /// Whether an IPv6 `to_bind` socket only sends IPv6 traffic (`IPV6_V6ONLY`), or also IPv4
+    /// traffic to IPv4-mapped addresses when bound to `::`, the system default being used when
+    /// `None`
+    pub ipv6_only: Option<bool>,
+    /// Size requested for the UDP socket send buffer, instead of one holding a block
+    pub send_buffer_size: Option<i32>,
     pub to_mtu: u16,
+    /// IP time to live (IPv6 hop limit) of the UDP packets, the system default being used when
+    /// `None`
+    pub ttl: Option<u8>,
+    /// IP time to live (IPv6 hop limit) of the UDP packets when `to` is a multicast address,
+    /// the system default (1) being used when `None`
+    pub multicast_ttl: Option<u32>,
     pub batch_send: Option<u32>,
+    /// Coalesce the packets of a block in buffers segmented in datagrams by the kernel (UDP
+    /// Generic Segmentation Offload), taking precedence over `batch_send` when available
+    #[serde(default)]
+    pub gso: bool,
+    /// Maximum aggregated rate of all transfers on the UDP link, in bits per second of UDP
+    /// payload (serialized packets, `RaptorQ` header included)
+    pub rate_limit_bps: Option<u64>,
+    /// Continuous ("fountain") mode: while no new block is ready, keep sending additional
+    /// repair packets of the last sent block, up to this number of packets per block
+    pub continuous_repair: Option<u32>,
+    /// Delay between two repair packets, which are then sent after the source packets of their
+    /// block spread over time instead of at once (microseconds in configuration files)
+    #[serde(default, deserialize_with = "crate::config::option_microseconds")]
+    pub repair_spacing: Option<time::Duration>,
+    /// Seed of the pseudo-random order in which the packets of a block are sent, to spread
+    /// periodic losses over source and repair symbols; packets are sent in the order they are
+    /// generated when `None`
+    pub shuffle_packets: Option<u64>,
+    /// Maximum number of blocks of a transfer waiting to be encoded or being encoded; reading
+    /// from the client pauses when it is reached
+    pub max_in_flight_blocks: Option<usize>,
+    /// Maximum number of client bytes accumulated before a block is emitted, lower than the
+    /// block capacity to reduce latency at the cost of padding overhead
+    pub virtual_block_size: Option<usize>,
+    #[serde(default)]
     pub cpu_affinity: bool,
+    /// Assignment of block ids to the blocks of the transfers, must be the same as the receiver
+    #[serde(default)]
+    pub block_ids: protocol::BlockIdScheme,
+    /// Send a [`protocol::TransferSummary`] in the `End` block of every transfer, must be the
+    /// same as the receiver
+    #[serde(default)]
+    pub transfer_summary: bool,
+    /// Send a random [`protocol::TransferUuid`] in the `Start` block of every transfer, logged
+    /// by both sides; must be the same as the receiver
+    #[serde(default)]
+    pub transfer_uuid: bool,
+    /// Maximum duration a worker may wait for the next worker of the pipeline to accept a block
+    /// or packets, the worker failing when it expires instead of hanging (seconds in
+    /// configuration files)
+    #[serde(default, deserialize_with = "crate::config::option_seconds")]
+    pub channel_send_timeout: Option<time::Duration>,
+    /// Log a line with the throughput and the packet rate sent on the UDP link every second
+    #[serde(default)]
+    pub log_alive: bool,
+    /// Record mode: client data is a sequence of records, each prefixed by its length as a
+    /// big-endian `u32`, every record being sent in its own block; must be the same as the
+    /// receiver
+    #[serde(default)]
+    pub records: bool,
+    /// Maximum number of clients open at once, being read from or waiting for a transfer slot;
+    /// clients accepted beyond are closed at once, so that the sender keeps descriptors
+    /// available instead of failing to accept with `EMFILE`
+    pub fd_ceiling: Option<u64>,
+    /// Sink to which every serialized block (before `RaptorQ` encoding) is written in emission
+    /// order, so that an audit receiver gets the exact block stream sent on the link; the
+    /// sender fails if it cannot be written to
+    #[serde(skip)]
+    pub audit_sink: Option<AuditSink>,
 }
 
+/// Writer shared by the encoding workers to copy the emitted blocks
+pub type AuditSink = sync::Mutex<Box<dyn io::Write + Send>>;
+
+/// Warns if `fd_ceiling` clients cannot be open with the open files limit of the process
+fn check_fd_ceiling(fd_ceiling: u64) {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &raw mut limit) } == 0
+        && limit.rlim_cur <= fd_ceiling
+    {
+        log::warn!(
+            "fd ceiling ({fd_ceiling}) >= open files limit ({}), accepting may fail with EMFILE",
+            limit.rlim_cur
+        );
+    }
+}
+
+#[derive(Debug)]
 pub enum Error {
     Io(io::Error),
     SendBlock,
     SendUdp,
+    SendTimeout(time::Duration),
     Receive(crossbeam_channel::RecvError),
     Protocol(protocol::Error),
     Diode(String),
+    TooManyClients(u64),
+    TransferTooLong(time::Duration),
     Other(String),
 }
 
@@ -62,14 +183,44 @@ impl fmt::Display for Error {
             Self::Io(e) => write!(fmt, "I/O error: {e}"),
             Self::SendBlock => write!(fmt, "crossbeam send block error"),
             Self::SendUdp => write!(fmt, "crossbeam send UDP error"),
+            Self::SendTimeout(timeout) => write!(
+                fmt,
+                "crossbeam send timed out after {} second(s), next worker is stuck",
+                timeout.as_secs()
+            ),
             Self::Receive(e) => write!(fmt, "crossbeam receive error: {e}"),
             Self::Protocol(e) => write!(fmt, "diode protocol error: {e}"),
             Self::Diode(e) => write!(fmt, "diode error: {e}"),
+            Self::TooManyClients(open) => {
+                write!(fmt, "too many open clients ({open}), client refused")
+            }
+            Self::TransferTooLong(max) => write!(
+                fmt,
+                "transfer aborted after the maximum duration of {} second(s)",
+                max.as_secs()
+            ),
             Self::Other(e) => write!(fmt, "{e}"),
         }
     }
 }
 
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Receive(e) => Some(e),
+            Self::Protocol(e) => Some(e),
+            Self::SendBlock
+            | Self::SendUdp
+            | Self::SendTimeout(_)
+            | Self::Diode(_)
+            | Self::TooManyClients(_)
+            | Self::TransferTooLong(_)
+            | Self::Other(_) => None,
+        }
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
         Self::Io(e)
@@ -109,14 +260,141 @@ pub struct Sender<C> {
     config: Config,
     raptorq: protocol::RaptorQ,
     multiplex_control: semka::Sem,
-    block_to_encode: sync::Mutex<u8>,
+    block_to_encode: sync::Mutex<encoding::BlockIds>,
     block_to_send: sync::Mutex<u8>,
+    /// Notified every time a block is forwarded to the udp worker, waking up the encoding
+    /// workers waiting for their turn
+    block_sent: sync::Condvar,
+    /// Set when an encoding worker failed to forward a block, so that the workers waiting for
+    /// their turn fail too instead of waiting forever for a block that will never be forwarded
+    encoding_failed: sync::atomic::AtomicBool,
+    /// Number of times the encoding workers checked whether it was their turn to forward a block
+    #[cfg(test)]
+    turn_checks: sync::atomic::AtomicUsize,
+    /// Waited for by every encoding worker when stopping, so that the end of the pipeline is
+    /// signaled to the udp worker only once every enqueued block has been forwarded to it
+    encoding_stopped: sync::Barrier,
+    in_flight_blocks: sync::Mutex<HashMap<protocol::ClientId, usize>>,
+    in_flight_released: sync::Condvar,
+    active_clients: sync::Mutex<HashSet<protocol::ClientId>>,
     to_server: crossbeam_channel::Sender<Option<C>>,
     for_server: crossbeam_channel::Receiver<Option<C>>,
     to_encoding: crossbeam_channel::Sender<Option<protocol::Block>>,
     for_encoding: crossbeam_channel::Receiver<Option<protocol::Block>>,
     to_send: crossbeam_channel::Sender<Option<Vec<raptorq::EncodingPacket>>>,
     for_send: crossbeam_channel::Receiver<Option<Vec<raptorq::EncodingPacket>>>,
+    stats: stats::Stats,
+}
+
+impl<C> Sender<C> {
+    /// Allocates a client id, skipping the ones of active transfers in case ids wrapped around
+    fn new_client_id(&self) -> Result<protocol::ClientId, Error> {
+        let mut active_clients = self
+            .active_clients
+            .lock()
+            .map_err(|e| Error::Other(format!("failed to acquire lock: {e}")))?;
+
+        loop {
+            let client_id = protocol::new_client_id();
+            if active_clients.insert(client_id) {
+                self.stats.client_started();
+                return Ok(client_id);
+            }
+            log::warn!("client id {client_id:x} still in use, skipping it");
+        }
+    }
+
+    fn release_client_id(&self, client_id: protocol::ClientId) -> Result<(), Error> {
+        if self
+            .active_clients
+            .lock()
+            .map_err(|e| Error::Other(format!("failed to acquire lock: {e}")))?
+            .remove(&client_id)
+        {
+            self.stats.client_ended();
+        }
+        Ok(())
+    }
+
+    /// Sends `msg` to the next worker of the pipeline, failing if it is not accepted within
+    /// `channel_send_timeout` when configured
+    fn send_to_worker<T>(&self, channel: &crossbeam_channel::Sender<T>, msg: T) -> Result<(), Error>
+    where
+        Error: From<crossbeam_channel::SendError<T>>,
+    {
+        let Some(timeout) = self.config.channel_send_timeout else {
+            return Ok(channel.send(msg)?);
+        };
+        channel.send_timeout(msg, timeout).map_err(|e| match e {
+            crossbeam_channel::SendTimeoutError::Timeout(_) => Error::SendTimeout(timeout),
+            crossbeam_channel::SendTimeoutError::Disconnected(msg) => {
+                crossbeam_channel::SendError(msg).into()
+            }
+        })
+    }
+
+    /// Copies `block` to the audit sink, if any
+    fn audit(&self, block: &protocol::Block) -> Result<(), Error> {
+        let Some(audit_sink) = self.config.audit_sink.as_ref() else {
+            return Ok(());
+        };
+        audit_sink
+            .lock()
+            .map_err(|e| Error::Other(format!("failed to acquire lock: {e}")))?
+            .write_all(block.serialized())?;
+        Ok(())
+    }
+
+    /// Waits, if `max_in_flight_blocks` is configured, for the number of in-flight blocks of the
+    /// client to be under the limit, then accounts for a new one
+    fn acquire_in_flight_block(&self, client_id: protocol::ClientId) -> Result<(), Error> {
+        let Some(max) = self.config.max_in_flight_blocks else {
+            return Ok(());
+        };
+
+        let in_flight_blocks = self
+            .in_flight_blocks
+            .lock()
+            .map_err(|e| Error::Other(format!("failed to acquire lock: {e}")))?;
+
+        let mut in_flight_blocks = self
+            .in_flight_released
+            .wait_while(in_flight_blocks, |in_flight_blocks| {
+                in_flight_blocks
+                    .get(&client_id)
+                    .is_some_and(|nb_blocks| max <= *nb_blocks)
+            })
+            .map_err(|e| Error::Other(format!("failed to acquire lock: {e}")))?;
+
+        *in_flight_blocks.entry(client_id).or_insert(0) += 1;
+
+        Ok(())
+    }
+
+    /// Releases an in-flight block of the client, once it has been handed to the UDP worker
+    fn release_in_flight_block(&self, client_id: protocol::ClientId) -> Result<(), Error> {
+        if self.config.max_in_flight_blocks.is_none() {
+            return Ok(());
+        }
+
+        let mut in_flight_blocks = self
+            .in_flight_blocks
+            .lock()
+            .map_err(|e| Error::Other(format!("failed to acquire lock: {e}")))?;
+
+        if let Some(nb_blocks) = in_flight_blocks.get_mut(&client_id) {
+            *nb_blocks -= 1;
+            if 0 == *nb_blocks {
+                in_flight_blocks.remove(&client_id);
+            }
+        }
+
+        drop(in_flight_blocks);
+
+        self.in_flight_released.notify_all();
+
+        Ok(())
+    }
 }
 
 impl<C> Sender<C>
@@ -126,15 +404,48 @@ where
     /// # Errors
     ///
     /// Will return `Err` if `multiplex_control` semaphore
-    /// cannot be created.
+    /// cannot be created, if continuous repair is requested in raw mode, if the virtual block
+    /// size is 0, or if there are more clients than transfers the block id scheme can tell
+    /// apart.
     pub fn new(config: Config, raptorq: protocol::RaptorQ) -> Result<Self, Error> {
+        if raptorq.is_raw() && config.continuous_repair.is_some() {
+            return Err(Error::Other(
+                "continuous repair is not available in raw mode".into(),
+            ));
+        }
+
+        if config.virtual_block_size == Some(0) {
+            return Err(Error::Other("virtual block size must not be 0".into()));
+        }
+
+        if let Some(max_clients) = config.block_ids.max_clients()
+            && max_clients < config.max_clients
+        {
+            return Err(Error::Other(format!(
+                "at most {max_clients} clients with {} block ids",
+                config.block_ids
+            )));
+        }
+
         let multiplex_control = semka::Sem::new(config.max_clients)
             .ok_or(Error::Other("failed to create semaphore".into()))?;
 
-        let block_to_encode = sync::Mutex::new(0);
+        let block_to_encode = sync::Mutex::new(encoding::BlockIds::new(config.block_ids));
 
         let block_to_send = sync::Mutex::new(0);
 
+        let block_sent = sync::Condvar::new();
+
+        let encoding_failed = sync::atomic::AtomicBool::new(false);
+
+        let encoding_stopped = sync::Barrier::new(usize::from(config.nb_encode_threads));
+
+        let in_flight_blocks = sync::Mutex::new(HashMap::new());
+
+        let in_flight_released = sync::Condvar::new();
+
+        let active_clients = sync::Mutex::new(HashSet::new());
+
         let (to_server, for_server) = crossbeam_channel::bounded(1);
         let (to_encoding, for_encoding) =
             crossbeam_channel::bounded(config.nb_encode_threads as usize);
@@ -146,19 +457,26 @@ where
             multiplex_control,
             block_to_encode,
             block_to_send,
+            block_sent,
+            encoding_failed,
+            #[cfg(test)]
+            turn_checks: sync::atomic::AtomicUsize::new(0),
+            encoding_stopped,
+            in_flight_blocks,
+            in_flight_released,
+            active_clients,
             to_server,
             for_server,
             to_encoding,
             for_encoding,
             to_send,
             for_send,
+            stats: stats::Stats::new(),
         })
     }
 
-    /// # Errors
-    ///
-    /// Will return `Err` if scoped threads cannot spawned.
-    pub fn start<'a>(&'a self, scope: &'a thread::Scope<'a, '_>) -> Result<(), Error> {
+    /// Logs the settings of the pipeline, warning about inconsistent ones
+    fn log_config(&self) {
         log::info!(
             "max {} simultaneous clients/transfers",
             self.config.max_clients
@@ -173,12 +491,41 @@ where
             }
         }
 
+        if let Some(max) = self.config.max_transfer_duration {
+            log::info!("transfers aborted after {} seconds", max.as_secs());
+        }
+
+        if let Some(fd_ceiling) = self.config.fd_ceiling {
+            log::info!("at most {fd_ceiling} clients open at once");
+            check_fd_ceiling(fd_ceiling);
+        }
+
+        if let Some(size) = self.config.virtual_block_size {
+            log::info!(
+                "blocks emitted every {} bytes of client data",
+                size.min(protocol::Block::max_data_len(&self.raptorq))
+            );
+        }
+    }
+
+    /// # Errors
+    ///
+    /// Will return `Err` if scoped threads cannot spawned.
+    pub fn start<'a>(&'a self, scope: &'a thread::Scope<'a, '_>) -> Result<(), Error> {
+        self.log_config();
+
         let mut cpu_ids = if self.config.cpu_affinity {
             core_affinity::get_core_ids().map(iter::IntoIterator::into_iter)
         } else {
             None
         };
 
+        if self.config.log_alive {
+            thread::Builder::new()
+                .name("alive".into())
+                .spawn_scoped(scope, move || alive::start(self))?;
+        }
+
         let cpu_id = cpu_ids.as_mut().and_then(iter::Iterator::next);
         thread::Builder::new()
             .name("udp".into())
@@ -228,6 +575,20 @@ where
             log::info!("heartbeat is disabled");
         }
 
+        if let Some(probe_interval) = self.config.probe_interval {
+            log::info!(
+                "probe block will be sent every {} seconds while idle",
+                probe_interval.as_secs()
+            );
+            thread::Builder::new()
+                .name("probe".into())
+                .spawn_scoped(scope, move || {
+                    if let Err(e) = probe::start(self) {
+                        log::error!("fatal probe error: {e}");
+                    }
+                })?;
+        }
+
         for i in 0..self.config.max_clients {
             let cpu_id = cpu_ids.as_mut().and_then(iter::Iterator::next);
             thread::Builder::new()
@@ -255,13 +616,30 @@ where
     }
     /// # Errors
     ///
-    /// Will return `Err` if the `send` returns a `SendError<T>`.
+    /// Will return `Err` if the `send` returns a `SendError<T>`, or if the client is refused
+    /// because `fd_ceiling` clients are already open, in which case the client is closed.
     pub fn new_client(&self, client: C) -> Result<(), Error> {
+        let open = self.stats.client_opened();
+        if let Some(fd_ceiling) = self.config.fd_ceiling
+            && fd_ceiling <= open
+        {
+            self.stats.client_closed();
+            return Err(Error::TooManyClients(open));
+        }
         if let Err(e) = self.to_server.send(Some(client)) {
+            self.stats.client_closed();
             return Err(Error::Diode(format!("failed to enqueue client: {e}")));
         }
         Ok(())
     }
+
+    /// Returns a snapshot of the sender counters, can be called while workers are running
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Stops the pipeline once the blocks already enqueued have been encoded and sent
+    ///
     /// # Errors
     ///
     /// Will return `Err` if the `send` returns a `SendError<T>`.
@@ -272,3 +650,44 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_id_in_use_skipped() {
+        let config = toml::from_str(
+            r#"
+            max_clients = 2
+            nb_encode_threads = 1
+            to = "127.0.0.1:5000"
+            to_bind = "0.0.0.0:0"
+            to_bind_retries = 0
+            to_mtu = 1500
+            "#,
+        )
+        .expect("valid configuration");
+        let raptorq = protocol::RaptorQ::new(1500, 8 * 1464, 10).expect("valid parameters");
+        let sender = Sender::<net::TcpStream>::new(config, raptorq).expect("sender");
+
+        // the ids following the first one are held as if by transfers started before the ids
+        // wrapped around, without touching the counter shared with other tests
+        let first = sender.new_client_id().expect("client id");
+        let held = (1..=64).map(|i| first.wrapping_add(i)).collect::<Vec<_>>();
+        sender
+            .active_clients
+            .lock()
+            .expect("lock")
+            .extend(held.iter().copied());
+
+        let next = sender.new_client_id().expect("client id");
+        assert!(!held.contains(&next));
+        assert_eq!(next.wrapping_sub(first), 65);
+        assert_eq!(sender.stats().clients_active, 2);
+
+        sender.release_client_id(next).expect("release");
+        assert!(!sender.active_clients.lock().expect("lock").contains(&next));
+        assert_eq!(sender.stats().clients_active, 1);
+    }
+}
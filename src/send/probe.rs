@@ -0,0 +1,32 @@
+//! Optional worker that inserts [`crate::protocol`] probe blocks in the encoding queue while no
+//! client is connected, so that the receiver keeps checking the link when there is no data
+
+use crate::{protocol, send};
+use std::thread;
+
+pub(crate) fn start<C>(sender: &send::Sender<C>) -> Result<(), send::Error> {
+    let Some(duration) = sender.config.probe_interval else {
+        return Err(send::Error::Other(
+            "no probe interval but probes enabled".into(),
+        ));
+    };
+
+    let mut sequence: u32 = 0;
+
+    loop {
+        thread::sleep(duration);
+
+        if 0 < sender.stats.snapshot().clients_active {
+            continue;
+        }
+
+        log::debug!("send probe {sequence}");
+
+        sender.send_to_worker(
+            &sender.to_encoding,
+            Some(protocol::Block::probe(&sender.raptorq, sequence)?),
+        )?;
+
+        sequence = sequence.wrapping_add(1);
+    }
+}
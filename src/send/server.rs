@@ -10,32 +10,42 @@ where
     loop {
         let Some(client) = sender.for_server.recv()? else {
             for _ in 0..sender.config.nb_encode_threads {
-                sender.to_encoding.send(None)?;
+                sender.send_to_worker(&sender.to_encoding, None)?;
             }
             return Ok(());
         };
 
         sender.multiplex_control.wait();
 
-        let client_id = protocol::new_client_id();
+        let client_id = sender.new_client_id()?;
 
         let client_res = client::start(sender, client_id, client);
-
-        sender.multiplex_control.signal();
+        sender.stats.client_closed();
 
         if let Err(e) = client_res {
             log::error!("client {client_id:x}: error: {e}");
 
-            if let Err(e) = sender.to_encoding.send(Some(protocol::Block::new(
-                protocol::BlockType::Abort,
-                &sender.raptorq,
-                client_id,
-                None,
-            )?)) {
+            sender.acquire_in_flight_block(client_id)?;
+            if let Err(e) = sender.send_to_worker(
+                &sender.to_encoding,
+                Some(protocol::Block::new(
+                    protocol::BlockType::Abort,
+                    &sender.raptorq,
+                    client_id,
+                    0,
+                    None,
+                )?),
+            ) {
                 log::error!("client {client_id:x}: failed to abort : {e}");
             }
         }
 
+        // signaled once the last block of the transfer is enqueued, so that a new transfer
+        // cannot start before the block id slot of this one is released
+        sender.multiplex_control.signal();
+
+        sender.release_client_id(client_id)?;
+
         thread::yield_now();
     }
 }
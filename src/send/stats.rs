@@ -0,0 +1,108 @@
+//! Counters updated by the sender workers and readable while the sender is running
+//!
+//! Counters are plain atomics updated with relaxed ordering, so reading a snapshot never blocks
+//! the workers pipeline.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+pub(crate) struct Stats {
+    bytes_read: AtomicU64,
+    blocks_encoded: AtomicU64,
+    packets_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    clients_active: AtomicU64,
+    clients_open: AtomicU64,
+}
+
+impl Stats {
+    pub(crate) fn new() -> Self {
+        Self {
+            bytes_read: AtomicU64::new(0),
+            blocks_encoded: AtomicU64::new(0),
+            packets_sent: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            clients_active: AtomicU64::new(0),
+            clients_open: AtomicU64::new(0),
+        }
+    }
+
+    /// Accounts `nb_bytes` of data read from a client
+    pub(crate) fn bytes_read(&self, nb_bytes: usize) {
+        self.bytes_read
+            .fetch_add(nb_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn block_encoded(&self) {
+        self.blocks_encoded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn client_started(&self) {
+        self.clients_active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn client_ended(&self) {
+        self.clients_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Accounts a client accepted, returning the number of clients open before it
+    pub(crate) fn client_opened(&self) -> u64 {
+        self.clients_open.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(crate) fn client_closed(&self) {
+        self.clients_open.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Accounts `nb_packets` UDP packets carrying `nb_bytes` of payload handed to the kernel
+    pub(crate) fn packets_sent(&self, nb_packets: usize, nb_bytes: usize) {
+        self.packets_sent
+            .fetch_add(nb_packets as u64, Ordering::Relaxed);
+        self.bytes_sent
+            .fetch_add(nb_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            blocks_encoded: self.blocks_encoded.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            clients_active: self.clients_active.load(Ordering::Relaxed),
+            clients_open: self.clients_open.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct StatsSnapshot {
+    /// Number of bytes of data read from clients
+    pub bytes_read: u64,
+    /// Number of blocks encoded (including heartbeat and transfer control blocks)
+    pub blocks_encoded: u64,
+    /// Number of UDP packets sent
+    pub packets_sent: u64,
+    /// Number of bytes of UDP payload sent
+    pub bytes_sent: u64,
+    /// Number of clients being read from
+    pub clients_active: u64,
+    /// Number of clients accepted and not closed yet, i.e. of client descriptors held, whether
+    /// being read from or waiting for a transfer slot
+    pub clients_open: u64,
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            fmt,
+            "read bytes: {}, encoded blocks: {}, sent packets: {}, sent bytes: {}, active clients: {}, open clients: {}",
+            self.bytes_read,
+            self.blocks_encoded,
+            self.packets_sent,
+            self.bytes_sent,
+            self.clients_active,
+            self.clients_open
+        )
+    }
+}
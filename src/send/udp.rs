@@ -1,22 +1,215 @@
 //! Worker that actually sends packets on the UDP diode link
 
-use crate::{send, sock_utils, udp};
-use std::{net, os::fd::AsRawFd, thread};
+use crate::{protocol, send, sock_utils, udp};
+use rand::{SeedableRng, seq::SliceRandom};
+use std::{collections, io, net, os::fd::AsRawFd, thread, time};
 
-pub(crate) fn start<C>(sender: &send::Sender<C>) -> Result<(), send::Error> {
-    log::info!(
-        "sending UDP traffic to {} with MTU {} binding to {}",
-        sender.config.to,
-        sender.config.to_mtu,
-        sender.config.to_bind
-    );
+/// Delay before the first bind retry, doubled after each failed attempt
+const BIND_RETRY_DELAY: time::Duration = time::Duration::from_millis(500);
+const BIND_RETRY_MAX_DELAY: time::Duration = time::Duration::from_secs(8);
+
+/// Binds the UDP socket, retrying with exponential backoff while the address is in use when a
+/// fixed source port is configured (an ephemeral port is never retried)
+fn bind(
+    to_bind: net::SocketAddr,
+    retries: u32,
+    ipv6_only: Option<bool>,
+) -> Result<net::UdpSocket, send::Error> {
+    let mut delay = BIND_RETRY_DELAY;
+    let mut attempt = 0;
+
+    if !(ipv6_only.is_none() || to_bind.is_ipv6()) {
+        log::warn!("{to_bind} is not an IPv6 address, IPv6 only setting ignored");
+    }
+
+    loop {
+        let socket = sock_utils::bind_udp_socket(to_bind, |socket| {
+            sock_utils::configure_ipv6_only(socket, to_bind, ipv6_only).map(|_| ())
+        });
+        match socket {
+            Ok(socket) => return Ok(socket),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && to_bind.port() != 0 => {
+                if retries <= attempt {
+                    return Err(send::Error::Other(format!(
+                        "failed to bind UDP socket to {to_bind}: source port {} still in use after {attempt} retries",
+                        to_bind.port()
+                    )));
+                }
+                attempt += 1;
+                log::warn!(
+                    "UDP source address {to_bind} already in use, retry {attempt}/{retries} in {} ms",
+                    delay.as_millis()
+                );
+                thread::sleep(delay);
+                delay = BIND_RETRY_MAX_DELAY.min(delay * 2);
+            }
+            Err(e) => {
+                return Err(send::Error::Other(format!(
+                    "failed to bind UDP socket to {to_bind}: {e}"
+                )));
+            }
+        }
+    }
+}
+
+/// Token bucket shared by all the traffic going through the UDP worker, whatever the client it
+/// originates from
+struct RateLimit {
+    /// Allowed rate, in bytes per second
+    rate: f64,
+    /// Maximum number of tokens that can be accumulated while idle
+    burst: f64,
+    tokens: f64,
+    last_refill: time::Instant,
+}
+
+impl RateLimit {
+    #[allow(clippy::cast_precision_loss)]
+    fn new(rate_limit_bps: u64, burst: usize) -> Self {
+        let burst = burst as f64;
+        Self {
+            rate: rate_limit_bps as f64 / 8.0,
+            burst,
+            tokens: burst,
+            last_refill: time::Instant::now(),
+        }
+    }
+
+    /// Rate limit allowing bursts of the size of the socket send buffer
+    fn with_burst(rate_limit_bps: u64, buffer_size: i32) -> Result<Self, send::Error> {
+        log::info!("UDP send rate limited to {rate_limit_bps} bits per second");
+        let burst = usize::try_from(buffer_size)
+            .map_err(|e| send::Error::Other(format!("rate limit burst: {e}")))?;
+        Ok(Self::new(rate_limit_bps, burst))
+    }
+
+    /// Consumes `nb_bytes` tokens, sleeping as long as needed for the bucket not to be in debt
+    #[allow(clippy::cast_precision_loss)]
+    fn consume(&mut self, nb_bytes: usize) {
+        let now = time::Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = self.burst.min(self.tokens + elapsed * self.rate);
+
+        self.tokens -= nb_bytes as f64;
+        if self.tokens < 0.0 {
+            thread::sleep(time::Duration::from_secs_f64(-self.tokens / self.rate));
+        }
+    }
+}
+
+/// Number of additional repair packets sent at once in continuous mode, between two checks for
+/// a new block to send
+const FOUNTAIN_BATCH: u32 = 16;
+
+/// Last sent block, for which additional repair packets are generated in continuous mode while
+/// no other block is ready to be sent
+struct Fountain {
+    block_id: u8,
+    /// Block content, taken from the source packets
+    data: Vec<u8>,
+    /// Created on first use, since encoding is costly
+    encoder: Option<raptorq::SourceBlockEncoder>,
+    next_repair_symbol_id: u32,
+    remaining: u32,
+}
 
-    let socket = net::UdpSocket::bind(sender.config.to_bind)?;
+impl Fountain {
+    fn new(raptorq: &protocol::RaptorQ, packets: &[raptorq::EncodingPacket], max: u32) -> Self {
+        let source_packets = &packets[..usize::from(raptorq.min_nb_packets()).min(packets.len())];
+        Self {
+            block_id: packets
+                .first()
+                .map_or(0, |packet| packet.payload_id().source_block_number()),
+            data: source_packets
+                .iter()
+                .flat_map(|packet| packet.data().iter().copied())
+                .collect(),
+            encoder: None,
+            next_repair_symbol_id: raptorq.next_repair_symbol_id(),
+            remaining: max,
+        }
+    }
+
+    fn repair_packets(&mut self, raptorq: &protocol::RaptorQ) -> Vec<raptorq::EncodingPacket> {
+        let encoder = self.encoder.get_or_insert_with(|| {
+            log::debug!("continuous mode: encoding block {}", self.block_id);
+            raptorq.encoder(self.block_id, &self.data)
+        });
+        let nb_packets = FOUNTAIN_BATCH.min(self.remaining);
+        let packets = encoder.repair_packets(self.next_repair_symbol_id, nb_packets);
+        self.next_repair_symbol_id += nb_packets;
+        self.remaining -= nb_packets;
+        packets
+    }
+}
+
+/// Shuffles the packets of a block sent right away, and its repair packets spread over time
+fn shuffle_block(
+    rng: &mut rand::rngs::StdRng,
+    packets: &mut [raptorq::EncodingPacket],
+    pending_repair: &mut collections::VecDeque<raptorq::EncodingPacket>,
+) {
+    packets.shuffle(rng);
+    pending_repair.make_contiguous().shuffle(rng);
+}
+
+fn send_packets<C>(
+    sender: &send::Sender<C>,
+    udp: &mut udp::Send,
+    rate_limit: Option<&mut RateLimit>,
+    packets: Vec<raptorq::EncodingPacket>,
+) -> Result<(), send::Error> {
+    let packet_key = sender.raptorq.packet_key();
+    let datagrams: Vec<_> = packets
+        .into_iter()
+        .map(|packet| {
+            let mut datagram = packet.serialize();
+            if let Some(packet_key) = packet_key {
+                packet_key.sign(&mut datagram);
+            }
+            datagram
+        })
+        .collect();
+
+    // the limit applies to the serialized datagrams, `RaptorQ` header and tag included
+    let nb_packets = datagrams.len();
+    let nb_bytes = datagrams.iter().map(Vec::len).sum();
+
+    if let Some(rate_limit) = rate_limit {
+        rate_limit.consume(nb_bytes);
+    }
+
+    udp.send(datagrams)?;
+    sender.stats.packets_sent(nb_packets, nb_bytes);
+    Ok(())
+}
+
+/// Binds the UDP socket and sizes its send buffer as configured or to hold a block, returning the
+/// socket and the expected buffer size
+fn socket<C>(sender: &send::Sender<C>) -> Result<(net::UdpSocket, i32), send::Error> {
+    let socket = bind(
+        sender.config.to_bind,
+        sender.config.to_bind_retries,
+        sender.config.ipv6_only,
+    )?;
     socket.set_nonblocking(false)?;
 
-    let buffer_size = i32::try_from(sender.raptorq.nb_packets())
-        .map_err(|e| send::Error::Other(format!("nb_packets: {e}")))?
-        * i32::from(sender.config.to_mtu);
+    if sender.config.ipv6_only.is_some() && sender.config.to_bind.is_ipv6() {
+        log::info!(
+            "UDP socket IPv6 only set to {}",
+            sock_utils::get_socket_ipv6_only(&socket)?
+        );
+    }
+
+    let buffer_size = match sender.config.send_buffer_size {
+        Some(size) => size,
+        None => {
+            i32::try_from(sender.raptorq.nb_packets())
+                .map_err(|e| send::Error::Other(format!("nb_packets: {e}")))?
+                * i32::from(sender.config.to_mtu)
+        }
+    };
     sock_utils::set_socket_send_buffer_size(&socket, buffer_size)?;
     let sock_buffer_size = sock_utils::get_socket_send_buffer_size(&socket)?;
     log::info!("UDP socket send buffer size set to {sock_buffer_size}");
@@ -28,19 +221,304 @@ pub(crate) fn start<C>(sender: &send::Sender<C>) -> Result<(), send::Error> {
         log::warn!("Please review the kernel parameters using sysctl");
     }
 
+    if let Some(ttl) = sender.config.ttl {
+        if sender.config.to_bind.is_ipv6() {
+            sock_utils::set_socket_ipv6_hop_limit(&socket, ttl)?;
+            log::info!(
+                "UDP socket IPv6 hop limit set to {}",
+                sock_utils::get_socket_ipv6_hop_limit(&socket)?
+            );
+        } else {
+            socket.set_ttl(u32::from(ttl))?;
+            log::info!("UDP socket TTL set to {}", socket.ttl()?);
+        }
+    }
+
+    if sender.config.to.ip().is_multicast() {
+        multicast(sender, &socket)?;
+    } else if sender.config.multicast_ttl.is_some() {
+        log::warn!(
+            "{} is not a multicast address, multicast TTL ignored",
+            sender.config.to.ip()
+        );
+    }
+
+    Ok((socket, buffer_size))
+}
+
+/// Sets the multicast TTL (IPv6 hop limit) of the socket, and sends multicast packets through
+/// the interface of the bind address (IPv4) or scope id (IPv6) if specified, instead of the one
+/// chosen by the system
+fn multicast<C>(sender: &send::Sender<C>, socket: &net::UdpSocket) -> Result<(), send::Error> {
+    match sender.config.to_bind {
+        net::SocketAddr::V4(to_bind) => {
+            if let Some(ttl) = sender.config.multicast_ttl {
+                socket.set_multicast_ttl_v4(ttl)?;
+                log::info!(
+                    "UDP socket multicast TTL set to {}",
+                    socket.multicast_ttl_v4()?
+                );
+            }
+            if !to_bind.ip().is_unspecified() {
+                sock_utils::set_socket_ipv4_multicast_if(socket, *to_bind.ip())?;
+                log::info!("multicast packets sent through interface {}", to_bind.ip());
+            }
+        }
+        net::SocketAddr::V6(to_bind) => {
+            if let Some(hops) = sender.config.multicast_ttl {
+                sock_utils::set_socket_ipv6_multicast_hops(socket, hops)?;
+                log::info!("UDP socket multicast hop limit set to {hops}");
+            }
+            if to_bind.scope_id() != 0 {
+                sock_utils::set_socket_ipv6_multicast_if(socket, to_bind.scope_id())?;
+                log::info!(
+                    "multicast packets sent through interface {}",
+                    to_bind.scope_id()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn start<C>(sender: &send::Sender<C>) -> Result<(), send::Error> {
+    log::info!(
+        "sending UDP traffic to {} with MTU {} binding to {}",
+        sender.config.to,
+        sender.config.to_mtu,
+        sender.config.to_bind
+    );
+
+    let (socket, buffer_size) = socket(sender)?;
+
     let mut udp = udp::Send::new(
         socket.as_raw_fd(),
         sender.config.to,
         sender.config.batch_send,
+        sender.config.gso.then(|| sender.raptorq.datagram_len()),
     )?;
 
+    let mut rate_limit = sender
+        .config
+        .rate_limit_bps
+        .map(|rate_limit_bps| RateLimit::with_burst(rate_limit_bps, buffer_size))
+        .transpose()?;
+
+    if let Some(max) = sender.config.continuous_repair {
+        log::info!(
+            "continuous mode: up to {max} additional repair packets per block while waiting for the next one"
+        );
+    }
+
+    if let Some(spacing) = sender.config.repair_spacing {
+        log::info!(
+            "repair packets spread with {} µs spacing",
+            spacing.as_micros()
+        );
+    }
+
+    let mut shuffle = sender.config.shuffle_packets.map(|seed| {
+        log::info!("packets of every block sent in pseudo-random order (seed {seed})");
+        rand::rngs::StdRng::seed_from_u64(seed)
+    });
+
+    let mut fountain: Option<Fountain> = None;
+
+    // repair packets of the last block not yet sent when they are spread over time
+    let mut pending_repair = collections::VecDeque::new();
+    let mut next_repair = time::Instant::now();
+
     loop {
-        let Some(packets) = sender.for_send.recv()? else {
+        let packets = if let (Some(spacing), false) =
+            (sender.config.repair_spacing, pending_repair.is_empty())
+        {
+            match sender.for_send.recv_deadline(next_repair) {
+                Ok(packets) => packets,
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                    let packets = pending_repair.pop_front().into_iter().collect();
+                    send_packets(sender, &mut udp, rate_limit.as_mut(), packets)?;
+                    next_repair += spacing;
+                    continue;
+                }
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                    return Err(send::Error::Receive(crossbeam_channel::RecvError));
+                }
+            }
+        } else {
+            match fountain.as_mut().filter(|fountain| 0 < fountain.remaining) {
+                None => sender.for_send.recv()?,
+                Some(fountain) => match sender.for_send.try_recv() {
+                    Ok(packets) => packets,
+                    Err(crossbeam_channel::TryRecvError::Empty) => {
+                        let packets = fountain.repair_packets(&sender.raptorq);
+                        send_packets(sender, &mut udp, rate_limit.as_mut(), packets)?;
+                        continue;
+                    }
+                    Err(crossbeam_channel::TryRecvError::Disconnected) => {
+                        return Err(send::Error::Receive(crossbeam_channel::RecvError));
+                    }
+                },
+            }
+        };
+
+        // repair packets of the previous block are sent before the packets of the next one
+        if !pending_repair.is_empty() {
+            send_packets(
+                sender,
+                &mut udp,
+                rate_limit.as_mut(),
+                pending_repair.drain(..).collect(),
+            )?;
+        }
+
+        let Some(mut packets) = packets else {
             return Ok(());
         };
 
-        udp.send(packets)?;
+        if let Some(max) = sender.config.continuous_repair {
+            fountain = Some(Fountain::new(&sender.raptorq, &packets, max));
+        }
+
+        if let Some(spacing) = sender.config.repair_spacing {
+            let nb_source_packets = usize::from(sender.raptorq.min_nb_packets()).min(packets.len());
+            pending_repair.extend(packets.drain(nb_source_packets..));
+            next_repair = time::Instant::now() + spacing;
+        }
+
+        // after the source and repair packets have been told apart by their position
+        if let Some(rng) = shuffle.as_mut() {
+            shuffle_block(rng, &mut packets, &mut pending_repair);
+        }
+
+        send_packets(sender, &mut udp, rate_limit.as_mut(), packets)?;
 
         thread::yield_now();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol_ids(packets: &[raptorq::EncodingPacket]) -> Vec<u32> {
+        packets
+            .iter()
+            .map(|packet| packet.payload_id().encoding_symbol_id())
+            .collect()
+    }
+
+    fn raptorq() -> protocol::RaptorQ {
+        protocol::RaptorQ::with_repair_packets(1500, 8 * 1464, 4).expect("valid parameters")
+    }
+
+    /// Packets of a block of 8 source and 4 repair packets, in generation order
+    fn packets(raptorq: &protocol::RaptorQ) -> Vec<raptorq::EncodingPacket> {
+        let data = (0..=u8::MAX).cycle().take(raptorq.block_size() as usize);
+        raptorq.encode(0, &data.collect::<Vec<_>>())
+    }
+
+    /// Symbol ids of the packets of a block, in sending order with packets shuffled by `seed`
+    fn shuffled(seed: u64) -> Vec<u32> {
+        let mut packets = packets(&raptorq());
+        shuffle_block(
+            &mut rand::rngs::StdRng::seed_from_u64(seed),
+            &mut packets,
+            &mut collections::VecDeque::new(),
+        );
+        symbol_ids(&packets)
+    }
+
+    #[test]
+    fn shuffle_deterministic() {
+        let generated = symbol_ids(&packets(&raptorq()));
+        let order = shuffled(7);
+        assert_eq!(order, shuffled(7));
+        assert_ne!(order, shuffled(8));
+        assert_ne!(order, generated);
+
+        let mut sorted = order.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, generated);
+    }
+
+    #[test]
+    fn shuffled_block_decoded_despite_periodic_loss() {
+        let raptorq = raptorq();
+        let mut sent = packets(&raptorq);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        shuffle_block(&mut rng, &mut sent, &mut collections::VecDeque::new());
+
+        // the link drops every fourth packet, the lost symbols depending on the order
+        let received = sent
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| i % 4 != 3)
+            .map(|(_, packet)| packet)
+            .collect::<Vec<_>>();
+
+        let data = (0..=u8::MAX)
+            .cycle()
+            .take(raptorq.block_size() as usize)
+            .collect::<Vec<_>>();
+        assert_eq!(raptorq.decode(0, received), Some(data));
+    }
+
+    #[test]
+    fn rate_limit_counts_whole_datagrams() {
+        let key_path = std::env::temp_dir().join(format!("lidi-rate-{}", std::process::id()));
+        std::fs::write(&key_path, b"shared key").expect("key file");
+        let packet_key = protocol::PacketKey::from_file(&key_path).expect("packet key");
+        std::fs::remove_file(&key_path).expect("remove key file");
+        let raptorq = raptorq().with_packet_key(Some(packet_key));
+
+        let receiving = net::UdpSocket::bind("127.0.0.1:0").expect("bind");
+        let to = receiving.local_addr().expect("address");
+        let config = toml::from_str(&format!(
+            r#"
+            max_clients = 2
+            nb_encode_threads = 1
+            to = "{to}"
+            to_bind = "127.0.0.1:0"
+            to_bind_retries = 0
+            to_mtu = 1500
+            "#
+        ))
+        .expect("valid configuration");
+        let sender = send::Sender::<net::TcpStream>::new(config, raptorq).expect("sender");
+
+        let sending = net::UdpSocket::bind("127.0.0.1:0").expect("bind");
+        let mut udp = udp::Send::new(sending.as_raw_fd(), to, None, None).expect("send");
+        let burst = 1_000_000;
+        let mut rate_limit = RateLimit::new(8 * 1_000_000, burst);
+
+        let packets = packets(&sender.raptorq);
+        let nb_packets = packets.len();
+        let payload_len = packets
+            .iter()
+            .map(|packet| packet.data().len())
+            .sum::<usize>();
+        send_packets(&sender, &mut udp, Some(&mut rate_limit), packets).expect("send packets");
+
+        // RaptorQ payload id and tag of every datagram included
+        let datagram_len = sender.raptorq.datagram_len();
+        let expected = nb_packets * datagram_len;
+        assert_eq!(
+            expected,
+            payload_len + nb_packets * (4 + usize::from(protocol::PACKET_TAG_SIZE))
+        );
+        assert!(
+            (rate_limit.burst
+                - rate_limit.tokens
+                - f64::from(u32::try_from(expected).expect("bytes")))
+            .abs()
+                < f64::EPSILON
+        );
+        assert_eq!(sender.stats().bytes_sent, expected as u64);
+
+        let mut buffer = [0; 1500];
+        for _ in 0..nb_packets {
+            let (len, _) = receiving.recv_from(&mut buffer).expect("datagram");
+            assert_eq!(len, datagram_len);
+        }
+    }
+}
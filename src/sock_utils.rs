@@ -1,32 +1,274 @@
-//! Bindings and wrappers for socket buffer size libc functions
+//! Bindings and wrappers for socket options libc functions (buffer sizes, receive timeout,
+//! IPv6 hop limit, dual-stack, multicast, port reuse)
 
-use std::os::fd::AsRawFd;
-use std::{io, mem, ptr};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::{io, mem, net, ptr, time};
+
+/// Binds a new UDP socket to `addr`, once `configure` has been applied to it, so that options
+/// only effective on unbound sockets can be set
+pub(crate) fn bind_udp_socket<F>(
+    addr: net::SocketAddr,
+    configure: F,
+) -> Result<net::UdpSocket, io::Error>
+where
+    F: FnOnce(&net::UdpSocket) -> Result<(), io::Error>,
+{
+    let domain = if addr.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // the socket now owns the descriptor, closing it on error
+    let socket = unsafe { net::UdpSocket::from_raw_fd(fd) };
+
+    configure(&socket)?;
+
+    let (sockaddr, len) = sockaddr(addr)?;
+    if unsafe { libc::bind(fd, (&raw const sockaddr).cast::<libc::sockaddr>(), len) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(socket)
+}
+
+fn sockaddr(addr: net::SocketAddr) -> Result<(libc::sockaddr_storage, libc::socklen_t), io::Error> {
+    let mut storage = unsafe { mem::zeroed::<libc::sockaddr_storage>() };
+    let len = match addr {
+        net::SocketAddr::V4(addr4) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::sa_family_t::try_from(libc::AF_INET).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("libc::AF_INET: {e}"))
+                })?,
+                sin_port: addr4.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from(*addr4.ip()).to_be(),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe { ptr::write((&raw mut storage).cast::<libc::sockaddr_in>(), sin) };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        net::SocketAddr::V6(addr6) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::sa_family_t::try_from(libc::AF_INET6).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("libc::AF_INET6: {e}"))
+                })?,
+                sin6_port: addr6.port().to_be(),
+                sin6_flowinfo: addr6.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr6.ip().octets(),
+                },
+                sin6_scope_id: addr6.scope_id(),
+            };
+            unsafe { ptr::write((&raw mut storage).cast::<libc::sockaddr_in6>(), sin6) };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    let len = libc::socklen_t::try_from(len)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("len: {e}")))?;
+    Ok((storage, len))
+}
+
+/// Sets whether IPv6 `socket` only exchanges IPv6 traffic (`IPV6_V6ONLY`), or also IPv4
+/// traffic through IPv4-mapped addresses when bound to `::`; must be set before binding
+pub(crate) fn set_socket_ipv6_only<S: AsRawFd>(socket: &S, only: bool) -> Result<(), io::Error> {
+    unsafe {
+        setsockopt_int(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            i32::from(only),
+        )
+    }
+}
+
+pub(crate) fn get_socket_ipv6_only<S: AsRawFd>(socket: &S) -> Result<bool, io::Error> {
+    Ok(unsafe { getsockopt_int(socket.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_V6ONLY) }? != 0)
+}
+
+/// Applies `ipv6_only`, when set, to `socket` about to be bound to `addr`, returning `false` if
+/// it is ignored because `addr` is an IPv4 address
+pub(crate) fn configure_ipv6_only<S: AsRawFd>(
+    socket: &S,
+    addr: net::SocketAddr,
+    ipv6_only: Option<bool>,
+) -> Result<bool, io::Error> {
+    match ipv6_only {
+        Some(only) if addr.is_ipv6() => set_socket_ipv6_only(socket, only).map(|()| true),
+        Some(_) => Ok(false),
+        None => Ok(true),
+    }
+}
+
+/// Lets other sockets bind to the same address as `socket`, the kernel spreading the datagrams
+/// of distinct sources between them; must be set before binding
+pub(crate) fn set_socket_reuseport<S: AsRawFd>(socket: &S) -> Result<(), io::Error> {
+    unsafe { setsockopt_int(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_REUSEPORT, 1) }
+}
 
 pub(crate) fn set_socket_send_buffer_size<S: AsRawFd>(
     socket: &S,
     size: i32,
 ) -> Result<(), io::Error> {
-    unsafe { setsockopt_buffer_size(socket.as_raw_fd(), size, libc::SO_SNDBUF) }
+    unsafe { setsockopt_int(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_SNDBUF, size) }
 }
 
 pub(crate) fn set_socket_recv_buffer_size<S: AsRawFd>(
     socket: &S,
     size: i32,
 ) -> Result<(), io::Error> {
-    unsafe { setsockopt_buffer_size(socket.as_raw_fd(), size, libc::SO_RCVBUF) }
+    unsafe { setsockopt_int(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVBUF, size) }
+}
+
+/// Sets the hop limit of unicast IPv6 packets sent through `socket`
+pub(crate) fn set_socket_ipv6_hop_limit<S: AsRawFd>(socket: &S, hops: u8) -> Result<(), io::Error> {
+    unsafe {
+        setsockopt_int(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_UNICAST_HOPS,
+            i32::from(hops),
+        )
+    }
+}
+
+pub(crate) fn get_socket_ipv6_hop_limit<S: AsRawFd>(socket: &S) -> Result<i32, io::Error> {
+    unsafe {
+        getsockopt_int(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_UNICAST_HOPS,
+        )
+    }
+}
+
+/// Sets the hop limit of multicast IPv6 packets sent through `socket`
+pub(crate) fn set_socket_ipv6_multicast_hops<S: AsRawFd>(
+    socket: &S,
+    hops: u32,
+) -> Result<(), io::Error> {
+    let hops = i32::try_from(hops)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("hops: {e}")))?;
+    unsafe {
+        setsockopt_int(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MULTICAST_HOPS,
+            hops,
+        )
+    }
+}
+
+/// Sets the interface, by index, through which multicast IPv6 packets are sent
+pub(crate) fn set_socket_ipv6_multicast_if<S: AsRawFd>(
+    socket: &S,
+    index: u32,
+) -> Result<(), io::Error> {
+    let index = i32::try_from(index)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("index: {e}")))?;
+    unsafe {
+        setsockopt_int(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MULTICAST_IF,
+            index,
+        )
+    }
+}
+
+/// Sets the interface, by local address, through which multicast IPv4 packets are sent
+pub(crate) fn set_socket_ipv4_multicast_if<S: AsRawFd>(
+    socket: &S,
+    addr: net::Ipv4Addr,
+) -> Result<(), io::Error> {
+    let addr = libc::in_addr {
+        s_addr: u32::from(addr).to_be(),
+    };
+    unsafe {
+        setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MULTICAST_IF,
+            &addr,
+        )
+    }
+}
+
+/// Sets the receive timeout (`SO_RCVTIMEO`) of `socket`, blocking receives waiting forever when
+/// `timeout` is `None`
+pub(crate) fn set_socket_recv_timeout<S: AsRawFd>(
+    socket: &S,
+    timeout: Option<time::Duration>,
+) -> Result<(), io::Error> {
+    let timeout = timeout.unwrap_or_default();
+    let timeval = libc::timeval {
+        tv_sec: libc::time_t::try_from(timeout.as_secs())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("timeout: {e}")))?,
+        tv_usec: libc::suseconds_t::from(timeout.subsec_micros()),
+    };
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            (&raw const timeval).cast::<libc::c_void>(),
+            libc::socklen_t::try_from(mem::size_of::<libc::timeval>())
+                .map_err(|e| io::Error::other(format!("timeval size: {e}")))?,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Sets the size of the datagrams a buffer sent through `socket` is segmented in by the kernel
+/// (Generic Segmentation Offload)
+pub(crate) fn set_socket_udp_segment<S: AsRawFd>(
+    socket: &S,
+    segment_size: usize,
+) -> Result<(), io::Error> {
+    let segment_size = i32::try_from(segment_size)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("segment_size: {e}")))?;
+    unsafe {
+        setsockopt_int(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            segment_size,
+        )
+    }
 }
 
-unsafe fn setsockopt_buffer_size(fd: i32, size: i32, option_name: i32) -> Result<(), io::Error> {
-    let len = libc::socklen_t::try_from(mem::size_of::<libc::c_int>())
+/// Lets the kernel aggregate datagrams received on `socket` into a single buffer (Generic Receive
+/// Offload), their size being then given in a `UDP_GRO` control message
+pub(crate) fn set_socket_udp_gro<S: AsRawFd>(socket: &S) -> Result<(), io::Error> {
+    unsafe { setsockopt_int(socket.as_raw_fd(), libc::SOL_UDP, libc::UDP_GRO, 1) }
+}
+
+unsafe fn setsockopt_int(
+    fd: i32,
+    level: i32,
+    option_name: i32,
+    value: i32,
+) -> Result<(), io::Error> {
+    unsafe { setsockopt(fd, level, option_name, &value) }
+}
+
+unsafe fn setsockopt<T>(fd: i32, level: i32, option_name: i32, value: &T) -> Result<(), io::Error> {
+    let len = libc::socklen_t::try_from(mem::size_of::<T>())
         .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("len: {e}")))?;
 
     let res = unsafe {
         libc::setsockopt(
             fd,
-            libc::SOL_SOCKET,
+            level,
             option_name,
-            ptr::addr_of!(size).cast::<libc::c_void>(),
+            ptr::from_ref(value).cast::<libc::c_void>(),
             len,
         )
     };
@@ -39,21 +281,21 @@ unsafe fn setsockopt_buffer_size(fd: i32, size: i32, option_name: i32) -> Result
 }
 
 pub(crate) fn get_socket_send_buffer_size<S: AsRawFd>(socket: &S) -> Result<i32, io::Error> {
-    unsafe { getsockopt_buffer_size(socket.as_raw_fd(), libc::SO_SNDBUF) }
+    unsafe { getsockopt_int(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_SNDBUF) }
 }
 
 pub(crate) fn get_socket_recv_buffer_size<S: AsRawFd>(socket: &S) -> Result<i32, io::Error> {
-    unsafe { getsockopt_buffer_size(socket.as_raw_fd(), libc::SO_RCVBUF) }
+    unsafe { getsockopt_int(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVBUF) }
 }
 
-unsafe fn getsockopt_buffer_size(fd: i32, option_name: i32) -> Result<i32, io::Error> {
+unsafe fn getsockopt_int(fd: i32, level: i32, option_name: i32) -> Result<i32, io::Error> {
     let mut sz = 0i32;
     let mut len = libc::socklen_t::try_from(mem::size_of::<libc::c_int>())
         .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("len: {e}")))?;
     let res = unsafe {
         libc::getsockopt(
             fd,
-            libc::SOL_SOCKET,
+            level,
             option_name,
             ptr::addr_of_mut!(sz).cast::<libc::c_void>(),
             &raw mut len,
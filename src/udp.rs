@@ -1,22 +1,152 @@
-//! Functions and wrappers over libc's UDP socket multiple messages receive and send
+//! Functions and wrappers over libc's UDP socket multiple messages receive and send, and over
+//! `io_uring` for receiving with the `io_uring` feature
+
+use crate::sock_utils;
+use std::{io, mem, net, num, ops, pin, ptr, slice, time};
+
+/// Batch sizes accepted for `recvmmsg`/`sendmmsg`, the kernel silently capping the number of
+/// messages per call to `UIO_MAXIOV` (1024)
+const BATCH_SIZE_RANGE: ops::RangeInclusive<u32> = 2..=1024;
+
+/// Checks a configured batch size, a batch of 1 datagram meaning no batching
+fn check_batch_size(batch: Option<u32>) -> Result<Option<u32>, io::Error> {
+    match batch {
+        None | Some(1) => Ok(None),
+        Some(n) if BATCH_SIZE_RANGE.contains(&n) => Ok(Some(n)),
+        Some(n) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "invalid batch size {n}, must be between {} and {}",
+                BATCH_SIZE_RANGE.start(),
+                BATCH_SIZE_RANGE.end()
+            ),
+        )),
+    }
+}
+
+/// Numbers of receive buffers accepted for an `io_uring` ring
+const RING_SIZE_RANGE: ops::RangeInclusive<u32> = 2..=4096;
+
+/// Maximum number of segments the kernel accepts in a single GSO send (`UDP_MAX_SEGMENTS`)
+const GSO_MAX_SEGMENTS: usize = 64;
+/// Maximum size of the buffer of a single GSO send, the largest UDP payload over IPv4
+const GSO_MAX_SIZE: usize = 65507;
+/// Size of the receive buffers with GRO, large enough for the datagrams the kernel may aggregate
+const GRO_BUFFER_SIZE: usize = 65535;
+
+/// Size of the control buffer a `UDP_GRO` control message (an `int`) is received into
+#[allow(clippy::cast_possible_truncation)]
+fn gro_control_len() -> usize {
+    unsafe { libc::CMSG_SPACE(mem::size_of::<i32>() as u32) as usize }
+}
+
+/// Segment size of the datagrams aggregated into the buffer of `msghdr`, found in its `UDP_GRO`
+/// control message, if any
+fn gro_segment_size(msghdr: &libc::msghdr) -> Option<usize> {
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(msghdr) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        if header.cmsg_level == libc::SOL_UDP && header.cmsg_type == libc::UDP_GRO {
+            let segment_size = unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<i32>()) };
+            return usize::try_from(segment_size).ok().filter(|size| 0 < *size);
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(msghdr, cmsg) };
+    }
+    None
+}
+
+/// Appends to `datagrams` those received into `buffer`: a single one without `segment_size`,
+/// otherwise those aggregated by the kernel, all of `segment_size` bytes but the last one
+fn push_datagrams(datagrams: &mut Vec<Vec<u8>>, buffer: &[u8], segment_size: Option<usize>) {
+    match segment_size {
+        Some(segment_size) => datagrams.extend(buffer.chunks(segment_size).map(<[u8]>::to_vec)),
+        None => datagrams.push(buffer.to_vec()),
+    }
+}
+
+/// Page size assumed when it cannot be queried
+const DEFAULT_PAGE_SIZE: usize = 4096;
 
-use std::{io, mem, net, num, pin, ptr};
+fn page_size() -> usize {
+    usize::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) })
+        .ok()
+        .filter(|size| 0 < *size)
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+}
+
+/// Writes a byte in every page spanned by `buffer`, so that all of them are mapped
+fn prefault(buffer: &mut [u8], page_size: usize) {
+    let Some(last) = buffer.len().checked_sub(1) else {
+        return;
+    };
+    // the last byte may lie in a page not reached by the page-sized steps
+    for i in (0..buffer.len()).step_by(page_size).chain([last]) {
+        unsafe { ptr::write_volatile(&raw mut buffer[i], buffer[i]) };
+    }
+}
+
+/// Pre-faults `buffer` then locks it in memory, so that receiving into it never triggers a page
+/// fault
+fn lock(buffer: &mut [u8], page_size: usize) -> Result<(), io::Error> {
+    prefault(buffer, page_size);
+    if unsafe { libc::mlock(buffer.as_ptr().cast::<libc::c_void>(), buffer.len()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
 
 pub(crate) enum Datagrams {
     Single(Vec<u8>),
     Multiple(Vec<Vec<u8>>),
 }
 
+impl Datagrams {
+    /// `recvmmsg` may return no message at all (e.g. when interrupted), resulting in an empty
+    /// `Multiple` batch
+    pub(crate) fn is_empty(&self) -> bool {
+        match self {
+            Self::Single(_) => false,
+            Self::Multiple(datagrams) => datagrams.is_empty(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Self::Single(_) => 1,
+            Self::Multiple(datagrams) => datagrams.len(),
+        }
+    }
+
+    /// Total size of the datagrams
+    pub(crate) fn nb_bytes(&self) -> usize {
+        match self {
+            Self::Single(datagram) => datagram.len(),
+            Self::Multiple(datagrams) => datagrams.iter().map(Vec::len).sum(),
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        match self {
+            Self::Single(datagram) => slice::from_ref(datagram).iter(),
+            Self::Multiple(datagrams) => datagrams.iter(),
+        }
+        .map(Vec::as_slice)
+    }
+}
+
 pub(crate) struct ReceiveMsg {
     socket: i32,
     udp_packet_size: u16,
     msghdr: libc::msghdr,
     _iovec: pin::Pin<Box<libc::iovec>>,
     buffer: pin::Pin<Vec<u8>>,
+    /// Receives the `UDP_GRO` control message, empty without GRO
+    control: pin::Pin<Vec<u8>>,
 }
 
 impl ReceiveMsg {
-    fn new(socket: i32, udp_packet_size: u16) -> Self {
+    /// With `gro`, the buffer is large enough for the datagrams the kernel may aggregate
+    fn new(socket: i32, udp_packet_size: u16, gro: bool) -> Self {
         let iovec = unsafe { mem::zeroed::<libc::iovec>() };
         let mut iovec = pin::Pin::new(Box::new(iovec));
 
@@ -24,10 +154,20 @@ impl ReceiveMsg {
         msghdr.msg_iov = &raw mut *iovec;
         msghdr.msg_iovlen = 1;
 
-        let mut buffer = pin::Pin::new(vec![0u8; udp_packet_size as usize]);
+        let buffer_size = if gro {
+            GRO_BUFFER_SIZE
+        } else {
+            udp_packet_size as usize
+        };
+        let mut buffer = pin::Pin::new(vec![0u8; buffer_size]);
 
         iovec.iov_base = buffer.as_mut_ptr().cast::<libc::c_void>();
-        iovec.iov_len = udp_packet_size as usize;
+        iovec.iov_len = buffer_size;
+
+        let mut control = pin::Pin::new(vec![0u8; if gro { gro_control_len() } else { 0 }]);
+        if gro {
+            msghdr.msg_control = control.as_mut_ptr().cast::<libc::c_void>();
+        }
 
         Self {
             socket,
@@ -35,37 +175,64 @@ impl ReceiveMsg {
             msghdr,
             _iovec: iovec,
             buffer,
+            control,
         }
     }
 
     fn recv(&mut self) -> Result<Datagrams, io::Error> {
+        // the kernel shrinks the control length to the size of the messages received
+        self.msghdr.msg_controllen = self.control.len();
+
         let recv = unsafe { libc::recvmsg(self.socket, &raw mut self.msghdr, 0) };
 
         if recv < 0 {
             let errno = unsafe { *libc::__errno_location() };
-            return Err(io::Error::other(format!(
-                "libc::recvmsg {recv} != {}, (errno == {errno})",
-                self.udp_packet_size
-            )));
+            return Err(io::Error::new(
+                io::Error::from_raw_os_error(errno).kind(),
+                format!(
+                    "libc::recvmsg {recv} != {}, (errno == {errno})",
+                    self.udp_packet_size
+                ),
+            ));
         }
 
         let recv = usize::try_from(recv)
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("recv: {e}")))?;
 
-        Ok(Datagrams::Single(self.buffer[0..recv].to_vec()))
+        let segment_size = if self.control.is_empty() {
+            None
+        } else {
+            gro_segment_size(&self.msghdr)
+        };
+
+        if segment_size.is_none() {
+            return Ok(Datagrams::Single(self.buffer[0..recv].to_vec()));
+        }
+
+        let mut datagrams = Vec::new();
+        push_datagrams(&mut datagrams, &self.buffer[0..recv], segment_size);
+        Ok(Datagrams::Multiple(datagrams))
     }
 }
 
 pub(crate) struct ReceiveMmsg {
     socket: i32,
+    buffer_size: usize,
     mmsghdr: Vec<libc::mmsghdr>,
-    _iovecs: pin::Pin<Vec<libc::iovec>>,
+    iovecs: pin::Pin<Vec<libc::iovec>>,
     buffers: Vec<pin::Pin<Vec<u8>>>,
+    /// Receive the `UDP_GRO` control messages, empty without GRO
+    controls: Vec<pin::Pin<Vec<u8>>>,
     batch_size: u32,
+    flags: i32,
 }
 
 impl ReceiveMmsg {
-    fn new(socket: i32, udp_packet_size: u16, batch_size: u32) -> Self {
+    /// When `wait_full` is set, `recvmmsg` blocks until `batch_size` datagrams have been
+    /// received, otherwise it returns as soon as at least one datagram is available
+    /// (`MSG_WAITFORONE`). With `gro`, every buffer is large enough for the datagrams the kernel
+    /// may aggregate.
+    fn new(socket: i32, udp_packet_size: u16, batch_size: u32, wait_full: bool, gro: bool) -> Self {
         let iovecs = vec![unsafe { mem::zeroed::<libc::iovec>() }; batch_size as usize];
         let mut iovecs = pin::Pin::new(iovecs);
 
@@ -75,37 +242,66 @@ impl ReceiveMmsg {
             mmsghdr[i].msg_hdr.msg_iovlen = 1;
         }
 
-        let mut buffers =
-            vec![pin::Pin::new(vec![0u8; udp_packet_size as usize]); batch_size as usize];
+        let buffer_size = if gro {
+            GRO_BUFFER_SIZE
+        } else {
+            udp_packet_size as usize
+        };
+        let mut buffers = vec![pin::Pin::new(vec![0u8; buffer_size]); batch_size as usize];
 
         for (i, buffer) in buffers.iter_mut().enumerate() {
             iovecs[i].iov_base = buffer.as_mut_ptr().cast::<libc::c_void>();
-            iovecs[i].iov_len = udp_packet_size as usize;
+            iovecs[i].iov_len = buffer_size;
+        }
+
+        let mut controls = Vec::new();
+        if gro {
+            controls = vec![pin::Pin::new(vec![0u8; gro_control_len()]); batch_size as usize];
+            for (mmsghdr, control) in mmsghdr.iter_mut().zip(controls.iter_mut()) {
+                mmsghdr.msg_hdr.msg_control = control.as_mut_ptr().cast::<libc::c_void>();
+            }
         }
 
         Self {
             socket,
+            buffer_size,
             mmsghdr,
-            _iovecs: iovecs,
+            iovecs,
             buffers,
+            controls,
             batch_size,
+            flags: if wait_full { 0 } else { libc::MSG_WAITFORONE },
         }
     }
 
     fn recv(&mut self) -> Result<Datagrams, io::Error> {
+        // restore the full capacity of every buffer and clear previous results, so that nothing
+        // from a previous (possibly short or truncated) receive can affect this one
+        for (mmsghdr, iovec) in self.mmsghdr.iter_mut().zip(self.iovecs.iter_mut()) {
+            iovec.iov_len = self.buffer_size;
+            mmsghdr.msg_len = 0;
+            mmsghdr.msg_hdr.msg_flags = 0;
+        }
+        for (mmsghdr, control) in self.mmsghdr.iter_mut().zip(self.controls.iter()) {
+            mmsghdr.msg_hdr.msg_controllen = control.len();
+        }
+
         let nb_msg = unsafe {
             libc::recvmmsg(
                 self.socket,
                 self.mmsghdr.as_mut_ptr(),
                 self.batch_size,
-                libc::MSG_WAITFORONE,
+                self.flags,
                 ptr::null_mut(),
             )
         };
 
         if nb_msg == -1 {
             let errno = unsafe { *libc::__errno_location() };
-            Err(io::Error::other(format!("libc::recvmmsg, errno = {errno}")))
+            Err(io::Error::new(
+                io::Error::from_raw_os_error(errno).kind(),
+                format!("libc::recvmmsg, errno = {errno}"),
+            ))
         } else {
             let nb_msg = usize::try_from(nb_msg)
                 .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("nb_msg: {e}")))?;
@@ -116,7 +312,12 @@ impl ReceiveMmsg {
                     let msg_len = usize::try_from(self.mmsghdr[i].msg_len).map_err(|e| {
                         io::Error::new(io::ErrorKind::InvalidData, format!("msg_len: {e}"))
                     })?;
-                    res.push(buffer[0..msg_len].to_vec());
+                    let segment_size = if self.controls.is_empty() {
+                        None
+                    } else {
+                        gro_segment_size(&self.mmsghdr[i].msg_hdr)
+                    };
+                    push_datagrams(&mut res, &buffer[0..msg_len], segment_size);
                     Ok::<_, io::Error>(res)
                 },
             )?;
@@ -126,16 +327,224 @@ impl ReceiveMmsg {
     }
 }
 
+/// Receiver keeping `ring_size` `recvmsg` operations submitted to an `io_uring` ring, each one
+/// with its own buffer, and resubmitting them as soon as their completions are reaped, so that
+/// datagrams are received without one system call per batch
+#[cfg(feature = "io_uring")]
+pub(crate) struct ReceiveRing {
+    ring: io_uring::IoUring,
+    socket: i32,
+    msghdrs: pin::Pin<Vec<libc::msghdr>>,
+    _iovecs: pin::Pin<Vec<libc::iovec>>,
+    buffers: Vec<pin::Pin<Vec<u8>>>,
+    timeout: Option<io_uring::types::Timespec>,
+}
+
+#[cfg(feature = "io_uring")]
+impl ReceiveRing {
+    fn new(
+        socket: i32,
+        udp_packet_size: u16,
+        ring_size: u32,
+        timeout: Option<time::Duration>,
+    ) -> Result<Self, io::Error> {
+        let ring = io_uring::IoUring::new(ring_size)?;
+
+        let mut iovecs = pin::Pin::new(vec![
+            unsafe { mem::zeroed::<libc::iovec>() };
+            ring_size as usize
+        ]);
+        let mut msghdrs = pin::Pin::new(vec![
+            unsafe { mem::zeroed::<libc::msghdr>() };
+            ring_size as usize
+        ]);
+        let mut buffers =
+            vec![pin::Pin::new(vec![0u8; udp_packet_size as usize]); ring_size as usize];
+
+        for (i, buffer) in buffers.iter_mut().enumerate() {
+            iovecs[i].iov_base = buffer.as_mut_ptr().cast::<libc::c_void>();
+            iovecs[i].iov_len = udp_packet_size as usize;
+            msghdrs[i].msg_iov = &raw mut iovecs[i];
+            msghdrs[i].msg_iovlen = 1;
+        }
+
+        let mut receiver = Self {
+            ring,
+            socket,
+            msghdrs,
+            _iovecs: iovecs,
+            buffers,
+            timeout: timeout.map(io_uring::types::Timespec::from),
+        };
+        for i in 0..receiver.buffers.len() {
+            receiver.push(i)?;
+        }
+        Ok(receiver)
+    }
+
+    /// Queues a `recvmsg` operation into buffer `i`, submitted with the next `recv` call
+    fn push(&mut self, i: usize) -> Result<(), io::Error> {
+        let entry = io_uring::opcode::RecvMsg::new(
+            io_uring::types::Fd(self.socket),
+            &raw mut self.msghdrs[i],
+        )
+        .build()
+        .user_data(i as u64);
+
+        // there are as many submission queue entries as buffers
+        unsafe { self.ring.submission().push(&entry) }
+            .map_err(|e| io::Error::other(format!("io_uring submission: {e}")))
+    }
+
+    fn recv(&mut self) -> Result<Datagrams, io::Error> {
+        let submitted = match self.timeout.as_ref() {
+            None => self.ring.submit_and_wait(1),
+            Some(timeout) => self
+                .ring
+                .submitter()
+                .submit_with_args(1, &io_uring::types::SubmitArgs::new().timespec(timeout)),
+        };
+        match submitted {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) if e.raw_os_error() == Some(libc::ETIME) => {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            Err(e) => return Err(e),
+        }
+
+        let completed = self
+            .ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect::<Vec<_>>();
+
+        let mut datagrams = Vec::with_capacity(completed.len());
+        for (i, result) in completed {
+            let i = usize::try_from(i)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("i: {e}")))?;
+            // interrupted or timed out receives are resubmitted
+            if result < 0 && !matches!(-result, libc::EINTR | libc::EAGAIN) {
+                return Err(io::Error::other(format!(
+                    "io_uring recvmsg, errno = {}",
+                    -result
+                )));
+            }
+            if let Ok(len) = usize::try_from(result) {
+                datagrams.push(self.buffers[i][0..len].to_vec());
+            }
+            self.push(i)?;
+        }
+
+        Ok(Datagrams::Multiple(datagrams))
+    }
+}
+
 pub(crate) enum Receive {
     Msg(ReceiveMsg),
     Mmsg(ReceiveMmsg),
+    #[cfg(feature = "io_uring")]
+    Ring(Box<ReceiveRing>),
 }
 
 impl Receive {
-    pub(crate) fn new(socket: i32, udp_packet_size: u16, batch_receive: Option<u32>) -> Self {
-        match batch_receive {
-            None | Some(1) => Self::Msg(ReceiveMsg::new(socket, udp_packet_size)),
-            Some(n) => Self::Mmsg(ReceiveMmsg::new(socket, udp_packet_size, n)),
+    /// `recv` fails with a `WouldBlock` error when no datagram is received within `timeout`
+    ///
+    /// With `gro`, the kernel is asked to aggregate datagrams into a single buffer (UDP Generic
+    /// Receive Offload), split back by `recv`; a warning is logged if it cannot, or with an
+    /// `io_uring` ring, which does not support it.
+    pub(crate) fn new(
+        socket: i32,
+        udp_packet_size: u16,
+        batch_receive: Option<u32>,
+        batch_wait_full: bool,
+        ring_size: Option<u32>,
+        timeout: Option<time::Duration>,
+        gro: bool,
+    ) -> Result<Self, io::Error> {
+        sock_utils::set_socket_recv_timeout(&socket, timeout)?;
+
+        if gro && ring_size.is_some() {
+            log::warn!("UDP GRO is not supported with io_uring, ignored");
+        }
+        let gro = gro && ring_size.is_none() && Self::enable_gro(socket);
+
+        if let Some(ring_size) = ring_size {
+            if !RING_SIZE_RANGE.contains(&ring_size) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "invalid io_uring ring size {ring_size}, must be between {} and {}",
+                        RING_SIZE_RANGE.start(),
+                        RING_SIZE_RANGE.end()
+                    ),
+                ));
+            }
+
+            #[cfg(feature = "io_uring")]
+            match ReceiveRing::new(socket, udp_packet_size, ring_size, timeout) {
+                Ok(receiver) => {
+                    log::info!("receiving with an io_uring ring of {ring_size} buffers");
+                    return Ok(Self::Ring(Box::new(receiver)));
+                }
+                Err(e) => log::warn!("io_uring unavailable ({e}), falling back to recvmmsg"),
+            }
+            #[cfg(not(feature = "io_uring"))]
+            log::warn!("io_uring support not compiled in, falling back to recvmmsg");
+
+            let batch_size = ring_size.min(*BATCH_SIZE_RANGE.end());
+            return Ok(Self::Mmsg(ReceiveMmsg::new(
+                socket,
+                udp_packet_size,
+                batch_size,
+                false,
+                false,
+            )));
+        }
+
+        match check_batch_size(batch_receive)? {
+            None => Ok(Self::Msg(ReceiveMsg::new(socket, udp_packet_size, gro))),
+            Some(n) => Ok(Self::Mmsg(ReceiveMmsg::new(
+                socket,
+                udp_packet_size,
+                n,
+                batch_wait_full,
+                gro,
+            ))),
+        }
+    }
+
+    /// Enables UDP GRO on `socket`, returning whether it is enabled
+    fn enable_gro(socket: i32) -> bool {
+        match sock_utils::set_socket_udp_gro(&socket) {
+            Ok(()) => {
+                log::info!("UDP GRO enabled, up to {GRO_BUFFER_SIZE} bytes per receive buffer");
+                true
+            }
+            Err(e) => {
+                log::warn!("UDP GRO unavailable ({e}), receiving datagrams one by one");
+                false
+            }
+        }
+    }
+
+    /// Pre-faults and locks the receive buffers in memory, returning their total size
+    pub(crate) fn lock_buffers(&mut self) -> Result<usize, io::Error> {
+        let page_size = page_size();
+        match self {
+            Self::Msg(receiver) => {
+                lock(&mut receiver.buffer, page_size)?;
+                Ok(receiver.buffer.len())
+            }
+            Self::Mmsg(receiver) => receiver.buffers.iter_mut().try_fold(0, |size, buffer| {
+                lock(buffer, page_size)?;
+                Ok(size + buffer.len())
+            }),
+            #[cfg(feature = "io_uring")]
+            Self::Ring(receiver) => receiver.buffers.iter_mut().try_fold(0, |size, buffer| {
+                lock(buffer, page_size)?;
+                Ok(size + buffer.len())
+            }),
         }
     }
 
@@ -143,6 +552,8 @@ impl Receive {
         match self {
             Self::Msg(receiver) => receiver.recv(),
             Self::Mmsg(receiver) => receiver.recv(),
+            #[cfg(feature = "io_uring")]
+            Self::Ring(receiver) => receiver.recv(),
         }
     }
 }
@@ -153,6 +564,14 @@ enum SendM {
         msghdr: libc::msghdr,
         iovec: pin::Pin<Box<libc::iovec>>,
     },
+    /// Equal-sized datagrams coalesced in a single buffer segmented by the kernel (`UDP_SEGMENT`)
+    Gso {
+        socket: i32,
+        msghdr: libc::msghdr,
+        iovec: pin::Pin<Box<libc::iovec>>,
+        segment_size: usize,
+        buffer: Vec<u8>,
+    },
     Mmsg {
         socket: i32,
         batch_size: usize,
@@ -161,25 +580,78 @@ enum SendM {
     },
 }
 
+/// Message header sending a single buffer, pointed to by the returned `iovec`, to `dest`
+fn single_msghdr(
+    dest: *mut libc::sockaddr,
+    dest_len: u32,
+) -> (libc::msghdr, pin::Pin<Box<libc::iovec>>) {
+    let iovec = unsafe { mem::zeroed::<libc::iovec>() };
+    let mut iovec = pin::Pin::new(Box::new(iovec));
+
+    let mut msghdr = unsafe { mem::zeroed::<libc::msghdr>() };
+
+    msghdr.msg_name = dest.cast::<libc::c_void>();
+    msghdr.msg_namelen = dest_len;
+    msghdr.msg_iov = &raw mut *iovec;
+    msghdr.msg_iovlen = 1;
+
+    (msghdr, iovec)
+}
+
+/// Sends `buffer` with `msghdr`, whose `iovec` is updated to point to it
+fn sendmsg(
+    socket: i32,
+    msghdr: &libc::msghdr,
+    iovec: &mut libc::iovec,
+    buffer: &mut [u8],
+) -> Result<(), io::Error> {
+    let len = buffer.len();
+
+    iovec.iov_base = buffer.as_mut_ptr().cast();
+    iovec.iov_len = len;
+
+    let sent = unsafe { libc::sendmsg(socket, msghdr, 0) };
+
+    if sent == len.cast_signed() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "libc::sendmsg failed {sent} != {len}"
+        )))
+    }
+}
+
 impl SendM {
     fn new(
         batch_send: Option<u32>,
+        gso_segment_size: Option<usize>,
         socket: i32,
         dest: *mut libc::sockaddr,
         dest_len: u32,
     ) -> Result<Self, io::Error> {
-        match batch_send {
-            None | Some(1) => {
-                let iovec = unsafe { mem::zeroed::<libc::iovec>() };
-                let mut iovec = pin::Pin::new(Box::new(iovec));
-
-                let mut msghdr = unsafe { mem::zeroed::<libc::msghdr>() };
-
-                msghdr.msg_name = dest.cast::<libc::c_void>();
-                msghdr.msg_namelen = dest_len;
-                msghdr.msg_iov = &raw mut *iovec;
-                msghdr.msg_iovlen = 1;
+        if let Some(segment_size) = gso_segment_size {
+            match sock_utils::set_socket_udp_segment(&socket, segment_size) {
+                Ok(()) => {
+                    let max_segments = GSO_MAX_SEGMENTS.min(GSO_MAX_SIZE / segment_size).max(1);
+                    log::info!(
+                        "UDP GSO enabled, up to {max_segments} datagrams of {segment_size} bytes per send"
+                    );
+                    let (msghdr, iovec) = single_msghdr(dest, dest_len);
+                    return Ok(Self::Gso {
+                        socket,
+                        msghdr,
+                        iovec,
+                        segment_size,
+                        buffer: Vec::with_capacity(max_segments * segment_size),
+                    });
+                }
+                Err(e) => log::warn!("UDP GSO unavailable ({e}), sending datagrams one by one"),
+            }
+        }
 
+        match check_batch_size(batch_send)? {
+            None => {
+                let (msghdr, iovec) = single_msghdr(dest, dest_len);
                 Ok(Self::Msg {
                     socket,
                     msghdr,
@@ -212,30 +684,50 @@ impl SendM {
         }
     }
 
-    fn send(&mut self, packets: Vec<raptorq::EncodingPacket>) -> Result<(), io::Error> {
-        let mut datagrams = packets.into_iter().map(|packet| packet.serialize());
+    fn send(&mut self, datagrams: Vec<Vec<u8>>) -> Result<(), io::Error> {
+        let mut datagrams = datagrams.into_iter();
 
         match self {
             Self::Msg {
                 socket,
                 msghdr,
                 iovec,
-            } => datagrams.try_for_each(|mut datagram| {
-                let len = datagram.len();
-
-                iovec.iov_base = datagram.as_mut_ptr().cast();
-                iovec.iov_len = len;
-
-                let sent = unsafe { libc::sendmsg(*socket, msghdr, 0) };
-
-                if sent == len.cast_signed() {
-                    Ok(())
-                } else {
-                    Err(io::Error::other(format!(
-                        "libc::sendmsg failed {sent} != {len}"
-                    )))
+            } => datagrams
+                .try_for_each(|mut datagram| sendmsg(*socket, msghdr, iovec, &mut datagram)),
+            Self::Gso {
+                socket,
+                msghdr,
+                iovec,
+                segment_size,
+                buffer,
+            } => {
+                let mut datagrams = datagrams.peekable();
+                while let Some(datagram) = datagrams.next() {
+                    if *segment_size < datagram.len() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "datagram of {} bytes larger than GSO segments of {segment_size} bytes",
+                                datagram.len()
+                            ),
+                        ));
+                    }
+                    buffer.clear();
+                    buffer.extend_from_slice(&datagram);
+                    // a smaller datagram is sent alone, since the kernel segments buffers in
+                    // datagrams of the segment size
+                    if datagram.len() == *segment_size {
+                        while buffer.len() < buffer.capacity()
+                            && let Some(datagram) =
+                                datagrams.next_if(|datagram| datagram.len() == *segment_size)
+                        {
+                            buffer.extend_from_slice(&datagram);
+                        }
+                    }
+                    sendmsg(*socket, msghdr, iovec, buffer)?;
                 }
-            }),
+                Ok(())
+            }
             Self::Mmsg {
                 socket,
                 batch_size,
@@ -294,6 +786,7 @@ impl Send {
         socket: i32,
         dest: net::SocketAddr,
         batch_send: Option<u32>,
+        gso_segment_size: Option<usize>,
     ) -> Result<Self, io::Error> {
         let (dest, dest_len) = match dest {
             net::SocketAddr::V4(addr4) => {
@@ -345,6 +838,7 @@ impl Send {
         let mut dest: pin::Pin<Box<libc::sockaddr>> = pin::Pin::new(dest);
         let sendm = SendM::new(
             batch_send,
+            gso_segment_size,
             socket,
             (&raw mut *dest).cast::<libc::sockaddr>(),
             u32::try_from(dest_len).map_err(|e| {
@@ -355,10 +849,93 @@ impl Send {
         Ok(Self { _dest: dest, sendm })
     }
 
-    pub(crate) fn send(
-        &mut self,
-        datagrams: Vec<raptorq::EncodingPacket>,
-    ) -> Result<(), io::Error> {
+    pub(crate) fn send(&mut self, datagrams: Vec<Vec<u8>>) -> Result<(), io::Error> {
         self.sendm.send(datagrams)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::AsRawFd;
+
+    /// Sends `nb` datagrams over the loopback, received in batches of up to 8 datagrams, and
+    /// returns the received ones
+    fn send_and_receive(nb: u8, batch_wait_full: bool) -> Vec<Vec<u8>> {
+        let socket = net::UdpSocket::bind("127.0.0.1:0").expect("bind");
+        let mut receive = Receive::new(
+            socket.as_raw_fd(),
+            1500,
+            Some(8),
+            batch_wait_full,
+            None,
+            Some(time::Duration::from_millis(100)),
+            false,
+        )
+        .expect("receive");
+
+        let sender = net::UdpSocket::bind("127.0.0.1:0").expect("bind");
+        for i in 0..nb {
+            sender
+                .send_to(&[i; 100], socket.local_addr().expect("address"))
+                .expect("send");
+        }
+
+        let mut received = Vec::new();
+        while received.len() < usize::from(nb) {
+            let datagrams = receive.recv().expect("recv");
+            received.extend(datagrams.iter().map(<[u8]>::to_vec));
+        }
+        received
+    }
+
+    #[test]
+    fn batch_size_boundaries() {
+        assert!(matches!(check_batch_size(None), Ok(None)));
+        assert!(matches!(check_batch_size(Some(1)), Ok(None)));
+        assert!(matches!(check_batch_size(Some(2)), Ok(Some(2))));
+        assert!(matches!(check_batch_size(Some(1024)), Ok(Some(1024))));
+        assert!(matches!(
+            check_batch_size(Some(1025)),
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput
+        ));
+        assert!(check_batch_size(Some(0)).is_err());
+    }
+
+    #[test]
+    fn batch_modes_deliver_all_datagrams() {
+        let expected: Vec<Vec<u8>> = (0..16).map(|i| vec![i; 100]).collect();
+        assert_eq!(send_and_receive(16, false), expected);
+        assert_eq!(send_and_receive(16, true), expected);
+    }
+
+    #[test]
+    fn prefault_touches_every_page() {
+        let page_size = page_size();
+        let len = 5 * page_size;
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(map, libc::MAP_FAILED);
+        let resident = || {
+            let mut pages = [0u8; 5];
+            assert_eq!(unsafe { libc::mincore(map, len, pages.as_mut_ptr()) }, 0);
+            pages.map(|page| page & 1 == 1)
+        };
+        assert_eq!(resident(), [false; 5]);
+
+        // neither the start nor the end of the buffer is page aligned
+        let map_bytes = unsafe { std::slice::from_raw_parts_mut(map.cast::<u8>(), len) };
+        prefault(&mut map_bytes[page_size / 2..3 * page_size + 10], page_size);
+
+        assert_eq!(resident(), [true, true, true, true, false]);
+        assert_eq!(unsafe { libc::munmap(map, len) }, 0);
+    }
+}